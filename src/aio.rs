@@ -0,0 +1,509 @@
+use std::io;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::{
+    runtime, validation, AddressFamily, ConnectionCustomizer, EndpointSelection,
+    RedisConnectionManager, ValidationMode,
+};
+
+/// A `bb8::ManageConnection` and `deadpool::managed::Manager` for
+/// `redis::aio::Connection`s.
+///
+/// It is configured with the same `RedisConnectionManagerBuilder` as
+/// `RedisConnectionManager`, through `build_async`, and connects and
+/// validates connections the same way: the endpoints are tried according
+/// to the `EndpointSelection`, with the credentials of the
+/// `CredentialsProvider` if set, the `client_name` is announced, the
+/// `ConnectionCustomizer::on_async_connect` hook runs, and connections are
+/// validated with the `ValidationMode`, `validation_timeout` and
+/// `busy_retry`. The `circuit_breaker` and `PoolMetricsSink` apply as well.
+///
+/// Settings that need to block or to track each connection, such as
+/// `read_timeout`, `max_lifetime` or a custom validation, are rejected.
+/// bb8 pools have their own `max_lifetime` and `idle_timeout`.
+/// `connect` and `is_valid` can be used with other pools. Like `redis`, they
+/// run on tokio when called within a tokio runtime, and on async-std
+/// otherwise if the `async-std` feature is enabled. Requires the
+/// `async-std`, `bb8`, `deadpool` or `tokio` feature.
+#[derive(Debug)]
+pub struct RedisAsyncConnectionManager {
+    manager: RedisConnectionManager,
+}
+
+impl RedisAsyncConnectionManager {
+    /// Creates a new `RedisAsyncConnectionManager`.
+    ///
+    /// See `redis::Client::open` for a description of the parameter
+    /// types.
+    pub fn new<T: redis::IntoConnectionInfo>(
+        params: T,
+    ) -> Result<RedisAsyncConnectionManager, redis::RedisError> {
+        RedisConnectionManager::builder().build_async(params)
+    }
+
+    /// Creates a new `RedisAsyncConnectionManager` with the settings of
+    /// `manager`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error naming the first setting of
+    /// `manager` async connections don't support.
+    pub fn from_manager(
+        manager: RedisConnectionManager,
+    ) -> Result<RedisAsyncConnectionManager, redis::RedisError> {
+        match unsupported_setting(&manager) {
+            Some(setting) => Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "setting not supported by async connections",
+                setting.to_string(),
+            )
+                .into()),
+            None => Ok(RedisAsyncConnectionManager { manager }),
+        }
+    }
+
+    /// Returns the URLs of the endpoints with any password masked, see
+    /// `RedisConnectionManager::display_safe_url`.
+    pub fn display_safe_url(&self) -> String {
+        self.manager.display_safe_url()
+    }
+
+    /// Returns a handle to the circuit breaker of this manager, if one was
+    /// configured.
+    pub fn circuit_breaker_handle(&self) -> Option<crate::CircuitBreakerHandle> {
+        self.manager.circuit_breaker_handle()
+    }
+
+    /// Opens a new connection, as `r2d2::ManageConnection::connect` does
+    /// for `RedisConnectionManager`.
+    pub async fn connect(&self) -> redis::RedisResult<redis::aio::Connection> {
+        connect(&self.manager).await
+    }
+
+    /// Checks a connection is usable before it is handed out.
+    pub async fn is_valid(&self, conn: &mut redis::aio::Connection) -> redis::RedisResult<()> {
+        let result = validate(&self.manager, conn).await;
+        if let Err(ref e) = result {
+            self.manager.metrics_sink.validation_failed(e);
+        }
+        result
+    }
+}
+
+/// A kind of connection the async managers open.
+pub(crate) trait AsyncConnection: redis::aio::ConnectionLike + Send + Sized {
+    /// Opens a connection with `client`.
+    fn open(client: redis::Client) -> redis::RedisFuture<'static, Self>;
+
+    /// Runs the hook of `customizer` for this kind of connection.
+    fn customize<'a>(
+        &'a mut self,
+        customizer: &'a dyn ConnectionCustomizer,
+    ) -> redis::RedisFuture<'a, ()>;
+}
+
+impl AsyncConnection for redis::aio::Connection {
+    fn open(client: redis::Client) -> redis::RedisFuture<'static, Self> {
+        Box::pin(async move { client.get_async_connection().await })
+    }
+
+    fn customize<'a>(
+        &'a mut self,
+        customizer: &'a dyn ConnectionCustomizer,
+    ) -> redis::RedisFuture<'a, ()> {
+        customizer.on_async_connect(self)
+    }
+}
+
+/// Opens a new connection with the settings of `manager`, recording the
+/// outcome with its circuit breaker and metrics sink.
+pub(crate) async fn connect<C: AsyncConnection>(
+    manager: &RedisConnectionManager,
+) -> redis::RedisResult<C> {
+    let start = Instant::now();
+    if let Some(ref breaker) = manager.circuit_breaker {
+        breaker.allow()?;
+    }
+    let result = establish(manager).await;
+    match result {
+        Ok(_) => manager.metrics_sink.connection_created(start.elapsed()),
+        Err(ref e) => manager.metrics_sink.connect_failed(e),
+    }
+    if let Some(ref breaker) = manager.circuit_breaker {
+        match result {
+            Ok(_) => breaker.succeeded(),
+            Err(_) => breaker.failed(),
+        }
+    }
+    result
+}
+
+/// Connects to the first endpoint that accepts the connection, starting at
+/// the one given by the `EndpointSelection`.
+async fn establish<C: AsyncConnection>(manager: &RedisConnectionManager) -> redis::RedisResult<C> {
+    let start = match manager.endpoint_selection {
+        EndpointSelection::Failover => 0,
+        EndpointSelection::RoundRobin => manager.next_endpoint.fetch_add(1, Ordering::Relaxed),
+    };
+    let mut last_error = None;
+    for i in 0..manager.endpoints.len() {
+        let endpoint_index = (start + i) % manager.endpoints.len();
+        match establish_to(manager, endpoint_index).await {
+            Ok(conn) => {
+                if manager.endpoint_selection == EndpointSelection::Failover {
+                    manager.failover.connected(endpoint_index);
+                }
+                return Ok(conn);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.expect("no endpoints"))
+}
+
+async fn establish_to<C: AsyncConnection>(
+    manager: &RedisConnectionManager,
+    endpoint_index: usize,
+) -> redis::RedisResult<C> {
+    let endpoint = &manager.endpoints[endpoint_index];
+    let (credentials, _) = manager.credentials(endpoint)?;
+    let client = redis::Client::open(redis::ConnectionInfo {
+        username: credentials.username,
+        passwd: credentials.password,
+        ..endpoint.connection_info.clone()
+    })?;
+    let mut conn = with_timeout(manager.connect_timeout, C::open(client)).await?;
+
+    if let Some(client_name) = manager.next_client_name() {
+        redis::cmd("CLIENT")
+            .arg("SETNAME")
+            .arg(client_name)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+    }
+    conn.customize(&*manager.connection_customizer).await?;
+    Ok(conn)
+}
+
+/// Validates `conn` with the `ValidationMode` of `manager`, retrying while
+/// the server is busy.
+pub(crate) async fn validate<C: AsyncConnection>(
+    manager: &RedisConnectionManager,
+    conn: &mut C,
+) -> redis::RedisResult<()> {
+    let mut retries = 0;
+    loop {
+        let result =
+            with_timeout(manager.validation_timeout, check(&manager.validation, conn)).await;
+        match (result, manager.busy_retry) {
+            (Err(ref e), Some(busy_retry))
+                if retries < busy_retry.max_retries && validation::is_server_busy(e) =>
+            {
+                retries += 1;
+                runtime::sleep(busy_retry.delay).await;
+            }
+            (result, _) => return result,
+        }
+    }
+}
+
+/// Runs the check of `validation` against `conn`.
+///
+/// `CheckConnection` has nothing to check locally on an async connection,
+/// so it doesn't check anything.
+async fn check<C: AsyncConnection>(
+    validation: &ValidationMode,
+    conn: &mut C,
+) -> redis::RedisResult<()> {
+    match *validation {
+        ValidationMode::Ping => redis::cmd("PING").query_async(conn).await,
+        ValidationMode::Command(ref cmd) => {
+            cmd.query_async::<_, redis::Value>(conn).await.map(|_| ())
+        }
+        ValidationMode::CheckConnection | ValidationMode::None => Ok(()),
+        ValidationMode::Custom(_) => unreachable!("rejected by from_manager"),
+    }
+}
+
+/// Awaits `future`, failing with a `TimedOut` I/O error after `timeout`.
+pub(crate) async fn with_timeout<T, F>(
+    timeout: Option<Duration>,
+    future: F,
+) -> redis::RedisResult<T>
+where
+    F: std::future::Future<Output = redis::RedisResult<T>>,
+{
+    match timeout {
+        Some(timeout) => runtime::timeout(timeout, future)
+            .await
+            .unwrap_or_else(|| Err(io::Error::from(io::ErrorKind::TimedOut).into())),
+        None => future.await,
+    }
+}
+
+/// Returns the name of the first setting of `manager` that async
+/// connections can't honor.
+pub(crate) fn unsupported_setting(manager: &RedisConnectionManager) -> Option<&'static str> {
+    if manager.sentinel.is_some() {
+        Some("sentinel")
+    } else if manager.srv.is_some() {
+        Some("srv")
+    } else if manager.resolver.is_some() || manager.address_family != AddressFamily::Any {
+        Some("resolver")
+    } else if manager.read_timeout.is_some() {
+        Some("read_timeout")
+    } else if manager.write_timeout.is_some() {
+        Some("write_timeout")
+    } else if manager.failback {
+        Some("failback_interval")
+    } else if manager.reauthenticate_interval.is_some() {
+        Some("reauthenticate_interval")
+    } else if manager.validation_interval.is_some() {
+        Some("validation_interval")
+    } else if let ValidationMode::Custom(_) = manager.validation {
+        Some("validation")
+    } else if manager.server_role.is_some() {
+        Some("server_role")
+    } else if manager.max_lifetime.is_some() {
+        Some("max_lifetime")
+    } else if manager.max_uses.is_some() {
+        Some("max_uses")
+    } else if manager.backoff.is_some() {
+        Some("reconnect_policy")
+    } else if manager.rate_limiter.is_some() {
+        Some("connect_rate_limit")
+    } else if manager.script_registry.is_some() {
+        Some("script_registry")
+    } else if manager.function_libraries.is_some() {
+        Some("function_libraries")
+    } else if !manager.required_modules.is_empty() {
+        Some("required_modules")
+    } else if manager.reset_on_checkin {
+        Some("reset_on_checkin")
+    } else if manager.check_unread_replies {
+        Some("check_unread_replies")
+    } else {
+        None
+    }
+}
+
+/// Pools `redis::aio::Connection`s in a `bb8::Pool`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{redis, RedisAsyncConnectionManager};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let manager = RedisAsyncConnectionManager::new("redis://localhost").unwrap();
+///     let pool = bb8::Pool::builder().build(manager).await.unwrap();
+///
+///     let mut conn = pool.get().await.unwrap();
+///     redis::cmd("SET")
+///         .arg("key")
+///         .arg(1)
+///         .query_async::<_, ()>(&mut *conn)
+///         .await
+///         .unwrap();
+/// }
+/// ```
+#[cfg(feature = "bb8")]
+#[async_trait::async_trait]
+impl bb8::ManageConnection for RedisAsyncConnectionManager {
+    type Connection = redis::aio::Connection;
+    type Error = redis::RedisError;
+
+    async fn connect(&self) -> Result<redis::aio::Connection, Self::Error> {
+        RedisAsyncConnectionManager::connect(self).await
+    }
+
+    async fn is_valid(
+        &self,
+        conn: &mut bb8::PooledConnection<'_, Self>,
+    ) -> Result<(), Self::Error> {
+        RedisAsyncConnectionManager::is_valid(self, conn).await
+    }
+
+    fn has_broken(&self, _conn: &mut redis::aio::Connection) -> bool {
+        // An async connection doesn't know it is closed until it is used.
+        false
+    }
+}
+
+/// Pools `redis::aio::Connection`s in a `deadpool::managed::Pool`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{redis, RedisAsyncConnectionManager};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let manager = RedisAsyncConnectionManager::new("redis://localhost").unwrap();
+///     let pool = deadpool::managed::Pool::new(manager, 16);
+///
+///     let mut conn = pool.get().await.unwrap();
+///     redis::cmd("SET")
+///         .arg("key")
+///         .arg(1)
+///         .query_async::<_, ()>(&mut *conn)
+///         .await
+///         .unwrap();
+/// }
+/// ```
+#[cfg(feature = "deadpool")]
+#[async_trait::async_trait]
+impl deadpool::managed::Manager<redis::aio::Connection, redis::RedisError>
+    for RedisAsyncConnectionManager
+{
+    async fn create(&self) -> Result<redis::aio::Connection, redis::RedisError> {
+        self.connect().await
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut redis::aio::Connection,
+    ) -> deadpool::managed::RecycleResult<redis::RedisError> {
+        Ok(self.is_valid(conn).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[cfg(feature = "bb8")]
+    #[tokio::test]
+    async fn test_bb8_pool() {
+        let manager = RedisConnectionManager::builder()
+            .client_name("redis_r2d2-async")
+            .db(1)
+            .build_async("redis://localhost")
+            .unwrap();
+        let pool = bb8::Pool::builder()
+            .max_size(2)
+            .build(manager)
+            .await
+            .unwrap();
+        let mut conn = pool.get().await.unwrap();
+        let name: String = redis::cmd("CLIENT")
+            .arg("GETNAME")
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!("redis_r2d2-async", name);
+        assert_eq!(1, redis::aio::ConnectionLike::get_db(&*conn));
+    }
+
+    #[cfg(feature = "deadpool")]
+    #[tokio::test]
+    async fn test_deadpool_pool() {
+        #[derive(Debug)]
+        struct Tracking;
+
+        impl crate::ConnectionCustomizer for Tracking {
+            fn on_async_connect<'a>(
+                &'a self,
+                conn: &'a mut redis::aio::Connection,
+            ) -> redis::RedisFuture<'a, ()> {
+                Box::pin(async move {
+                    redis::cmd("SET")
+                        .arg("redis_r2d2:async_connect")
+                        .arg(1)
+                        .query_async(conn)
+                        .await
+                })
+            }
+        }
+
+        let manager = RedisConnectionManager::builder()
+            .connection_customizer(Box::new(Tracking))
+            .build_async("redis://localhost/3")
+            .unwrap();
+        let pool = deadpool::managed::Pool::new(manager, 2);
+        let mut conn = pool.get().await.unwrap();
+        let value: i64 = redis::cmd("GET")
+            .arg("redis_r2d2:async_connect")
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(1, value);
+        drop(conn);
+        // Recycling validates the connection.
+        pool.get().await.unwrap();
+        assert_eq!(1, pool.status().size);
+    }
+
+    #[tokio::test]
+    async fn test_async_validation() {
+        let manager = RedisConnectionManager::builder()
+            .validation(ValidationMode::Command(redis::cmd("NOSUCHCOMMAND")))
+            .build_async("redis://localhost")
+            .unwrap();
+        let mut conn = manager.connect().await.unwrap();
+        assert!(manager.is_valid(&mut conn).await.is_err());
+
+        let manager = RedisAsyncConnectionManager::new("redis://localhost").unwrap();
+        manager.is_valid(&mut conn).await.unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let manager = RedisAsyncConnectionManager::new(format!("redis://{}", addr)).unwrap();
+        assert!(manager.connect().await.is_err());
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_std() {
+        async_std::task::block_on(async {
+            let manager = RedisConnectionManager::builder()
+                .client_name("redis_r2d2-async-std")
+                .build_async("redis://localhost")
+                .unwrap();
+            let mut conn = manager.connect().await.unwrap();
+            let name: String = redis::cmd("CLIENT")
+                .arg("GETNAME")
+                .query_async(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!("redis_r2d2-async-std", name);
+            manager.is_valid(&mut conn).await.unwrap();
+
+            // Answers the connect, but not the validation.
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = crate::fake_server::fake_server(listener, |request| {
+                if request.contains("PING") {
+                    std::thread::sleep(Duration::from_secs(5));
+                }
+                "+OK\r\n".to_string()
+            });
+            let manager = RedisConnectionManager::builder()
+                .validation_timeout(Some(Duration::from_millis(50)))
+                .build_async(format!("redis://{}", addr))
+                .unwrap();
+            let mut conn = manager.connect().await.unwrap();
+            let e = manager.is_valid(&mut conn).await.unwrap_err();
+            assert!(e.is_timeout());
+        });
+    }
+
+    #[test]
+    fn test_unsupported_settings() {
+        let err = RedisConnectionManager::builder()
+            .read_timeout(Some(Duration::from_secs(1)))
+            .build_async("redis://localhost")
+            .unwrap_err();
+        assert_eq!(redis::ErrorKind::InvalidClientConfig, err.kind());
+        assert!(err.to_string().contains("read_timeout"), "{}", err);
+
+        let manager = RedisConnectionManager::builder()
+            .max_uses(Some(10))
+            .build("redis://localhost")
+            .unwrap();
+        assert!(RedisAsyncConnectionManager::from_manager(manager).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Controls how `RedisConnectionManager` backs off after failed connection
+/// attempts.
+///
+/// After the `n`th consecutive failure the next attempt is delayed by
+/// `initial_delay * multiplier^(n - 1)`, capped at `max_delay`, and then
+/// randomly spread by up to `jitter` (a fraction of the delay) in either
+/// direction. A successful connect resets the delay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// The delay after the first failure.
+    pub initial_delay: Duration,
+    /// The factor the delay grows by with each further failure.
+    pub multiplier: f64,
+    /// The largest delay, before jitter is applied.
+    pub max_delay: Duration,
+    /// The fraction of the delay, between 0 and 1, by which it is randomly
+    /// spread.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Panics unless the delays can be computed: the `multiplier` must be at
+    /// least 1, the `jitter` between 0 and 1, and the `initial_delay` at most
+    /// the `max_delay`.
+    pub(crate) fn assert_valid(&self) {
+        assert!(self.multiplier >= 1.0, "multiplier must be at least 1");
+        assert!(
+            (0.0..=1.0).contains(&self.jitter),
+            "jitter must be between 0 and 1"
+        );
+        assert!(
+            self.initial_delay <= self.max_delay,
+            "initial_delay must not exceed max_delay"
+        );
+    }
+
+    /// Returns the un-jittered delay after `failures` consecutive failures.
+    fn base_delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::from_secs(0);
+        }
+        let exponent = i32::try_from(failures - 1).unwrap_or(i32::MAX);
+        let factor = self.multiplier.powi(exponent);
+        let max_factor = self.max_delay.as_secs_f64() / self.initial_delay.as_secs_f64();
+        if factor.is_nan() || factor >= max_factor {
+            self.max_delay
+        } else {
+            self.initial_delay.mul_f64(factor)
+        }
+    }
+
+    /// Returns the jittered delay after `failures` consecutive failures.
+    pub(crate) fn delay(&self, failures: u32) -> Duration {
+        jittered(self.base_delay(failures), self.jitter)
+    }
+}
+
+/// Tracks consecutive connect failures for a `ReconnectPolicy`.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    policy: ReconnectPolicy,
+    state: Mutex<BackoffState>,
+}
+
+#[derive(Debug, Default)]
+struct BackoffState {
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub(crate) fn new(policy: ReconnectPolicy) -> Backoff {
+        Backoff {
+            policy,
+            state: Mutex::new(BackoffState::default()),
+        }
+    }
+
+    /// Blocks until the next connection attempt is allowed.
+    pub(crate) fn wait(&self) {
+        let next_attempt = self.state.lock().unwrap().next_attempt;
+        if let Some(next_attempt) = next_attempt {
+            let now = Instant::now();
+            if next_attempt > now {
+                thread::sleep(next_attempt - now);
+            }
+        }
+    }
+
+    pub(crate) fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.next_attempt = None;
+    }
+
+    pub(crate) fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        state.next_attempt = Some(Instant::now() + self.policy.delay(state.failures));
+    }
+}
+
+/// Randomly spreads `delay` by up to `jitter` (a fraction of it) in either
+/// direction.
+pub(crate) fn jittered(delay: Duration, jitter: f64) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    let spread = (random() * 2.0 - 1.0) * jitter;
+    delay.mul_f64(1.0 + spread)
+}
+
+/// Returns a random number in `[0, 1)`, from the randomly keyed hashers of
+/// `RandomState`.
+///
+/// Good enough for spreading out timings and load, and for DNS query IDs,
+/// but not for secrets.
+pub(crate) fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(Instant::now().elapsed().as_nanos());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_delay() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_millis(500),
+            jitter: 0.0,
+        };
+
+        assert_eq!(Duration::from_millis(0), policy.base_delay(0));
+        assert_eq!(Duration::from_millis(100), policy.base_delay(1));
+        assert_eq!(Duration::from_millis(200), policy.base_delay(2));
+        assert_eq!(Duration::from_millis(400), policy.base_delay(3));
+        assert_eq!(Duration::from_millis(500), policy.base_delay(4));
+        assert_eq!(Duration::from_millis(500), policy.base_delay(u32::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "multiplier must be at least 1")]
+    fn test_invalid_multiplier() {
+        let _ = crate::RedisConnectionManager::builder().reconnect_policy(Some(ReconnectPolicy {
+            multiplier: f64::NAN,
+            ..ReconnectPolicy::default()
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "jitter must be between 0 and 1")]
+    fn test_invalid_jitter() {
+        let _ = crate::RedisConnectionManager::builder().reconnect_policy(Some(ReconnectPolicy {
+            jitter: -0.5,
+            ..ReconnectPolicy::default()
+        }));
+    }
+
+    #[test]
+    fn test_jittered() {
+        let delay = Duration::from_millis(1000);
+        for _ in 0..100 {
+            let jittered = jittered(delay, 0.2);
+            assert!(jittered >= Duration::from_millis(800), "{:?}", jittered);
+            assert!(jittered <= Duration::from_millis(1200), "{:?}", jittered);
+        }
+        assert_eq!(delay, jittered(delay, 0.0));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::new(ReconnectPolicy {
+            initial_delay: Duration::from_millis(50),
+            jitter: 0.0,
+            ..ReconnectPolicy::default()
+        });
+
+        backoff.failed();
+        let start = Instant::now();
+        backoff.wait();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        backoff.succeeded();
+        let start = Instant::now();
+        backoff.wait();
+        assert!(start.elapsed() < Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,307 @@
+use std::marker::PhantomData;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use redis::{ConnectionLike, FromRedisValue, Value};
+
+use crate::pool_ext::checkout_error;
+use crate::RedisConnectionManager;
+
+/// A command waiting to be sent, with where to send its reply.
+struct Job {
+    packed: Vec<u8>,
+    reply: mpsc::Sender<redis::RedisResult<Value>>,
+}
+
+/// Sends the commands submitted by any number of callers in pipelines over
+/// a single pooled connection, and hands each caller its own reply.
+///
+/// A background thread takes the commands in the order they are submitted
+/// and sends each batch, of up to `batch_size` commands, in one write,
+/// which saves a round trip per command for write-heavy workloads. The
+/// thread doesn't wait for a batch to fill up unless a `linger` is set, so
+/// batches form whenever commands are submitted faster than they are sent,
+/// e.g. by a loop that submits without waiting for the replies, or by many
+/// threads.
+///
+/// Error replies are per command, except that the redis crate closes the
+/// connection on `ERR` replies, e.g. to an unknown command, and the
+/// replies of the rest of the batch can't be read: those commands, and the
+/// ones whose reply was lost to an I/O error, fail with a `ClientError`
+/// since the server may have run them, so they aren't safe to retry
+/// unless they are idempotent. A failed checkout fails the whole batch
+/// before anything is sent. The thread exits once every clone of the
+/// `PipelineBatcher` was dropped and the submitted commands were sent.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, PipelineBatcher, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let batcher = PipelineBatcher::builder(pool).batch_size(500).start();
+///     let replies: Vec<_> = (0..10_000)
+///         .map(|i| batcher.submit::<()>(redis::cmd("SET").arg(format!("key:{}", i)).arg(i)))
+///         .collect();
+///     for reply in replies {
+///         reply.wait().unwrap();
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PipelineBatcher {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl PipelineBatcher {
+    /// Creates a `PipelineBatcher` with the default settings, sending the
+    /// commands on connections of `pool`.
+    pub fn new(pool: r2d2::Pool<RedisConnectionManager>) -> PipelineBatcher {
+        PipelineBatcher::builder(pool).start()
+    }
+
+    /// Returns a builder for a `PipelineBatcher` sending the commands on
+    /// connections of `pool`.
+    pub fn builder(pool: r2d2::Pool<RedisConnectionManager>) -> PipelineBatcherBuilder {
+        PipelineBatcherBuilder {
+            pool,
+            batch_size: 100,
+            linger: Duration::from_secs(0),
+        }
+    }
+
+    /// Queues `cmd` for the next batch, returning a handle to its reply.
+    pub fn submit<T: FromRedisValue>(&self, cmd: &redis::Cmd) -> BatchReply<T> {
+        let (reply, receiver) = mpsc::channel();
+        let job = Job {
+            packed: cmd.get_packed_command(),
+            reply,
+        };
+        // The thread only exits once all senders are gone, so this can't
+        // fail.
+        let _ = self.jobs.send(job);
+        BatchReply {
+            receiver,
+            _reply: PhantomData,
+        }
+    }
+
+    /// Queues `cmd` for the next batch and waits for its reply.
+    pub fn query<T: FromRedisValue>(&self, cmd: &redis::Cmd) -> redis::RedisResult<T> {
+        self.submit(cmd).wait()
+    }
+}
+
+/// A builder for a `PipelineBatcher`.
+#[derive(Debug)]
+pub struct PipelineBatcherBuilder {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    batch_size: usize,
+    linger: Duration,
+}
+
+impl PipelineBatcherBuilder {
+    /// Sets the largest number of commands sent in one pipeline.
+    ///
+    /// Defaults to 100.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn batch_size(mut self, batch_size: usize) -> PipelineBatcherBuilder {
+        assert_ne!(batch_size, 0, "batch_size must be positive");
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Sets how long a batch waits for more commands after its first one,
+    /// trading latency for fewer round trips when commands trickle in.
+    ///
+    /// Defaults to 0: a batch holds the commands queued by the time it is
+    /// sent.
+    pub fn linger(mut self, linger: Duration) -> PipelineBatcherBuilder {
+        self.linger = linger;
+        self
+    }
+
+    /// Starts the background thread sending the batches.
+    pub fn start(self) -> PipelineBatcher {
+        let (jobs, receiver) = mpsc::channel();
+        thread::spawn(move || run(&self, &receiver));
+        PipelineBatcher { jobs }
+    }
+}
+
+fn run(builder: &PipelineBatcherBuilder, jobs: &mpsc::Receiver<Job>) {
+    while let Ok(first) = jobs.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + builder.linger;
+        while batch.len() < builder.batch_size {
+            let job = match deadline.checked_duration_since(Instant::now()) {
+                Some(linger) if !linger.is_zero() => jobs.recv_timeout(linger).ok(),
+                _ => jobs.try_recv().ok(),
+            };
+            match job {
+                Some(job) => batch.push(job),
+                None => break,
+            }
+        }
+        send(&builder.pool, batch);
+    }
+}
+
+/// Sends `batch` in one write and routes the replies.
+fn send(pool: &r2d2::Pool<RedisConnectionManager>, batch: Vec<Job>) {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            for job in batch {
+                let _ = job.reply.send(Err(checkout_error(&e)));
+            }
+            return;
+        }
+    };
+    let packed: Vec<u8> = batch
+        .iter()
+        .flat_map(|job| job.packed.iter().copied())
+        .collect();
+    // Once a reply can't be read, the replies of the following commands
+    // are lost, but the server may have run the commands anyway.
+    let mut lost = conn.send_packed(&packed).err();
+    for job in batch {
+        let result = match lost {
+            Some(ref e) => Err(outcome_unknown(e)),
+            None => match conn.recv_reply() {
+                Err(e) if e.is_io_error() || e.is_timeout() => {
+                    let result = Err(outcome_unknown(&e));
+                    lost = Some(e);
+                    result
+                }
+                result => {
+                    // The redis crate closes the connection on `ERR`
+                    // replies, after which no reply can be read.
+                    if !conn.is_open() {
+                        if let Err(ref e) = result {
+                            lost = Some(redis::RedisError::from((
+                                e.kind(),
+                                "the connection was closed after an error reply",
+                                e.to_string(),
+                            )));
+                        }
+                    }
+                    result
+                }
+            },
+        };
+        if lost.is_some() {
+            conn.mark_broken();
+        }
+        let _ = job.reply.send(result);
+    }
+}
+
+/// Returns the error of a command whose reply was lost because of `cause`:
+/// it may or may not have run, so it isn't safe to retry blindly.
+fn outcome_unknown(cause: &redis::RedisError) -> redis::RedisError {
+    redis::RedisError::from((
+        redis::ErrorKind::ClientError,
+        "pipeline batch failed, the command may have run",
+        cause.to_string(),
+    ))
+}
+
+/// The pending reply of a command submitted to a `PipelineBatcher`.
+#[derive(Debug)]
+pub struct BatchReply<T> {
+    receiver: mpsc::Receiver<redis::RedisResult<Value>>,
+    _reply: PhantomData<fn() -> T>,
+}
+
+impl<T: FromRedisValue> BatchReply<T> {
+    /// Waits for the command to be sent, and returns its reply.
+    pub fn wait(self) -> redis::RedisResult<T> {
+        let value = self.receiver.recv().map_err(|_| {
+            redis::RedisError::from((redis::ErrorKind::IoError, "the pipeline batcher stopped"))
+        })??;
+        redis::from_redis_value(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batcher() {
+        let pool = crate::local_pool(1);
+        let batcher = PipelineBatcher::builder(pool).batch_size(8).start();
+
+        let replies: Vec<_> = (0..20)
+            .map(|i| batcher.submit::<i64>(redis::cmd("INCRBY").arg("redis_r2d2-batch").arg(i)))
+            .collect();
+        let mut totals: Vec<i64> = replies
+            .into_iter()
+            .map(|reply| reply.wait().unwrap())
+            .collect();
+        let first = totals[0];
+        totals.iter_mut().for_each(|total| *total -= first);
+        assert_eq!((0..20).map(|i| i * (i + 1) / 2).collect::<Vec<_>>(), totals);
+
+        // Submitters on other threads get their own replies.
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let batcher = batcher.clone();
+                thread::spawn(move || {
+                    batcher
+                        .query::<String>(redis::cmd("ECHO").arg(i.to_string()))
+                        .unwrap()
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(i.to_string(), thread.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_errors_per_command() {
+        let pool = crate::local_pool(1);
+        let batcher = PipelineBatcher::new(pool);
+        let missing = batcher.submit::<()>(redis::cmd("EVALSHA").arg("0".repeat(40)).arg(0));
+        let echo = batcher.submit::<String>(redis::cmd("ECHO").arg("ok"));
+        assert_eq!(
+            redis::ErrorKind::NoScriptError,
+            missing.wait().unwrap_err().kind()
+        );
+        assert_eq!("ok", echo.wait().unwrap());
+    }
+
+    #[test]
+    fn test_err_reply_mid_batch() {
+        let pool = crate::local_pool(1);
+        let batcher = PipelineBatcher::builder(pool.clone())
+            .linger(Duration::from_millis(200))
+            .start();
+        let key = format!("redis_r2d2-batch-{}", crate::lock::token());
+        let first = batcher.submit::<i64>(redis::cmd("INCR").arg(&key));
+        let unknown = batcher.submit::<()>(&redis::cmd("REDIS_R2D2_UNKNOWN"));
+        let last = batcher.submit::<i64>(redis::cmd("INCR").arg(&key));
+        assert_eq!(1, first.wait().unwrap());
+        assert_eq!(
+            redis::ErrorKind::ResponseError,
+            unknown.wait().unwrap_err().kind()
+        );
+        // The reply of the last command was lost, but the command ran.
+        let e = last.wait().unwrap_err();
+        assert_eq!(redis::ErrorKind::ClientError, e.kind());
+        assert!(!crate::ErrorCategory::of(&e).is_transient());
+        let total: i64 = redis::cmd("GET")
+            .arg(&key)
+            .query(&mut *pool.get().unwrap())
+            .unwrap();
+        assert_eq!(2, total);
+    }
+}
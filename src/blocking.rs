@@ -0,0 +1,167 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+use redis::ConnectionLike;
+
+use crate::RedisConnection;
+
+/// How much longer than a blocking command's timeout its reply is waited for
+/// before the connection is considered dead.
+pub(crate) const BLOCK_MARGIN: Duration = Duration::from_secs(5);
+
+/// A pooled connection checked out for blocking commands such as `BLPOP`,
+/// `BRPOP` or `XREAD BLOCK`, see `RedisPoolExt::get_blocking`.
+///
+/// While it is checked out, its read timeout is the blocking timeout plus 5
+/// seconds, or none if the commands block indefinitely, so the pool's read
+/// timeout doesn't cut them short. When it is dropped, the manager's read
+/// timeout is restored, and the connection is closed instead of being reused
+/// if a reply is still unread, e.g. because a blocking command was sent
+/// with `send_packed_command` and never received, or if a blocking command
+/// timed out on the client side. Either would hand the next borrower a reply
+/// meant for another command.
+pub struct BlockingConnection<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    conn: r2d2::PooledConnection<M>,
+}
+
+impl<M> BlockingConnection<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    pub(crate) fn new(conn: r2d2::PooledConnection<M>, block: Duration) -> BlockingConnection<M> {
+        let mut conn = BlockingConnection { conn };
+        if conn.set_block(block).is_err() {
+            conn.conn.mark_broken();
+        }
+        conn
+    }
+
+    /// Adjusts the read timeout for commands blocking for up to `block`, with
+    /// zero meaning indefinitely, as for `BLPOP`.
+    pub fn set_block(&mut self, block: Duration) -> redis::RedisResult<()> {
+        let read_timeout = if block == Duration::from_secs(0) {
+            None
+        } else {
+            Some(block + BLOCK_MARGIN)
+        };
+        self.conn.set_read_timeout(read_timeout)
+    }
+}
+
+impl<M> Drop for BlockingConnection<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    fn drop(&mut self) {
+        let read_timeout = self.conn.read_timeout();
+        if self.conn.is_broken() || !self.conn.is_open() {
+            return;
+        }
+        match self.conn.has_unread_replies(read_timeout) {
+            Ok(false) => {}
+            _ => self.conn.mark_broken(),
+        }
+    }
+}
+
+impl<M> Deref for BlockingConnection<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    type Target = RedisConnection;
+
+    fn deref(&self) -> &RedisConnection {
+        &self.conn
+    }
+}
+
+impl<M> DerefMut for BlockingConnection<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    fn deref_mut(&mut self) -> &mut RedisConnection {
+        &mut self.conn
+    }
+}
+
+impl<M> ConnectionLike for BlockingConnection<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        self.conn.req_packed_command(cmd)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisResult<Vec<redis::Value>> {
+        self.conn.req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.conn.check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.conn.is_open()
+    }
+}
+
+impl<M> fmt::Debug for BlockingConnection<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BlockingConnection")
+            .field("broken", &self.conn.is_broken())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{RedisConnectionManager, RedisPoolExt};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_get_blocking() {
+        let manager = RedisConnectionManager::builder()
+            .read_timeout(Some(Duration::from_millis(200)))
+            .build("redis://localhost")
+            .unwrap();
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+
+        {
+            let mut conn = pool.get_blocking(Duration::from_secs(1)).unwrap();
+            let start = Instant::now();
+            // Longer than the pool's read timeout.
+            let reply: Option<(String, String)> = redis::cmd("BLPOP")
+                .arg("redis_r2d2-blocking")
+                .arg(0.5)
+                .query(&mut *conn)
+                .unwrap();
+            assert_eq!(None, reply);
+            assert!(start.elapsed() >= Duration::from_millis(400));
+            assert!(!conn.is_broken());
+
+            // Left unread, so the connection isn't reused.
+            conn.send_packed_command(&redis::cmd("PING").get_packed_command())
+                .unwrap();
+        }
+        let mut conn = pool.get().unwrap();
+        assert_eq!(0, conn.checkins());
+        let reply: String = redis::cmd("PING").query(&mut *conn).unwrap();
+        assert_eq!("PONG", reply);
+    }
+}
@@ -0,0 +1,394 @@
+use redis::{ConnectionLike, ToRedisArgs};
+
+/// The settings of a Bloom filter, see `BloomCommands::bf_reserve`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomOptions {
+    capacity: u64,
+    error_rate: f64,
+    expansion: Option<u32>,
+    non_scaling: bool,
+}
+
+impl BloomOptions {
+    /// A filter for `capacity` items with a false positive rate of
+    /// `error_rate`, e.g. `0.001`, until it is full.
+    pub fn new(capacity: u64, error_rate: f64) -> BloomOptions {
+        BloomOptions {
+            capacity,
+            error_rate,
+            expansion: None,
+            non_scaling: false,
+        }
+    }
+
+    /// Sets how many times larger each sub-filter added when the filter is
+    /// full is.
+    ///
+    /// Defaults to the server's, 2.
+    pub fn expansion(mut self, expansion: u32) -> BloomOptions {
+        self.expansion = Some(expansion);
+        self
+    }
+
+    /// Makes adding items to a full filter fail instead of growing it.
+    pub fn non_scaling(mut self) -> BloomOptions {
+        self.non_scaling = true;
+        self
+    }
+}
+
+/// The settings of a cuckoo filter, see `BloomCommands::cf_reserve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CuckooOptions {
+    capacity: u64,
+    bucket_size: Option<u32>,
+    max_iterations: Option<u32>,
+    expansion: Option<u32>,
+}
+
+impl CuckooOptions {
+    /// A filter for about `capacity` items.
+    pub fn new(capacity: u64) -> CuckooOptions {
+        CuckooOptions {
+            capacity,
+            bucket_size: None,
+            max_iterations: None,
+            expansion: None,
+        }
+    }
+
+    /// Sets the number of items per bucket; larger buckets fill up better
+    /// but raise the false positive rate.
+    ///
+    /// Defaults to the server's, 2.
+    pub fn bucket_size(mut self, bucket_size: u32) -> CuckooOptions {
+        self.bucket_size = Some(bucket_size);
+        self
+    }
+
+    /// Sets how many times items are swapped between buckets before the
+    /// filter counts as full.
+    ///
+    /// Defaults to the server's, 20.
+    pub fn max_iterations(mut self, max_iterations: u32) -> CuckooOptions {
+        self.max_iterations = Some(max_iterations);
+        self
+    }
+
+    /// Sets how many times larger each sub-filter added when the filter is
+    /// full is.
+    ///
+    /// Defaults to the server's, 1.
+    pub fn expansion(mut self, expansion: u32) -> CuckooOptions {
+        self.expansion = Some(expansion);
+        self
+    }
+}
+
+/// The settings of a top-k sketch, see `BloomCommands::topk_reserve`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopKOptions {
+    k: u32,
+    width: u32,
+    depth: u32,
+    decay: f64,
+}
+
+impl TopKOptions {
+    /// A sketch keeping the `k` most frequent items.
+    pub fn new(k: u32) -> TopKOptions {
+        TopKOptions {
+            k,
+            width: 8,
+            depth: 7,
+            decay: 0.9,
+        }
+    }
+
+    /// Sets the number of counters per array.
+    ///
+    /// Defaults to 8.
+    pub fn width(mut self, width: u32) -> TopKOptions {
+        self.width = width;
+        self
+    }
+
+    /// Sets the number of arrays.
+    ///
+    /// Defaults to 7.
+    pub fn depth(mut self, depth: u32) -> TopKOptions {
+        self.depth = depth;
+        self
+    }
+
+    /// Sets the probability of decrementing a counter on a collision.
+    ///
+    /// Defaults to 0.9.
+    pub fn decay(mut self, decay: f64) -> TopKOptions {
+        self.decay = decay;
+        self
+    }
+}
+
+/// Typed RedisBloom commands for Bloom filters, cuckoo filters and top-k
+/// sketches, for any connection, e.g. a pooled `RedisConnection`.
+///
+/// Items are anything that converts to Redis arguments; the commands taking
+/// several take anything converting to several, e.g. a slice. Filters that
+/// don't exist are created with the server's defaults by `bf_add`,
+/// `bf_madd` and `cf_add`, and reserved with explicit settings by
+/// `bf_reserve` and `cf_reserve`.
+///
+/// Requires the `bloom` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, BloomCommands, BloomOptions, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut conn = pool.get().unwrap();
+///     conn.bf_reserve("seen", BloomOptions::new(1_000_000, 0.001))
+///         .unwrap();
+///     if conn.bf_add("seen", "user:42").unwrap() {
+///         println!("first visit");
+///     }
+/// }
+/// ```
+pub trait BloomCommands: ConnectionLike + Sized {
+    /// Creates the Bloom filter `key` (`BF.RESERVE`).
+    fn bf_reserve<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        options: BloomOptions,
+    ) -> redis::RedisResult<()> {
+        let mut cmd = redis::cmd("BF.RESERVE");
+        cmd.arg(key).arg(options.error_rate).arg(options.capacity);
+        if let Some(expansion) = options.expansion {
+            cmd.arg("EXPANSION").arg(expansion);
+        }
+        if options.non_scaling {
+            cmd.arg("NONSCALING");
+        }
+        cmd.query(self)
+    }
+
+    /// Adds `item` to the Bloom filter `key` (`BF.ADD`), returning false if
+    /// it may have been added already.
+    fn bf_add<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        item: I,
+    ) -> redis::RedisResult<bool> {
+        redis::cmd("BF.ADD").arg(key).arg(item).query(self)
+    }
+
+    /// Adds `items` to the Bloom filter `key` (`BF.MADD`), returning for
+    /// each whether it was certainly new.
+    fn bf_madd<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        items: I,
+    ) -> redis::RedisResult<Vec<bool>> {
+        redis::cmd("BF.MADD").arg(key).arg(items).query(self)
+    }
+
+    /// Returns false if `item` certainly isn't in the Bloom filter `key`
+    /// (`BF.EXISTS`).
+    fn bf_exists<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        item: I,
+    ) -> redis::RedisResult<bool> {
+        redis::cmd("BF.EXISTS").arg(key).arg(item).query(self)
+    }
+
+    /// Returns for each of `items` false if it certainly isn't in the Bloom
+    /// filter `key` (`BF.MEXISTS`).
+    fn bf_mexists<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        items: I,
+    ) -> redis::RedisResult<Vec<bool>> {
+        redis::cmd("BF.MEXISTS").arg(key).arg(items).query(self)
+    }
+
+    /// Creates the cuckoo filter `key` (`CF.RESERVE`).
+    fn cf_reserve<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        options: CuckooOptions,
+    ) -> redis::RedisResult<()> {
+        let mut cmd = redis::cmd("CF.RESERVE");
+        cmd.arg(key).arg(options.capacity);
+        if let Some(bucket_size) = options.bucket_size {
+            cmd.arg("BUCKETSIZE").arg(bucket_size);
+        }
+        if let Some(max_iterations) = options.max_iterations {
+            cmd.arg("MAXITERATIONS").arg(max_iterations);
+        }
+        if let Some(expansion) = options.expansion {
+            cmd.arg("EXPANSION").arg(expansion);
+        }
+        cmd.query(self)
+    }
+
+    /// Adds `item` to the cuckoo filter `key` (`CF.ADD`), even if it is
+    /// there already.
+    fn cf_add<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        item: I,
+    ) -> redis::RedisResult<()> {
+        redis::cmd("CF.ADD").arg(key).arg(item).query(self)
+    }
+
+    /// Adds `item` to the cuckoo filter `key` unless it may be there already
+    /// (`CF.ADDNX`), returning whether it was added.
+    fn cf_add_nx<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        item: I,
+    ) -> redis::RedisResult<bool> {
+        redis::cmd("CF.ADDNX").arg(key).arg(item).query(self)
+    }
+
+    /// Returns false if `item` certainly isn't in the cuckoo filter `key`
+    /// (`CF.EXISTS`).
+    fn cf_exists<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        item: I,
+    ) -> redis::RedisResult<bool> {
+        redis::cmd("CF.EXISTS").arg(key).arg(item).query(self)
+    }
+
+    /// Removes one occurrence of `item` from the cuckoo filter `key`
+    /// (`CF.DEL`), returning false if there was none.
+    fn cf_del<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        item: I,
+    ) -> redis::RedisResult<bool> {
+        redis::cmd("CF.DEL").arg(key).arg(item).query(self)
+    }
+
+    /// Returns how many times `item` may be in the cuckoo filter `key`
+    /// (`CF.COUNT`).
+    fn cf_count<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        item: I,
+    ) -> redis::RedisResult<u64> {
+        redis::cmd("CF.COUNT").arg(key).arg(item).query(self)
+    }
+
+    /// Creates the top-k sketch `key` (`TOPK.RESERVE`).
+    fn topk_reserve<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        options: TopKOptions,
+    ) -> redis::RedisResult<()> {
+        redis::cmd("TOPK.RESERVE")
+            .arg(key)
+            .arg(options.k)
+            .arg(options.width)
+            .arg(options.depth)
+            .arg(options.decay)
+            .query(self)
+    }
+
+    /// Counts `items` in the top-k sketch `key` (`TOPK.ADD`), returning for
+    /// each the item it pushed out of the top k, if any.
+    fn topk_add<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        items: I,
+    ) -> redis::RedisResult<Vec<Option<String>>> {
+        redis::cmd("TOPK.ADD").arg(key).arg(items).query(self)
+    }
+
+    /// Returns for each of `items` whether it is in the top k of the sketch
+    /// `key` (`TOPK.QUERY`).
+    fn topk_query<K: ToRedisArgs, I: ToRedisArgs>(
+        &mut self,
+        key: K,
+        items: I,
+    ) -> redis::RedisResult<Vec<bool>> {
+        redis::cmd("TOPK.QUERY").arg(key).arg(items).query(self)
+    }
+
+    /// Returns the top k items of the sketch `key` with their estimated
+    /// counts, most frequent first (`TOPK.LIST ... WITHCOUNT`).
+    fn topk_list<K: ToRedisArgs>(&mut self, key: K) -> redis::RedisResult<Vec<(String, u64)>> {
+        redis::cmd("TOPK.LIST")
+            .arg(key)
+            .arg("WITHCOUNT")
+            .query(self)
+    }
+}
+
+impl<C: ConnectionLike> BloomCommands for C {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConnectionManager;
+
+    fn conn() -> r2d2::PooledConnection<RedisConnectionManager> {
+        let pool = crate::local_pool(1);
+        pool.get().unwrap()
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut conn = conn();
+        let key = format!("redis_r2d2-bloom-{}", crate::lock::token());
+        conn.bf_reserve(&key, BloomOptions::new(1000, 0.01).expansion(4))
+            .unwrap();
+        assert!(conn.bf_add(&key, "a").unwrap());
+        assert!(!conn.bf_add(&key, "a").unwrap());
+        assert_eq!(vec![false, true], conn.bf_madd(&key, &["a", "b"]).unwrap());
+        assert!(conn.bf_exists(&key, "b").unwrap());
+        assert_eq!(
+            vec![true, false],
+            conn.bf_mexists(&key, &["a", "c"]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cuckoo_filter() {
+        let mut conn = conn();
+        let key = format!("redis_r2d2-cuckoo-{}", crate::lock::token());
+        conn.cf_reserve(&key, CuckooOptions::new(1000).bucket_size(4))
+            .unwrap();
+        conn.cf_add(&key, "a").unwrap();
+        conn.cf_add(&key, "a").unwrap();
+        assert!(!conn.cf_add_nx(&key, "a").unwrap());
+        assert_eq!(2, conn.cf_count(&key, "a").unwrap());
+        assert!(conn.cf_del(&key, "a").unwrap());
+        assert!(conn.cf_exists(&key, "a").unwrap());
+        assert!(conn.cf_del(&key, "a").unwrap());
+        assert!(!conn.cf_exists(&key, "a").unwrap());
+    }
+
+    #[test]
+    fn test_top_k() {
+        let mut conn = conn();
+        let key = format!("redis_r2d2-topk-{}", crate::lock::token());
+        conn.topk_reserve(&key, TopKOptions::new(2)).unwrap();
+        conn.topk_add(&key, &["a", "b", "a"]).unwrap();
+        let dropped = conn.topk_add(&key, &["c", "c", "c"]).unwrap();
+        assert_eq!(vec![None, Some("b".to_string()), None], dropped);
+        assert_eq!(
+            vec![true, false, true],
+            conn.topk_query(&key, &["a", "b", "c"]).unwrap()
+        );
+        assert_eq!(
+            vec![("c".to_string(), 3), ("a".to_string(), 2)],
+            conn.topk_list(&key).unwrap()
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::Arc;
+
+use async_lock::Semaphore;
+use r2d2::{ManageConnection, Pool};
+
+use crate::{runtime, RedisConnectionManager};
+
+/// Runs closures on connections of a sync `r2d2::Pool` from async code, on
+/// the blocking threads of the async runtime.
+///
+/// At most `max_blocking` closures run at a time, by default the pool's
+/// `max_size`. Further calls wait asynchronously for a permit instead of
+/// taking up blocking threads that would only wait for a connection. A
+/// permit is held until its closure returns, even if the calling future is
+/// dropped, since the closure can't be interrupted.
+///
+/// Panics in the closure are propagated to the caller. Requires the `tokio`
+/// or `async-std` feature; closures run on tokio when called within a tokio
+/// runtime with the `tokio` feature, and on async-std otherwise.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, redis, AsyncPoolBridge, RedisConnectionManager};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let bridge = AsyncPoolBridge::new(pool);
+///
+///     let value = bridge
+///         .with_conn(|conn| redis::cmd("GET").arg("key").query::<Option<String>>(conn))
+///         .await
+///         .unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct AsyncPoolBridge<M: ManageConnection = RedisConnectionManager> {
+    pool: Pool<M>,
+    permits: Arc<Semaphore>,
+}
+
+impl<M: ManageConnection> Clone for AsyncPoolBridge<M> {
+    fn clone(&self) -> AsyncPoolBridge<M> {
+        AsyncPoolBridge {
+            pool: self.pool.clone(),
+            permits: self.permits.clone(),
+        }
+    }
+}
+
+impl<M: ManageConnection> AsyncPoolBridge<M> {
+    /// Creates an `AsyncPoolBridge` running at most `pool.max_size()`
+    /// closures at a time.
+    pub fn new(pool: Pool<M>) -> AsyncPoolBridge<M> {
+        let max_blocking = pool.max_size() as usize;
+        AsyncPoolBridge::with_max_blocking(pool, max_blocking)
+    }
+
+    /// Creates an `AsyncPoolBridge` running at most `max_blocking` closures
+    /// at a time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_blocking` is zero.
+    pub fn with_max_blocking(pool: Pool<M>, max_blocking: usize) -> AsyncPoolBridge<M> {
+        assert!(max_blocking > 0, "max_blocking must be positive");
+        AsyncPoolBridge {
+            pool,
+            permits: Arc::new(Semaphore::new(max_blocking)),
+        }
+    }
+
+    /// Returns the pool.
+    pub fn pool(&self) -> &Pool<M> {
+        &self.pool
+    }
+
+    /// Checks out a connection on a blocking thread and calls `f` with it
+    /// there.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `Pool::get` if no connection could be checked
+    /// out.
+    pub async fn with_conn<F, R>(&self, f: F) -> Result<R, r2d2::Error>
+    where
+        F: FnOnce(&mut M::Connection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let permit = self.permits.acquire_arc().await;
+        let pool = self.pool.clone();
+        runtime::spawn_blocking(move || {
+            let _permit = permit;
+            pool.get().map(|mut conn| f(&mut conn))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_async_pool_bridge() {
+        let pool = crate::local_pool(4);
+        let bridge = AsyncPoolBridge::with_max_blocking(pool, 2);
+
+        let pong: String = bridge
+            .with_conn(|conn| redis::cmd("PING").query(conn))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!("PONG", pong);
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let handles = (0..6)
+            .map(|_| {
+                let (bridge, running, most) = (bridge.clone(), running.clone(), most.clone());
+                tokio::spawn(async move {
+                    bridge
+                        .with_conn(move |_| {
+                            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            most.fetch_max(now, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(50));
+                            running.fetch_sub(1, Ordering::SeqCst);
+                        })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(2, most.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_pool_bridge_async_std() {
+        let pool = crate::local_pool(2);
+        let bridge = AsyncPoolBridge::new(pool);
+        async_std::task::block_on(async {
+            let pong: String = bridge
+                .with_conn(|conn| redis::cmd("PING").query(conn))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!("PONG", pong);
+
+            let handles = (0..4)
+                .map(|_| {
+                    let bridge = bridge.clone();
+                    async_std::task::spawn(async move {
+                        bridge
+                            .with_conn(|conn| redis::cmd("PING").query::<String>(conn))
+                            .await
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                assert_eq!("PONG", handle.await.unwrap().unwrap());
+            }
+        });
+    }
+}
@@ -0,0 +1,41 @@
+//! Time buckets of fixed-period boards and counters, e.g. one key per day.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Returns the index of the bucket of `period` containing `time`; buckets
+/// start at multiples of `period` since the Unix epoch.
+pub(crate) fn index(time: SystemTime, period: Duration) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_millis() / period.as_millis().max(1)) as u64
+}
+
+/// Returns how long the bucket `index` of `period` is to be kept from now:
+/// until it ends, and then for `retention`. Never zero, so it can be passed
+/// to `PEXPIRE`.
+pub(crate) fn ttl(index: u64, period: Duration, retention: Duration) -> Duration {
+    let end = Duration::from_millis((index + 1) * period.as_millis().max(1) as u64);
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (end + retention)
+        .saturating_sub(since_epoch)
+        .max(Duration::from_millis(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let hour = Duration::from_secs(3600);
+        let time = UNIX_EPOCH + Duration::from_secs(2 * 3600 + 59);
+        assert_eq!(2, index(time, hour));
+        assert_eq!(0, index(UNIX_EPOCH, hour));
+
+        let now = index(SystemTime::now(), hour);
+        assert!(ttl(now, hour, hour) > hour);
+        assert!(ttl(now, hour, hour) <= 2 * hour);
+        assert_eq!(Duration::from_millis(1), ttl(0, hour, hour));
+    }
+}
@@ -0,0 +1,1400 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backoff::Backoff;
+use crate::circuit::Breaker;
+use crate::credentials::Secret;
+use crate::failover::Failover;
+use crate::rate_limit::ConnectLimiter;
+use crate::sentinel::Sentinel;
+use crate::srv::Srv;
+#[cfg(any(
+    feature = "async-std",
+    feature = "bb8",
+    feature = "deadpool",
+    feature = "tokio"
+))]
+use crate::RedisAsyncConnectionManager;
+#[cfg(feature = "cluster")]
+use crate::RedisClusterConnectionManager;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use crate::RedisMultiplexedConnectionManager;
+use crate::{
+    AddressFamily, BusyRetry, CircuitBreaker, ClientName, ConnectRateLimit, ConnectionCustomizer,
+    CredentialsProvider, DnsSrvResolver, Endpoint, EndpointSelection, FunctionLibraries,
+    NopConnectionCustomizer, NopMetricsSink, PoolMetricsSink, ReconnectPolicy,
+    RedisConnectionManager, RedisPubSubConnectionManager, RedisSentinelConnectionManager, Resolver,
+    ScriptRegistry, ServerRole, SrvResolver, ValidationMode,
+};
+
+/// The key `proxy_mode` validates connections with, which proxies route to
+/// a server like any other key.
+const PROXY_VALIDATION_KEY: &str = "redis_r2d2:validate";
+
+/// A builder for a `RedisConnectionManager`.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::builder()
+///         .connect_timeout(Some(Duration::from_secs(1)))
+///         .read_timeout(Some(Duration::from_secs(5)))
+///         .db(1)
+///         .client_name("my-app")
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder()
+///         .build(manager)
+///         .unwrap();
+///
+///     pool.get().unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RedisConnectionManagerBuilder {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    db: Option<i64>,
+    username: Option<String>,
+    password: Option<Secret>,
+    endpoint_selection: EndpointSelection,
+    failback_interval: Option<Duration>,
+    accept_invalid_certs: bool,
+    credentials_provider: Option<Arc<dyn CredentialsProvider>>,
+    reauthenticate_interval: Option<Duration>,
+    resolver: Option<Arc<dyn Resolver>>,
+    address_family: AddressFamily,
+    client_name: Option<ClientName>,
+    validation: ValidationMode,
+    validation_interval: Option<Duration>,
+    validation_timeout: Option<Duration>,
+    busy_retry: Option<BusyRetry>,
+    connection_customizer: Option<Arc<dyn ConnectionCustomizer>>,
+    metrics_sink: Arc<dyn PoolMetricsSink>,
+    max_lifetime: Option<Duration>,
+    max_uses: Option<u64>,
+    reconnect_policy: Option<ReconnectPolicy>,
+    circuit_breaker: Option<CircuitBreaker>,
+    connect_rate_limit: Option<ConnectRateLimit>,
+    script_registry: Option<ScriptRegistry>,
+    function_libraries: Option<FunctionLibraries>,
+    required_modules: Vec<String>,
+    reset_on_checkin: bool,
+    check_unread_replies: bool,
+    proxy_mode: bool,
+    watch_switch_master: bool,
+    sentinel_username: Option<String>,
+    sentinel_password: Option<Secret>,
+    master_tls: bool,
+    srv_resolver: Option<Arc<dyn SrvResolver>>,
+    srv_refresh_interval: Duration,
+    srv_tls: bool,
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    heartbeat_interval: Option<Duration>,
+    server_role: Option<ServerRole>,
+}
+
+impl Default for RedisConnectionManagerBuilder {
+    fn default() -> RedisConnectionManagerBuilder {
+        RedisConnectionManagerBuilder {
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            db: None,
+            username: None,
+            password: None,
+            endpoint_selection: EndpointSelection::default(),
+            failback_interval: None,
+            accept_invalid_certs: false,
+            credentials_provider: None,
+            reauthenticate_interval: None,
+            resolver: None,
+            address_family: AddressFamily::default(),
+            client_name: None,
+            validation: ValidationMode::default(),
+            validation_interval: None,
+            validation_timeout: None,
+            busy_retry: None,
+            connection_customizer: None,
+            metrics_sink: Arc::new(NopMetricsSink),
+            max_lifetime: None,
+            max_uses: None,
+            reconnect_policy: None,
+            circuit_breaker: None,
+            connect_rate_limit: None,
+            script_registry: None,
+            function_libraries: None,
+            required_modules: Vec::new(),
+            reset_on_checkin: false,
+            check_unread_replies: false,
+            proxy_mode: false,
+            watch_switch_master: true,
+            sentinel_username: None,
+            sentinel_password: None,
+            master_tls: false,
+            srv_resolver: None,
+            srv_refresh_interval: Duration::from_secs(30),
+            srv_tls: false,
+            #[cfg(any(feature = "async-std", feature = "tokio"))]
+            heartbeat_interval: Some(Duration::from_secs(10)),
+            server_role: None,
+        }
+    }
+}
+
+impl RedisConnectionManagerBuilder {
+    /// Constructs a new `RedisConnectionManagerBuilder`.
+    ///
+    /// Parameters are initialized with their default values.
+    pub fn new() -> RedisConnectionManagerBuilder {
+        RedisConnectionManagerBuilder::default()
+    }
+
+    /// Sets the timeout used when establishing new connections.
+    ///
+    /// Defaults to `None` (block until the operating system gives up).
+    pub fn connect_timeout(
+        mut self,
+        connect_timeout: Option<Duration>,
+    ) -> RedisConnectionManagerBuilder {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets the read timeout applied to every connection handed out by the
+    /// pool.
+    ///
+    /// Defaults to `None` (reads block indefinitely).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `read_timeout` is the zero `Duration`.
+    pub fn read_timeout(mut self, read_timeout: Option<Duration>) -> RedisConnectionManagerBuilder {
+        assert_ne!(
+            read_timeout,
+            Some(Duration::from_secs(0)),
+            "read_timeout must be positive"
+        );
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Sets the write timeout applied to every connection handed out by the
+    /// pool.
+    ///
+    /// Defaults to `None` (writes block indefinitely).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `write_timeout` is the zero `Duration`.
+    pub fn write_timeout(
+        mut self,
+        write_timeout: Option<Duration>,
+    ) -> RedisConnectionManagerBuilder {
+        assert_ne!(
+            write_timeout,
+            Some(Duration::from_secs(0)),
+            "write_timeout must be positive"
+        );
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// Sets the database index selected on new connections, overriding the
+    /// one given in the connection parameters.
+    ///
+    /// A connection that was switched to another database with `SELECT` is
+    /// switched back when it is validated on its next checkout.
+    ///
+    /// Defaults to the database of the connection parameters.
+    pub fn db(mut self, db: i64) -> RedisConnectionManagerBuilder {
+        self.db = Some(db);
+        self
+    }
+
+    /// Sets the ACL username used to authenticate new connections,
+    /// overriding the one given in the connection parameters.
+    ///
+    /// The username is only sent along with a password.
+    ///
+    /// Defaults to the username of the connection parameters.
+    pub fn username<U: Into<String>>(mut self, username: U) -> RedisConnectionManagerBuilder {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Sets the password used to authenticate new connections, overriding
+    /// the one given in the connection parameters.
+    ///
+    /// Defaults to the password of the connection parameters.
+    pub fn password<P: Into<String>>(mut self, password: P) -> RedisConnectionManagerBuilder {
+        self.password = Some(Secret(password.into()));
+        self
+    }
+
+    /// Sets how an endpoint is picked for new connections when the manager
+    /// is built with `build_with_endpoints`.
+    ///
+    /// Defaults to `EndpointSelection::Failover`.
+    pub fn endpoint_selection(
+        mut self,
+        endpoint_selection: EndpointSelection,
+    ) -> RedisConnectionManagerBuilder {
+        self.endpoint_selection = endpoint_selection;
+        self
+    }
+
+    /// Sets how often a manager using `EndpointSelection::Failover` checks
+    /// whether an endpoint with a higher priority than the one it connects
+    /// to is reachable again.
+    ///
+    /// Once one is, new connections go to it and connections to endpoints
+    /// with a lower priority are closed when they are next checked out or
+    /// returned, so the pool moves back. Until then, new connections go
+    /// straight to the endpoint that last accepted one instead of trying the
+    /// unreachable ones first.
+    ///
+    /// Defaults to `None` (new connections always try the endpoints in
+    /// order, and existing connections are kept until they break or
+    /// expire).
+    pub fn failback_interval(
+        mut self,
+        failback_interval: Option<Duration>,
+    ) -> RedisConnectionManagerBuilder {
+        self.failback_interval = failback_interval;
+        self
+    }
+
+    /// If true, TLS connections accept any server certificate, for any host
+    /// name.
+    ///
+    /// # Warning
+    ///
+    /// This makes TLS connections open to man-in-the-middle attacks and is
+    /// only meant for development against servers with self-signed
+    /// certificates. A warning is logged for every affected endpoint when
+    /// the manager is built.
+    ///
+    /// Defaults to false.
+    pub fn danger_accept_invalid_certs(
+        mut self,
+        accept_invalid_certs: bool,
+    ) -> RedisConnectionManagerBuilder {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Sets a provider consulted for credentials whenever a connection is
+    /// authenticated, replacing those in the connection parameters.
+    ///
+    /// Defaults to `None` (use the credentials of the connection
+    /// parameters).
+    pub fn credentials_provider(
+        mut self,
+        credentials_provider: Box<dyn CredentialsProvider>,
+    ) -> RedisConnectionManagerBuilder {
+        self.credentials_provider = Some(Arc::from(credentials_provider));
+        self
+    }
+
+    /// Sets how often connections re-authenticate with fresh credentials
+    /// from the `CredentialsProvider`, e.g. for GCP Memorystore with IAM
+    /// authentication, which expects connections to send a new access
+    /// token before the one they authenticated with expires.
+    ///
+    /// Connections are re-authenticated when they are checked out after the
+    /// interval elapsed. This is in addition to the re-authentication of
+    /// connections whose credentials are about to expire, see
+    /// `CredentialsProvider::expiring_credentials`. It has no effect without
+    /// a `CredentialsProvider`.
+    ///
+    /// Defaults to `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reauthenticate_interval` is zero.
+    pub fn reauthenticate_interval(
+        mut self,
+        reauthenticate_interval: Option<Duration>,
+    ) -> RedisConnectionManagerBuilder {
+        assert_ne!(
+            reauthenticate_interval,
+            Some(Duration::from_secs(0)),
+            "reauthenticate_interval must be positive"
+        );
+        self.reauthenticate_interval = reauthenticate_interval;
+        self
+    }
+
+    /// Sets the resolver used to look up the addresses of `redis://`
+    /// endpoints on every connection attempt.
+    ///
+    /// Defaults to `None` (leave resolution to `redis`, which asks the
+    /// system resolver on every connection attempt).
+    pub fn resolver(mut self, resolver: Box<dyn Resolver>) -> RedisConnectionManagerBuilder {
+        self.resolver = Some(Arc::from(resolver));
+        self
+    }
+
+    /// Sets which IP versions are used to connect to `redis://` endpoints.
+    ///
+    /// This resolves host names with the `resolver`, or the system resolver
+    /// if none is set, and orders or filters the addresses accordingly. TLS
+    /// endpoints and Unix sockets are not affected.
+    ///
+    /// Defaults to `AddressFamily::Any` (use addresses in resolver order).
+    pub fn address_family(
+        mut self,
+        address_family: AddressFamily,
+    ) -> RedisConnectionManagerBuilder {
+        self.address_family = address_family;
+        self
+    }
+
+    /// Sets the name announced with `CLIENT SETNAME` on new connections.
+    ///
+    /// Replaces any prefix set with `client_name_prefix`.
+    ///
+    /// Defaults to no name.
+    pub fn client_name<N: Into<String>>(mut self, client_name: N) -> RedisConnectionManagerBuilder {
+        self.client_name = Some(ClientName::Fixed(client_name.into()));
+        self
+    }
+
+    /// Names every new connection `<prefix>-<n>` with `CLIENT SETNAME`,
+    /// where `n` counts the connections created by the manager.
+    ///
+    /// This makes it possible to tell the pool's connections apart in
+    /// `CLIENT LIST`. Replaces any name set with `client_name`.
+    ///
+    /// Defaults to no name.
+    pub fn client_name_prefix<P: Into<String>>(
+        mut self,
+        prefix: P,
+    ) -> RedisConnectionManagerBuilder {
+        self.client_name = Some(ClientName::Prefix(prefix.into()));
+        self
+    }
+
+    /// Sets how connections are validated before being checked out of the
+    /// pool.
+    ///
+    /// Defaults to `ValidationMode::Ping`.
+    pub fn validation(mut self, validation: ValidationMode) -> RedisConnectionManagerBuilder {
+        self.validation = validation;
+        self
+    }
+
+    /// Sets a grace period during which recently used connections are
+    /// handed out without being validated.
+    ///
+    /// A connection counts as used whenever a command sent through it
+    /// succeeds, so hot connections skip the validation round-trip while
+    /// idle ones are still checked.
+    ///
+    /// Defaults to `None` (always validate).
+    pub fn validation_interval(
+        mut self,
+        validation_interval: Option<Duration>,
+    ) -> RedisConnectionManagerBuilder {
+        self.validation_interval = validation_interval;
+        self
+    }
+
+    /// Sets the read and write timeout used while validating a connection.
+    ///
+    /// The connection's regular timeouts are restored once validation
+    /// completes, so a wedged server only stalls a checkout for this long.
+    ///
+    /// Defaults to `None` (validation uses the regular timeouts).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `validation_timeout` is the zero `Duration`.
+    pub fn validation_timeout(
+        mut self,
+        validation_timeout: Option<Duration>,
+    ) -> RedisConnectionManagerBuilder {
+        assert_ne!(
+            validation_timeout,
+            Some(Duration::from_secs(0)),
+            "validation_timeout must be positive"
+        );
+        self.validation_timeout = validation_timeout;
+        self
+    }
+
+    /// Sets how validation is retried while the server reports being busy
+    /// (`LOADING`, `BUSY`, `CLUSTERDOWN` and similar).
+    ///
+    /// Defaults to `None` (such connections fail validation immediately).
+    pub fn busy_retry(mut self, busy_retry: Option<BusyRetry>) -> RedisConnectionManagerBuilder {
+        self.busy_retry = busy_retry;
+        self
+    }
+
+    /// Sets the connection customizer used by the manager.
+    ///
+    /// Defaults to `NopConnectionCustomizer`.
+    pub fn connection_customizer(
+        mut self,
+        connection_customizer: Box<dyn ConnectionCustomizer>,
+    ) -> RedisConnectionManagerBuilder {
+        self.connection_customizer = Some(Arc::from(connection_customizer));
+        self
+    }
+
+    /// Sets the sink receiving the manager's connection lifecycle events.
+    ///
+    /// Defaults to `NopMetricsSink`.
+    pub fn metrics_sink(
+        mut self,
+        metrics_sink: Box<dyn PoolMetricsSink>,
+    ) -> RedisConnectionManagerBuilder {
+        self.metrics_sink = Arc::from(metrics_sink);
+        self
+    }
+
+    /// Sets the maximum age of a connection.
+    ///
+    /// Unlike `r2d2::Builder::max_lifetime`, which closes idle connections in
+    /// the background, this is checked whenever a connection is returned to
+    /// the pool, so busy connections are recycled promptly as well.
+    ///
+    /// Defaults to `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_lifetime` is the zero `Duration`.
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> RedisConnectionManagerBuilder {
+        assert_ne!(
+            max_lifetime,
+            Some(Duration::from_secs(0)),
+            "max_lifetime must be positive"
+        );
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Sets the maximum number of times a connection is checked out before
+    /// it is closed and replaced.
+    ///
+    /// Defaults to `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_uses` is 0.
+    pub fn max_uses(mut self, max_uses: Option<u64>) -> RedisConnectionManagerBuilder {
+        assert_ne!(max_uses, Some(0), "max_uses must be positive");
+        self.max_uses = max_uses;
+        self
+    }
+
+    /// Sets the policy for backing off after failed connection attempts.
+    ///
+    /// While connects keep failing, each new attempt first waits for the
+    /// delay given by the policy, so an unavailable server isn't hammered by
+    /// the pool.
+    ///
+    /// Defaults to `None` (retry immediately).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `multiplier` of the policy is less than 1 or NaN, its
+    /// `jitter` isn't between 0 and 1, or its `initial_delay` exceeds its
+    /// `max_delay`.
+    pub fn reconnect_policy(
+        mut self,
+        reconnect_policy: Option<ReconnectPolicy>,
+    ) -> RedisConnectionManagerBuilder {
+        if let Some(policy) = &reconnect_policy {
+            policy.assert_valid();
+        }
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// Sets the circuit breaker for connection attempts.
+    ///
+    /// Once the circuit opens, connects fail immediately until its cool-down
+    /// has elapsed. Use `RedisConnectionManager::circuit_breaker_handle` to
+    /// fail checkouts fast as well, instead of waiting for the pool's
+    /// connection timeout.
+    ///
+    /// Defaults to `None` (always attempt to connect).
+    pub fn circuit_breaker(
+        mut self,
+        circuit_breaker: Option<CircuitBreaker>,
+    ) -> RedisConnectionManagerBuilder {
+        if let Some(ref circuit_breaker) = circuit_breaker {
+            assert_ne!(
+                circuit_breaker.failure_threshold, 0,
+                "failure_threshold must be positive"
+            );
+        }
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    /// Sets a limit on how fast new connections are established.
+    ///
+    /// Connects beyond the limit block until they may start, which counts
+    /// towards the pool's connection timeout.
+    ///
+    /// Defaults to `None` (no limit).
+    pub fn connect_rate_limit(
+        mut self,
+        connect_rate_limit: Option<ConnectRateLimit>,
+    ) -> RedisConnectionManagerBuilder {
+        if let Some(ref connect_rate_limit) = connect_rate_limit {
+            assert_ne!(
+                connect_rate_limit.max_in_flight, 0,
+                "max_in_flight must be positive"
+            );
+        }
+        self.connect_rate_limit = connect_rate_limit;
+        self
+    }
+
+    /// Sets the registry of Lua scripts loaded on every new connection, so
+    /// `RegisteredScript::invoke` can run them with `EVALSHA` right away.
+    ///
+    /// Defaults to `None`.
+    pub fn script_registry(
+        mut self,
+        script_registry: Option<ScriptRegistry>,
+    ) -> RedisConnectionManagerBuilder {
+        self.script_registry = script_registry;
+        self
+    }
+
+    /// Sets the Redis 7 function libraries every new connection verifies
+    /// the server has, loading them if it doesn't.
+    ///
+    /// Defaults to `None`.
+    pub fn function_libraries(
+        mut self,
+        function_libraries: Option<FunctionLibraries>,
+    ) -> RedisConnectionManagerBuilder {
+        self.function_libraries = function_libraries;
+        self
+    }
+
+    /// Sets the modules, e.g. `ReJSON` for RedisJSON, that new connections
+    /// check with `MODULE LIST` the server has loaded, failing with an
+    /// error of kind `ClientError` if it hasn't.
+    ///
+    /// Defaults to none.
+    pub fn required_modules<I, S>(mut self, modules: I) -> RedisConnectionManagerBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.required_modules = modules.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// If true, connections are returned to a clean state whenever they are
+    /// returned to the pool, so no borrower inherits an open transaction,
+    /// watched keys or subscriptions from the previous one.
+    ///
+    /// On Redis 6.2 and later this issues `RESET` and then re-applies the
+    /// credentials, database, client name and
+    /// `ConnectionCustomizer::on_connect` setup of a fresh connection. On
+    /// older servers it issues `DISCARD`, `UNWATCH` and unsubscribes instead.
+    /// Connections that cannot be reset are discarded.
+    ///
+    /// This costs a round-trip on every check-in.
+    ///
+    /// Defaults to false.
+    pub fn reset_on_checkin(mut self, reset_on_checkin: bool) -> RedisConnectionManagerBuilder {
+        self.reset_on_checkin = reset_on_checkin;
+        self
+    }
+
+    /// If true, connections are checked for replies nobody read whenever they
+    /// are returned to the pool, and discarded if any are found, so the next
+    /// borrower can't receive a stale reply.
+    ///
+    /// This catches commands sent with `send_packed_command` that were never
+    /// received. Connections on which a command failed with an I/O error or
+    /// timeout are always discarded, since their reply may still arrive.
+    ///
+    /// The check waits briefly (a few milliseconds at most) for pending data
+    /// on every check-in.
+    ///
+    /// Defaults to false.
+    pub fn check_unread_replies(
+        mut self,
+        check_unread_replies: bool,
+    ) -> RedisConnectionManagerBuilder {
+        self.check_unread_replies = check_unread_replies;
+        self
+    }
+
+    /// If true, the manager only uses commands that command proxies such as
+    /// twemproxy and Envoy's Redis proxy forward.
+    ///
+    /// Connections are validated with an `EXISTS` on a fixed key instead of
+    /// `PING`, which proxies may answer themselves, unless another
+    /// `validation` is set. Building the manager fails if the configuration
+    /// needs a command proxies don't support: a non-zero database
+    /// (`SELECT`), a `username` (ACL `AUTH`), a `client_name`
+    /// (`CLIENT SETNAME`), `reset_on_checkin` (`RESET`), a `server_role`
+    /// (`ROLE`), `required_modules` (`MODULE LIST`), `function_libraries`
+    /// (`FUNCTION LOAD`), a `script_registry` (`SCRIPT LOAD`), sentinels or a
+    /// cluster.
+    ///
+    /// Defaults to false.
+    pub fn proxy_mode(mut self, proxy_mode: bool) -> RedisConnectionManagerBuilder {
+        self.proxy_mode = proxy_mode;
+        self
+    }
+
+    /// Returns the first setting that `proxy_mode` rules out, if any.
+    fn proxy_conflict(&self, endpoints: &[Endpoint]) -> Option<&'static str> {
+        if endpoints
+            .iter()
+            .any(|endpoint| endpoint.connection_info.db != 0)
+        {
+            Some("SELECT is not supported behind a proxy, use database 0")
+        } else if endpoints
+            .iter()
+            .any(|endpoint| endpoint.connection_info.username.is_some())
+        {
+            Some("AUTH with a username is not supported behind a proxy")
+        } else if self.client_name.is_some() {
+            Some("CLIENT SETNAME is not supported behind a proxy")
+        } else if self.reset_on_checkin {
+            Some("RESET is not supported behind a proxy")
+        } else if self.server_role.is_some() {
+            Some("ROLE is not supported behind a proxy")
+        } else if !self.required_modules.is_empty() {
+            Some("MODULE LIST is not supported behind a proxy")
+        } else if self.function_libraries.is_some() {
+            Some("FUNCTION LOAD is not supported behind a proxy")
+        } else if self.script_registry.is_some() {
+            Some("SCRIPT LOAD is not supported behind a proxy")
+        } else {
+            None
+        }
+    }
+
+    /// Consumes the builder, returning a new `RedisConnectionManager` for
+    /// the given connection parameters.
+    ///
+    /// See `redis::Client::open` for a description of the parameter
+    /// types.
+    pub fn build<T: redis::IntoConnectionInfo>(
+        self,
+        params: T,
+    ) -> Result<RedisConnectionManager, redis::RedisError> {
+        self.build_with_endpoints(Some(params))
+    }
+
+    /// Requires the server to have the given replication role, e.g. for a
+    /// pool dedicated to reads from replicas.
+    ///
+    /// The role is checked with `ROLE` when a connection is established and
+    /// whenever it is validated, so connections to a replica that was
+    /// promoted (or a master that was demoted) are closed. For a cluster,
+    /// `ServerRole::Replica` sends `READONLY` to the nodes instead and routes
+    /// commands to replicas where there are any. A
+    /// `RedisSentinelConnectionManager` always requires a master.
+    ///
+    /// Defaults to `None` (any role).
+    pub fn server_role(mut self, server_role: Option<ServerRole>) -> RedisConnectionManagerBuilder {
+        self.server_role = server_role;
+        self
+    }
+
+    /// Consumes the builder, returning a new `RedisPubSubConnectionManager`
+    /// connecting to `params`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error naming the first setting
+    /// subscribed connections don't support, see
+    /// `RedisPubSubConnectionManager`.
+    pub fn build_pubsub<T: redis::IntoConnectionInfo>(
+        self,
+        params: T,
+    ) -> Result<RedisPubSubConnectionManager, redis::RedisError> {
+        RedisPubSubConnectionManager::from_manager(self.build(params)?)
+    }
+
+    /// Consumes the builder, returning a new `RedisSentinelConnectionManager`
+    /// configured by a URL of the form
+    /// `redis+sentinel://[user:pass@]host1[:port],host2[:port]/master_name[?db=N]`.
+    ///
+    /// The credentials and database apply to the master, unless set on the
+    /// builder. Sentinels without a port use 26379. A `rediss+sentinel://`
+    /// URL connects to both the sentinels and the master with TLS.
+    ///
+    /// See `build_sentinel` for how the other settings apply.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if the URL is invalid, names no
+    /// master or has other query parameters than `db`.
+    pub fn build_sentinel_url(
+        mut self,
+        url: &str,
+    ) -> Result<RedisSentinelConnectionManager, redis::RedisError> {
+        let url = crate::url::parse(url, "sentinel", 26379)?;
+        if url.path.is_empty() {
+            return Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "invalid URL",
+                "no master name in sentinel URL".to_string(),
+            )
+                .into());
+        }
+        for (name, value) in &url.query {
+            match name.as_str() {
+                "db" if self.db.is_none() => {
+                    self.db = Some(
+                        value
+                            .parse()
+                            .map_err(|_| crate::url::invalid_parameter(name, value))?,
+                    );
+                }
+                "db" => {}
+                _ => return Err(crate::url::unknown_parameter(name)),
+            }
+        }
+        if self.username.is_none() {
+            self.username = url.username;
+        }
+        if self.password.is_none() {
+            self.password = url.password.map(Secret);
+        }
+        self.master_tls |= url.tls;
+
+        let (tls, insecure) = (url.tls, self.accept_invalid_certs);
+        let sentinels = url
+            .hosts
+            .into_iter()
+            .map(|(host, port)| redis::ConnectionInfo {
+                addr: Box::new(if tls {
+                    redis::ConnectionAddr::TcpTls {
+                        host,
+                        port,
+                        insecure,
+                    }
+                } else {
+                    redis::ConnectionAddr::Tcp(host, port)
+                }),
+                db: 0,
+                username: None,
+                passwd: None,
+            })
+            .collect::<Vec<_>>();
+        self.build_sentinel(sentinels, url.path)
+    }
+
+    /// If true, a `RedisSentinelConnectionManager` subscribes to the
+    /// sentinels' `+switch-master` events on a background thread, so that
+    /// after a failover connections to the old master are closed when they
+    /// are next checked out or returned, rather than when a command fails.
+    ///
+    /// Without it, a failover is only noticed once a new connection is made.
+    /// Has no effect on managers built with `build`.
+    ///
+    /// Defaults to `true`.
+    pub fn watch_switch_master(
+        mut self,
+        watch_switch_master: bool,
+    ) -> RedisConnectionManagerBuilder {
+        self.watch_switch_master = watch_switch_master;
+        self
+    }
+
+    /// Sets the username used to authenticate with the sentinels of a
+    /// `RedisSentinelConnectionManager`, overriding the ones given in their
+    /// connection parameters.
+    ///
+    /// The username is only sent along with a password.
+    pub fn sentinel_username<U: Into<String>>(
+        mut self,
+        username: U,
+    ) -> RedisConnectionManagerBuilder {
+        self.sentinel_username = Some(username.into());
+        self
+    }
+
+    /// Sets the password used to authenticate with the sentinels of a
+    /// `RedisSentinelConnectionManager`, overriding the ones given in their
+    /// connection parameters.
+    ///
+    /// The master is authenticated with `username` and `password` instead.
+    pub fn sentinel_password<P: Into<String>>(
+        mut self,
+        password: P,
+    ) -> RedisConnectionManagerBuilder {
+        self.sentinel_password = Some(Secret(password.into()));
+        self
+    }
+
+    /// If true, a `RedisSentinelConnectionManager` connects to the master
+    /// with TLS, verified according to `danger_accept_invalid_certs`.
+    ///
+    /// Whether the sentinels are connected to with TLS is given by their
+    /// connection parameters, e.g. a `rediss://` URL.
+    ///
+    /// Defaults to `false`.
+    pub fn master_tls(mut self, master_tls: bool) -> RedisConnectionManagerBuilder {
+        self.master_tls = master_tls;
+        self
+    }
+
+    /// Consumes the builder, returning a new `RedisSentinelConnectionManager`
+    /// which connects to the master named `master_name`, as reported by the
+    /// first of `sentinels` that answers.
+    ///
+    /// The settings of the builder apply to the connections to the master;
+    /// the sentinels are connected to with the parameters given for them,
+    /// apart from `sentinel_username` and `sentinel_password`, and
+    /// `connect_timeout` also bounds each sentinel query.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if `sentinels` is empty.
+    pub fn build_sentinel<I, T, N>(
+        self,
+        sentinels: I,
+        master_name: N,
+    ) -> Result<RedisSentinelConnectionManager, redis::RedisError>
+    where
+        I: IntoIterator<Item = T>,
+        T: redis::IntoConnectionInfo,
+        N: Into<String>,
+    {
+        let sentinels = sentinels
+            .into_iter()
+            .map(|params| {
+                let mut connection_info = params.into_connection_info()?;
+                if let Some(ref username) = self.sentinel_username {
+                    connection_info.username = Some(username.clone());
+                }
+                if let Some(ref password) = self.sentinel_password {
+                    connection_info.passwd = Some(password.0.clone());
+                }
+                Endpoint::new(connection_info)
+            })
+            .collect::<redis::RedisResult<Vec<_>>>()?;
+        if sentinels.is_empty() {
+            return Err((redis::ErrorKind::InvalidClientConfig, "no sentinels given").into());
+        }
+        if self.proxy_mode {
+            return Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "sentinels are not supported behind a proxy",
+            )
+                .into());
+        }
+        let master_name = master_name.into();
+        let timeout = self.connect_timeout;
+        let watch_switch_master = self.watch_switch_master;
+
+        // The address is replaced by the one the sentinels report.
+        let addr = if self.master_tls {
+            redis::ConnectionAddr::TcpTls {
+                host: master_name.clone(),
+                port: 0,
+                insecure: false,
+            }
+        } else {
+            redis::ConnectionAddr::Tcp(master_name.clone(), 0)
+        };
+        let mut manager =
+            self.server_role(Some(ServerRole::Master))
+                .build(redis::ConnectionInfo {
+                    addr: Box::new(addr),
+                    db: 0,
+                    username: None,
+                    passwd: None,
+                })?;
+        let sentinel = Sentinel::new(sentinels, master_name, timeout);
+        if watch_switch_master {
+            sentinel.watch();
+        }
+        manager.sentinel = Some(sentinel);
+        Ok(RedisSentinelConnectionManager::from_manager(manager))
+    }
+
+    /// Consumes the builder, returning a new `RedisClusterConnectionManager`
+    /// which discovers the cluster from `initial_nodes`.
+    ///
+    /// Of the builder's settings only `password`, `server_role`,
+    /// `read_timeout` and `write_timeout` apply to cluster connections.
+    /// Requires the `cluster` feature.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if `initial_nodes` is empty,
+    /// if any of them is a TLS or Unix socket address, or if a `username`
+    /// was set, none of which `redis::cluster` supports.
+    #[cfg(feature = "cluster")]
+    pub fn build_cluster<T: redis::IntoConnectionInfo>(
+        self,
+        initial_nodes: Vec<T>,
+    ) -> Result<RedisClusterConnectionManager, redis::RedisError> {
+        if self.username.is_some() {
+            return Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "cluster connections do not support usernames",
+            )
+                .into());
+        }
+        if self.proxy_mode {
+            return Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "cluster connections are not supported behind a proxy",
+            )
+                .into());
+        }
+        let initial_nodes = initial_nodes
+            .into_iter()
+            .map(redis::IntoConnectionInfo::into_connection_info)
+            .collect::<redis::RedisResult<Vec<_>>>()?;
+        RedisClusterConnectionManager::from_parts(
+            initial_nodes,
+            self.password.map(|password| password.0),
+            self.server_role == Some(ServerRole::Replica),
+            self.read_timeout,
+            self.write_timeout,
+        )
+    }
+
+    /// Consumes the builder, returning a new `RedisClusterConnectionManager`
+    /// configured by a URL of the form
+    /// `redis+cluster://[:pass@]host1[:port],host2[:port]`.
+    ///
+    /// The password applies unless one is set on the builder. Nodes without
+    /// a port use 6379. Requires the `cluster` feature.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if the URL is invalid, has a
+    /// path or query parameters, or if `build_cluster` rejects the
+    /// configuration. In particular, `rediss+cluster://` URLs and usernames
+    /// are rejected, since `redis::cluster` supports neither TLS nor ACL
+    /// users.
+    #[cfg(feature = "cluster")]
+    pub fn build_cluster_url(
+        mut self,
+        url: &str,
+    ) -> Result<RedisClusterConnectionManager, redis::RedisError> {
+        let url = crate::url::parse(url, "cluster", 6379)?;
+        if !url.path.is_empty() || !url.query.is_empty() {
+            return Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "invalid URL",
+                "cluster URLs take no database or parameters".to_string(),
+            )
+                .into());
+        }
+        if self.username.is_none() {
+            self.username = url.username;
+        }
+        if self.password.is_none() {
+            self.password = url.password.map(Secret);
+        }
+
+        let (tls, insecure) = (url.tls, self.accept_invalid_certs);
+        let initial_nodes = url
+            .hosts
+            .into_iter()
+            .map(|(host, port)| redis::ConnectionInfo {
+                addr: Box::new(if tls {
+                    redis::ConnectionAddr::TcpTls {
+                        host,
+                        port,
+                        insecure,
+                    }
+                } else {
+                    redis::ConnectionAddr::Tcp(host, port)
+                }),
+                db: 0,
+                username: None,
+                passwd: None,
+            })
+            .collect();
+        self.build_cluster(initial_nodes)
+    }
+
+    /// Consumes the builder, returning a new `RedisConnectionManager` which
+    /// connects to the Unix socket at `path`.
+    ///
+    /// The socket is checked up front, so a wrong path or missing
+    /// permissions are reported here rather than by every connection
+    /// attempt. A socket nobody listens on is accepted, since the server may
+    /// just not be running yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error naming the path if it doesn't
+    /// exist, isn't a socket or can't be connected to for lack of
+    /// permissions.
+    #[cfg(unix)]
+    pub fn build_unix<P: AsRef<std::path::Path>>(
+        self,
+        path: P,
+    ) -> Result<RedisConnectionManager, redis::RedisError> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let path = path.as_ref();
+        let invalid = |reason: &'static str| -> redis::RedisError {
+            (
+                redis::ErrorKind::InvalidClientConfig,
+                reason,
+                path.display().to_string(),
+            )
+                .into()
+        };
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {}
+            Ok(_) => return Err(invalid("not a Unix socket")),
+            Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Err(invalid("permission denied for Unix socket"))
+            }
+            Err(_) => return Err(invalid("Unix socket does not exist")),
+        }
+        if let Err(e) = std::os::unix::net::UnixStream::connect(path) {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                return Err(invalid("permission denied for Unix socket"));
+            }
+        }
+
+        self.build(redis::ConnectionInfo {
+            addr: Box::new(redis::ConnectionAddr::Unix(path.to_owned())),
+            db: 0,
+            username: None,
+            passwd: None,
+        })
+    }
+
+    /// Sets the resolver looking up the SRV record of managers built with
+    /// `build_srv`.
+    ///
+    /// Defaults to `DnsSrvResolver::system()`.
+    pub fn srv_resolver(mut self, resolver: Box<dyn SrvResolver>) -> RedisConnectionManagerBuilder {
+        self.srv_resolver = Some(Arc::from(resolver));
+        self
+    }
+
+    /// Sets how long the targets of the SRV record of managers built with
+    /// `build_srv` are used before the record is looked up again.
+    ///
+    /// The record is also looked up again when none of its targets accepts
+    /// a connection.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn srv_refresh_interval(
+        mut self,
+        srv_refresh_interval: Duration,
+    ) -> RedisConnectionManagerBuilder {
+        self.srv_refresh_interval = srv_refresh_interval;
+        self
+    }
+
+    /// If true, managers built with `build_srv` connect to the targets of
+    /// the SRV record with TLS, verified according to
+    /// `danger_accept_invalid_certs`.
+    ///
+    /// Defaults to `false`.
+    pub fn srv_tls(mut self, srv_tls: bool) -> RedisConnectionManagerBuilder {
+        self.srv_tls = srv_tls;
+        self
+    }
+
+    /// Consumes the builder, returning a new `RedisConnectionManager` which
+    /// connects to the targets of the DNS SRV record `name`, e.g.
+    /// `_redis._tcp.cache.internal`.
+    ///
+    /// Targets are tried in order of priority, and within a priority in a
+    /// random order favoring those of higher weight, as in RFC 2782. The
+    /// record is looked up on the first connection attempt, then again every
+    /// `srv_refresh_interval` and whenever none of the targets accepts a
+    /// connection. If a lookup fails, the previous targets keep being used.
+    pub fn build_srv<N: Into<String>>(
+        self,
+        name: N,
+    ) -> Result<RedisConnectionManager, redis::RedisError> {
+        let name = name.into();
+        let resolver = self
+            .srv_resolver
+            .clone()
+            .unwrap_or_else(|| Arc::new(DnsSrvResolver::system()));
+        let refresh_interval = self.srv_refresh_interval;
+
+        // The address is replaced by the targets of the record.
+        let addr = if self.srv_tls {
+            redis::ConnectionAddr::TcpTls {
+                host: name.clone(),
+                port: 0,
+                insecure: false,
+            }
+        } else {
+            redis::ConnectionAddr::Tcp(name.clone(), 0)
+        };
+        let mut manager = self.build(redis::ConnectionInfo {
+            addr: Box::new(addr),
+            db: 0,
+            username: None,
+            passwd: None,
+        })?;
+        manager.srv = Some(Srv::new(name, resolver, refresh_interval));
+        Ok(manager)
+    }
+
+    /// Consumes the builder, returning a new `RedisAsyncConnectionManager`
+    /// connecting to `params`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error naming the first setting
+    /// async connections don't support, see `RedisAsyncConnectionManager`.
+    #[cfg(any(
+        feature = "async-std",
+        feature = "bb8",
+        feature = "deadpool",
+        feature = "tokio"
+    ))]
+    pub fn build_async<T: redis::IntoConnectionInfo>(
+        self,
+        params: T,
+    ) -> Result<RedisAsyncConnectionManager, redis::RedisError> {
+        RedisAsyncConnectionManager::from_manager(self.build(params)?)
+    }
+
+    /// Sets how often the connections of a
+    /// `RedisMultiplexedConnectionManager` are checked in the background
+    /// with the `ValidationMode` command, or `None` to only notice broken
+    /// connections when a command fails.
+    ///
+    /// Defaults to 10 seconds.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub fn heartbeat_interval(
+        mut self,
+        heartbeat_interval: Option<Duration>,
+    ) -> RedisConnectionManagerBuilder {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    /// Consumes the builder, returning a new
+    /// `RedisMultiplexedConnectionManager` connecting to `params`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error naming the first setting
+    /// async connections don't support, see `RedisAsyncConnectionManager`,
+    /// or a `connection_customizer`.
+    #[cfg(any(feature = "async-std", feature = "tokio"))]
+    pub fn build_multiplexed<T: redis::IntoConnectionInfo>(
+        self,
+        params: T,
+    ) -> Result<RedisMultiplexedConnectionManager, redis::RedisError> {
+        let heartbeat_interval = self.heartbeat_interval;
+        RedisMultiplexedConnectionManager::from_parts(self.build(params)?, heartbeat_interval)
+    }
+
+    /// Consumes the builder, returning a new `RedisConnectionManager` for a
+    /// URL whose query parameters may configure the manager, e.g.
+    /// `redis://localhost/?connect_timeout=500ms&read_timeout=2s&db=3&client_name=api`.
+    ///
+    /// The parameters are `connect_timeout`, `read_timeout` and
+    /// `write_timeout`, given as `500ms`, `2s`, `1m` or a bare number of
+    /// seconds, `db` and `client_name`. They apply unless the corresponding
+    /// setting was made on the builder.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if the URL is invalid or has an
+    /// unknown or invalid parameter. `redis+sentinel://` URLs are rejected,
+    /// they are built with `build_sentinel_url`.
+    pub fn build_url(mut self, url: &str) -> Result<RedisConnectionManager, redis::RedisError> {
+        if crate::url::has_kind(url, "sentinel") {
+            return Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "invalid URL",
+                "sentinel URLs need a RedisSentinelConnectionManager, see \
+                 RedisSentinelConnectionManager::from_url"
+                    .to_owned(),
+            )
+                .into());
+        }
+        let (url, query) = crate::url::split_query(url)?;
+        for (name, value) in &query {
+            let duration = || {
+                crate::url::parse_duration(value)
+                    .ok_or_else(|| crate::url::invalid_parameter(name, value))
+            };
+            match name.as_str() {
+                "connect_timeout" => {
+                    let timeout = duration()?;
+                    self.connect_timeout = self.connect_timeout.or(Some(timeout));
+                }
+                "read_timeout" => {
+                    let timeout = duration()?;
+                    self.read_timeout = self.read_timeout.or(Some(timeout));
+                }
+                "write_timeout" => {
+                    let timeout = duration()?;
+                    self.write_timeout = self.write_timeout.or(Some(timeout));
+                }
+                "db" => {
+                    let db = value
+                        .parse()
+                        .map_err(|_| crate::url::invalid_parameter(name, value))?;
+                    self.db = self.db.or(Some(db));
+                }
+                "client_name" if value.is_empty() => {
+                    return Err(crate::url::invalid_parameter(name, value))
+                }
+                "client_name" => {
+                    if self.client_name.is_none() {
+                        self.client_name = Some(ClientName::Fixed(value.clone()));
+                    }
+                }
+                _ => return Err(crate::url::unknown_parameter(name)),
+            }
+        }
+        self.build(url.as_str())
+    }
+
+    /// Consumes the builder, returning a new `RedisConnectionManager` which
+    /// connects to whichever of the given endpoints is available, as chosen
+    /// by `endpoint_selection`.
+    ///
+    /// Connections established to the endpoints after the first are only
+    /// replaced once they break or expire, e.g. by `max_lifetime`, unless a
+    /// `failback_interval` is set.
+    ///
+    /// See `redis::Client::open` for a description of the parameter
+    /// types.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if `endpoints` is empty.
+    pub fn build_with_endpoints<I, T>(
+        self,
+        endpoints: I,
+    ) -> Result<RedisConnectionManager, redis::RedisError>
+    where
+        I: IntoIterator<Item = T>,
+        T: redis::IntoConnectionInfo,
+    {
+        let endpoints = endpoints
+            .into_iter()
+            .map(|params| {
+                let mut connection_info = params.into_connection_info()?;
+                if let Some(db) = self.db {
+                    connection_info.db = db;
+                }
+                if let Some(ref username) = self.username {
+                    connection_info.username = Some(username.clone());
+                }
+                if let Some(ref password) = self.password {
+                    connection_info.passwd = Some(password.0.clone());
+                }
+                if let redis::ConnectionAddr::TcpTls {
+                    ref host,
+                    port,
+                    ref mut insecure,
+                } = *connection_info.addr
+                {
+                    if self.accept_invalid_certs {
+                        *insecure = true;
+                    }
+                    if *insecure {
+                        log::warn!(
+                            "TLS certificate verification is disabled for {}:{}",
+                            host,
+                            port
+                        );
+                    }
+                }
+                Endpoint::new(connection_info)
+            })
+            .collect::<redis::RedisResult<Vec<_>>>()?;
+        if endpoints.is_empty() {
+            return Err((redis::ErrorKind::InvalidClientConfig, "no endpoints given").into());
+        }
+        let mut validation = self.validation.clone();
+        if self.proxy_mode {
+            if let Some(conflict) = self.proxy_conflict(&endpoints) {
+                return Err((redis::ErrorKind::InvalidClientConfig, conflict).into());
+            }
+            if let ValidationMode::Ping = validation {
+                let mut exists = redis::cmd("EXISTS");
+                exists.arg(PROXY_VALIDATION_KEY);
+                validation = ValidationMode::Command(exists);
+            }
+        }
+
+        let failover = Arc::new(Failover::default());
+        let failback = self.endpoint_selection == EndpointSelection::Failover
+            && self.failback_interval.is_some();
+        if let (true, Some(interval)) = (failback && endpoints.len() > 1, self.failback_interval) {
+            let clients = endpoints
+                .iter()
+                .map(|endpoint| endpoint.client.clone())
+                .collect();
+            failover.watch(clients, interval);
+        }
+
+        Ok(RedisConnectionManager {
+            endpoints,
+            endpoint_selection: self.endpoint_selection,
+            next_endpoint: AtomicUsize::new(0),
+            failover,
+            failback,
+            credentials_provider: self.credentials_provider,
+            reauthenticate_interval: self.reauthenticate_interval,
+            resolver: self.resolver,
+            address_family: self.address_family,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            client_name: self.client_name,
+            sequence: AtomicU64::new(0),
+            validation,
+            validation_interval: self.validation_interval,
+            validation_timeout: self.validation_timeout,
+            busy_retry: self.busy_retry,
+            #[cfg(any(feature = "async-std", feature = "tokio"))]
+            customized: self.connection_customizer.is_some(),
+            connection_customizer: self
+                .connection_customizer
+                .unwrap_or_else(|| Arc::new(NopConnectionCustomizer)),
+            metrics_sink: self.metrics_sink,
+            max_lifetime: self.max_lifetime,
+            max_uses: self.max_uses,
+            backoff: self.reconnect_policy.map(Backoff::new),
+            circuit_breaker: self
+                .circuit_breaker
+                .map(|policy| Arc::new(Breaker::new(policy))),
+            rate_limiter: self.connect_rate_limit.map(ConnectLimiter::new),
+            script_registry: self.script_registry,
+            function_libraries: self.function_libraries,
+            required_modules: self.required_modules,
+            sentinel: None,
+            srv: None,
+            server_role: self.server_role,
+            reset_on_checkin: self.reset_on_checkin,
+            supports_reset: AtomicBool::new(true),
+            check_unread_replies: self.check_unread_replies,
+            draining: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
@@ -0,0 +1,708 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::backoff::jittered;
+use crate::pool_ext::checkout;
+use crate::{
+    Codec, DistributedLock, JsonCodec, RedisConnectionManager, StreamConsumer, StreamConsumerHandle,
+};
+
+/// Keeps the callers of `Cache::get_or_compute` that miss the same key at
+/// once from all computing its value.
+///
+/// The first caller takes a short lock in Redis, at
+/// `<prefix>\0meta:lock:<key>`, and computes the value while the others, in
+/// any process, wait up to `wait` for it to be cached. If `serve_stale_for`
+/// is set, values are kept that much longer than their TTL, and the callers
+/// that don't get the lock serve the stale value instead of waiting; the TTL
+/// is then tracked by a marker key at `<prefix>\0meta:fresh:<key>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StampedeProtection {
+    /// How long the lock is held at most, in case its holder dies while
+    /// computing.
+    pub lock_ttl: Duration,
+    /// How long callers wait for the lock holder to cache the value before
+    /// computing it themselves.
+    pub wait: Duration,
+    /// How often waiting callers check whether the value was cached.
+    pub poll_interval: Duration,
+    /// How long values are served after their TTL while they are computed
+    /// again.
+    pub serve_stale_for: Option<Duration>,
+}
+
+impl Default for StampedeProtection {
+    fn default() -> StampedeProtection {
+        StampedeProtection {
+            lock_ttl: Duration::from_secs(10),
+            wait: Duration::from_secs(5),
+            poll_interval: Duration::from_millis(50),
+            serve_stale_for: None,
+        }
+    }
+}
+
+/// Persists a value written through a `Cache`.
+type Persist<T> = Arc<dyn Fn(&str, &T) -> Result<(), String> + Send + Sync>;
+
+/// How `Cache::write` persists values besides caching them.
+enum WriteStrategy<T> {
+    CacheOnly,
+    Through(Persist<T>),
+    Behind,
+}
+
+impl<T> Clone for WriteStrategy<T> {
+    fn clone(&self) -> WriteStrategy<T> {
+        match *self {
+            WriteStrategy::CacheOnly => WriteStrategy::CacheOnly,
+            WriteStrategy::Through(ref persist) => WriteStrategy::Through(persist.clone()),
+            WriteStrategy::Behind => WriteStrategy::Behind,
+        }
+    }
+}
+
+impl<T> fmt::Debug for WriteStrategy<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            WriteStrategy::CacheOnly => "CacheOnly",
+            WriteStrategy::Through(_) => "Through",
+            WriteStrategy::Behind => "Behind",
+        })
+    }
+}
+
+/// A cache of values of type `T` with expiring entries, on connections of
+/// a pool.
+///
+/// Values are serialized with a `Codec`, JSON by default, and stored under
+/// `<prefix>:<key>`, so several caches can share a database. Keys used by
+/// the cache itself, e.g. for locks, are under `<prefix>\0meta:`, which no
+/// key of a value can clash with.
+///
+/// Requires the `serde` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, Cache, RedisConnectionManager};
+///
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct User {
+///     name: String,
+/// }
+///
+/// fn load_user(id: u64) -> redis::RedisResult<User> {
+///     Ok(User { name: format!("user {}", id) })
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let users = Cache::<User>::new(pool, "users");
+///     let user = users
+///         .get_or_compute("42", Duration::from_secs(300), || load_user(42))
+///         .unwrap();
+///     println!("{}", user.name);
+/// }
+/// ```
+pub struct Cache<T, C = JsonCodec> {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    prefix: String,
+    codec: C,
+    stampede_protection: Option<StampedeProtection>,
+    ttl_jitter: f64,
+    write_strategy: WriteStrategy<T>,
+    _value: PhantomData<fn(&T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Cache<T> {
+    /// Creates a `Cache` storing JSON values under `prefix`.
+    pub fn new<S: Into<String>>(pool: r2d2::Pool<RedisConnectionManager>, prefix: S) -> Cache<T> {
+        Cache::with_codec(pool, prefix, JsonCodec)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> Cache<T, C> {
+    /// Creates a `Cache` storing values encoded with `codec` under `prefix`.
+    pub fn with_codec<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        prefix: S,
+        codec: C,
+    ) -> Cache<T, C> {
+        Cache {
+            pool,
+            prefix: prefix.into(),
+            codec,
+            stampede_protection: None,
+            ttl_jitter: 0.0,
+            write_strategy: WriteStrategy::CacheOnly,
+            _value: PhantomData,
+        }
+    }
+
+    /// Sets how `get_or_compute` keeps concurrent misses of a key from all
+    /// computing its value.
+    ///
+    /// Defaults to `None`: every caller that misses computes the value.
+    pub fn stampede_protection(
+        mut self,
+        stampede_protection: Option<StampedeProtection>,
+    ) -> Cache<T, C> {
+        self.stampede_protection = stampede_protection;
+        self
+    }
+
+    /// Sets the fraction of the TTL, between 0 and 1, by which the TTL of
+    /// each write is randomly spread in either direction, so keys written
+    /// together don't all expire at once.
+    ///
+    /// Defaults to 0 (no jitter).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ttl_jitter` isn't between 0 and 1.
+    pub fn ttl_jitter(mut self, ttl_jitter: f64) -> Cache<T, C> {
+        assert!(
+            (0.0..=1.0).contains(&ttl_jitter),
+            "ttl_jitter must be between 0 and 1"
+        );
+        self.ttl_jitter = ttl_jitter;
+        self
+    }
+
+    /// Returns the value cached for `key`, if any and fresh.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `TypeError` if the cached value can't be
+    /// decoded, e.g. because it was written by an older version of `T`.
+    pub fn get(&self, key: &str) -> redis::RedisResult<Option<T>> {
+        Ok(self
+            .lookup(key)?
+            .and_then(|(value, fresh)| if fresh { Some(value) } else { None }))
+    }
+
+    /// Caches `value` for `key`, for `ttl` spread by the `ttl_jitter`.
+    ///
+    /// This only updates the cache, whatever the write strategy; see
+    /// `write`.
+    pub fn set_with_ttl(&self, key: &str, value: &T, ttl: Duration) -> redis::RedisResult<()> {
+        let mut pipe = redis::pipe();
+        self.queue_set(&mut pipe, key, self.codec.encode(value)?, ttl);
+        pipe.query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Makes `write` call `persist` with each value, e.g. to store it in a
+    /// database, and only cache it once that succeeded.
+    pub fn write_through<F, E>(mut self, persist: F) -> Cache<T, C>
+    where
+        F: Fn(&str, &T) -> Result<(), E> + Send + Sync + 'static,
+        E: fmt::Display,
+    {
+        self.write_strategy = WriteStrategy::Through(Arc::new(move |key, value| {
+            persist(key, value).map_err(|e| e.to_string())
+        }));
+        self
+    }
+
+    /// Makes `write` queue each value, along with caching it, in a stream at
+    /// `<prefix>\0meta:write-behind`, for a `write_behind_worker` to
+    /// persist.
+    pub fn write_behind(mut self) -> Cache<T, C> {
+        self.write_strategy = WriteStrategy::Behind;
+        self
+    }
+
+    /// Caches `value` for `key`, for `ttl`, and persists it according to the
+    /// write strategy.
+    ///
+    /// # Errors
+    ///
+    /// With `write_through`, returns an error of kind `ClientError`, without
+    /// caching the value, if it couldn't be persisted.
+    pub fn write(&self, key: &str, value: &T, ttl: Duration) -> redis::RedisResult<()> {
+        match self.write_strategy {
+            WriteStrategy::CacheOnly => self.set_with_ttl(key, value, ttl),
+            WriteStrategy::Through(ref persist) => {
+                persist(key, value).map_err(|e| {
+                    redis::RedisError::from((
+                        redis::ErrorKind::ClientError,
+                        "couldn't persist value",
+                        e,
+                    ))
+                })?;
+                self.set_with_ttl(key, value, ttl)
+            }
+            WriteStrategy::Behind => {
+                let payload = self.codec.encode(value)?;
+                let mut pipe = redis::pipe();
+                pipe.atomic();
+                self.queue_set(&mut pipe, key, payload.clone(), ttl);
+                pipe.cmd("XADD")
+                    .arg(self.write_behind_key())
+                    .arg("*")
+                    .arg("key")
+                    .arg(key)
+                    .arg("value")
+                    .arg(payload)
+                    .ignore();
+                pipe.query(&mut *checkout(&self.pool)?)
+            }
+        }
+    }
+
+    /// Starts a consumer named `consumer` of the `write_behind` stream,
+    /// passing each queued value to `persist`.
+    ///
+    /// The stream is read in the consumer group `write-behind` with a
+    /// `StreamConsumer`, so several workers can share the load, and values
+    /// `persist` fails for are passed again later, e.g. by another worker.
+    /// `persist` must therefore be idempotent, and values of the same key
+    /// may be persisted out of order after a failure. Persisted entries are
+    /// deleted from the stream, and entries that can't be decoded are
+    /// logged and dropped.
+    pub fn write_behind_worker<F, E>(
+        &self,
+        consumer: &str,
+        mut persist: F,
+    ) -> redis::RedisResult<StreamConsumerHandle>
+    where
+        F: FnMut(&str, T) -> Result<(), E> + Send + 'static,
+        E: fmt::Display,
+        T: 'static,
+        C: Clone + Send + 'static,
+    {
+        let codec = self.codec.clone();
+        let pool = self.pool.clone();
+        let stream = self.write_behind_key();
+        StreamConsumer::new(pool.clone(), stream.clone(), "write-behind", consumer)
+            .create_group(Some("0"))
+            .start(move |entry| {
+                let key: Option<String> = entry.get("key");
+                let payload: Option<Vec<u8>> = entry.get("value");
+                let value = match (key, payload) {
+                    (Some(key), Some(payload)) => {
+                        codec.decode::<T>(&payload).map(|value| (key, value))
+                    }
+                    _ => Err((redis::ErrorKind::TypeError, "missing key or value").into()),
+                };
+                match value {
+                    Ok((key, value)) => persist(&key, value).map_err(|e| e.to_string())?,
+                    Err(e) => log::warn!("dropping write-behind entry {}: {}", entry.id, e),
+                }
+                if let Ok(mut conn) = pool.get() {
+                    let _ = redis::cmd("XDEL")
+                        .arg(&stream)
+                        .arg(&entry.id)
+                        .query::<()>(&mut *conn);
+                }
+                Ok::<(), String>(())
+            })
+    }
+
+    /// Removes the value cached for `key`, returning false if there was
+    /// none.
+    pub fn delete(&self, key: &str) -> redis::RedisResult<bool> {
+        let mut conn = checkout(&self.pool)?;
+        let deleted: u32 = redis::cmd("DEL")
+            .arg(self.key(key))
+            .arg(self.fresh_key(key))
+            .query(&mut *conn)?;
+        Ok(deleted > 0)
+    }
+
+    /// Returns the value cached for `key`, or computes it with `f` and
+    /// caches it for `ttl`.
+    ///
+    /// A cached value that can't be decoded is computed again and
+    /// overwritten. Failures of `f` aren't cached. With
+    /// `stampede_protection`, concurrent callers missing the same key wait
+    /// for one of them to compute it, or serve the stale value.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `f`, or the error reading or writing the cache.
+    pub fn get_or_compute<F, E>(&self, key: &str, ttl: Duration, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<redis::RedisError>,
+    {
+        let cached = self.lookup_decodable(key)?;
+        let stale = match cached {
+            Some((value, true)) => return Ok(value),
+            Some((value, false)) => Some(value),
+            None => None,
+        };
+        let protection = match self.stampede_protection {
+            Some(protection) => protection,
+            None => return self.compute(key, ttl, f),
+        };
+        let lock = DistributedLock::new(self.pool.clone()).retry(0, Duration::from_secs(0));
+        match lock.acquire(self.meta_key(&format!("lock:{}", key)), protection.lock_ttl)? {
+            Some(_guard) => {
+                // The previous holder may have cached it since the lookup.
+                if let Some((value, true)) = self.lookup_decodable(key)? {
+                    return Ok(value);
+                }
+                self.compute(key, ttl, f)
+            }
+            None => {
+                if let Some(value) = stale {
+                    return Ok(value);
+                }
+                let deadline = Instant::now() + protection.wait;
+                while Instant::now() < deadline {
+                    thread::sleep(protection.poll_interval);
+                    if let Some((value, _)) = self.lookup_decodable(key)? {
+                        return Ok(value);
+                    }
+                }
+                // The holder is slow or failed.
+                self.compute(key, ttl, f)
+            }
+        }
+    }
+
+    /// Queues the commands caching `payload` for `key` on `pipe`.
+    fn queue_set(&self, pipe: &mut redis::Pipeline, key: &str, payload: Vec<u8>, ttl: Duration) {
+        let ttl_ms = jittered(ttl, self.ttl_jitter).as_millis().max(1) as u64;
+        pipe.cmd("SET").arg(self.key(key)).arg(payload).arg("PX");
+        match self.serve_stale_for() {
+            Some(stale) => {
+                pipe.arg(ttl_ms + stale.as_millis() as u64)
+                    .ignore()
+                    .cmd("SET")
+                    .arg(self.fresh_key(key))
+                    .arg(1)
+                    .arg("PX")
+                    .arg(ttl_ms)
+                    .ignore();
+            }
+            None => {
+                pipe.arg(ttl_ms).ignore();
+            }
+        }
+    }
+
+    fn write_behind_key(&self) -> String {
+        self.meta_key("write-behind")
+    }
+
+    fn compute<F, E>(&self, key: &str, ttl: Duration, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<redis::RedisError>,
+    {
+        let value = f()?;
+        self.set_with_ttl(key, &value, ttl)?;
+        Ok(value)
+    }
+
+    /// Returns the value cached for `key`, if any, and whether it is fresh.
+    fn lookup(&self, key: &str) -> redis::RedisResult<Option<(T, bool)>> {
+        let mut conn = checkout(&self.pool)?;
+        let (payload, fresh) = match self.serve_stale_for() {
+            Some(_) => {
+                let (payload, fresh): (Option<Vec<u8>>, Option<u8>) = redis::cmd("MGET")
+                    .arg(self.key(key))
+                    .arg(self.fresh_key(key))
+                    .query(&mut *conn)?;
+                (payload, fresh.is_some())
+            }
+            None => (
+                redis::cmd("GET").arg(self.key(key)).query(&mut *conn)?,
+                true,
+            ),
+        };
+        match payload {
+            Some(payload) => Ok(Some((self.codec.decode(&payload)?, fresh))),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `lookup`, but treats values that can't be decoded as missing.
+    fn lookup_decodable(&self, key: &str) -> redis::RedisResult<Option<(T, bool)>> {
+        match self.lookup(key) {
+            Err(ref e) if e.kind() == redis::ErrorKind::TypeError => Ok(None),
+            result => result,
+        }
+    }
+
+    fn serve_stale_for(&self) -> Option<Duration> {
+        self.stampede_protection
+            .and_then(|protection| protection.serve_stale_for)
+    }
+
+    fn fresh_key(&self, key: &str) -> String {
+        self.meta_key(&format!("fresh:{}", key))
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+
+    /// Returns the key of the metadata `name`, under `<prefix>\0meta:`
+    /// so it can't be the key of a value, which follows `<prefix>:`.
+    fn meta_key(&self, name: &str) -> String {
+        format!("{}\0meta:{}", self.prefix, name)
+    }
+}
+
+impl<T, C: Clone> Clone for Cache<T, C> {
+    fn clone(&self) -> Cache<T, C> {
+        Cache {
+            pool: self.pool.clone(),
+            prefix: self.prefix.clone(),
+            codec: self.codec.clone(),
+            stampede_protection: self.stampede_protection,
+            ttl_jitter: self.ttl_jitter,
+            write_strategy: self.write_strategy.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for Cache<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Cache")
+            .field("prefix", &self.prefix)
+            .field("codec", &self.codec)
+            .field("stampede_protection", &self.stampede_protection)
+            .field("ttl_jitter", &self.ttl_jitter)
+            .field("write_strategy", &self.write_strategy)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Item {
+        id: u64,
+    }
+
+    fn cache() -> Cache<Item> {
+        let pool = crate::local_pool(1);
+        Cache::new(pool, format!("redis_r2d2-cache-{}", crate::lock::token()))
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = cache();
+        assert_eq!(None, cache.get("a").unwrap());
+        cache
+            .set_with_ttl("a", &Item { id: 1 }, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(Some(Item { id: 1 }), cache.get("a").unwrap());
+        assert!(cache.delete("a").unwrap());
+        assert!(!cache.delete("a").unwrap());
+
+        cache
+            .set_with_ttl("b", &Item { id: 2 }, Duration::from_millis(50))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(None, cache.get("b").unwrap());
+    }
+
+    #[test]
+    fn test_get_or_compute() {
+        let cache = cache();
+        let ttl = Duration::from_secs(10);
+        let mut computed = 0;
+        for _ in 0..2 {
+            let item = cache
+                .get_or_compute("a", ttl, || -> redis::RedisResult<Item> {
+                    computed += 1;
+                    Ok(Item { id: 1 })
+                })
+                .unwrap();
+            assert_eq!(Item { id: 1 }, item);
+        }
+        assert_eq!(1, computed);
+
+        let failed: Result<Item, redis::RedisError> = cache.get_or_compute("b", ttl, || {
+            Err((redis::ErrorKind::IoError, "database down").into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(None, cache.get("b").unwrap());
+
+        // Values that no longer decode are replaced.
+        let strings = Cache::<String>::new(cache.pool.clone(), cache.prefix.clone());
+        strings.set_with_ttl("c", &"old".to_string(), ttl).unwrap();
+        assert!(cache.get("c").is_err());
+        let item = cache
+            .get_or_compute("c", ttl, || -> redis::RedisResult<Item> {
+                Ok(Item { id: 3 })
+            })
+            .unwrap();
+        assert_eq!(Item { id: 3 }, item);
+    }
+
+    #[test]
+    fn test_ttl_jitter() {
+        let cache = cache().ttl_jitter(0.1);
+        let mut ttls = Vec::new();
+        for i in 0..20 {
+            let key = i.to_string();
+            cache
+                .set_with_ttl(&key, &Item { id: i }, Duration::from_secs(100))
+                .unwrap();
+            let ttl: u64 = redis::cmd("PTTL")
+                .arg(cache.key(&key))
+                .query(&mut *cache.pool.get().unwrap())
+                .unwrap();
+            assert!((89_000..=110_000).contains(&ttl), "{}", ttl);
+            ttls.push(ttl);
+        }
+        ttls.dedup();
+        assert!(ttls.len() > 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "ttl_jitter must be between 0 and 1")]
+    fn test_invalid_ttl_jitter() {
+        let _ = cache().ttl_jitter(f64::NAN);
+    }
+
+    #[test]
+    fn test_stampede_protection() {
+        let cache = cache().stampede_protection(Some(StampedeProtection {
+            poll_interval: Duration::from_millis(10),
+            ..StampedeProtection::default()
+        }));
+        let computed = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                let computed = computed.clone();
+                thread::spawn(move || {
+                    cache
+                        .get_or_compute(
+                            "a",
+                            Duration::from_secs(10),
+                            || -> redis::RedisResult<_> {
+                                computed.fetch_add(1, Ordering::SeqCst);
+                                thread::sleep(Duration::from_millis(200));
+                                Ok(Item { id: 1 })
+                            },
+                        )
+                        .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(Item { id: 1 }, thread.join().unwrap());
+        }
+        assert_eq!(1, computed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_serve_stale() {
+        let cache = cache().stampede_protection(Some(StampedeProtection {
+            serve_stale_for: Some(Duration::from_secs(10)),
+            ..StampedeProtection::default()
+        }));
+        cache
+            .set_with_ttl("a", &Item { id: 1 }, Duration::from_millis(50))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(None, cache.get("a").unwrap());
+
+        // While one caller recomputes the value, the others get the stale
+        // one.
+        let recomputing = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.get_or_compute("a", Duration::from_secs(10), || -> redis::RedisResult<_> {
+                    thread::sleep(Duration::from_millis(300));
+                    Ok(Item { id: 2 })
+                })
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        let stale = cache
+            .get_or_compute("a", Duration::from_secs(10), || -> redis::RedisResult<_> {
+                Ok(Item { id: 3 })
+            })
+            .unwrap();
+        assert_eq!(Item { id: 1 }, stale);
+        assert_eq!(Item { id: 2 }, recomputing.join().unwrap().unwrap());
+        assert_eq!(Some(Item { id: 2 }), cache.get("a").unwrap());
+    }
+
+    #[test]
+    fn test_write_through() {
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        let cache = {
+            let store = store.clone();
+            cache().write_through(move |key: &str, item: &Item| {
+                if item.id == 0 {
+                    return Err("invalid id");
+                }
+                store.lock().unwrap().insert(key.to_string(), item.clone());
+                Ok(())
+            })
+        };
+        let ttl = Duration::from_secs(10);
+        cache.write("a", &Item { id: 1 }, ttl).unwrap();
+        assert_eq!(Some(&Item { id: 1 }), store.lock().unwrap().get("a"));
+        assert_eq!(Some(Item { id: 1 }), cache.get("a").unwrap());
+
+        // A value that couldn't be persisted isn't cached.
+        let err = cache.write("a", &Item { id: 0 }, ttl).unwrap_err();
+        assert_eq!(redis::ErrorKind::ClientError, err.kind());
+        assert_eq!(Some(Item { id: 1 }), cache.get("a").unwrap());
+    }
+
+    #[test]
+    fn test_write_behind() {
+        let pool = crate::local_pool(3);
+        let prefix = format!("redis_r2d2-cache-{}", crate::lock::token());
+        let cache: Cache<Item> = Cache::new(pool, prefix).write_behind();
+        for id in 1..=3 {
+            cache
+                .write(&id.to_string(), &Item { id }, Duration::from_secs(10))
+                .unwrap();
+        }
+        // Keys can't clash with the stream.
+        cache
+            .write("write-behind", &Item { id: 4 }, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(Some(Item { id: 2 }), cache.get("2").unwrap());
+
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        let worker = {
+            let store = store.clone();
+            cache
+                .write_behind_worker("worker-1", move |key, item| {
+                    store.lock().unwrap().insert(key.to_string(), item);
+                    Ok::<(), String>(())
+                })
+                .unwrap()
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while store.lock().unwrap().len() < 4 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        worker.stop();
+        let store = store.lock().unwrap();
+        for id in 1..=3 {
+            assert_eq!(Some(&Item { id }), store.get(&id.to_string()));
+        }
+        assert_eq!(Some(&Item { id: 4 }), store.get("write-behind"));
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Controls when `RedisConnectionManager` stops attempting to connect to a
+/// server that keeps failing.
+///
+/// After `failure_threshold` consecutive connect failures the circuit opens
+/// and connects fail immediately for `cool_down`. Then a single attempt is
+/// let through: if it succeeds the circuit closes again, otherwise it stays
+/// open for another `cool_down`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// The number of consecutive failures that opens the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before the next attempt.
+    pub cool_down: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A handle to the circuit breaker of a `RedisConnectionManager`, see
+/// `RedisConnectionManager::circuit_breaker_handle`.
+///
+/// r2d2 checkouts wait for the pool's connection timeout however quickly
+/// connects fail, so callers that want to fail fast while the server is
+/// unavailable should `check` the handle before checking out.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerHandle {
+    breaker: Arc<Breaker>,
+}
+
+impl CircuitBreakerHandle {
+    pub(crate) fn new(breaker: Arc<Breaker>) -> CircuitBreakerHandle {
+        CircuitBreakerHandle { breaker }
+    }
+
+    /// Returns true while connects fail immediately.
+    pub fn is_open(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    /// Returns an error while the circuit is open.
+    pub fn check(&self) -> redis::RedisResult<()> {
+        if self.is_open() {
+            Err(open_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Tracks consecutive connect failures for a `CircuitBreaker`.
+#[derive(Debug)]
+pub(crate) struct Breaker {
+    policy: CircuitBreaker,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+impl Breaker {
+    pub(crate) fn new(policy: CircuitBreaker) -> Breaker {
+        Breaker {
+            policy,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .open_until
+            .is_some_and(|open_until| state.probing || Instant::now() < open_until)
+    }
+
+    /// Returns an error if a connection attempt must not be made now.
+    pub(crate) fn allow(&self) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => Ok(()),
+            Some(open_until) if !state.probing && Instant::now() >= open_until => {
+                state.probing = true;
+                Ok(())
+            }
+            Some(_) => Err(open_error()),
+        }
+    }
+
+    pub(crate) fn succeeded(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    pub(crate) fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.probing || state.failures >= self.policy.failure_threshold {
+            state.open_until = Some(Instant::now() + self.policy.cool_down);
+            state.probing = false;
+        }
+    }
+}
+
+fn open_error() -> redis::RedisError {
+    (
+        redis::ErrorKind::IoError,
+        "circuit breaker is open after repeated connect failures",
+    )
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_breaker() {
+        let breaker = Breaker::new(CircuitBreaker {
+            failure_threshold: 2,
+            cool_down: Duration::from_millis(50),
+        });
+
+        breaker.allow().unwrap();
+        breaker.failed();
+        breaker.allow().unwrap();
+        breaker.failed();
+        assert!(breaker.is_open());
+        assert!(breaker.allow().is_err());
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(!breaker.is_open());
+        breaker.allow().unwrap();
+        assert!(breaker.allow().is_err(), "only one probe at a time");
+        breaker.failed();
+        assert!(breaker.allow().is_err());
+
+        thread::sleep(Duration::from_millis(60));
+        breaker.allow().unwrap();
+        breaker.succeeded();
+        assert!(!breaker.is_open());
+        breaker.allow().unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use r2d2::ManageConnection;
+use redis::{ConnectionLike, FromRedisValue, Value};
+
+use crate::backoff::Backoff;
+use crate::subscriber::{HEARTBEAT_INTERVAL, POLL_INTERVAL};
+use crate::{ReconnectPolicy, RedisConnectionManager, RedisPubSubConnection};
+
+/// The channel Redis sends invalidation messages to in RESP2.
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// A client-side cache of Redis replies, kept up to date with
+/// `CLIENT TRACKING`.
+///
+/// A background thread keeps an invalidation connection of the
+/// `RedisConnectionManager` subscribed to `__redis__:invalidate`. On a
+/// cache miss, `get` and `query` read the key on the given connection,
+/// usually a pooled one, after turning on tracking in `OPTIN` mode with the
+/// invalidation connection as redirect, so the server reports when the key
+/// changes and only the keys read through the cache are tracked. Cached
+/// replies are dropped when their key is invalidated, and all of them when
+/// the invalidation connection is lost, since invalidations may have been
+/// missed; until it is reconnected, reads go to the server.
+///
+/// Replies are cached by key alone, so the connections passed to `get` and
+/// `query` should all use the same database. At most `max_entries` replies
+/// are kept, by default 10000; an arbitrary one is evicted to make room.
+/// Clones share the cache, and the background thread stops once all of them
+/// are dropped.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, ClientSideCache, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let cache =
+///         ClientSideCache::new(RedisConnectionManager::new("redis://localhost").unwrap());
+///
+///     let mut conn = pool.get().unwrap();
+///     let value: Option<String> = cache.get(&mut *conn, "config:feature-flags").unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientSideCache {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    max_entries: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<Vec<u8>, Value>,
+    /// The client ID of the invalidation connection, while it is connected.
+    redirect: Option<i64>,
+    /// Bumped with each invalidation, so a reply read before one isn't
+    /// cached after it.
+    generation: u64,
+}
+
+impl State {
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.generation += 1;
+    }
+}
+
+impl ClientSideCache {
+    /// Creates a `ClientSideCache` keeping at most 10000 replies.
+    pub fn new(manager: RedisConnectionManager) -> ClientSideCache {
+        ClientSideCache::with_max_entries(manager, 10_000)
+    }
+
+    /// Creates a `ClientSideCache` keeping at most `max_entries` replies.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_entries` is zero.
+    pub fn with_max_entries(
+        manager: RedisConnectionManager,
+        max_entries: usize,
+    ) -> ClientSideCache {
+        assert!(max_entries > 0, "max_entries must be positive");
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            max_entries,
+        });
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || run(&manager, &weak));
+        ClientSideCache { shared }
+    }
+
+    /// Returns the value of the string at `key`, from the cache if possible.
+    pub fn get<C, K, V>(&self, conn: &mut C, key: K) -> redis::RedisResult<V>
+    where
+        C: ConnectionLike,
+        K: AsRef<[u8]>,
+        V: FromRedisValue,
+    {
+        let key = key.as_ref();
+        self.query(conn, key, redis::cmd("GET").arg(key))
+    }
+
+    /// Returns the reply to `cmd`, a read-only command on nothing but
+    /// `key`, from the cache if possible.
+    ///
+    /// The reply is cached under `key` alone, so a single command should be
+    /// used for each key, e.g. `HGETALL` for a hash.
+    pub fn query<C, K, V>(&self, conn: &mut C, key: K, cmd: &redis::Cmd) -> redis::RedisResult<V>
+    where
+        C: ConnectionLike,
+        K: AsRef<[u8]>,
+        V: FromRedisValue,
+    {
+        let key = key.as_ref();
+        let (redirect, generation) = {
+            let state = self.shared.state.lock().unwrap();
+            if let Some(value) = state.entries.get(key) {
+                return redis::from_redis_value(value);
+            }
+            (state.redirect, state.generation)
+        };
+        let redirect = match redirect {
+            Some(redirect) => redirect,
+            None => return cmd.query(conn),
+        };
+
+        let (value,): (Value,) = redis::pipe()
+            .cmd("CLIENT")
+            .arg("TRACKING")
+            .arg("on")
+            .arg("REDIRECT")
+            .arg(redirect)
+            .arg("OPTIN")
+            .ignore()
+            .cmd("CLIENT")
+            .arg("CACHING")
+            .arg("yes")
+            .ignore()
+            .add_command(cmd.clone())
+            .query(conn)?;
+        let result = redis::from_redis_value(&value);
+        let mut state = self.shared.state.lock().unwrap();
+        if state.generation == generation && state.redirect == Some(redirect) {
+            if state.entries.len() >= self.shared.max_entries && !state.entries.contains_key(key) {
+                if let Some(evicted) = state.entries.keys().next().cloned() {
+                    state.entries.remove(&evicted);
+                }
+            }
+            state.entries.insert(key.to_vec(), value);
+        }
+        result
+    }
+
+    /// Drops the cached reply for `key`, if any.
+    pub fn invalidate<K: AsRef<[u8]>>(&self, key: K) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.entries.remove(key.as_ref());
+        state.generation += 1;
+    }
+
+    /// Drops all cached replies.
+    pub fn clear(&self) {
+        self.shared.state.lock().unwrap().clear();
+    }
+
+    /// Returns the number of cached replies.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().entries.len()
+    }
+
+    /// Returns true if no replies are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if the invalidation connection is up, so replies are
+    /// cached.
+    pub fn is_connected(&self) -> bool {
+        self.shared.state.lock().unwrap().redirect.is_some()
+    }
+}
+
+/// Keeps the invalidation connection up until the `ClientSideCache` is
+/// dropped.
+fn run(manager: &RedisConnectionManager, shared: &Weak<Shared>) {
+    let backoff = Backoff::new(ReconnectPolicy::default());
+    loop {
+        backoff.wait();
+        if shared.strong_count() == 0 {
+            return;
+        }
+        let result = connect(manager).and_then(|(mut conn, id)| {
+            backoff.succeeded();
+            match shared.upgrade() {
+                Some(shared) => shared.state.lock().unwrap().redirect = Some(id),
+                None => return Ok(()),
+            }
+            listen(&mut conn, shared, manager.validation_timeout)
+        });
+        if let Some(shared) = shared.upgrade() {
+            let mut state = shared.state.lock().unwrap();
+            state.redirect = None;
+            state.clear();
+        }
+        match result {
+            Ok(()) => return,
+            Err(e) => {
+                log::warn!("client-side cache invalidation connection failed: {}", e);
+                backoff.failed();
+            }
+        }
+    }
+}
+
+fn connect(manager: &RedisConnectionManager) -> redis::RedisResult<(RedisPubSubConnection, i64)> {
+    let mut conn = manager.connect()?;
+    let id = redis::cmd("CLIENT").arg("ID").query(&mut conn)?;
+    let mut conn = RedisPubSubConnection::new(conn, manager.read_timeout);
+    conn.set_read_timeout(Some(POLL_INTERVAL))?;
+    conn.subscribe(INVALIDATE_CHANNEL)?;
+    Ok((conn, id))
+}
+
+/// Applies the invalidations received on `conn`, returning `Ok` once the
+/// `ClientSideCache` is dropped.
+fn listen(
+    conn: &mut RedisPubSubConnection,
+    shared: &Weak<Shared>,
+    validation_timeout: Option<Duration>,
+) -> redis::RedisResult<()> {
+    loop {
+        let msg = match conn.get_message() {
+            Ok(msg) => msg,
+            Err(ref e) if e.is_timeout() => {
+                if shared.strong_count() == 0 {
+                    return Ok(());
+                }
+                if conn.idle_time() >= HEARTBEAT_INTERVAL {
+                    conn.ping(Some(validation_timeout.unwrap_or(HEARTBEAT_INTERVAL)))?;
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        let mut state = shared.state.lock().unwrap();
+        // The payload is the invalidated keys, or nil when the database was
+        // flushed.
+        match msg.get_payload()? {
+            Value::Bulk(keys) => {
+                for key in keys {
+                    if let Value::Data(key) = key {
+                        state.entries.remove(&key);
+                    }
+                }
+                state.generation += 1;
+            }
+            Value::Data(key) => {
+                state.entries.remove(&key);
+                state.generation += 1;
+            }
+            _ => state.clear(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::fake_server;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    #[test]
+    fn test_client_side_cache() {
+        let cache = ClientSideCache::with_max_entries(
+            RedisConnectionManager::new("redis://localhost").unwrap(),
+            2,
+        );
+        let start = Instant::now();
+        while !cache.is_connected() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut conn = redis::Client::open("redis://localhost/4")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        redis::cmd("SET")
+            .arg("redis_r2d2-cached")
+            .arg("two")
+            .query::<()>(&mut conn)
+            .unwrap();
+        // Cache a value the server doesn't hold, read from a fake server.
+        let fake = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |_| {
+            "+OK\r\n+OK\r\n$3\r\none\r\n".to_string()
+        });
+        let mut fake = redis::Client::open(format!("redis://{}", fake))
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let value: String = cache.get(&mut fake, "redis_r2d2-cached").unwrap();
+        assert_eq!("one", value);
+        assert_eq!(1, cache.len());
+
+        // Served from the cache until the key is invalidated.
+        let value: String = cache.get(&mut conn, "redis_r2d2-cached").unwrap();
+        assert_eq!("one", value);
+
+        redis::cmd("PUBLISH")
+            .arg(INVALIDATE_CHANNEL)
+            .arg("redis_r2d2-cached")
+            .query::<i64>(&mut conn)
+            .unwrap();
+        let start = Instant::now();
+        while !cache.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let value: String = cache.get(&mut conn, "redis_r2d2-cached").unwrap();
+        assert_eq!("two", value);
+
+        let _: Option<String> = cache.get(&mut conn, "redis_r2d2-cached-2").unwrap();
+        let _: Option<String> = cache.get(&mut conn, "redis_r2d2-cached-3").unwrap();
+        assert_eq!(2, cache.len());
+        cache.invalidate("redis_r2d2-cached-3");
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
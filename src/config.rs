@@ -0,0 +1,315 @@
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+use crate::{RedisConnectionManager, ValidationMode};
+
+/// A pool configuration that can be loaded from configuration files with
+/// `serde`.
+///
+/// All fields except `url` are optional, and durations are given in
+/// seconds, e.g. in TOML:
+///
+/// ```toml
+/// url = "redis://localhost/1"
+/// connect_timeout = 1.5
+/// max_size = 16
+/// validation = "check_connection"
+/// ```
+///
+/// `validation` is one of `"ping"`, `"check_connection"`, `"none"` or
+/// `{ command = ["CONFIG", "GET", "maxmemory"] }`.
+///
+/// Requires the `serde` feature.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisPoolConfig {
+    /// The connection URL, e.g. `redis://localhost:6379/0`.
+    pub url: String,
+    /// See `RedisConnectionManagerBuilder::connect_timeout`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub connect_timeout: Option<Duration>,
+    /// See `RedisConnectionManagerBuilder::read_timeout`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub read_timeout: Option<Duration>,
+    /// See `RedisConnectionManagerBuilder::write_timeout`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub write_timeout: Option<Duration>,
+    /// See `RedisConnectionManagerBuilder::db`.
+    #[serde(default)]
+    pub db: Option<i64>,
+    /// See `RedisConnectionManagerBuilder::username`.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// See `RedisConnectionManagerBuilder::password`.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// See `RedisConnectionManagerBuilder::client_name`.
+    #[serde(default)]
+    pub client_name: Option<String>,
+    /// See `RedisConnectionManagerBuilder::validation`.
+    #[serde(default)]
+    pub validation: ValidationMode,
+    /// See `RedisConnectionManagerBuilder::validation_interval`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub validation_interval: Option<Duration>,
+    /// See `RedisConnectionManagerBuilder::validation_timeout`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub validation_timeout: Option<Duration>,
+    /// See `RedisConnectionManagerBuilder::max_lifetime`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub max_lifetime: Option<Duration>,
+    /// See `RedisConnectionManagerBuilder::max_uses`.
+    #[serde(default)]
+    pub max_uses: Option<u64>,
+    /// See `RedisConnectionManagerBuilder::reset_on_checkin`.
+    #[serde(default)]
+    pub reset_on_checkin: bool,
+    /// See `RedisConnectionManagerBuilder::check_unread_replies`.
+    #[serde(default)]
+    pub check_unread_replies: bool,
+    /// See `r2d2::Builder::max_size`.
+    #[serde(default)]
+    pub max_size: Option<u32>,
+    /// See `r2d2::Builder::min_idle`.
+    #[serde(default)]
+    pub min_idle: Option<u32>,
+    /// See `r2d2::Builder::connection_timeout`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub pool_timeout: Option<Duration>,
+    /// See `r2d2::Builder::idle_timeout`.
+    #[serde(default, deserialize_with = "seconds")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl RedisPoolConfig {
+    /// Creates a new `RedisConnectionManager` from the configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if the URL is invalid or a
+    /// duration or `max_uses` is zero.
+    pub fn build_manager(&self) -> Result<RedisConnectionManager, redis::RedisError> {
+        check_positive("connect_timeout", self.connect_timeout)?;
+        check_positive("read_timeout", self.read_timeout)?;
+        check_positive("write_timeout", self.write_timeout)?;
+        check_positive("validation_timeout", self.validation_timeout)?;
+        check_positive("max_lifetime", self.max_lifetime)?;
+        if self.max_uses == Some(0) {
+            return Err(invalid("max_uses must be positive"));
+        }
+
+        let mut builder = RedisConnectionManager::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .write_timeout(self.write_timeout)
+            .validation(self.validation.clone())
+            .validation_interval(self.validation_interval)
+            .validation_timeout(self.validation_timeout)
+            .max_lifetime(self.max_lifetime)
+            .max_uses(self.max_uses)
+            .reset_on_checkin(self.reset_on_checkin)
+            .check_unread_replies(self.check_unread_replies);
+        if let Some(db) = self.db {
+            builder = builder.db(db);
+        }
+        if let Some(ref username) = self.username {
+            builder = builder.username(username.as_str());
+        }
+        if let Some(ref password) = self.password {
+            builder = builder.password(password.as_str());
+        }
+        if let Some(ref client_name) = self.client_name {
+            builder = builder.client_name(client_name.as_str());
+        }
+        builder.build(self.url.as_str())
+    }
+
+    /// Returns an `r2d2::Builder` with the pool settings of the
+    /// configuration applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error if `max_size` or a duration is
+    /// zero, or `min_idle` exceeds `max_size`.
+    pub fn pool_builder(&self) -> Result<r2d2::Builder<RedisConnectionManager>, redis::RedisError> {
+        check_positive("pool_timeout", self.pool_timeout)?;
+        check_positive("idle_timeout", self.idle_timeout)?;
+
+        // The default of `r2d2::Builder::max_size`.
+        let max_size = self.max_size.unwrap_or(10);
+        if max_size == 0 {
+            return Err(invalid("max_size must be positive"));
+        }
+        if self.min_idle.unwrap_or(0) > max_size {
+            return Err(invalid("min_idle must be no larger than max_size"));
+        }
+
+        let mut builder = r2d2::Pool::builder()
+            .max_size(max_size)
+            .min_idle(self.min_idle)
+            .idle_timeout(self.idle_timeout);
+        if let Some(pool_timeout) = self.pool_timeout {
+            builder = builder.connection_timeout(pool_timeout);
+        }
+        Ok(builder)
+    }
+
+    /// Creates a new `r2d2::Pool` from the configuration.
+    ///
+    /// Like `r2d2::Builder::build`, this waits until the pool holds
+    /// `min_idle` connections.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `build_manager` and `pool_builder`, and an
+    /// `IoError` if the initial connections could not be established within
+    /// `pool_timeout`.
+    pub fn build_pool(&self) -> Result<r2d2::Pool<RedisConnectionManager>, redis::RedisError> {
+        let manager = self.build_manager()?;
+        self.pool_builder()?.build(manager).map_err(|e| {
+            (
+                redis::ErrorKind::IoError,
+                "failed to establish connections",
+                e.to_string(),
+            )
+                .into()
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ValidationMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ValidationMode, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        enum Repr {
+            Ping,
+            CheckConnection,
+            None,
+            Command(Vec<String>),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Ping => ValidationMode::Ping,
+            Repr::CheckConnection => ValidationMode::CheckConnection,
+            Repr::None => ValidationMode::None,
+            Repr::Command(args) => {
+                let (name, args) = args
+                    .split_first()
+                    .ok_or_else(|| de::Error::invalid_length(0, &"a command name"))?;
+                let mut cmd = redis::cmd(name);
+                for arg in args {
+                    cmd.arg(arg);
+                }
+                ValidationMode::Command(cmd)
+            }
+        })
+    }
+}
+
+/// Deserializes an optional duration given as a number of seconds.
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(secs) => Duration::try_from_secs_f64(secs).map(Some).map_err(|_| {
+            de::Error::invalid_value(
+                de::Unexpected::Float(secs),
+                &"a non-negative number of seconds",
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+fn check_positive(name: &str, duration: Option<Duration>) -> Result<(), redis::RedisError> {
+    if duration == Some(Duration::from_secs(0)) {
+        Err(invalid(&format!("{} must be positive", name)))
+    } else {
+        Ok(())
+    }
+}
+
+fn invalid(detail: &str) -> redis::RedisError {
+    (
+        redis::ErrorKind::InvalidClientConfig,
+        "invalid pool configuration",
+        detail.to_owned(),
+    )
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> RedisPoolConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let config = parse(
+            r#"{
+                "url": "redis://localhost/1",
+                "connect_timeout": 1.5,
+                "read_timeout": 2,
+                "client_name": "my-app",
+                "validation": {"command": ["ECHO", "hello"]},
+                "max_size": 4,
+                "min_idle": 1
+            }"#,
+        );
+
+        assert_eq!("redis://localhost/1", config.url);
+        assert_eq!(Some(Duration::from_millis(1500)), config.connect_timeout);
+        assert_eq!(Some(Duration::from_secs(2)), config.read_timeout);
+        assert_eq!(None, config.write_timeout);
+        assert_eq!(Some("my-app"), config.client_name.as_deref());
+        match config.validation {
+            ValidationMode::Command(ref cmd) => assert_eq!(
+                redis::cmd("ECHO").arg("hello").get_packed_command(),
+                cmd.get_packed_command()
+            ),
+            ref validation => panic!("unexpected validation {:?}", validation),
+        }
+
+        let config = parse(r#"{"url": "redis://localhost", "validation": "check_connection"}"#);
+        assert_eq!("CheckConnection", format!("{:?}", config.validation));
+
+        assert!(serde_json::from_str::<RedisPoolConfig>(r#"{"connect_timeout": 1}"#).is_err());
+        assert!(serde_json::from_str::<RedisPoolConfig>(
+            r#"{"url": "redis://localhost", "connect_timeout": -1}"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<RedisPoolConfig>(
+            r#"{"url": "redis://localhost", "connect_timeout": 1e30}"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<RedisPoolConfig>(
+            r#"{"url": "redis://localhost", "validation": {"command": []}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let config = parse(r#"{"url": "redis://localhost", "read_timeout": 0}"#);
+        assert!(config.build_manager().is_err());
+
+        let config = parse(r#"{"url": "redis://localhost", "max_size": 0}"#);
+        assert!(config.pool_builder().is_err());
+
+        let config = parse(r#"{"url": "redis://localhost", "max_size": 2, "min_idle": 3}"#);
+        assert!(config.pool_builder().is_err());
+    }
+
+    #[test]
+    fn test_build_pool() {
+        let config = parse(r#"{"url": "redis://localhost", "max_size": 2, "db": 1}"#);
+        let pool = config.build_pool().unwrap();
+
+        assert_eq!(2, pool.max_size());
+        let mut conn = pool.get().unwrap();
+        assert_eq!(1, redis::ConnectionLike::get_db(&*conn));
+        redis::cmd("PING").query::<()>(&mut *conn).unwrap();
+    }
+}
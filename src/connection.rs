@@ -0,0 +1,410 @@
+use std::collections::HashSet;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use redis::{ConnectionLike, FromRedisValue, ToRedisArgs};
+
+use crate::functions::is_function_not_found;
+use crate::ConnectionCustomizer;
+
+/// A `redis::Connection` managed by `RedisConnectionManager`.
+///
+/// It implements `redis::ConnectionLike`, so commands can be sent to it
+/// exactly as to a plain connection, and dereferences to the wrapped
+/// `redis::Connection` for everything else (e.g. `as_pubsub`).
+///
+/// Commands sent through the wrapper are tracked so the manager can skip
+/// validation of connections that were used recently.
+pub struct RedisConnection {
+    conn: redis::Connection,
+    endpoint: usize,
+    client_name: Option<String>,
+    customizer: Arc<dyn ConnectionCustomizer>,
+    created: Instant,
+    last_used: Instant,
+    checked_out: Option<Instant>,
+    checkins: u64,
+    db_changed: bool,
+    broken: bool,
+    generation: u64,
+    authenticated: Instant,
+    auth_expires_at: Option<Instant>,
+    read_timeout: Option<Duration>,
+    scripts: HashSet<String>,
+}
+
+impl RedisConnection {
+    pub(crate) fn new(
+        conn: redis::Connection,
+        endpoint: usize,
+        client_name: Option<String>,
+        customizer: Arc<dyn ConnectionCustomizer>,
+    ) -> RedisConnection {
+        RedisConnection {
+            conn,
+            endpoint,
+            client_name,
+            customizer,
+            created: Instant::now(),
+            last_used: Instant::now(),
+            checked_out: None,
+            checkins: 0,
+            db_changed: false,
+            broken: false,
+            generation: 0,
+            authenticated: Instant::now(),
+            auth_expires_at: None,
+            read_timeout: None,
+            scripts: HashSet::new(),
+        }
+    }
+
+    /// Returns the name this connection announced with `CLIENT SETNAME`, if
+    /// any.
+    pub fn client_name(&self) -> Option<&str> {
+        self.client_name.as_deref()
+    }
+
+    /// Returns the time elapsed since the connection was established.
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Returns the number of times the connection was returned to the pool.
+    pub fn checkins(&self) -> u64 {
+        self.checkins
+    }
+
+    /// Returns the time elapsed since a command last succeeded on this
+    /// connection (or since it was established).
+    pub fn idle_time(&self) -> Duration {
+        self.last_used.elapsed()
+    }
+
+    /// Returns how long the connection has been checked out of the pool.
+    ///
+    /// The checkout is noticed when the manager validates the connection,
+    /// so this is `None` unless the pool tests connections on checkout.
+    pub fn checked_out_for(&self) -> Option<Duration> {
+        self.checked_out.map(|checked_out| checked_out.elapsed())
+    }
+
+    /// Calls the function `function` with `FCALL`, passing `keys` and
+    /// `args`.
+    ///
+    /// If the server doesn't know the function, e.g. after a failover to a
+    /// replica that lacks its library, the connection is marked broken, so
+    /// the pool replaces it with one that verified the manager's
+    /// `FunctionLibraries`.
+    pub fn fcall<T: FromRedisValue, K: ToRedisArgs, A: ToRedisArgs>(
+        &mut self,
+        function: &str,
+        keys: K,
+        args: A,
+    ) -> redis::RedisResult<T> {
+        self.call_function("FCALL", function, keys, args)
+    }
+
+    /// Calls the read-only function `function` with `FCALL_RO`, which
+    /// replicas accept too, passing `keys` and `args`.
+    ///
+    /// Missing functions are handled as by `fcall`.
+    pub fn fcall_ro<T: FromRedisValue, K: ToRedisArgs, A: ToRedisArgs>(
+        &mut self,
+        function: &str,
+        keys: K,
+        args: A,
+    ) -> redis::RedisResult<T> {
+        self.call_function("FCALL_RO", function, keys, args)
+    }
+
+    fn call_function<T: FromRedisValue, K: ToRedisArgs, A: ToRedisArgs>(
+        &mut self,
+        command: &str,
+        function: &str,
+        keys: K,
+        args: A,
+    ) -> redis::RedisResult<T> {
+        let keys = keys.to_redis_args();
+        let mut cmd = redis::cmd(command);
+        cmd.arg(function).arg(keys.len()).arg(keys).arg(args);
+        let result = cmd.query(self);
+        if let Err(ref e) = result {
+            if is_function_not_found(e) {
+                self.broken = true;
+            }
+        }
+        result
+    }
+
+    /// Flags the connection so it is closed instead of being reused when it
+    /// is returned to the pool.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
+
+    /// Returns true if the connection will be closed when it is returned to
+    /// the pool.
+    ///
+    /// This is the case after `mark_broken`, after a command failed with an
+    /// I/O error or timeout (its reply may still arrive and be read by the
+    /// next command), and after the server answered `READONLY` or `MOVED`,
+    /// which means it is no longer the master the pool should talk to.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Returns the index of the manager's endpoint the connection was
+    /// established to.
+    pub(crate) fn endpoint(&self) -> usize {
+        self.endpoint
+    }
+
+    /// Returns the generation of the sentinel's master when the connection
+    /// was established, see `sentinel::Sentinel::generation`.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub(crate) fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Returns when the credentials the connection authenticated with
+    /// expire, if they do.
+    pub(crate) fn auth_expires_at(&self) -> Option<Instant> {
+        self.auth_expires_at
+    }
+
+    /// Returns the time elapsed since the connection last authenticated.
+    pub(crate) fn authenticated_for(&self) -> Duration {
+        self.authenticated.elapsed()
+    }
+
+    /// Records that the connection just authenticated with credentials
+    /// expiring at `auth_expires_at`.
+    pub(crate) fn set_authenticated(&mut self, auth_expires_at: Option<Instant>) {
+        self.authenticated = Instant::now();
+        self.auth_expires_at = auth_expires_at;
+    }
+
+    /// Returns the read timeout the manager configured for the connection.
+    pub(crate) fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub(crate) fn set_configured_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// Returns true if the script with SHA1 `sha` is known to be in the
+    /// server's script cache.
+    pub(crate) fn has_script(&self, sha: &str) -> bool {
+        self.scripts.contains(sha)
+    }
+
+    pub(crate) fn set_script_loaded(&mut self, sha: &str) {
+        self.scripts.insert(sha.to_string());
+    }
+
+    /// Forgets the loaded scripts, e.g. after the server's script cache was
+    /// flushed.
+    pub(crate) fn clear_scripts(&mut self) {
+        self.scripts.clear();
+    }
+
+    pub(crate) fn touch(&mut self) {
+        self.last_used = Instant::now();
+    }
+
+    pub(crate) fn mark_checked_out(&mut self) {
+        self.checked_out = Some(Instant::now());
+    }
+
+    pub(crate) fn checkin(&mut self) -> redis::RedisResult<()> {
+        let customizer = self.customizer.clone();
+        let result = customizer.on_checkin(self);
+        self.checked_out = None;
+        self.checkins += 1;
+        result
+    }
+
+    /// Returns true if a command that may have switched the selected
+    /// database was sent since the last call to `clear_db_changed`.
+    pub(crate) fn db_changed(&self) -> bool {
+        self.db_changed
+    }
+
+    pub(crate) fn clear_db_changed(&mut self) {
+        self.db_changed = false;
+    }
+
+    /// Sends the packed command(s) `packed` without reading the replies,
+    /// tracked like the commands sent through `ConnectionLike`.
+    pub(crate) fn send_packed(&mut self, packed: &[u8]) -> redis::RedisResult<()> {
+        self.db_changed |= may_change_db(packed);
+        let result = self.conn.send_packed_command(packed);
+        self.track(result)
+    }
+
+    /// Reads the next reply to the commands sent with `send_packed`.
+    pub(crate) fn recv_reply(&mut self) -> redis::RedisResult<redis::Value> {
+        let result = self.conn.recv_response();
+        self.track(result)
+    }
+
+    /// Checks whether replies the connection hasn't read yet are waiting,
+    /// e.g. because a command was sent with `send_packed_command` and never
+    /// received.
+    ///
+    /// This briefly waits for data to arrive and then restores the read
+    /// timeout to `read_timeout`.
+    pub(crate) fn has_unread_replies(
+        &mut self,
+        read_timeout: Option<Duration>,
+    ) -> redis::RedisResult<bool> {
+        self.conn.set_read_timeout(Some(Duration::from_micros(1)))?;
+        let result = self.conn.recv_response();
+        self.conn.set_read_timeout(read_timeout)?;
+        match result {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.is_timeout() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn track<T>(&mut self, result: redis::RedisResult<T>) -> redis::RedisResult<T> {
+        match result {
+            Ok(_) => self.touch(),
+            Err(ref e) if e.is_io_error() || is_role_change(e) => self.broken = true,
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+impl Drop for RedisConnection {
+    fn drop(&mut self) {
+        let customizer = self.customizer.clone();
+        customizer.on_release(self);
+    }
+}
+
+impl Deref for RedisConnection {
+    type Target = redis::Connection;
+
+    fn deref(&self) -> &redis::Connection {
+        &self.conn
+    }
+}
+
+impl DerefMut for RedisConnection {
+    fn deref_mut(&mut self) -> &mut redis::Connection {
+        &mut self.conn
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        self.db_changed |= may_change_db(cmd);
+        let result = self.conn.req_packed_command(cmd);
+        self.track(result)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisResult<Vec<redis::Value>> {
+        self.db_changed |= may_change_db(cmd);
+        let result = self.conn.req_packed_commands(cmd, offset, count);
+        // The replies are read one by one and reading stops at the first
+        // error reply, leaving the rest of the pipeline's replies unread.
+        if result.is_err() && offset + count > 1 {
+            self.broken = true;
+        }
+        self.track(result)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.conn.check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.conn.is_open()
+    }
+}
+
+/// Returns true if the error means the server is no longer the master for
+/// the requested data, e.g. after a failover demoted it to a replica.
+fn is_role_change(error: &redis::RedisError) -> bool {
+    error.kind() == redis::ErrorKind::Moved || error.code() == Some("READONLY")
+}
+
+/// Returns true if the packed command(s) contain a `SELECT` or `RESET`.
+///
+/// This only looks for the command names as bulk strings, so an argument
+/// spelled the same way yields a false positive, which merely costs an
+/// extra `SELECT` on the next checkout.
+fn may_change_db(packed: &[u8]) -> bool {
+    packed.windows(12).any(|window| {
+        window[..4] == *b"$6\r\n"
+            && window[10..] == *b"\r\n"
+            && window[4..10].eq_ignore_ascii_case(b"SELECT")
+    }) || packed.windows(11).any(|window| {
+        window[..4] == *b"$5\r\n"
+            && window[9..] == *b"\r\n"
+            && window[4..9].eq_ignore_ascii_case(b"RESET")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_role_change() {
+        let server_error = |reply: &[u8]| redis::parse_redis_value(reply).unwrap_err();
+
+        assert!(is_role_change(&server_error(
+            b"-READONLY You can't write against a read only replica.\r\n"
+        )));
+        assert!(is_role_change(&server_error(
+            b"-MOVED 3999 127.0.0.1:6381\r\n"
+        )));
+        assert!(!is_role_change(&server_error(
+            b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        )));
+    }
+
+    #[test]
+    fn test_may_change_db() {
+        assert!(may_change_db(
+            &redis::cmd("SELECT").arg(2).get_packed_command()
+        ));
+        assert!(may_change_db(
+            &redis::cmd("select").arg(2).get_packed_command()
+        ));
+        assert!(may_change_db(&redis::cmd("RESET").get_packed_command()));
+        assert!(may_change_db(
+            &redis::pipe()
+                .cmd("PING")
+                .cmd("SELECT")
+                .arg(2)
+                .get_packed_pipeline()
+        ));
+        assert!(!may_change_db(&redis::cmd("PING").get_packed_command()));
+        assert!(!may_change_db(
+            &redis::cmd("SET")
+                .arg("selection")
+                .arg(1)
+                .get_packed_command()
+        ));
+    }
+}
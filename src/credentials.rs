@@ -0,0 +1,132 @@
+use std::fmt;
+use std::time::Instant;
+
+/// The username and password used to authenticate a connection.
+///
+/// For token based authentication (e.g. IAM tokens on managed offerings),
+/// pass the token as the password.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    /// The ACL username, or `None` for the `default` user.
+    pub username: Option<String>,
+    /// The password, or `None` to skip `AUTH`.
+    pub password: Option<String>,
+}
+
+impl Credentials {
+    /// Creates `Credentials` for the `default` user.
+    pub fn password<P: Into<String>>(password: P) -> Credentials {
+        Credentials {
+            username: None,
+            password: Some(password.into()),
+        }
+    }
+
+    /// Creates `Credentials` for an ACL user.
+    pub fn user<U: Into<String>, P: Into<String>>(username: U, password: P) -> Credentials {
+        Credentials {
+            username: Some(username.into()),
+            password: Some(password.into()),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// A password that is redacted from `Debug` output.
+#[derive(Clone)]
+pub(crate) struct Secret(pub(crate) String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("<redacted>")
+    }
+}
+
+/// A source of credentials consulted by `RedisConnectionManager` whenever
+/// it authenticates a connection.
+///
+/// This allows credentials to change, e.g. when passwords are rotated,
+/// without rebuilding the pool. Credentials given in the connection
+/// parameters are ignored when a provider is set.
+///
+/// ## Example
+///
+/// ```
+/// use std::env;
+///
+/// use redis_r2d2::{r2d2, redis, Credentials, CredentialsProvider, RedisConnectionManager};
+///
+/// #[derive(Debug)]
+/// struct FromEnv;
+///
+/// impl CredentialsProvider for FromEnv {
+///     fn credentials(&self) -> redis::RedisResult<Credentials> {
+///         Ok(Credentials {
+///             username: None,
+///             password: env::var("REDIS_PASSWORD").ok(),
+///         })
+///     }
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::builder()
+///         .credentials_provider(Box::new(FromEnv))
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder()
+///         .build(manager)
+///         .unwrap();
+///
+///     pool.get().unwrap();
+/// }
+/// ```
+pub trait CredentialsProvider: fmt::Debug + Send + Sync + 'static {
+    /// Returns the credentials for a connection about to be authenticated.
+    ///
+    /// This is called synchronously before every new connection and every
+    /// re-authentication after `RESET`, so slow lookups should be cached.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection attempt fails with it.
+    fn credentials(&self) -> redis::RedisResult<Credentials>;
+
+    /// Returns the credentials like `credentials`, along with when they
+    /// expire, if they do.
+    ///
+    /// Connections authenticated with expiring credentials are
+    /// re-authenticated with newer ones when they are checked out less
+    /// than a minute before the expiry, so servers that drop connections
+    /// when their token expires (e.g. Azure Cache for Redis with Entra ID)
+    /// keep accepting them.
+    ///
+    /// Defaults to `credentials`, without an expiry.
+    fn expiring_credentials(&self) -> redis::RedisResult<(Credentials, Option<Instant>)> {
+        Ok((self.credentials()?, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug() {
+        assert_eq!(
+            r#"Credentials { username: Some("app"), password: Some("<redacted>") }"#,
+            format!("{:?}", Credentials::user("app", "secret"))
+        );
+        assert_eq!(
+            "Credentials { username: None, password: None }",
+            format!("{:?}", Credentials::default())
+        );
+    }
+}
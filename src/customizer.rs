@@ -0,0 +1,104 @@
+use std::fmt;
+
+use crate::RedisConnection;
+
+/// A trait which allows for customization of connections created by
+/// `RedisConnectionManager`.
+///
+/// ## Example
+///
+/// ```
+/// use redis_r2d2::{r2d2, redis, ConnectionCustomizer, RedisConnectionManager};
+///
+/// #[derive(Debug)]
+/// struct TrackingOff;
+///
+/// impl ConnectionCustomizer for TrackingOff {
+///     fn on_connect(&self, conn: &mut redis::Connection) -> redis::RedisResult<()> {
+///         redis::cmd("CLIENT").arg("TRACKING").arg("off").query(conn)
+///     }
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::builder()
+///         .connection_customizer(Box::new(TrackingOff))
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder()
+///         .build(manager)
+///         .unwrap();
+///
+///     pool.get().unwrap();
+/// }
+/// ```
+pub trait ConnectionCustomizer: fmt::Debug + Send + Sync + 'static {
+    /// Called with connections immediately after they are established and
+    /// configured by the manager, before they are handed to the pool.
+    ///
+    /// The default implementation simply returns `Ok(())`.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection will be discarded and
+    /// the error reported by `ManageConnection::connect`.
+    #[allow(unused_variables)]
+    fn on_connect(&self, conn: &mut redis::Connection) -> redis::RedisResult<()> {
+        Ok(())
+    }
+
+    /// Called with the connections of a `RedisAsyncConnectionManager`
+    /// immediately after they are established and configured, like
+    /// `on_connect`.
+    ///
+    /// The default implementation simply returns `Ok(())`.
+    ///
+    /// # Errors
+    ///
+    /// If the future fails, the connection will be discarded and the error
+    /// reported by the pool.
+    #[cfg(any(
+        feature = "async-std",
+        feature = "bb8",
+        feature = "deadpool",
+        feature = "tokio"
+    ))]
+    #[allow(unused_variables)]
+    fn on_async_connect<'a>(
+        &'a self,
+        conn: &'a mut redis::aio::Connection,
+    ) -> redis::RedisFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Called with connections when they are returned to the pool.
+    ///
+    /// Connections the manager already knows to be unusable, e.g. because a
+    /// command failed with an I/O error, are discarded without calling this.
+    ///
+    /// This runs synchronously on the thread dropping the
+    /// `r2d2::PooledConnection`, so it should be quick.
+    ///
+    /// The default implementation simply returns `Ok(())`.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection will be reported as
+    /// broken and discarded.
+    #[allow(unused_variables)]
+    fn on_checkin(&self, conn: &mut RedisConnection) -> redis::RedisResult<()> {
+        Ok(())
+    }
+
+    /// Called with connections when they are closed, whether because they
+    /// broke, expired, or the pool was dropped.
+    ///
+    /// The default implementation does nothing.
+    #[allow(unused_variables)]
+    fn on_release(&self, conn: &mut RedisConnection) {}
+}
+
+/// A `ConnectionCustomizer` which does nothing.
+#[derive(Copy, Clone, Debug)]
+pub struct NopConnectionCustomizer;
+
+impl ConnectionCustomizer for NopConnectionCustomizer {}
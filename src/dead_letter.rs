@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use redis::streams::StreamId;
+use redis::{FromRedisValue, Value};
+
+use crate::pool_ext::checkout;
+use crate::stream_consumer::parse_entries;
+use crate::RedisConnectionManager;
+
+/// The field of a dead letter holding the stream it comes from.
+pub(crate) const STREAM_FIELD: &str = "dead-letter-stream";
+/// The field of a dead letter holding its ID in the stream it comes from.
+pub(crate) const ID_FIELD: &str = "dead-letter-id";
+/// The field of a dead letter holding how many times it was delivered.
+pub(crate) const DELIVERIES_FIELD: &str = "dead-letter-deliveries";
+
+/// Adds the dead letter `ARGV[1]` of `KEYS[1]` back to `KEYS[2]`, without
+/// the fields describing it, and deletes it. Returns the ID it was added
+/// under, or nil if it doesn't exist.
+const REPLAY_SCRIPT: &str = r#"
+local entries = redis.call("xrange", KEYS[1], ARGV[1], ARGV[1])
+if #entries == 0 then
+    return false
+end
+local fields = {}
+local entry = entries[1][2]
+for i = 1, #entry, 2 do
+    if string.sub(entry[i], 1, 12) ~= "dead-letter-" then
+        table.insert(fields, entry[i])
+        table.insert(fields, entry[i + 1])
+    end
+end
+local id = redis.call("xadd", KEYS[2], "*", unpack(fields))
+redis.call("xdel", KEYS[1], ARGV[1])
+return id
+"#;
+
+/// An entry moved to a dead-letter stream, see `DeadLetterStream`.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    /// The ID of the dead letter in the dead-letter stream.
+    pub id: String,
+    /// The stream the entry comes from.
+    pub stream: String,
+    /// The entry, with its ID in the stream it comes from.
+    pub entry: StreamId,
+    /// How many times the entry was delivered.
+    pub deliveries: u64,
+}
+
+/// A stream of the entries a `StreamConsumer` gave up on, see
+/// `StreamConsumer::max_deliveries`, to inspect, replay or discard them.
+///
+/// Each dead letter holds the fields of the entry, and the
+/// `dead-letter-stream`, `dead-letter-id` and `dead-letter-deliveries` fields
+/// describing where it comes from. With Redis Cluster, the dead-letter
+/// stream must be on the same node as the streams its entries are replayed
+/// to, e.g. with a hash tag.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, DeadLetterStream, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let dead_letters = DeadLetterStream::new(pool, "orders:dead");
+///     for dead_letter in dead_letters.list(10).unwrap() {
+///         println!("{} failed {} times", dead_letter.entry.id, dead_letter.deliveries);
+///     }
+///     // Once the bug is fixed.
+///     dead_letters.replay_all().unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeadLetterStream {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    stream: String,
+}
+
+impl DeadLetterStream {
+    /// Creates a `DeadLetterStream` for `stream`, with connections of `pool`.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        stream: S,
+    ) -> DeadLetterStream {
+        DeadLetterStream {
+            pool,
+            stream: stream.into(),
+        }
+    }
+
+    /// Returns the name of the dead-letter stream.
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    /// Returns the number of dead letters.
+    pub fn len(&self) -> redis::RedisResult<u64> {
+        redis::cmd("XLEN")
+            .arg(&self.stream)
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns whether there are no dead letters.
+    pub fn is_empty(&self) -> redis::RedisResult<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Returns the `count` oldest dead letters.
+    pub fn list(&self, count: usize) -> redis::RedisResult<Vec<DeadLetter>> {
+        let reply: Value = redis::cmd("XRANGE")
+            .arg(&self.stream)
+            .arg("-")
+            .arg("+")
+            .arg("COUNT")
+            .arg(count)
+            .query(&mut *checkout(&self.pool)?)?;
+        parse_entries(&reply)?
+            .into_iter()
+            .filter_map(|(id, map)| map.map(|map| (id, map)))
+            .map(|(id, map)| parse_dead_letter(id, map))
+            .collect()
+    }
+
+    /// Adds the dead letter `id` back to the stream it comes from, as a new
+    /// entry, and deletes it, returning the ID of the new entry or `None` if
+    /// there is no such dead letter.
+    pub fn replay(&self, id: &str) -> redis::RedisResult<Option<String>> {
+        let mut conn = checkout(&self.pool)?;
+        let stream: Option<String> = {
+            let reply: Value = redis::cmd("XRANGE")
+                .arg(&self.stream)
+                .arg(id)
+                .arg(id)
+                .query(&mut *conn)?;
+            parse_entries(&reply)?
+                .into_iter()
+                .filter_map(|(_, map)| map)
+                .find_map(|mut map| map.remove(STREAM_FIELD))
+                .map(|stream| String::from_redis_value(&stream))
+                .transpose()?
+        };
+        match stream {
+            Some(stream) => redis::Script::new(REPLAY_SCRIPT)
+                .key(&self.stream)
+                .key(stream)
+                .arg(id)
+                .invoke(&mut *conn),
+            None => Ok(None),
+        }
+    }
+
+    /// Replays every dead letter, returning how many there were.
+    pub fn replay_all(&self) -> redis::RedisResult<u64> {
+        let mut replayed = 0;
+        loop {
+            let dead_letters = self.list(100)?;
+            if dead_letters.is_empty() {
+                return Ok(replayed);
+            }
+            for dead_letter in dead_letters {
+                if self.replay(&dead_letter.id)?.is_some() {
+                    replayed += 1;
+                }
+            }
+        }
+    }
+
+    /// Deletes the dead letter `id`, returning false if there is none.
+    pub fn discard(&self, id: &str) -> redis::RedisResult<bool> {
+        redis::cmd("XDEL")
+            .arg(&self.stream)
+            .arg(id)
+            .query(&mut *checkout(&self.pool)?)
+    }
+}
+
+fn parse_dead_letter(
+    id: String,
+    mut map: HashMap<String, Value>,
+) -> redis::RedisResult<DeadLetter> {
+    let mut field = |name: &str| map.remove(name).unwrap_or(Value::Nil);
+    let stream = String::from_redis_value(&field(STREAM_FIELD))?;
+    let entry_id = String::from_redis_value(&field(ID_FIELD))?;
+    let deliveries = u64::from_redis_value(&field(DELIVERIES_FIELD))?;
+    Ok(DeadLetter {
+        id,
+        stream,
+        entry: StreamId { id: entry_id, map },
+        deliveries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_letter_stream() {
+        let pool = crate::local_pool(1);
+        let token = crate::lock::token();
+        let source = format!("redis_r2d2-source-{}", token);
+        let dead_letters = DeadLetterStream::new(pool.clone(), format!("{}:dead", source));
+        assert!(dead_letters.is_empty().unwrap());
+
+        let mut conn = pool.get().unwrap();
+        for n in 0..3 {
+            redis::cmd("XADD")
+                .arg(dead_letters.stream())
+                .arg("*")
+                .arg("n")
+                .arg(n)
+                .arg(STREAM_FIELD)
+                .arg(&source)
+                .arg(ID_FIELD)
+                .arg(format!("{}-0", n + 1))
+                .arg(DELIVERIES_FIELD)
+                .arg(5)
+                .query::<String>(&mut *conn)
+                .unwrap();
+        }
+        drop(conn);
+        let listed = dead_letters.list(10).unwrap();
+        assert_eq!(3, listed.len());
+        assert_eq!(source, listed[0].stream);
+        assert_eq!("1-0", listed[0].entry.id);
+        assert_eq!(Some(0), listed[0].entry.get("n"));
+        assert_eq!(1, listed[0].entry.map.len());
+        assert_eq!(5, listed[0].deliveries);
+
+        assert!(dead_letters.replay(&listed[0].id).unwrap().is_some());
+        assert!(dead_letters.replay(&listed[0].id).unwrap().is_none());
+        assert!(dead_letters.discard(&listed[1].id).unwrap());
+        assert_eq!(1, dead_letters.replay_all().unwrap());
+        assert!(dead_letters.is_empty().unwrap());
+
+        let mut conn = pool.get().unwrap();
+        let replayed: Value = redis::cmd("XRANGE")
+            .arg(&source)
+            .arg("-")
+            .arg("+")
+            .query(&mut *conn)
+            .unwrap();
+        let replayed = parse_entries(&replayed).unwrap();
+        assert_eq!(2, replayed.len());
+        let fields = replayed[1].1.as_ref().unwrap();
+        assert_eq!(1, fields.len());
+        assert_eq!(Value::Data(b"2".to_vec()), fields["n"]);
+    }
+}
@@ -0,0 +1,400 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::pool_ext::checkout;
+use crate::queue::now_ms;
+use crate::{Codec, Job, JsonCodec, ReliableQueue};
+
+/// Moves up to `ARGV[2]` jobs due at `ARGV[1]` to the pending list.
+///
+/// `KEYS`: the schedule, the jobs, the pending list. Returns how many jobs
+/// were moved.
+const POLL_SCRIPT: &str = r#"
+local ids = redis.call("zrangebyscore", KEYS[1], "-inf", ARGV[1], "LIMIT", 0, ARGV[2])
+for _, id in ipairs(ids) do
+    local job = redis.call("hget", KEYS[2], id)
+    if job then
+        redis.call("lpush", KEYS[3], job)
+    end
+    redis.call("hdel", KEYS[2], id)
+    redis.call("zrem", KEYS[1], id)
+end
+return #ids
+"#;
+
+/// Moves a job from a processing list to the schedule, unless it was
+/// acknowledged or requeued already, and drops its lease.
+///
+/// `KEYS`: the processing list, the leases, the schedule, the jobs. `ARGV`:
+/// the job, its ID, when it is due. Returns whether the job was scheduled.
+const RETRY_SCRIPT: &str = r#"
+if redis.call("lrem", KEYS[1], 1, ARGV[1]) == 0 then
+    return 0
+end
+redis.call("zrem", KEYS[2], ARGV[1])
+redis.call("hset", KEYS[4], ARGV[2], ARGV[1])
+redis.call("zadd", KEYS[3], ARGV[3], ARGV[2])
+return 1
+"#;
+
+/// Jobs of a `ReliableQueue` to be pushed to it later, see
+/// `ReliableQueue::delayed`.
+///
+/// Jobs are scheduled in the sorted set `<name>:delayed`, by ID with the
+/// time they are due as score, and stored in the hash `<name>:delayed:jobs`.
+/// `poll`, which the poller started with `start_poller` runs regularly,
+/// moves the jobs that are due to the queue with a script, so a job is
+/// never lost or pushed twice. Times are taken from the clocks of the
+/// clients, which should be in sync.
+///
+/// Requires the `serde` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, RedisConnectionManager, ReliableQueue};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let queue = ReliableQueue::<String>::new(pool, "reminders");
+///     let delayed = queue.delayed();
+///     let id = delayed
+///         .schedule_in(&"call mom".to_string(), Duration::from_secs(3600))
+///         .unwrap();
+///     delayed.reschedule_in(&id, Duration::from_secs(7200)).unwrap();
+///
+///     let poller = delayed.start_poller(Duration::from_secs(1));
+///     while let Some(job) = queue.reserve("reminder-1", Duration::from_secs(5)).unwrap() {
+///         if job.data().is_empty() {
+///             // Try again in a minute.
+///             delayed.retry_in(&job, Duration::from_secs(60)).unwrap();
+///         } else {
+///             queue.ack(&job).unwrap();
+///         }
+///     }
+///     poller.stop();
+/// }
+/// ```
+pub struct DelayedQueue<T, C = JsonCodec> {
+    queue: ReliableQueue<T, C>,
+    batch_size: usize,
+}
+
+impl<T, C: Clone> ReliableQueue<T, C> {
+    /// Returns the `DelayedQueue` of the queue, to schedule jobs for later.
+    pub fn delayed(&self) -> DelayedQueue<T, C> {
+        DelayedQueue {
+            queue: self.clone(),
+            batch_size: 100,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> DelayedQueue<T, C> {
+    /// Sets how many due jobs each script run of `poll` moves at most.
+    ///
+    /// Defaults to 100.
+    pub fn batch_size(mut self, batch_size: usize) -> DelayedQueue<T, C> {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Schedules a job holding `data` to be pushed to the queue at `at`,
+    /// returning its ID.
+    pub fn schedule(&self, data: &T, at: SystemTime) -> redis::RedisResult<String> {
+        let (id, raw) = self.queue.envelope(data)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("HSET")
+            .arg(self.jobs_key())
+            .arg(&id)
+            .arg(raw)
+            .ignore()
+            .cmd("ZADD")
+            .arg(self.schedule_key())
+            .arg(millis(at))
+            .arg(&id)
+            .ignore();
+        pipe.query::<()>(&mut *checkout(&self.queue.pool)?)?;
+        Ok(id)
+    }
+
+    /// Schedules a job holding `data` to be pushed to the queue after
+    /// `delay`, returning its ID.
+    pub fn schedule_in(&self, data: &T, delay: Duration) -> redis::RedisResult<String> {
+        self.schedule(data, SystemTime::now() + delay)
+    }
+
+    /// Moves the job `id` to `at`, returning false if it isn't scheduled,
+    /// e.g. because it was pushed to the queue already.
+    pub fn reschedule(&self, id: &str, at: SystemTime) -> redis::RedisResult<bool> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("ZSCORE")
+            .arg(self.schedule_key())
+            .arg(id)
+            .cmd("ZADD")
+            .arg(self.schedule_key())
+            .arg("XX")
+            .arg(millis(at))
+            .arg(id)
+            .ignore();
+        let (due,): (Option<f64>,) = pipe.query(&mut *checkout(&self.queue.pool)?)?;
+        Ok(due.is_some())
+    }
+
+    /// Moves the job `id` to `delay` from now, see `reschedule`.
+    pub fn reschedule_in(&self, id: &str, delay: Duration) -> redis::RedisResult<bool> {
+        self.reschedule(id, SystemTime::now() + delay)
+    }
+
+    /// Unschedules the job `id`, returning false if it isn't scheduled.
+    pub fn cancel(&self, id: &str) -> redis::RedisResult<bool> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("ZREM")
+            .arg(self.schedule_key())
+            .arg(id)
+            .cmd("HDEL")
+            .arg(self.jobs_key())
+            .arg(id)
+            .ignore();
+        let (removed,): (u32,) = pipe.query(&mut *checkout(&self.queue.pool)?)?;
+        Ok(removed > 0)
+    }
+
+    /// Returns when the job `id` is due, or `None` if it isn't scheduled.
+    pub fn due_at(&self, id: &str) -> redis::RedisResult<Option<SystemTime>> {
+        let due: Option<f64> = redis::cmd("ZSCORE")
+            .arg(self.schedule_key())
+            .arg(id)
+            .query(&mut *checkout(&self.queue.pool)?)?;
+        Ok(due.map(|due| UNIX_EPOCH + Duration::from_millis(due as u64)))
+    }
+
+    /// Takes `job`, a job of the queue its handler failed for, off its
+    /// worker and schedules it to be pushed to the queue again after
+    /// `delay`, e.g. to retry it with a backoff.
+    ///
+    /// Returns false if the lease of the job had expired and it was
+    /// requeued in the meantime, or if it was acknowledged already.
+    pub fn retry_in(&self, job: &Job<T>, delay: Duration) -> redis::RedisResult<bool> {
+        redis::Script::new(RETRY_SCRIPT)
+            .key(self.queue.processing_key(&job.worker))
+            .key(self.queue.key("leases"))
+            .key(self.schedule_key())
+            .key(self.jobs_key())
+            .arg(&job.raw[..])
+            .arg(&job.id)
+            .arg(now_ms() + delay.as_millis() as u64)
+            .invoke(&mut *checkout(&self.queue.pool)?)
+    }
+
+    /// Returns the number of scheduled jobs.
+    pub fn len(&self) -> redis::RedisResult<u64> {
+        redis::cmd("ZCARD")
+            .arg(self.schedule_key())
+            .query(&mut *checkout(&self.queue.pool)?)
+    }
+
+    /// Returns whether no job is scheduled.
+    pub fn is_empty(&self) -> redis::RedisResult<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Pushes the jobs that are due to the queue, returning how many there
+    /// were.
+    pub fn poll(&self) -> redis::RedisResult<u64> {
+        let script = redis::Script::new(POLL_SCRIPT);
+        let mut conn = checkout(&self.queue.pool)?;
+        let mut moved = 0;
+        loop {
+            let batch: u64 = script
+                .key(self.schedule_key())
+                .key(self.jobs_key())
+                .key(self.queue.key("pending"))
+                .arg(now_ms())
+                .arg(self.batch_size)
+                .invoke(&mut *conn)?;
+            moved += batch;
+            if batch < self.batch_size as u64 {
+                return Ok(moved);
+            }
+        }
+    }
+
+    fn schedule_key(&self) -> String {
+        self.queue.key("delayed")
+    }
+
+    fn jobs_key(&self) -> String {
+        self.queue.key("delayed:jobs")
+    }
+}
+
+impl<T, C> DelayedQueue<T, C>
+where
+    T: Serialize + DeserializeOwned + 'static,
+    C: Codec + Clone + Send + 'static,
+{
+    /// Starts running `poll` every `interval` on a background thread, until
+    /// the returned `DelayedQueueHandle` is stopped or dropped.
+    ///
+    /// Failures are logged, and polling is tried again after `interval`.
+    pub fn start_poller(&self, interval: Duration) -> DelayedQueueHandle {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let delayed = self.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    if let Err(e) = delayed.poll() {
+                        log::warn!("delayed queue poller failed: {}", e);
+                    }
+                    thread::park_timeout(interval);
+                }
+            })
+        };
+        DelayedQueueHandle {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl<T, C: Clone> Clone for DelayedQueue<T, C> {
+    fn clone(&self) -> DelayedQueue<T, C> {
+        DelayedQueue {
+            queue: self.queue.clone(),
+            batch_size: self.batch_size,
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for DelayedQueue<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DelayedQueue")
+            .field("queue", &self.queue)
+            .field("batch_size", &self.batch_size)
+            .finish()
+    }
+}
+
+/// Runs the poller of a `DelayedQueue` until it is stopped or dropped.
+#[derive(Debug)]
+pub struct DelayedQueueHandle {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DelayedQueueHandle {
+    /// Stops the poller, waiting for the current poll to return.
+    ///
+    /// Dropping the handle stops the poller without waiting.
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for DelayedQueueHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = &self.thread {
+            thread.thread().unpark();
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> ReliableQueue<u32> {
+        let pool = crate::local_pool(3);
+        let name = format!("redis_r2d2-delayed-{}", crate::lock::token());
+        ReliableQueue::new(pool, name)
+    }
+
+    #[test]
+    fn test_schedule() {
+        let queue = queue();
+        let delayed = queue.delayed().batch_size(2);
+        let hour = Duration::from_secs(3600);
+        let now = SystemTime::now();
+        for n in 0..3 {
+            let at = now - hour + Duration::from_secs(n.into());
+            delayed.schedule(&n, at).unwrap();
+        }
+        let later = delayed.schedule_in(&3, hour).unwrap();
+        let cancelled = delayed.schedule_in(&4, hour).unwrap();
+        assert_eq!(5, delayed.len().unwrap());
+        assert!(delayed.due_at(&later).unwrap().unwrap() > now);
+
+        assert_eq!(3, delayed.poll().unwrap());
+        assert_eq!(3, queue.len().unwrap());
+        assert!(delayed.cancel(&cancelled).unwrap());
+        assert!(!delayed.cancel(&cancelled).unwrap());
+        assert_eq!(0, delayed.poll().unwrap());
+
+        assert!(delayed.reschedule(&later, now).unwrap());
+        assert_eq!(1, delayed.poll().unwrap());
+        assert!(!delayed.reschedule(&later, now).unwrap());
+        assert!(delayed.due_at(&later).unwrap().is_none());
+        assert!(delayed.is_empty().unwrap());
+        let ns: Vec<u32> = (0..4)
+            .map(|_| {
+                let job = queue
+                    .reserve("w1", Duration::from_secs(1))
+                    .unwrap()
+                    .unwrap();
+                queue.ack(&job).unwrap();
+                job.into_data()
+            })
+            .collect();
+        assert_eq!(vec![0, 1, 2, 3], ns);
+    }
+
+    #[test]
+    fn test_retry_in() {
+        let queue = queue();
+        let delayed = queue.delayed();
+        let id = queue.push(&7).unwrap();
+        let job = queue
+            .reserve("w1", Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert!(delayed.retry_in(&job, Duration::from_millis(100)).unwrap());
+        assert!(!delayed.retry_in(&job, Duration::from_millis(100)).unwrap());
+        assert!(!queue.ack(&job).unwrap());
+        assert_eq!(0, queue.sweep().unwrap());
+
+        let poller = delayed.start_poller(Duration::from_millis(20));
+        let job = queue
+            .reserve("w1", Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        poller.stop();
+        assert_eq!(id, job.id());
+        assert_eq!(&7, job.data());
+        assert!(queue.ack(&job).unwrap());
+    }
+}
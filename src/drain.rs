@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::RedisConnection;
+
+/// A handle for shutting down a pool gracefully, see
+/// `RedisConnectionManager::drain_handle`.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let drain = manager.drain_handle();
+///     let pool = r2d2::Pool::builder()
+///         .build(manager)
+///         .unwrap();
+///
+///     // ...
+///
+///     assert!(drain.drain(&pool, Duration::from_secs(10)));
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DrainHandle {
+    draining: Arc<AtomicBool>,
+}
+
+impl DrainHandle {
+    pub(crate) fn new(draining: Arc<AtomicBool>) -> DrainHandle {
+        DrainHandle { draining }
+    }
+
+    /// Returns true once `drain` was called.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Stops the manager from handing out connections, waits up to
+    /// `timeout` for checked out connections to be returned, and closes
+    /// every connection of `pool` with `QUIT`.
+    ///
+    /// Afterwards the manager refuses to connect and fails validation, so
+    /// checkouts from the pool fail once its connection timeout elapses.
+    /// Connections returned after `timeout` are closed as they come back.
+    ///
+    /// `pool` must be the pool of the manager this handle belongs to.
+    ///
+    /// Returns true if all connections were closed before `timeout`.
+    pub fn drain<M>(&self, pool: &r2d2::Pool<M>, timeout: Duration) -> bool
+    where
+        M: r2d2::ManageConnection<Connection = RedisConnection>,
+    {
+        self.draining.store(true, Ordering::Relaxed);
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let state = pool.state();
+            if state.connections == state.idle_connections || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Idle connections fail validation, or are found broken when put
+        // back, and are closed either way.
+        while let Some(conn) = pool.try_get() {
+            drop(conn);
+        }
+        pool.state().connections == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConnectionManager;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_drain() {
+        let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+        let drain = manager.drain_handle();
+        let pool = r2d2::Pool::builder()
+            .max_size(2)
+            .connection_timeout(Duration::from_millis(100))
+            .build(manager)
+            .unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let pool2 = pool.clone();
+        let t = thread::spawn(move || {
+            let conn = pool2.get().unwrap();
+            sender.send(()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            drop(conn);
+        });
+        receiver.recv().unwrap();
+
+        assert!(drain.drain(&pool, Duration::from_secs(5)));
+        assert!(drain.is_draining());
+        assert_eq!(0, pool.state().connections);
+        assert!(pool.get().is_err());
+        t.join().unwrap();
+    }
+}
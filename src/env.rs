@@ -0,0 +1,127 @@
+use std::env::VarError;
+use std::time::Duration;
+
+use crate::RedisConnectionManager;
+
+/// Builds a manager from the variables described at
+/// `RedisConnectionManager::from_env`, looked up with `var`.
+pub(crate) fn from_env<F>(var: F) -> redis::RedisResult<RedisConnectionManager>
+where
+    F: Fn(&str) -> Result<String, VarError>,
+{
+    let url = lookup(&var, "REDIS_URL")?.ok_or_else(|| invalid("REDIS_URL", "not set".into()))?;
+    let mut connection_info = redis::IntoConnectionInfo::into_connection_info(url.as_str())
+        .map_err(|e| invalid("REDIS_URL", e.to_string()))?;
+    if let Some(db) = lookup(&var, "REDIS_DB")? {
+        connection_info.db = db
+            .parse()
+            .map_err(|_| invalid("REDIS_DB", format!("{:?} is not a number", db)))?;
+    }
+    if let Some(username) = lookup(&var, "REDIS_USERNAME")? {
+        connection_info.username = Some(username);
+    }
+    if let Some(password) = lookup(&var, "REDIS_PASSWORD")? {
+        connection_info.passwd = Some(password);
+    }
+    let connect_timeout = match lookup(&var, "REDIS_CONNECT_TIMEOUT")? {
+        Some(timeout) => Some(parse_timeout(&timeout).ok_or_else(|| {
+            invalid(
+                "REDIS_CONNECT_TIMEOUT",
+                format!("{:?} is not a positive number of seconds", timeout),
+            )
+        })?),
+        None => None,
+    };
+
+    RedisConnectionManager::builder()
+        .connect_timeout(connect_timeout)
+        .build(connection_info)
+}
+
+/// Returns the value of `name`, treating an empty value as unset.
+fn lookup<F>(var: &F, name: &'static str) -> redis::RedisResult<Option<String>>
+where
+    F: Fn(&str) -> Result<String, VarError>,
+{
+    match var(name) {
+        Ok(ref value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(invalid(name, "not valid unicode".into())),
+    }
+}
+
+fn parse_timeout(timeout: &str) -> Option<Duration> {
+    let secs = timeout.parse::<f64>().ok()?;
+    if secs > 0.0 {
+        Duration::try_from_secs_f64(secs).ok()
+    } else {
+        None
+    }
+}
+
+fn invalid(name: &'static str, detail: String) -> redis::RedisError {
+    (redis::ErrorKind::InvalidClientConfig, name, detail).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn from_vars(vars: &[(&str, &str)]) -> redis::RedisResult<RedisConnectionManager> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        from_env(|name| vars.get(name).cloned().ok_or(VarError::NotPresent))
+    }
+
+    #[test]
+    fn test_from_env() {
+        let manager = from_vars(&[
+            ("REDIS_URL", "redis://localhost/1"),
+            ("REDIS_DB", "2"),
+            ("REDIS_USERNAME", "app"),
+            ("REDIS_PASSWORD", "secret"),
+            ("REDIS_CONNECT_TIMEOUT", "1.5"),
+        ])
+        .unwrap();
+
+        assert_eq!(2, manager.endpoints[0].connection_info.db);
+        assert_eq!(
+            Some("app"),
+            manager.endpoints[0].connection_info.username.as_deref()
+        );
+        assert_eq!(
+            Some("secret"),
+            manager.endpoints[0].connection_info.passwd.as_deref()
+        );
+        assert_eq!(Some(Duration::from_millis(1500)), manager.connect_timeout);
+
+        let manager = from_vars(&[("REDIS_URL", "redis://localhost/1"), ("REDIS_DB", "")]).unwrap();
+        assert_eq!(1, manager.endpoints[0].connection_info.db);
+        assert_eq!(None, manager.connect_timeout);
+    }
+
+    #[test]
+    fn test_from_env_errors() {
+        let error = |vars: &[(&str, &str)]| from_vars(vars).unwrap_err().to_string();
+
+        assert!(error(&[]).contains("REDIS_URL"));
+        assert!(error(&[("REDIS_URL", "localhost")]).contains("REDIS_URL"));
+        assert!(
+            error(&[("REDIS_URL", "redis://localhost"), ("REDIS_DB", "one")]).contains("REDIS_DB")
+        );
+        assert!(error(&[
+            ("REDIS_URL", "redis://localhost"),
+            ("REDIS_CONNECT_TIMEOUT", "0"),
+        ])
+        .contains("REDIS_CONNECT_TIMEOUT"));
+        assert!(error(&[
+            ("REDIS_URL", "redis://localhost"),
+            ("REDIS_CONNECT_TIMEOUT", "1e30"),
+        ])
+        .contains("REDIS_CONNECT_TIMEOUT"));
+    }
+}
@@ -0,0 +1,174 @@
+use redis::{ErrorKind, RedisError};
+
+/// A coarse classification of a `RedisError`, for deciding how to react to
+/// it without matching on error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    /// The connection failed or was lost.
+    Network,
+    /// An I/O operation timed out.
+    Timeout,
+    /// The server rejected the credentials, or the command requires
+    /// authentication or permissions the user lacks.
+    Auth,
+    /// The server is temporarily unable to serve the command, e.g. while
+    /// loading its dataset, running a busy script or electing a master.
+    ServerBusy,
+    /// The command must be sent to another server, e.g. a `MOVED` reply or
+    /// a `READONLY` reply after a failover.
+    Redirect,
+    /// The reply could not be parsed or had an unexpected type.
+    Protocol,
+    /// The server rejected the command itself.
+    Command,
+    /// The client was configured incorrectly.
+    Config,
+    /// Any other error, e.g. one raised by this crate because the pool is
+    /// draining.
+    Other,
+}
+
+impl ErrorCategory {
+    /// Classifies `error`.
+    pub fn of(error: &RedisError) -> ErrorCategory {
+        if error.is_timeout() {
+            return ErrorCategory::Timeout;
+        }
+        match error.kind() {
+            ErrorKind::IoError => ErrorCategory::Network,
+            ErrorKind::AuthenticationFailed => ErrorCategory::Auth,
+            ErrorKind::BusyLoadingError
+            | ErrorKind::TryAgain
+            | ErrorKind::ClusterDown
+            | ErrorKind::MasterDown => ErrorCategory::ServerBusy,
+            ErrorKind::Moved | ErrorKind::Ask => ErrorCategory::Redirect,
+            ErrorKind::TypeError => ErrorCategory::Protocol,
+            ErrorKind::ResponseError if is_parse_error(error) => ErrorCategory::Protocol,
+            ErrorKind::ResponseError
+            | ErrorKind::ExecAbortError
+            | ErrorKind::NoScriptError
+            | ErrorKind::CrossSlot => ErrorCategory::Command,
+            ErrorKind::InvalidClientConfig => ErrorCategory::Config,
+            ErrorKind::ExtensionError => match error.code() {
+                Some("NOAUTH") | Some("WRONGPASS") | Some("NOPERM") => ErrorCategory::Auth,
+                Some("BUSY") => ErrorCategory::ServerBusy,
+                Some("READONLY") => ErrorCategory::Redirect,
+                _ => ErrorCategory::Command,
+            },
+            ErrorKind::ClientError => ErrorCategory::Other,
+        }
+    }
+
+    /// Returns true if retrying the command, possibly on a new connection,
+    /// may succeed without any other change.
+    pub fn is_transient(self) -> bool {
+        match self {
+            ErrorCategory::Network
+            | ErrorCategory::Timeout
+            | ErrorCategory::ServerBusy
+            | ErrorCategory::Redirect => true,
+            ErrorCategory::Auth
+            | ErrorCategory::Protocol
+            | ErrorCategory::Command
+            | ErrorCategory::Config
+            | ErrorCategory::Other => false,
+        }
+    }
+}
+
+#[allow(deprecated)]
+fn is_parse_error(error: &RedisError) -> bool {
+    use std::error::Error;
+
+    error.description() == "parse error"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn server_error(reply: &str) -> RedisError {
+        redis::parse_redis_value(reply.as_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn test_error_category() {
+        let cases = vec![
+            (
+                io::Error::from(io::ErrorKind::ConnectionRefused).into(),
+                ErrorCategory::Network,
+            ),
+            (
+                io::Error::from(io::ErrorKind::TimedOut).into(),
+                ErrorCategory::Timeout,
+            ),
+            (
+                server_error("-WRONGPASS invalid username-password pair\r\n"),
+                ErrorCategory::Auth,
+            ),
+            (
+                server_error("-NOAUTH Authentication required.\r\n"),
+                ErrorCategory::Auth,
+            ),
+            (
+                (
+                    ErrorKind::AuthenticationFailed,
+                    "Password authentication failed",
+                )
+                    .into(),
+                ErrorCategory::Auth,
+            ),
+            (
+                server_error("-LOADING Redis is loading the dataset in memory\r\n"),
+                ErrorCategory::ServerBusy,
+            ),
+            (
+                server_error("-BUSY Redis is busy running a script.\r\n"),
+                ErrorCategory::ServerBusy,
+            ),
+            (
+                server_error("-READONLY You can't write against a read only replica.\r\n"),
+                ErrorCategory::Redirect,
+            ),
+            (
+                server_error("-MOVED 3999 127.0.0.1:6381\r\n"),
+                ErrorCategory::Redirect,
+            ),
+            (
+                redis::parse_redis_value(b"?\r\n").unwrap_err(),
+                ErrorCategory::Protocol,
+            ),
+            (
+                (ErrorKind::TypeError, "Response was of incompatible type").into(),
+                ErrorCategory::Protocol,
+            ),
+            (
+                server_error("-ERR unknown command 'FOO'\r\n"),
+                ErrorCategory::Command,
+            ),
+            (
+                server_error(
+                    "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+                ),
+                ErrorCategory::Command,
+            ),
+            (
+                (ErrorKind::InvalidClientConfig, "Redis URL did not parse").into(),
+                ErrorCategory::Config,
+            ),
+            (
+                (ErrorKind::ClientError, "the pool is draining").into(),
+                ErrorCategory::Other,
+            ),
+        ];
+        for (error, category) in cases {
+            assert_eq!(category, ErrorCategory::of(&error), "{}", error);
+        }
+
+        assert!(ErrorCategory::Network.is_transient());
+        assert!(ErrorCategory::ServerBusy.is_transient());
+        assert!(!ErrorCategory::Auth.is_transient());
+        assert!(!ErrorCategory::Command.is_transient());
+    }
+}
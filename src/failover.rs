@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Tracks the endpoint new connections go to with
+/// `EndpointSelection::Failover`.
+#[derive(Debug, Default)]
+pub(crate) struct Failover {
+    active: AtomicUsize,
+}
+
+impl Failover {
+    /// Returns the index of the endpoint that last accepted a connection, or
+    /// was found reachable again.
+    pub(crate) fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Records that endpoint `index` accepted a connection.
+    pub(crate) fn connected(&self, index: usize) {
+        self.active.store(index, Ordering::Relaxed);
+    }
+
+    /// Spawns a thread checking every `interval` whether an endpoint with a
+    /// higher priority than the active one is reachable again, and making
+    /// it the active one if so.
+    ///
+    /// The thread stops once the `Failover` is dropped.
+    pub(crate) fn watch(self: &Arc<Failover>, clients: Vec<redis::Client>, interval: Duration) {
+        let failover = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let failover = match failover.upgrade() {
+                Some(failover) => failover,
+                None => return,
+            };
+            let active = failover.active();
+            if let Some(index) = clients[..active]
+                .iter()
+                .position(|client| is_reachable(client, interval))
+            {
+                log::info!("endpoint {} is reachable again, failing back", index);
+                failover.connected(index);
+            }
+        });
+    }
+}
+
+/// Returns true if the server behind `client` answers a `PING`.
+///
+/// Error replies count as answers, since the probe doesn't authenticate
+/// when the credentials come from a `CredentialsProvider`.
+fn is_reachable(client: &redis::Client, timeout: Duration) -> bool {
+    let mut conn = match client.get_connection_with_timeout(timeout) {
+        Ok(conn) => conn,
+        Err(ref e) if e.is_io_error() => return false,
+        Err(_) => return true,
+    };
+    if conn.set_read_timeout(Some(timeout)).is_err() {
+        return false;
+    }
+    match redis::cmd("PING").query::<()>(&mut conn) {
+        Ok(()) => true,
+        Err(e) => !e.is_io_error() && !e.is_timeout(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::fake_server;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_failover_watch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let clients = vec![
+            redis::Client::open(format!("redis://{}", addr)).unwrap(),
+            redis::Client::open("redis://localhost").unwrap(),
+        ];
+        assert!(!is_reachable(&clients[0], Duration::from_millis(100)));
+        assert!(is_reachable(&clients[1], Duration::from_millis(100)));
+
+        let failover = Arc::new(Failover::default());
+        failover.connected(1);
+        failover.watch(clients, Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(1, failover.active());
+
+        fake_server(TcpListener::bind(addr).unwrap(), |_| {
+            "+PONG\r\n".to_string()
+        });
+        for _ in 0..50 {
+            if failover.active() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(0, failover.active());
+    }
+}
@@ -0,0 +1,37 @@
+//! A minimal stand-in for Redis servers the tests can't rely on, such as
+//! sentinels and cluster nodes.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread;
+
+/// Answers whatever is read from each connection to `listener` with
+/// `reply`, which is passed the request upper-cased.
+pub(crate) fn fake_server<F>(listener: TcpListener, reply: F) -> SocketAddr
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    let addr = listener.local_addr().unwrap();
+    let reply = Arc::new(reply);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let reply = reply.clone();
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                loop {
+                    let n = match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => n,
+                    };
+                    let request = String::from_utf8_lossy(&buf[..n]).to_uppercase();
+                    if stream.write_all(reply(&request).as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
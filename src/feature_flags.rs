@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::pool_ext::checkout;
+use crate::{
+    InvalidationBus, InvalidationListener, RedisConnectionManager, RedisPubSubConnectionManager,
+};
+
+/// Feature flags stored in a hash, read from a snapshot kept in memory.
+///
+/// Flags are the fields of the hash, with values such as `true` or `25`,
+/// set with `set` or directly, e.g. with `HSET`. The accessors read the last
+/// snapshot of the hash, taken by `refresh`, which the handle returned by
+/// `start` runs periodically and, when given a pub/sub connection manager,
+/// whenever a flag is changed with `set` or `remove` by any instance, as
+/// those announce the change on the channel `<key>:updates`. Clones share
+/// the snapshot.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, FeatureFlags, RedisConnectionManager, RedisPubSubConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let flags = FeatureFlags::new(pool, "feature-flags");
+///     let updates = RedisPubSubConnectionManager::new("redis://localhost").unwrap();
+///     let _refresher = flags.start(Duration::from_secs(30), Some(updates)).unwrap();
+///
+///     flags.set("new-checkout", "25").unwrap();
+///     if flags.bool_flag("dark-mode", false) || flags.percent_rollout("new-checkout", "user:42") {
+///         // ...
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FeatureFlags {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    key: String,
+    bus: InvalidationBus,
+    snapshot: Arc<RwLock<Arc<HashMap<String, String>>>>,
+}
+
+impl FeatureFlags {
+    /// Creates `FeatureFlags` stored in the hash `key`, with connections of
+    /// `pool`, and an empty snapshot until the first `refresh`.
+    pub fn new<S: Into<String>>(pool: r2d2::Pool<RedisConnectionManager>, key: S) -> FeatureFlags {
+        let key = key.into();
+        FeatureFlags {
+            bus: InvalidationBus::new(pool.clone(), format!("{}:updates", key)),
+            pool,
+            key,
+            snapshot: Arc::new(RwLock::new(Arc::new(HashMap::new()))),
+        }
+    }
+
+    /// Replaces the snapshot with the current flags (`HGETALL`).
+    pub fn refresh(&self) -> redis::RedisResult<()> {
+        let flags: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(&self.key)
+            .query(&mut *checkout(&self.pool)?)?;
+        *self.snapshot.write().unwrap() = Arc::new(flags);
+        Ok(())
+    }
+
+    /// Returns the snapshot of the flags.
+    pub fn snapshot(&self) -> Arc<HashMap<String, String>> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Returns the value of the flag `name`, if it is set.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.snapshot.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns whether the flag `name` is on: `true`, `on`, `yes` and `1`
+    /// are on, `false`, `off`, `no` and `0` off, and `default` is returned
+    /// for other values and unset flags.
+    pub fn bool_flag(&self, name: &str, default: bool) -> bool {
+        match self
+            .get(name)
+            .map(|value| value.trim().to_ascii_lowercase())
+        {
+            Some(ref value) if ["true", "on", "yes", "1"].contains(&&value[..]) => true,
+            Some(ref value) if ["false", "off", "no", "0"].contains(&&value[..]) => false,
+            _ => default,
+        }
+    }
+
+    /// Returns whether the rollout of the flag `name`, a percentage such as
+    /// `25` or `12.5`, includes `user_id`.
+    ///
+    /// Users are assigned to a bucket by hashing the flag name and their ID,
+    /// so a user stays in the rollout as it grows, and on every instance.
+    /// Returns false if the flag is unset or not a number.
+    pub fn percent_rollout<U: AsRef<[u8]>>(&self, name: &str, user_id: U) -> bool {
+        let percent = match self
+            .get(name)
+            .and_then(|value| value.trim().parse::<f64>().ok())
+        {
+            Some(percent) => percent,
+            None => return false,
+        };
+        let bucket = fnv1a(&[name.as_bytes(), b":", user_id.as_ref()]) % 10_000;
+        (bucket as f64) < percent * 100.0
+    }
+
+    /// Sets the flag `name` to `value`, in the snapshot too, and announces
+    /// the change to the other instances.
+    pub fn set<V: Into<String>>(&self, name: &str, value: V) -> redis::RedisResult<()> {
+        let value = value.into();
+        redis::cmd("HSET")
+            .arg(&self.key)
+            .arg(name)
+            .arg(&value)
+            .query::<()>(&mut *checkout(&self.pool)?)?;
+        self.update(|flags| {
+            flags.insert(name.to_string(), value);
+        });
+        self.bus.invalidate(name)
+    }
+
+    /// Unsets the flag `name`, in the snapshot too, and announces the change
+    /// to the other instances.
+    pub fn remove(&self, name: &str) -> redis::RedisResult<()> {
+        redis::cmd("HDEL")
+            .arg(&self.key)
+            .arg(name)
+            .query::<()>(&mut *checkout(&self.pool)?)?;
+        self.update(|flags| {
+            flags.remove(name);
+        });
+        self.bus.invalidate(name)
+    }
+
+    /// Refreshes the snapshot, then starts refreshing it every
+    /// `refresh_interval` on a background thread, and whenever flags are
+    /// changed if `updates` is given, until the returned `FeatureFlagsHandle`
+    /// is stopped or dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the first refresh failed.
+    pub fn start(
+        &self,
+        refresh_interval: Duration,
+        updates: Option<RedisPubSubConnectionManager>,
+    ) -> redis::RedisResult<FeatureFlagsHandle> {
+        self.refresh()?;
+        let listener = updates.map(|manager| {
+            let flags = self.clone();
+            self.bus
+                .subscriber(manager)
+                .skip_own(true)
+                .on_invalidate(move |_| {
+                    if let Err(e) = flags.refresh() {
+                        log::warn!("couldn't refresh the feature flags {}: {}", flags.key, e);
+                    }
+                })
+                .start()
+        });
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let flags = self.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || loop {
+                thread::park_timeout(refresh_interval);
+                if stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Err(e) = flags.refresh() {
+                    log::warn!("couldn't refresh the feature flags {}: {}", flags.key, e);
+                }
+            })
+        };
+        Ok(FeatureFlagsHandle {
+            stopped,
+            thread: Some(thread),
+            _listener: listener,
+        })
+    }
+
+    fn update<F: FnOnce(&mut HashMap<String, String>)>(&self, f: F) {
+        let mut snapshot = self.snapshot.write().unwrap();
+        let mut flags = HashMap::clone(&snapshot);
+        f(&mut flags);
+        *snapshot = Arc::new(flags);
+    }
+}
+
+/// The 64-bit FNV-1a hash of the concatenation of `parts`, stable across
+/// processes unlike the hashers of the standard library.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Refreshes `FeatureFlags` until it is stopped or dropped, see
+/// `FeatureFlags::start`.
+#[derive(Debug)]
+pub struct FeatureFlagsHandle {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    _listener: Option<InvalidationListener>,
+}
+
+impl FeatureFlagsHandle {
+    /// Stops refreshing the flags, waiting for the current refresh, if any.
+    ///
+    /// Dropping the handle stops refreshing without waiting.
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for FeatureFlagsHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = &self.thread {
+            thread.thread().unpark();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn flags(key: &str) -> FeatureFlags {
+        let pool = crate::local_pool(2);
+        FeatureFlags::new(pool, key)
+    }
+
+    #[test]
+    fn test_accessors() {
+        let key = format!("redis_r2d2-flags-{}", crate::lock::token());
+        let flags = flags(&key);
+        flags.set("on", "True").unwrap();
+        flags.set("off", "0").unwrap();
+        flags.set("odd", "maybe").unwrap();
+        flags.set("half", "50").unwrap();
+        flags.set("none", "0").unwrap();
+        flags.set("all", "100").unwrap();
+        assert!(flags.bool_flag("on", false));
+        assert!(!flags.bool_flag("off", true));
+        assert!(flags.bool_flag("odd", true));
+        assert!(!flags.bool_flag("unset", false));
+
+        let users: Vec<String> = (0..1000).map(|n| format!("user:{}", n)).collect();
+        let included = users
+            .iter()
+            .filter(|user| flags.percent_rollout("half", user))
+            .count();
+        assert!(included > 400 && included < 600, "{}", included);
+        assert!(users.iter().all(|user| flags.percent_rollout("all", user)));
+        assert!(!users.iter().any(|user| flags.percent_rollout("none", user)));
+        assert!(!flags.percent_rollout("odd", "user:1"));
+        assert_eq!(
+            flags.percent_rollout("half", "user:7"),
+            flags.percent_rollout("half", "user:7")
+        );
+
+        flags.remove("on").unwrap();
+        assert_eq!(None, flags.get("on"));
+        let other = self::flags(&key);
+        assert!(other.snapshot().is_empty());
+        other.refresh().unwrap();
+        assert_eq!(5, other.snapshot().len());
+    }
+
+    #[test]
+    fn test_live_updates() {
+        let key = format!("redis_r2d2-flags-{}", crate::lock::token());
+        let writer = flags(&key);
+        let reader = flags(&key);
+        let updates = RedisPubSubConnectionManager::new("redis://localhost").unwrap();
+        let handle = reader
+            .start(Duration::from_secs(60), Some(updates))
+            .unwrap();
+
+        // Set the flag until the subscription is up.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reader.get("beta").is_none() && Instant::now() < deadline {
+            writer.set("beta", "on").unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(reader.bool_flag("beta", false));
+        writer.set("beta", "off").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reader.bool_flag("beta", true) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!reader.bool_flag("beta", true));
+        handle.stop();
+    }
+}
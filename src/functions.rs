@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use redis::{ConnectionLike, Value};
+
+/// Redis 7 function libraries loaded on every connection of a
+/// `RedisConnectionManager`.
+///
+/// Libraries are registered once, e.g. at startup, and the registry given to
+/// `RedisConnectionManagerBuilder::function_libraries` checks with
+/// `FUNCTION LIST` on each new connection that the server has them, with the
+/// registered code, and loads the missing or outdated ones with
+/// `FUNCTION LOAD REPLACE`. As connections are established again after a
+/// failover, this covers a promoted replica that lacks a library. Replicas
+/// refuse `FUNCTION LOAD` and get the libraries from their master, so they
+/// are only checked.
+///
+/// The functions are called with `RedisConnection::fcall`, which discards
+/// the connection if the server doesn't know the function, e.g. after
+/// `FUNCTION FLUSH`, so the next one verifies the libraries again.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, FunctionLibraries, RedisConnectionManager};
+///
+/// fn main() {
+///     let libraries = FunctionLibraries::new();
+///     libraries.register(
+///         r#"#!lua name=counters
+/// redis.register_function("incr_max", function(keys, args)
+///     return math.min(redis.call("incr", keys[1]), tonumber(args[1]))
+/// end)"#,
+///     );
+///
+///     let manager = RedisConnectionManager::builder()
+///         .function_libraries(Some(libraries))
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut conn = pool.get().unwrap();
+///     let n: i64 = conn.fcall("incr_max", &["counter"], 10).unwrap();
+///     println!("{}", n);
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FunctionLibraries {
+    libraries: Arc<RwLock<Vec<FunctionLibrary>>>,
+}
+
+#[derive(Debug, Clone)]
+struct FunctionLibrary {
+    name: String,
+    code: String,
+}
+
+impl FunctionLibraries {
+    /// Creates an empty `FunctionLibraries`.
+    pub fn new() -> FunctionLibraries {
+        FunctionLibraries::default()
+    }
+
+    /// Registers the library `code`, returning its name.
+    ///
+    /// Registering a library with the name of a registered one replaces its
+    /// code.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `code` doesn't start with a `#!lua name=<name>` line.
+    pub fn register(&self, code: &str) -> String {
+        let name = library_name(code).expect("a library starts with `#!lua name=<name>`");
+        let mut libraries = self.libraries.write().unwrap();
+        libraries.retain(|library| library.name != name);
+        libraries.push(FunctionLibrary {
+            name: name.clone(),
+            code: code.to_string(),
+        });
+        name
+    }
+
+    /// Returns the names of the registered libraries.
+    pub fn names(&self) -> Vec<String> {
+        let libraries = self.libraries.read().unwrap();
+        libraries
+            .iter()
+            .map(|library| library.name.clone())
+            .collect()
+    }
+
+    /// Loads the registered libraries the server behind `conn` lacks, or has
+    /// with different code, returning the names of those it loaded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the libraries couldn't be listed or loaded, e.g.
+    /// because the code is invalid, except if the server is a replica.
+    pub fn verify<C: ConnectionLike>(&self, conn: &mut C) -> redis::RedisResult<Vec<String>> {
+        let libraries = self.libraries.read().unwrap().clone();
+        if libraries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let loaded = loaded_libraries(conn)?;
+        let mut replaced = Vec::new();
+        for library in libraries {
+            if loaded.get(&library.name) == Some(&library.code) {
+                continue;
+            }
+            let result = redis::cmd("FUNCTION")
+                .arg("LOAD")
+                .arg("REPLACE")
+                .arg(&library.code)
+                .query::<String>(conn);
+            match result {
+                Ok(_) => replaced.push(library.name),
+                Err(ref e) if e.code() == Some("READONLY") => return Ok(replaced),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(replaced)
+    }
+}
+
+/// Returns the name declared by the `#!lua name=<name>` line of a library.
+fn library_name(code: &str) -> Option<String> {
+    let shebang = code.lines().next()?.strip_prefix("#!")?;
+    shebang
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix("name="))
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// Returns the code of the libraries loaded on the server, by name.
+fn loaded_libraries<C: ConnectionLike>(
+    conn: &mut C,
+) -> redis::RedisResult<HashMap<String, String>> {
+    let entries: Vec<HashMap<String, Value>> = redis::cmd("FUNCTION")
+        .arg("LIST")
+        .arg("WITHCODE")
+        .query(conn)?;
+    let mut libraries = HashMap::new();
+    for entry in entries {
+        if let (Some(name), Some(code)) = (entry.get("library_name"), entry.get("library_code")) {
+            libraries.insert(
+                redis::from_redis_value(name)?,
+                redis::from_redis_value(code)?,
+            );
+        }
+    }
+    Ok(libraries)
+}
+
+/// Returns true if `e` means the server doesn't know the called function.
+pub(crate) fn is_function_not_found(e: &redis::RedisError) -> bool {
+    e.kind() == redis::ErrorKind::ResponseError
+        && e.detail()
+            .is_some_and(|detail| detail.starts_with("Function not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConnectionManager;
+
+    const LIBRARY: &str = r#"#!lua name=redis_r2d2
+redis.register_function("redis_r2d2_incrby", function(keys, args)
+    return redis.call("incrby", keys[1], args[1])
+end)"#;
+
+    #[test]
+    fn test_library_name() {
+        assert_eq!(Some("redis_r2d2".to_string()), library_name(LIBRARY));
+        assert_eq!(
+            Some("lib".to_string()),
+            library_name("#!lua engine=x name=lib\nreturn")
+        );
+        assert_eq!(None, library_name("redis.register_function()"));
+        assert_eq!(None, library_name("#!lua name=\n"));
+    }
+
+    #[test]
+    fn test_fcall() {
+        let libraries = FunctionLibraries::new();
+        assert_eq!("redis_r2d2", libraries.register(LIBRARY));
+        let manager = RedisConnectionManager::builder()
+            .function_libraries(Some(libraries.clone()))
+            .build("redis://localhost")
+            .unwrap();
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+        assert!(libraries.verify(&mut *conn).unwrap().is_empty());
+
+        redis::cmd("DEL")
+            .arg("redis_r2d2-fcall")
+            .query::<()>(&mut *conn)
+            .unwrap();
+        let n: i64 = conn
+            .fcall("redis_r2d2_incrby", &["redis_r2d2-fcall"], 2)
+            .unwrap();
+        assert_eq!(2, n);
+
+        // The library is loaded again by the next connection.
+        redis::cmd("FUNCTION")
+            .arg("DELETE")
+            .arg("redis_r2d2")
+            .query::<()>(&mut *conn)
+            .unwrap();
+        assert!(conn
+            .fcall::<i64, _, _>("redis_r2d2_incrby", &["redis_r2d2-fcall"], 3)
+            .is_err());
+        assert!(conn.is_broken());
+        drop(conn);
+        let mut conn = pool.get().unwrap();
+        let n: i64 = conn
+            .fcall("redis_r2d2_incrby", &["redis_r2d2-fcall"], 3)
+            .unwrap();
+        assert_eq!(5, n);
+    }
+}
@@ -0,0 +1,423 @@
+use redis::{ConnectionLike, FromRedisValue, RedisWrite, ToRedisArgs, Value};
+
+/// A unit of distance of geo commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeoUnit {
+    /// Meters, `m`.
+    Meters,
+    /// Kilometers, `km`.
+    Kilometers,
+    /// Miles, `mi`.
+    Miles,
+    /// Feet, `ft`.
+    Feet,
+}
+
+impl ToRedisArgs for GeoUnit {
+    fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
+        out.write_arg(match *self {
+            GeoUnit::Meters => b"m",
+            GeoUnit::Kilometers => b"km",
+            GeoUnit::Miles => b"mi",
+            GeoUnit::Feet => b"ft",
+        })
+    }
+}
+
+/// A position on Earth, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    /// The longitude, from -180 to 180.
+    pub longitude: f64,
+    /// The latitude, from about -85.05 to 85.05.
+    pub latitude: f64,
+}
+
+impl GeoPoint {
+    /// The point at `longitude` and `latitude`; note the order, which is
+    /// Redis'.
+    pub fn new(longitude: f64, latitude: f64) -> GeoPoint {
+        GeoPoint {
+            longitude,
+            latitude,
+        }
+    }
+}
+
+impl ToRedisArgs for GeoPoint {
+    fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
+        self.longitude.write_redis_args(out);
+        self.latitude.write_redis_args(out);
+    }
+
+    fn is_single_arg(&self) -> bool {
+        false
+    }
+}
+
+impl FromRedisValue for GeoPoint {
+    fn from_redis_value(v: &Value) -> redis::RedisResult<GeoPoint> {
+        let (longitude, latitude) = FromRedisValue::from_redis_value(v)?;
+        Ok(GeoPoint::new(longitude, latitude))
+    }
+}
+
+/// Where a `GeoSearch` is centered.
+#[derive(Debug, Clone, PartialEq)]
+enum GeoOrigin {
+    Member(Vec<u8>),
+    Point(GeoPoint),
+}
+
+/// The area of a `GeoSearch` around its center.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoShape {
+    /// A circle of `radius` (`BYRADIUS`).
+    Radius {
+        /// The radius of the circle.
+        radius: f64,
+        /// The unit of the radius, and of the distances returned.
+        unit: GeoUnit,
+    },
+    /// An axis-aligned rectangle of `width` by `height` (`BYBOX`).
+    Box {
+        /// The east-west size of the rectangle.
+        width: f64,
+        /// The north-south size of the rectangle.
+        height: f64,
+        /// The unit of the sizes, and of the distances returned.
+        unit: GeoUnit,
+    },
+}
+
+impl GeoShape {
+    /// A circle of `radius` in `unit`.
+    pub fn radius(radius: f64, unit: GeoUnit) -> GeoShape {
+        GeoShape::Radius { radius, unit }
+    }
+
+    /// A rectangle of `width` by `height` in `unit`.
+    pub fn rectangle(width: f64, height: f64, unit: GeoUnit) -> GeoShape {
+        GeoShape::Box {
+            width,
+            height,
+            unit,
+        }
+    }
+}
+
+/// A `GEOSEARCH` query (Redis 6.2 and later), see
+/// `GeoCommands::geo_search`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoSearch {
+    origin: GeoOrigin,
+    shape: GeoShape,
+    ascending: Option<bool>,
+    count: Option<(usize, bool)>,
+    with_coord: bool,
+    with_dist: bool,
+}
+
+impl GeoSearch {
+    /// Searches `shape` around the position of `member` of the index.
+    pub fn from_member<M: AsRef<[u8]>>(member: M, shape: GeoShape) -> GeoSearch {
+        GeoSearch::new(GeoOrigin::Member(member.as_ref().to_vec()), shape)
+    }
+
+    /// Searches `shape` around `point`.
+    pub fn from_point(point: GeoPoint, shape: GeoShape) -> GeoSearch {
+        GeoSearch::new(GeoOrigin::Point(point), shape)
+    }
+
+    fn new(origin: GeoOrigin, shape: GeoShape) -> GeoSearch {
+        GeoSearch {
+            origin,
+            shape,
+            ascending: None,
+            count: None,
+            with_coord: false,
+            with_dist: false,
+        }
+    }
+
+    /// Returns the nearest members first (`ASC`), or the farthest ones.
+    ///
+    /// Defaults to the order of the index.
+    pub fn sort(mut self, nearest_first: bool) -> GeoSearch {
+        self.ascending = Some(nearest_first);
+        self
+    }
+
+    /// Returns at most `count` members (`COUNT`); with `sort`, the nearest
+    /// or farthest ones.
+    pub fn count(mut self, count: usize) -> GeoSearch {
+        self.count = Some((count, false));
+        self
+    }
+
+    /// Returns the first `count` members found (`COUNT ... ANY`), which is
+    /// faster on large areas, but not necessarily the nearest ones.
+    pub fn any(mut self, count: usize) -> GeoSearch {
+        self.count = Some((count, true));
+        self
+    }
+
+    /// Returns the position of each member, in `GeoMatch::point`
+    /// (`WITHCOORD`).
+    pub fn with_coord(mut self) -> GeoSearch {
+        self.with_coord = true;
+        self
+    }
+
+    /// Returns the distance of each member from the center, in the unit of
+    /// the shape, in `GeoMatch::distance` (`WITHDIST`).
+    pub fn with_dist(mut self) -> GeoSearch {
+        self.with_dist = true;
+        self
+    }
+
+    /// Appends the arguments after the key, except `WITHCOORD` and
+    /// `WITHDIST`, which `GEOSEARCHSTORE` doesn't take.
+    fn write_args(&self, cmd: &mut redis::Cmd) {
+        match self.origin {
+            GeoOrigin::Member(ref member) => cmd.arg("FROMMEMBER").arg(&member[..]),
+            GeoOrigin::Point(point) => cmd.arg("FROMLONLAT").arg(point),
+        };
+        match self.shape {
+            GeoShape::Radius { radius, unit } => cmd.arg("BYRADIUS").arg(radius).arg(unit),
+            GeoShape::Box {
+                width,
+                height,
+                unit,
+            } => cmd.arg("BYBOX").arg(width).arg(height).arg(unit),
+        };
+        match self.ascending {
+            Some(true) => cmd.arg("ASC"),
+            Some(false) => cmd.arg("DESC"),
+            None => cmd,
+        };
+        if let Some((count, any)) = self.count {
+            cmd.arg("COUNT").arg(count);
+            if any {
+                cmd.arg("ANY");
+            }
+        }
+    }
+}
+
+/// A member found by `GeoCommands::geo_search`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoMatch {
+    /// The member.
+    pub member: String,
+    /// The distance from the center, with `GeoSearch::with_dist`.
+    pub distance: Option<f64>,
+    /// The position, with `GeoSearch::with_coord`.
+    pub point: Option<GeoPoint>,
+}
+
+impl FromRedisValue for GeoMatch {
+    fn from_redis_value(v: &Value) -> redis::RedisResult<GeoMatch> {
+        let items = match *v {
+            Value::Bulk(ref items) => items,
+            _ => {
+                return Ok(GeoMatch {
+                    member: String::from_redis_value(v)?,
+                    distance: None,
+                    point: None,
+                })
+            }
+        };
+        let (member, rest) = items.split_first().ok_or_else(|| {
+            redis::RedisError::from((redis::ErrorKind::TypeError, "empty geo search match"))
+        })?;
+        let mut found = GeoMatch {
+            member: String::from_redis_value(member)?,
+            distance: None,
+            point: None,
+        };
+        // The distance is a string, the hash an integer and the position a
+        // pair, in that order.
+        for item in rest {
+            match *item {
+                Value::Data(_) | Value::Status(_) => {
+                    found.distance = Some(f64::from_redis_value(item)?)
+                }
+                Value::Bulk(_) => found.point = Some(GeoPoint::from_redis_value(item)?),
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// Typed geo commands, for any connection, e.g. a pooled
+/// `RedisConnection`.
+///
+/// A geo index is a sorted set whose members have a position, so the other
+/// sorted set commands, e.g. `ZREM`, work on it too.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, GeoCommands, GeoPoint, GeoSearch, GeoShape, GeoUnit, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut conn = pool.get().unwrap();
+///     conn.geo_add_points(
+///         "stores",
+///         &[
+///             ("louvre", GeoPoint::new(2.3376, 48.8606)),
+///             ("opera", GeoPoint::new(2.3316, 48.8720)),
+///         ],
+///     )
+///     .unwrap();
+///     let here = GeoPoint::new(2.3364, 48.8621);
+///     let search = GeoSearch::from_point(here, GeoShape::radius(2.0, GeoUnit::Kilometers))
+///         .sort(true)
+///         .count(10)
+///         .with_dist();
+///     for store in conn.geo_search("stores", &search).unwrap() {
+///         println!("{} at {:?} km", store.member, store.distance);
+///     }
+/// }
+/// ```
+pub trait GeoCommands: ConnectionLike + Sized {
+    /// Adds `members` at their positions to the geo index `key`, or moves
+    /// them (`GEOADD`), returning how many were new.
+    fn geo_add_points<K: ToRedisArgs, M: ToRedisArgs>(
+        &mut self,
+        key: K,
+        members: &[(M, GeoPoint)],
+    ) -> redis::RedisResult<u64> {
+        let mut cmd = redis::cmd("GEOADD");
+        cmd.arg(key);
+        for (member, point) in members {
+            cmd.arg(*point);
+            member.write_redis_args(&mut cmd);
+        }
+        cmd.query(self)
+    }
+
+    /// Returns the members of the geo index `key` in the area of `search`
+    /// (`GEOSEARCH`).
+    fn geo_search<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        search: &GeoSearch,
+    ) -> redis::RedisResult<Vec<GeoMatch>> {
+        let mut cmd = redis::cmd("GEOSEARCH");
+        cmd.arg(key);
+        search.write_args(&mut cmd);
+        if search.with_coord {
+            cmd.arg("WITHCOORD");
+        }
+        if search.with_dist {
+            cmd.arg("WITHDIST");
+        }
+        cmd.query(self)
+    }
+
+    /// Stores the members of the geo index `key` in the area of `search` in
+    /// `destination` (`GEOSEARCHSTORE`), replacing it, and returns how many
+    /// there are.
+    ///
+    /// With `store_dist`, `destination` is a plain sorted set scored by the
+    /// distance from the center (`STOREDIST`), instead of a geo index.
+    fn geo_search_store<D: ToRedisArgs, K: ToRedisArgs>(
+        &mut self,
+        destination: D,
+        key: K,
+        search: &GeoSearch,
+        store_dist: bool,
+    ) -> redis::RedisResult<u64> {
+        let mut cmd = redis::cmd("GEOSEARCHSTORE");
+        cmd.arg(destination).arg(key);
+        search.write_args(&mut cmd);
+        if store_dist {
+            cmd.arg("STOREDIST");
+        }
+        cmd.query(self)
+    }
+}
+
+impl<C: ConnectionLike> GeoCommands for C {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_match() {
+        let plain = Value::Data(b"a".to_vec());
+        assert_eq!("a", GeoMatch::from_redis_value(&plain).unwrap().member);
+        let full = Value::Bulk(vec![
+            Value::Data(b"a".to_vec()),
+            Value::Data(b"1.25".to_vec()),
+            Value::Int(3471579339700058),
+            Value::Bulk(vec![
+                Value::Data(b"2.5".to_vec()),
+                Value::Data(b"48.75".to_vec()),
+            ]),
+        ]);
+        assert_eq!(
+            GeoMatch {
+                member: "a".to_string(),
+                distance: Some(1.25),
+                point: Some(GeoPoint::new(2.5, 48.75)),
+            },
+            GeoMatch::from_redis_value(&full).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_geo_search() {
+        let pool = crate::local_pool(1);
+        let mut conn = pool.get().unwrap();
+        let key = format!("redis_r2d2-geo-{}", crate::lock::token());
+        // b is 1.1 km east of a and c 2.8 km, along the equator, and d 11 km
+        // north of a.
+        let points = [
+            ("a", GeoPoint::new(0.0, 0.0)),
+            ("b", GeoPoint::new(0.01, 0.0)),
+            ("c", GeoPoint::new(0.025, 0.0)),
+            ("d", GeoPoint::new(0.0, 0.1)),
+        ];
+        assert_eq!(4, conn.geo_add_points(&key, &points).unwrap());
+        assert_eq!(0, conn.geo_add_points(&key, &points[..1]).unwrap());
+
+        let search = GeoSearch::from_member("a", GeoShape::radius(1.5, GeoUnit::Kilometers))
+            .sort(true)
+            .with_dist()
+            .with_coord();
+        let found = conn.geo_search(&key, &search).unwrap();
+        let members: Vec<_> = found.iter().map(|found| found.member.as_str()).collect();
+        assert_eq!(vec!["a", "b"], members);
+        assert!((found[1].distance.unwrap() - 1.11).abs() < 0.01);
+        let point = found[1].point.unwrap();
+        assert!((point.longitude - 0.01).abs() < 1e-5 && point.latitude.abs() < 1e-5);
+
+        let here = GeoPoint::new(0.01, 0.0);
+        let search =
+            GeoSearch::from_point(here, GeoShape::rectangle(4.0, 30.0, GeoUnit::Kilometers))
+                .sort(false)
+                .count(2);
+        let farthest: Vec<_> = conn
+            .geo_search(&key, &search)
+            .unwrap()
+            .into_iter()
+            .map(|found| found.member)
+            .collect();
+        assert_eq!(vec!["d", "c"], farthest);
+
+        let destination = format!("{}:near", key);
+        let search = GeoSearch::from_point(here, GeoShape::radius(2.0, GeoUnit::Kilometers));
+        assert_eq!(
+            3,
+            conn.geo_search_store(&destination, &key, &search, false)
+                .unwrap()
+        );
+    }
+}
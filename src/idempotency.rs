@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use crate::lock::RELEASE_SCRIPT;
+use crate::pool_ext::checkout;
+use crate::RedisConnectionManager;
+
+/// The prefix of the value of a key being handled.
+const IN_PROGRESS: &[u8] = b"in-progress:";
+/// The prefix of the value of a key handled, followed by the result.
+const COMPLETED: &[u8] = b"completed:";
+
+/// Replaces the value of a key being handled with its result, if it is still
+/// being handled by the caller.
+const COMPLETE_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    redis.call("set", KEYS[1], ARGV[2], "PX", ARGV[3])
+    return 1
+else
+    return 0
+end
+"#;
+
+/// What `IdempotencyStore::begin` found for a key.
+#[derive(Debug)]
+pub enum IdempotencyState {
+    /// The key is new, and now claimed by the caller, who is to handle the
+    /// request and record its result with `IdempotencyStore::complete`.
+    Fresh(IdempotencyClaim),
+    /// Another caller is handling the request.
+    InProgress,
+    /// The request was handled, with this result.
+    Completed(Vec<u8>),
+}
+
+/// A key claimed by `IdempotencyStore::begin`.
+#[derive(Debug)]
+pub struct IdempotencyClaim {
+    key: String,
+    marker: Vec<u8>,
+}
+
+impl IdempotencyClaim {
+    /// Returns the Redis key of the claim.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// Deduplicates retried requests by their idempotency key, e.g. the
+/// `Idempotency-Key` header of an API.
+///
+/// `begin` claims a key with `SET NX PX`, so only one caller handles each
+/// request, for up to the TTL given, in case it crashes. The caller then
+/// records the result with `complete`, which callers that retry get back
+/// from `begin` instead of handling the request again, or gives the key up
+/// with `release` if the request failed and may be retried. Both check with
+/// a script that the key is still claimed by the caller. Results are bytes,
+/// e.g. an encoded response.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, IdempotencyState, IdempotencyStore, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let store = IdempotencyStore::new(pool);
+///     match store.begin("payment-8f2c", Duration::from_secs(30)).unwrap() {
+///         IdempotencyState::Fresh(claim) => {
+///             let response = b"201 Created".to_vec();
+///             store
+///                 .complete(claim, &response, Duration::from_secs(24 * 3600))
+///                 .unwrap();
+///         }
+///         IdempotencyState::InProgress => println!("409 Conflict"),
+///         IdempotencyState::Completed(response) => println!("{:?}", response),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct IdempotencyStore {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    prefix: String,
+}
+
+impl IdempotencyStore {
+    /// Creates an `IdempotencyStore` with connections of `pool`.
+    pub fn new(pool: r2d2::Pool<RedisConnectionManager>) -> IdempotencyStore {
+        IdempotencyStore {
+            pool,
+            prefix: "idempotency:".to_string(),
+        }
+    }
+
+    /// Sets the prefix of the Redis keys of the idempotency keys.
+    ///
+    /// Defaults to `idempotency:`.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> IdempotencyStore {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Claims `key` for up to `ttl` if it is new, or returns whether it is
+    /// being handled or its result.
+    pub fn begin(&self, key: &str, ttl: Duration) -> redis::RedisResult<IdempotencyState> {
+        let key = format!("{}{}", self.prefix, key);
+        let mut marker = IN_PROGRESS.to_vec();
+        marker.extend(crate::lock::token().into_bytes());
+        let ttl = ttl.as_millis().max(1) as u64;
+        let mut conn = checkout(&self.pool)?;
+        loop {
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .cmd("SET")
+                .arg(&key)
+                .arg(&marker[..])
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl)
+                .ignore()
+                .cmd("GET")
+                .arg(&key);
+            let (value,): (Option<Vec<u8>>,) = pipe.query(&mut *conn)?;
+            match value {
+                Some(ref value) if *value == marker => {
+                    return Ok(IdempotencyState::Fresh(IdempotencyClaim { key, marker }))
+                }
+                Some(value) if value.starts_with(COMPLETED) => {
+                    return Ok(IdempotencyState::Completed(
+                        value[COMPLETED.len()..].to_vec(),
+                    ))
+                }
+                Some(_) => return Ok(IdempotencyState::InProgress),
+                // The key expired right after being set.
+                None => continue,
+            }
+        }
+    }
+
+    /// Records `result` as the result of the request of `claim`, kept for
+    /// `ttl`, returning false if the claim expired in the meantime.
+    pub fn complete(
+        &self,
+        claim: IdempotencyClaim,
+        result: &[u8],
+        ttl: Duration,
+    ) -> redis::RedisResult<bool> {
+        let mut value = COMPLETED.to_vec();
+        value.extend_from_slice(result);
+        redis::Script::new(COMPLETE_SCRIPT)
+            .key(&claim.key)
+            .arg(&claim.marker[..])
+            .arg(value)
+            .arg(ttl.as_millis().max(1) as u64)
+            .invoke(&mut *checkout(&self.pool)?)
+    }
+
+    /// Gives up `claim`, so the request can be handled again, returning false
+    /// if the claim expired in the meantime.
+    pub fn release(&self, claim: IdempotencyClaim) -> redis::RedisResult<bool> {
+        redis::Script::new(RELEASE_SCRIPT)
+            .key(&claim.key)
+            .arg(&claim.marker[..])
+            .invoke(&mut *checkout(&self.pool)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn store() -> IdempotencyStore {
+        let pool = crate::local_pool(1);
+        IdempotencyStore::new(pool)
+            .prefix(format!("redis_r2d2-idempotency-{}:", crate::lock::token()))
+    }
+
+    #[test]
+    fn test_idempotency_store() {
+        let store = store();
+        let ttl = Duration::from_secs(10);
+        let claim = match store.begin("a", ttl).unwrap() {
+            IdempotencyState::Fresh(claim) => claim,
+            state => panic!("unexpected {:?}", state),
+        };
+        assert!(matches!(
+            store.begin("a", ttl).unwrap(),
+            IdempotencyState::InProgress
+        ));
+        assert!(store.complete(claim, b"done", ttl).unwrap());
+        match store.begin("a", ttl).unwrap() {
+            IdempotencyState::Completed(result) => assert_eq!(b"done".to_vec(), result),
+            state => panic!("unexpected {:?}", state),
+        }
+
+        let claim = match store.begin("b", ttl).unwrap() {
+            IdempotencyState::Fresh(claim) => claim,
+            state => panic!("unexpected {:?}", state),
+        };
+        assert!(store.release(claim).unwrap());
+        assert!(matches!(
+            store.begin("b", ttl).unwrap(),
+            IdempotencyState::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn test_expired_claim() {
+        let store = store();
+        let claim = match store.begin("a", Duration::from_millis(20)).unwrap() {
+            IdempotencyState::Fresh(claim) => claim,
+            state => panic!("unexpected {:?}", state),
+        };
+        thread::sleep(Duration::from_millis(50));
+        let other = match store.begin("a", Duration::from_secs(10)).unwrap() {
+            IdempotencyState::Fresh(claim) => claim,
+            state => panic!("unexpected {:?}", state),
+        };
+        assert!(!store
+            .complete(claim, b"late", Duration::from_secs(10))
+            .unwrap());
+        assert!(store
+            .complete(other, b"done", Duration::from_secs(10))
+            .unwrap());
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::pool_ext::checkout;
+use crate::subscriber::POLL_INTERVAL;
+use crate::{
+    ClientSideCache, RedisConnectionManager, RedisPubSubConnectionManager, ResilientSubscriber,
+    SubscriberEvent,
+};
+
+/// What an `InvalidationBus` message invalidates.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Invalidation {
+    /// The value cached for the key.
+    Key(Vec<u8>),
+    /// Every cached value, e.g. after a bulk update, or because messages
+    /// may have been missed while the subscriber was disconnected.
+    All,
+}
+
+/// Announces cache invalidations to the other instances of a service, over a
+/// pub/sub channel.
+///
+/// Each instance publishes the keys it changed with `invalidate`, and
+/// listens to the channel with a `subscriber`, which passes every
+/// `Invalidation` to callbacks or drops the value from local caches, so
+/// values cached in memory don't outlive their update elsewhere. Messages
+/// are published on connections of the pool and carry the random ID of the
+/// bus that sent them, so an instance can skip its own with
+/// `InvalidationSubscriber::skip_own`.
+///
+/// Pub/sub delivers messages at most once: after the subscriber reconnects,
+/// the listeners get `Invalidation::All`, as messages may have been lost in
+/// the meantime.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, InvalidationBus, RedisConnectionManager, RedisPubSubConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let bus = InvalidationBus::new(pool, "invalidations");
+///     let _listener = bus
+///         .subscriber(RedisPubSubConnectionManager::new("redis://localhost").unwrap())
+///         .on_invalidate(|invalidation| println!("{:?}", invalidation))
+///         .start();
+///
+///     bus.invalidate("user:42").unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct InvalidationBus {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    channel: String,
+    origin: Arc<str>,
+}
+
+impl InvalidationBus {
+    /// Creates an `InvalidationBus` publishing on `channel` with connections
+    /// of `pool`.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        channel: S,
+    ) -> InvalidationBus {
+        InvalidationBus {
+            pool,
+            channel: channel.into(),
+            origin: crate::lock::token().into(),
+        }
+    }
+
+    /// Returns the channel of the bus.
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Announces that the value of `key` changed.
+    pub fn invalidate<K: AsRef<[u8]>>(&self, key: K) -> redis::RedisResult<()> {
+        self.invalidate_many(std::iter::once(key))
+    }
+
+    /// Announces that the values of `keys` changed, in a single round trip.
+    pub fn invalidate_many<I, K>(&self, keys: I) -> redis::RedisResult<()>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let messages = keys
+            .into_iter()
+            .map(|key| self.message(&Invalidation::Key(key.as_ref().to_vec())));
+        self.publish(messages)
+    }
+
+    /// Announces that every cached value may have changed.
+    pub fn invalidate_all(&self) -> redis::RedisResult<()> {
+        self.publish(std::iter::once(self.message(&Invalidation::All)))
+    }
+
+    /// Returns a builder for a listener of the bus, subscribing with
+    /// `manager`.
+    pub fn subscriber(&self, manager: RedisPubSubConnectionManager) -> InvalidationSubscriber {
+        InvalidationSubscriber {
+            manager,
+            channel: self.channel.clone(),
+            origin: self.origin.clone(),
+            skip_own: false,
+            handlers: Vec::new(),
+        }
+    }
+
+    fn message(&self, invalidation: &Invalidation) -> Vec<u8> {
+        let mut message = format!("{} ", self.origin).into_bytes();
+        match *invalidation {
+            Invalidation::Key(ref key) => {
+                message.push(b'k');
+                message.extend_from_slice(key);
+            }
+            Invalidation::All => message.push(b'*'),
+        }
+        message
+    }
+
+    fn publish<I: Iterator<Item = Vec<u8>>>(&self, messages: I) -> redis::RedisResult<()> {
+        let mut pipe = redis::pipe();
+        for message in messages {
+            pipe.cmd("PUBLISH").arg(&self.channel).arg(message).ignore();
+        }
+        let mut conn = checkout(&self.pool)?;
+        pipe.query(&mut *conn)
+    }
+}
+
+/// Parses a message of an `InvalidationBus` into the ID of the bus that sent
+/// it and the invalidation.
+fn parse_message(message: &[u8]) -> Option<(&[u8], Invalidation)> {
+    let space = message.iter().position(|&byte| byte == b' ')?;
+    let (origin, rest) = (&message[..space], &message[space + 1..]);
+    let invalidation = match rest.split_first()? {
+        (b'k', key) => Invalidation::Key(key.to_vec()),
+        (b'*', []) => Invalidation::All,
+        _ => return None,
+    };
+    Some((origin, invalidation))
+}
+
+type Handler = Box<dyn FnMut(&Invalidation) + Send>;
+
+/// A builder for a listener of an `InvalidationBus`, see
+/// `InvalidationBus::subscriber`.
+pub struct InvalidationSubscriber {
+    manager: RedisPubSubConnectionManager,
+    channel: String,
+    origin: Arc<str>,
+    skip_own: bool,
+    handlers: Vec<Handler>,
+}
+
+impl InvalidationSubscriber {
+    /// Calls `handler` with every invalidation.
+    pub fn on_invalidate<F>(mut self, handler: F) -> InvalidationSubscriber
+    where
+        F: FnMut(&Invalidation) + Send + 'static,
+    {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Drops the invalidated keys from `cache`, and clears it on
+    /// `Invalidation::All`.
+    pub fn local_cache(self, cache: ClientSideCache) -> InvalidationSubscriber {
+        self.on_invalidate(move |invalidation| match *invalidation {
+            Invalidation::Key(ref key) => cache.invalidate(key),
+            Invalidation::All => cache.clear(),
+        })
+    }
+
+    /// Skips the invalidations published by the bus this subscriber was
+    /// created from, e.g. because the instance already updated its local
+    /// caches.
+    ///
+    /// Defaults to false.
+    pub fn skip_own(mut self, skip_own: bool) -> InvalidationSubscriber {
+        self.skip_own = skip_own;
+        self
+    }
+
+    /// Subscribes to the channel of the bus, and starts dispatching the
+    /// invalidations on a background thread until the returned
+    /// `InvalidationListener` is dropped.
+    pub fn start(self) -> InvalidationListener {
+        let subscriber = ResilientSubscriber::new(self.manager.subscribe(self.channel));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let origin = self.origin;
+        let skip_own = self.skip_own;
+        let mut handlers = self.handlers;
+        {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let invalidation = match subscriber.recv_timeout(POLL_INTERVAL) {
+                        Some(SubscriberEvent::Message(msg)) => {
+                            match parse_message(msg.get_payload_bytes()) {
+                                Some((sender, _)) if skip_own && sender == origin.as_bytes() => {
+                                    continue
+                                }
+                                Some((_, invalidation)) => invalidation,
+                                None => continue,
+                            }
+                        }
+                        Some(SubscriberEvent::Resubscribed { .. }) => Invalidation::All,
+                        _ => continue,
+                    };
+                    for handler in &mut handlers {
+                        handler(&invalidation);
+                    }
+                }
+            });
+        }
+        InvalidationListener { stopped }
+    }
+}
+
+impl fmt::Debug for InvalidationSubscriber {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("InvalidationSubscriber")
+            .field("manager", &self.manager)
+            .field("channel", &self.channel)
+            .field("origin", &self.origin)
+            .field("skip_own", &self.skip_own)
+            .field("handlers", &self.handlers.len())
+            .finish()
+    }
+}
+
+/// Dispatches the invalidations of an `InvalidationBus` until it is dropped,
+/// see `InvalidationBus::subscriber`.
+#[derive(Debug)]
+pub struct InvalidationListener {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for InvalidationListener {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(
+            Some((&b"abc"[..], Invalidation::Key(b"user:1".to_vec()))),
+            parse_message(b"abc kuser:1")
+        );
+        assert_eq!(
+            Some((&b"abc"[..], Invalidation::All)),
+            parse_message(b"abc *")
+        );
+        assert_eq!(None, parse_message(b"abc"));
+        assert_eq!(None, parse_message(b"abc x"));
+    }
+
+    #[test]
+    fn test_invalidation_bus() {
+        let pool = crate::local_pool(1);
+        let channel = format!("redis_r2d2-invalidations-{}", crate::lock::token());
+        let bus = InvalidationBus::new(pool.clone(), channel.clone());
+        let other = InvalidationBus::new(pool, channel);
+
+        let (invalidated, received) = mpsc::channel();
+        let _listener = bus
+            .subscriber(RedisPubSubConnectionManager::new("redis://localhost").unwrap())
+            .skip_own(true)
+            .on_invalidate(move |invalidation| {
+                let _ = invalidated.send(invalidation.clone());
+            })
+            .start();
+
+        // Publish until the subscription is up.
+        let first = (0..100)
+            .find_map(|_| {
+                other.invalidate("warm-up").unwrap();
+                received.recv_timeout(Duration::from_millis(50)).ok()
+            })
+            .unwrap();
+        assert_eq!(Invalidation::Key(b"warm-up".to_vec()), first);
+
+        // The bus's own messages are skipped.
+        bus.invalidate("own").unwrap();
+        other.invalidate_many(["a", "b"]).unwrap();
+        other.invalidate_all().unwrap();
+        let rest: Vec<_> =
+            std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(5)).ok())
+                .filter(|invalidation| *invalidation != first)
+                .take(3)
+                .collect();
+        assert_eq!(
+            vec![
+                Invalidation::Key(b"a".to_vec()),
+                Invalidation::Key(b"b".to_vec()),
+                Invalidation::All,
+            ],
+            rest
+        );
+    }
+}
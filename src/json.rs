@@ -0,0 +1,230 @@
+use redis::{ConnectionLike, ToRedisArgs};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Typed RedisJSON commands, for any connection, e.g. a pooled
+/// `RedisConnection`.
+///
+/// Values are serialized to JSON with `serde_json`. Paths are either
+/// JSONPath expressions, starting with `$`, or legacy paths such as `.name`;
+/// `$` and `.` are the root. To fail early when the server lacks the
+/// module, pass `ReJSON` to `RedisConnectionManagerBuilder::required_modules`.
+///
+/// Requires the `json` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, JsonCommands, RedisConnectionManager};
+///
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct User {
+///     name: String,
+///     visits: u64,
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::builder()
+///         .required_modules(["ReJSON"])
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut conn = pool.get().unwrap();
+///     let user = User { name: "Ada".to_string(), visits: 1 };
+///     conn.json_set("user:42", "$", &user).unwrap();
+///     conn.json_merge("user:42", "$", &serde_json::json!({ "visits": 2 })).unwrap();
+///     let name: Option<String> = conn.json_get("user:42", "$.name").unwrap();
+///     println!("{:?}", name);
+/// }
+/// ```
+pub trait JsonCommands: ConnectionLike + Sized {
+    /// Sets the value at `path` of `key` to `value` (`JSON.SET`).
+    fn json_set<K: ToRedisArgs, T: Serialize>(
+        &mut self,
+        key: K,
+        path: &str,
+        value: &T,
+    ) -> redis::RedisResult<()> {
+        redis::cmd("JSON.SET")
+            .arg(key)
+            .arg(path)
+            .arg(encode(value)?)
+            .query(self)
+    }
+
+    /// Sets the value at `path` of `key` to `value` unless there is one
+    /// already (`JSON.SET ... NX`), returning false if there was.
+    fn json_set_nx<K: ToRedisArgs, T: Serialize>(
+        &mut self,
+        key: K,
+        path: &str,
+        value: &T,
+    ) -> redis::RedisResult<bool> {
+        let reply: Option<String> = redis::cmd("JSON.SET")
+            .arg(key)
+            .arg(path)
+            .arg(encode(value)?)
+            .arg("NX")
+            .query(self)?;
+        Ok(reply.is_some())
+    }
+
+    /// Returns the value at `path` of `key` (`JSON.GET`), or `None` if there
+    /// is no `key`.
+    ///
+    /// For a JSONPath matching several values, returns the first one, and
+    /// `None` if it matches none; see `json_query`. A legacy path that
+    /// doesn't exist is an error.
+    fn json_get<K: ToRedisArgs, T: DeserializeOwned>(
+        &mut self,
+        key: K,
+        path: &str,
+    ) -> redis::RedisResult<Option<T>> {
+        let reply: Option<String> = redis::cmd("JSON.GET").arg(key).arg(path).query(self)?;
+        match reply {
+            Some(ref json) if path.starts_with('$') => {
+                Ok(decode::<Vec<T>>(json)?.into_iter().next())
+            }
+            Some(ref json) => decode(json).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns all the values matched by the JSONPath `path` in `key`, none
+    /// if there is no `key`.
+    fn json_query<K: ToRedisArgs, T: DeserializeOwned>(
+        &mut self,
+        key: K,
+        path: &str,
+    ) -> redis::RedisResult<Vec<T>> {
+        let reply: Option<String> = redis::cmd("JSON.GET").arg(key).arg(path).query(self)?;
+        match reply {
+            Some(ref json) => decode(json),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Merges `value` into the value at `path` of `key`, as a JSON merge
+    /// patch (`JSON.MERGE`, RedisJSON 2.6 and later): fields set to `null`
+    /// are removed, and the others are merged recursively.
+    fn json_merge<K: ToRedisArgs, T: Serialize>(
+        &mut self,
+        key: K,
+        path: &str,
+        value: &T,
+    ) -> redis::RedisResult<()> {
+        redis::cmd("JSON.MERGE")
+            .arg(key)
+            .arg(path)
+            .arg(encode(value)?)
+            .query(self)
+    }
+
+    /// Deletes the values at `path` of `key` (`JSON.DEL`), returning how
+    /// many were deleted.
+    fn json_del<K: ToRedisArgs>(&mut self, key: K, path: &str) -> redis::RedisResult<u64> {
+        redis::cmd("JSON.DEL").arg(key).arg(path).query(self)
+    }
+}
+
+impl<C: ConnectionLike> JsonCommands for C {}
+
+fn encode<T: Serialize>(value: &T) -> redis::RedisResult<String> {
+    serde_json::to_string(value).map_err(|e| {
+        (
+            redis::ErrorKind::TypeError,
+            "couldn't encode JSON",
+            e.to_string(),
+        )
+            .into()
+    })
+}
+
+fn decode<T: DeserializeOwned>(json: &str) -> redis::RedisResult<T> {
+    serde_json::from_str(json).map_err(|e| {
+        (
+            redis::ErrorKind::TypeError,
+            "couldn't decode JSON",
+            e.to_string(),
+        )
+            .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::fake_server;
+    use crate::RedisConnectionManager;
+    use std::net::TcpListener;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct User {
+        name: String,
+        visits: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        email: Option<String>,
+    }
+
+    #[test]
+    fn test_json_commands() {
+        let manager = RedisConnectionManager::builder()
+            .required_modules(["ReJSON"])
+            .build("redis://localhost")
+            .unwrap();
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+        let key = format!("redis_r2d2-json-{}", crate::lock::token());
+        assert_eq!(None, conn.json_get::<_, User>(&key, "$").unwrap());
+
+        let user = User {
+            name: "Ada".to_string(),
+            visits: 1,
+            email: Some("ada@example.com".to_string()),
+        };
+        conn.json_set(&key, "$", &user).unwrap();
+        assert!(!conn.json_set_nx(&key, "$", &user).unwrap());
+        assert_eq!(Some(user), conn.json_get(&key, "$").unwrap());
+
+        conn.json_merge(
+            &key,
+            "$",
+            &serde_json::json!({ "visits": 2, "email": null }),
+        )
+        .unwrap();
+        let user: Option<User> = conn.json_get(&key, ".").unwrap();
+        assert_eq!(2, user.unwrap().visits);
+        assert_eq!(
+            vec![2],
+            conn.json_query::<_, u64>(&key, "$.visits").unwrap()
+        );
+        assert_eq!(None, conn.json_get::<_, String>(&key, "$.email").unwrap());
+
+        assert_eq!(1, conn.json_del(&key, "$.name").unwrap());
+        assert!(conn
+            .json_query::<_, String>(&key, "$.name")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_missing_module() {
+        let addr = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |request| {
+            if request.contains("MODULE") {
+                "*1\r\n*4\r\n$4\r\nname\r\n$6\r\nReJSON\r\n$3\r\nver\r\n:20008\r\n".to_string()
+            } else {
+                "+OK\r\n".to_string()
+            }
+        });
+        let manager = RedisConnectionManager::builder()
+            .required_modules(["ReJSON", "search"])
+            .build(format!("redis://{}", addr))
+            .unwrap();
+        let err = match r2d2::ManageConnection::connect(&manager) {
+            Ok(_) => panic!("connected without the search module"),
+            Err(err) => err,
+        };
+        assert_eq!(redis::ErrorKind::ClientError, err.kind());
+        assert_eq!(Some("search"), err.detail());
+    }
+}
@@ -0,0 +1,404 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use r2d2::ManageConnection;
+
+use crate::subscriber::POLL_INTERVAL;
+use crate::{RedisPubSubConnectionManager, ResilientSubscriber, SubscriberEvent};
+
+/// The kind of a keyspace notification, i.e. the command or server event
+/// that touched the key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyEventKind {
+    /// `SET` and its variants.
+    Set,
+    /// `DEL` and `UNLINK`.
+    Del,
+    /// A timeout was set on the key.
+    Expire,
+    /// The key expired.
+    Expired,
+    /// The key was evicted under `maxmemory`.
+    Evicted,
+    /// The key was renamed to another one.
+    RenameFrom,
+    /// Another key was renamed to the key.
+    RenameTo,
+    /// The key was created (Redis 7 and later, flag `n`).
+    New,
+    /// Any other event, by its name, e.g. `hset` or `lpush`.
+    Other(String),
+}
+
+impl KeyEventKind {
+    /// Returns the kind of the event named `name`.
+    pub fn from_name(name: &str) -> KeyEventKind {
+        match name {
+            "set" => KeyEventKind::Set,
+            "del" => KeyEventKind::Del,
+            "expire" => KeyEventKind::Expire,
+            "expired" => KeyEventKind::Expired,
+            "evicted" => KeyEventKind::Evicted,
+            "rename_from" => KeyEventKind::RenameFrom,
+            "rename_to" => KeyEventKind::RenameTo,
+            "new" => KeyEventKind::New,
+            _ => KeyEventKind::Other(name.to_string()),
+        }
+    }
+
+    /// Returns the name Redis gives the event.
+    pub fn name(&self) -> &str {
+        match *self {
+            KeyEventKind::Set => "set",
+            KeyEventKind::Del => "del",
+            KeyEventKind::Expire => "expire",
+            KeyEventKind::Expired => "expired",
+            KeyEventKind::Evicted => "evicted",
+            KeyEventKind::RenameFrom => "rename_from",
+            KeyEventKind::RenameTo => "rename_to",
+            KeyEventKind::New => "new",
+            KeyEventKind::Other(ref name) => name,
+        }
+    }
+}
+
+/// A keyspace notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyspaceEvent {
+    /// The database of the key.
+    pub db: i64,
+    /// The key.
+    pub key: Vec<u8>,
+    /// What happened to the key.
+    pub kind: KeyEventKind,
+}
+
+impl KeyspaceEvent {
+    /// Parses a message on a `__keyevent@<db>__:<event>` channel, whose
+    /// payload is the key, or on a `__keyspace@<db>__:<key>` channel, whose
+    /// payload is the event.
+    fn from_msg(msg: &redis::Msg) -> Option<KeyspaceEvent> {
+        let channel: Vec<u8> = msg.get_channel().ok()?;
+        let payload = msg.get_payload_bytes();
+        let (db, key, event) = if let Some(channel) = channel.strip_prefix(b"__keyevent@") {
+            let (db, event) = split_db(channel)?;
+            (db, payload, event)
+        } else {
+            let (db, key) = split_db(channel.strip_prefix(b"__keyspace@")?)?;
+            (db, key, payload)
+        };
+        Some(KeyspaceEvent {
+            db,
+            key: key.to_vec(),
+            kind: KeyEventKind::from_name(std::str::from_utf8(event).ok()?),
+        })
+    }
+}
+
+/// Splits the `<db>__:<rest>` following the prefix of a notification
+/// channel.
+fn split_db(channel: &[u8]) -> Option<(i64, &[u8])> {
+    let end = channel.windows(3).position(|sep| sep == b"__:")?;
+    let db = std::str::from_utf8(&channel[..end]).ok()?.parse().ok()?;
+    Some((db, &channel[end + 3..]))
+}
+
+type Handler = Box<dyn FnMut(&KeyspaceEvent) + Send>;
+type GapHandler = Box<dyn FnMut(Duration) + Send>;
+
+/// Dispatches keyspace notifications to callbacks.
+///
+/// The notifications are received from the `__keyevent@<db>__:*` and
+/// `__keyspace@<db>__:*` channels by a `ResilientSubscriber`, parsed into
+/// `KeyspaceEvent`s and passed to the callbacks registered for their kind
+/// with `on`, and to those registered with `on_any`, on a background thread.
+/// As notifications published while the subscriber is disconnected are lost,
+/// the callbacks registered with `on_gap` are called with the length of the
+/// gap after it reconnects; caches relying on invalidation events may have
+/// to be cleared then.
+///
+/// Redis only publishes the notifications enabled with the
+/// `notify-keyspace-events` setting, which `configure` can set when the
+/// listener starts. Either key-event notifications (flag `E`) or keyspace
+/// notifications (flag `K`) are needed; with both, the callbacks are called
+/// twice for each change, once for each class.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{KeyEventKind, KeyspaceNotifications, RedisPubSubConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisPubSubConnectionManager::new("redis://localhost").unwrap();
+///     let listener = KeyspaceNotifications::new(manager)
+///         .db(0)
+///         .configure("Ex")
+///         .on(KeyEventKind::Expired, |event| {
+///             println!("{} expired", String::from_utf8_lossy(&event.key));
+///         })
+///         .start()
+///         .unwrap();
+/// }
+/// ```
+pub struct KeyspaceNotifications {
+    manager: RedisPubSubConnectionManager,
+    db: Option<i64>,
+    configure: Option<String>,
+    handlers: Vec<(Option<KeyEventKind>, Handler)>,
+    gap_handlers: Vec<GapHandler>,
+}
+
+impl KeyspaceNotifications {
+    /// Creates a `KeyspaceNotifications` for the server of `manager`.
+    pub fn new(manager: RedisPubSubConnectionManager) -> KeyspaceNotifications {
+        KeyspaceNotifications {
+            manager,
+            db: None,
+            configure: None,
+            handlers: Vec::new(),
+            gap_handlers: Vec::new(),
+        }
+    }
+
+    /// Only listens to the notifications of database `db`.
+    ///
+    /// Defaults to all databases.
+    pub fn db(mut self, db: i64) -> KeyspaceNotifications {
+        self.db = Some(db);
+        self
+    }
+
+    /// Sets `notify-keyspace-events` to `flags` with `CONFIG SET` when the
+    /// listener starts, e.g. `Ex` for expirations or `KEA` for everything.
+    ///
+    /// The setting is server-wide, and isn't restored if the server
+    /// restarts without it.
+    pub fn configure<S: Into<String>>(mut self, flags: S) -> KeyspaceNotifications {
+        self.configure = Some(flags.into());
+        self
+    }
+
+    /// Calls `handler` with the events of kind `kind`.
+    pub fn on<F>(mut self, kind: KeyEventKind, handler: F) -> KeyspaceNotifications
+    where
+        F: FnMut(&KeyspaceEvent) + Send + 'static,
+    {
+        self.handlers.push((Some(kind), Box::new(handler)));
+        self
+    }
+
+    /// Calls `handler` with every event.
+    pub fn on_any<F>(mut self, handler: F) -> KeyspaceNotifications
+    where
+        F: FnMut(&KeyspaceEvent) + Send + 'static,
+    {
+        self.handlers.push((None, Box::new(handler)));
+        self
+    }
+
+    /// Calls `handler` with the time during which notifications may have
+    /// been missed, after the subscriber reconnected.
+    pub fn on_gap<F>(mut self, handler: F) -> KeyspaceNotifications
+    where
+        F: FnMut(Duration) + Send + 'static,
+    {
+        self.gap_handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Configures the server if requested, and starts dispatching
+    /// notifications until the returned `KeyspaceListener` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `notify-keyspace-events` couldn't be set.
+    pub fn start(self) -> redis::RedisResult<KeyspaceListener> {
+        if let Some(ref flags) = self.configure {
+            let mut conn = self.manager.manager().connect()?;
+            redis::cmd("CONFIG")
+                .arg("SET")
+                .arg("notify-keyspace-events")
+                .arg(flags)
+                .query::<()>(&mut conn)?;
+        }
+        let db = self.db.map_or("*".to_string(), |db| db.to_string());
+        let patterns = vec![
+            format!("__keyevent@{}__:*", db),
+            format!("__keyspace@{}__:*", db),
+        ];
+        let subscriber = ResilientSubscriber::new(self.manager.psubscribe(patterns));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut dispatcher = Dispatcher {
+            handlers: self.handlers,
+            gap_handlers: self.gap_handlers,
+        };
+        {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    if let Some(event) = subscriber.recv_timeout(POLL_INTERVAL) {
+                        dispatcher.dispatch(event);
+                    }
+                }
+            });
+        }
+        Ok(KeyspaceListener { stopped })
+    }
+}
+
+impl fmt::Debug for KeyspaceNotifications {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("KeyspaceNotifications")
+            .field("manager", &self.manager)
+            .field("db", &self.db)
+            .field("configure", &self.configure)
+            .field("handlers", &self.handlers.len())
+            .field("gap_handlers", &self.gap_handlers.len())
+            .finish()
+    }
+}
+
+struct Dispatcher {
+    handlers: Vec<(Option<KeyEventKind>, Handler)>,
+    gap_handlers: Vec<GapHandler>,
+}
+
+impl Dispatcher {
+    fn dispatch(&mut self, event: SubscriberEvent) {
+        match event {
+            SubscriberEvent::Message(msg) => {
+                let event = match KeyspaceEvent::from_msg(&msg) {
+                    Some(event) => event,
+                    None => return,
+                };
+                for (kind, handler) in &mut self.handlers {
+                    if kind.as_ref().is_none_or(|kind| *kind == event.kind) {
+                        handler(&event);
+                    }
+                }
+            }
+            SubscriberEvent::Resubscribed { gap } => {
+                for handler in &mut self.gap_handlers {
+                    handler(gap);
+                }
+            }
+            SubscriberEvent::Disconnected(_) => {}
+        }
+    }
+}
+
+/// Dispatches keyspace notifications until it is dropped, see
+/// `KeyspaceNotifications`.
+#[derive(Debug)]
+pub struct KeyspaceListener {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for KeyspaceListener {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_from_msg() {
+        let msg = redis::Msg::from_value(&redis::Value::Bulk(vec![
+            redis::Value::Data(b"pmessage".to_vec()),
+            redis::Value::Data(b"__keyevent@*__:*".to_vec()),
+            redis::Value::Data(b"__keyevent@3__:expired".to_vec()),
+            redis::Value::Data(b"session:1".to_vec()),
+        ]))
+        .unwrap();
+        assert_eq!(
+            Some(KeyspaceEvent {
+                db: 3,
+                key: b"session:1".to_vec(),
+                kind: KeyEventKind::Expired,
+            }),
+            KeyspaceEvent::from_msg(&msg)
+        );
+        let msg = redis::Msg::from_value(&redis::Value::Bulk(vec![
+            redis::Value::Data(b"pmessage".to_vec()),
+            redis::Value::Data(b"__keyspace@*__:*".to_vec()),
+            redis::Value::Data(b"__keyspace@0__:user:__:1".to_vec()),
+            redis::Value::Data(b"del".to_vec()),
+        ]))
+        .unwrap();
+        assert_eq!(
+            Some(KeyspaceEvent {
+                db: 0,
+                key: b"user:__:1".to_vec(),
+                kind: KeyEventKind::Del,
+            }),
+            KeyspaceEvent::from_msg(&msg)
+        );
+        assert_eq!(
+            KeyEventKind::Other("hset".to_string()),
+            KeyEventKind::from_name("hset")
+        );
+        assert_eq!("rename_to", KeyEventKind::RenameTo.name());
+    }
+
+    #[test]
+    fn test_keyspace_notifications() {
+        let (expired, received) = mpsc::channel();
+        let (any, received_any) = mpsc::channel();
+        let manager = RedisPubSubConnectionManager::new("redis://localhost").unwrap();
+        let _listener = KeyspaceNotifications::new(manager)
+            .db(5)
+            .configure("Ex")
+            .on(KeyEventKind::Expired, move |event| {
+                let _ = expired.send(event.clone());
+            })
+            .on_any(move |event| {
+                let _ = any.send(event.kind.clone());
+            })
+            .start()
+            .unwrap();
+
+        // The local server doesn't publish notifications itself.
+        let mut conn = redis::Client::open("redis://localhost")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let publish_message = |conn: &mut redis::Connection, channel: &str, message: &str| {
+            redis::cmd("PUBLISH")
+                .arg(channel)
+                .arg(message)
+                .query::<i64>(conn)
+                .unwrap();
+        };
+        let publish = |conn: &mut redis::Connection, channel: &str| {
+            publish_message(conn, channel, "key");
+        };
+        let event = (0..100)
+            .find_map(|_| {
+                publish(&mut conn, "__keyevent@6__:expired");
+                publish(&mut conn, "__keyevent@5__:expired");
+                received.recv_timeout(Duration::from_millis(50)).ok()
+            })
+            .unwrap();
+        assert_eq!(5, event.db);
+        assert_eq!(b"key".to_vec(), event.key);
+
+        publish(&mut conn, "__keyevent@5__:set");
+        let kind = std::iter::from_fn(|| received_any.recv_timeout(Duration::from_secs(5)).ok())
+            .find(|kind| *kind != KeyEventKind::Expired);
+        assert_eq!(Some(KeyEventKind::Set), kind);
+
+        // A keyspace (`K`) notification.
+        publish_message(&mut conn, "__keyspace@5__:key", "del");
+        let kind = std::iter::from_fn(|| received_any.recv_timeout(Duration::from_secs(5)).ok())
+            .find(|kind| *kind != KeyEventKind::Expired);
+        assert_eq!(Some(KeyEventKind::Del), kind);
+        assert!(received.try_iter().all(|event| event.db == 5));
+    }
+}
@@ -0,0 +1,415 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{SrvRecord, SrvResolver};
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// A ready pod behind a Kubernetes service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KubernetesPod {
+    /// The name of the pod, or its address if the endpoint doesn't refer to
+    /// a pod.
+    pub name: String,
+    /// The IP address of the pod.
+    pub ip: String,
+    /// The port of the pod the service port maps to.
+    pub port: u16,
+}
+
+/// Discovers the ready pods behind a Kubernetes service from its
+/// EndpointSlices.
+///
+/// As a `SrvResolver`, it makes a manager built with
+/// `RedisConnectionManagerBuilder::build_srv` connect to the pods of the
+/// service it is given, in order of pod name, so a StatefulSet's `redis-0`
+/// comes first and `redis-10` after `redis-9`. The pods are listed again
+/// every `srv_refresh_interval` and whenever none of them accepts a
+/// connection. For a `ShardedPool`, pass the pod names from `pods` to
+/// `ShardedPool::set_shards` periodically.
+///
+/// The service account needs permission to `list` `endpointslices` in the
+/// namespace. Requires the `kubernetes` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, KubernetesEndpoints, RedisConnectionManager};
+///
+/// fn main() {
+///     let endpoints = KubernetesEndpoints::in_cluster().unwrap().port_name("redis");
+///     let manager = RedisConnectionManager::builder()
+///         .srv_resolver(Box::new(endpoints))
+///         .srv_refresh_interval(Duration::from_secs(10))
+///         .build_srv("redis-headless")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+/// }
+/// ```
+pub struct KubernetesEndpoints {
+    host: String,
+    port: u16,
+    tls: Option<native_tls::TlsConnector>,
+    token_path: Option<PathBuf>,
+    namespace: String,
+    port_name: Option<String>,
+    timeout: Duration,
+}
+
+impl KubernetesEndpoints {
+    /// Creates a `KubernetesEndpoints` for the namespace of the pod it runs
+    /// in, authenticating with the pod's service account.
+    ///
+    /// The token is read again for every request, since Kubernetes rotates
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if not running in a pod.
+    pub fn in_cluster() -> io::Result<KubernetesEndpoints> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "KUBERNETES_SERVICE_HOST is not set",
+            )
+        })?;
+        let port = match std::env::var("KUBERNETES_SERVICE_PORT") {
+            Ok(port) => port
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid port"))?,
+            Err(_) => 443,
+        };
+        let dir = PathBuf::from(SERVICE_ACCOUNT_DIR);
+        let namespace = std::fs::read_to_string(dir.join("namespace"))?;
+        let ca = native_tls::Certificate::from_pem(&std::fs::read(dir.join("ca.crt"))?)
+            .map_err(tls_error)?;
+        let tls = native_tls::TlsConnector::builder()
+            .add_root_certificate(ca)
+            .build()
+            .map_err(tls_error)?;
+        Ok(KubernetesEndpoints {
+            host,
+            port,
+            tls: Some(tls),
+            token_path: Some(dir.join("token")),
+            namespace: namespace.trim().to_owned(),
+            port_name: None,
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// Creates a `KubernetesEndpoints` for `namespace` sending plain HTTP
+    /// requests to `addr`, e.g. `kubectl proxy` on `127.0.0.1:8001`, which
+    /// authenticates them. The port defaults to 80, and IPv6 addresses are
+    /// given in brackets, e.g. `[::1]:8001`.
+    pub fn proxy(addr: &str, namespace: &str) -> io::Result<KubernetesEndpoints> {
+        let (host, port) = parse_addr(addr)?;
+        Ok(KubernetesEndpoints {
+            host,
+            port,
+            tls: None,
+            token_path: None,
+            namespace: namespace.to_owned(),
+            port_name: None,
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// Sets the name of the service port to connect to.
+    ///
+    /// Defaults to `None` (the service must have a single port).
+    pub fn port_name<N: Into<String>>(mut self, port_name: N) -> KubernetesEndpoints {
+        self.port_name = Some(port_name.into());
+        self
+    }
+
+    /// Sets how long to wait for the API server.
+    ///
+    /// Defaults to 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> KubernetesEndpoints {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the ready pods of `service`, ordered by name and ordinal.
+    pub fn pods(&self, service: &str) -> io::Result<Vec<KubernetesPod>> {
+        let path = format!(
+            "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}",
+            self.namespace, service
+        );
+        let body = self.get(&path)?;
+        let list: serde_json::Value = serde_json::from_slice(&body)?;
+        let mut pods = parse_pods(&list, self.port_name.as_deref())?;
+        pods.sort_by(|a, b| by_ordinal(&a.name).cmp(&by_ordinal(&b.name)));
+        pods.dedup();
+        Ok(pods)
+    }
+
+    fn get(&self, path: &str) -> io::Result<Vec<u8>> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        // HTTP/1.0, so the response is neither chunked nor kept alive.
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let mut request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n",
+            path, host
+        );
+        if let Some(ref token_path) = self.token_path {
+            let token = std::fs::read_to_string(token_path)?;
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token.trim()));
+        }
+        request.push_str("\r\n");
+
+        let response = match self.tls {
+            Some(ref tls) => {
+                let stream = tls.connect(&self.host, stream).map_err(tls_error)?;
+                exchange(stream, &request)?
+            }
+            None => exchange(stream, &request)?,
+        };
+        parse_response(&response)
+    }
+}
+
+impl std::fmt::Debug for KubernetesEndpoints {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("KubernetesEndpoints")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls.is_some())
+            .field("namespace", &self.namespace)
+            .field("port_name", &self.port_name)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl SrvResolver for KubernetesEndpoints {
+    fn resolve_srv(&self, service: &str) -> io::Result<Vec<SrvRecord>> {
+        Ok(self
+            .pods(service)?
+            .into_iter()
+            .map(|pod| SrvRecord {
+                priority: 0,
+                weight: 0,
+                port: pod.port,
+                target: pod.ip,
+            })
+            .collect())
+    }
+}
+
+/// Splits `addr`, a host and an optional port, defaulting to 80.
+fn parse_addr(addr: &str) -> io::Result<(String, u16)> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), addr.port()));
+    }
+    let ip = addr.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Ok((ip.to_string(), 80));
+    }
+    let (host, port) = match addr.rfind(':') {
+        Some(i) => (&addr[..i], &addr[i + 1..]),
+        None => (addr, "80"),
+    };
+    let port = port
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port"))?;
+    Ok((host.to_owned(), port))
+}
+
+/// Returns a key ordering pod names by their StatefulSet ordinal, e.g.
+/// `redis-2` before `redis-10`, and by name otherwise.
+fn by_ordinal(name: &str) -> (&str, Option<u64>, &str) {
+    match name.rfind('-') {
+        Some(i) => match name[i + 1..].parse() {
+            Ok(ordinal) => (&name[..i], Some(ordinal), name),
+            Err(_) => (name, None, name),
+        },
+        None => (name, None, name),
+    }
+}
+
+fn exchange<S: Read + Write>(mut stream: S, request: &str) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+/// Returns the body of a `200 OK` response.
+fn parse_response(response: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| invalid())?;
+    let body = &response[end + 4..];
+    let status = head.split(' ').nth(1).ok_or_else(invalid)?;
+    if status == "200" {
+        return Ok(body.to_vec());
+    }
+    // Errors come as a `Status` object with a message.
+    let message = serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|status| status["message"].as_str().map(str::to_owned))
+        .unwrap_or_default();
+    Err(io::Error::other(format!(
+        "Kubernetes API returned {}: {}",
+        status, message
+    )))
+}
+
+/// Returns the ready pods of an `EndpointSliceList`.
+fn parse_pods(list: &serde_json::Value, port_name: Option<&str>) -> io::Result<Vec<KubernetesPod>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid EndpointSliceList");
+    let slices = list["items"].as_array().ok_or_else(invalid)?;
+    let mut pods = Vec::new();
+    for slice in slices {
+        let ports = slice["ports"].as_array().map_or(&[][..], Vec::as_slice);
+        let port = match port_name {
+            Some(name) => ports.iter().find(|port| port["name"] == name),
+            None if ports.len() == 1 => ports.first(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the service has several ports, set a port_name",
+                ))
+            }
+        };
+        let port = match port.and_then(|port| port["port"].as_u64()) {
+            Some(port) => port as u16,
+            None => continue,
+        };
+        let endpoints = slice["endpoints"].as_array().map_or(&[][..], Vec::as_slice);
+        for endpoint in endpoints {
+            // A missing condition means ready.
+            if endpoint["conditions"]["ready"] == false {
+                continue;
+            }
+            let name = endpoint["targetRef"]["name"].as_str();
+            for ip in endpoint["addresses"].as_array().ok_or_else(invalid)? {
+                let ip = ip.as_str().ok_or_else(invalid)?;
+                pods.push(KubernetesPod {
+                    name: name.unwrap_or(ip).to_owned(),
+                    ip: ip.to_owned(),
+                    port,
+                });
+            }
+        }
+    }
+    Ok(pods)
+}
+
+fn tls_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const SLICES: &str = r#"{
+        "kind": "EndpointSliceList",
+        "items": [{
+            "ports": [{"name": "metrics", "port": 9121}, {"name": "redis", "port": 6379}],
+            "endpoints": [
+                {"addresses": ["10.0.0.2"], "conditions": {"ready": true},
+                 "targetRef": {"kind": "Pod", "name": "redis-1"}},
+                {"addresses": ["10.0.0.3"], "conditions": {"ready": false},
+                 "targetRef": {"kind": "Pod", "name": "redis-2"}},
+                {"addresses": ["10.0.0.1"], "targetRef": {"kind": "Pod", "name": "redis-0"}},
+                {"addresses": ["10.0.0.10"], "targetRef": {"kind": "Pod", "name": "redis-10"}}
+            ]
+        }]
+    }"#;
+
+    /// Answers one request with `response`, returning the request line.
+    fn fake_api_server(response: String) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            let request = String::from_utf8(request).unwrap();
+            request.lines().next().unwrap().to_string()
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_kubernetes_endpoints() {
+        let (addr, request) = fake_api_server(format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
+            SLICES
+        ));
+        let endpoints = KubernetesEndpoints::proxy(&addr, "cache")
+            .unwrap()
+            .port_name("redis");
+        let records = endpoints.resolve_srv("redis").unwrap();
+        assert_eq!(
+            vec![("10.0.0.1", 6379), ("10.0.0.2", 6379), ("10.0.0.10", 6379)],
+            records
+                .iter()
+                .map(|record| (record.target.as_str(), record.port))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "GET /apis/discovery.k8s.io/v1/namespaces/cache/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3Dredis HTTP/1.0",
+            request.join().unwrap()
+        );
+
+        let list = serde_json::from_str(SLICES).unwrap();
+        assert_eq!("redis-0", parse_pods(&list, Some("redis")).unwrap()[1].name);
+        assert!(parse_pods(&list, None).is_err());
+        assert!(parse_pods(&list, Some("other")).unwrap().is_empty());
+
+        let (addr, _) = fake_api_server(
+            "HTTP/1.0 403 Forbidden\r\n\r\n{\"kind\":\"Status\",\"message\":\"forbidden\"}"
+                .to_string(),
+        );
+        let err = KubernetesEndpoints::proxy(&addr, "cache")
+            .unwrap()
+            .pods("redis")
+            .unwrap_err();
+        assert_eq!("Kubernetes API returned 403: forbidden", err.to_string());
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(("::1".to_string(), 8001), parse_addr("[::1]:8001").unwrap());
+        assert_eq!(("::1".to_string(), 80), parse_addr("[::1]").unwrap());
+        assert_eq!(
+            ("127.0.0.1".to_string(), 8001),
+            parse_addr("127.0.0.1:8001").unwrap()
+        );
+        assert_eq!(
+            ("localhost".to_string(), 80),
+            parse_addr("localhost").unwrap()
+        );
+        assert!(parse_addr("localhost:x").is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::lock::{token, CLOCK_DRIFT_FACTOR, EXTEND_SCRIPT, RELEASE_SCRIPT};
+use crate::pool_ext::checkout;
+use crate::{ReconnectPolicy, RedisConnectionManager};
+
+type Callback = Box<dyn FnMut() + Send>;
+
+/// Elects one leader among the processes running a role, e.g. the one
+/// running scheduled jobs.
+///
+/// A background thread takes the key `leader:<role>` with `SET NX PX` for
+/// the `lease`, and renews it every `renew_interval` with a script checking
+/// it still holds it. An elector only considers itself the leader until the
+/// lease it last took or renewed ends, counted from before the request was
+/// sent and less an allowance for clock drift, so it steps down before
+/// Redis could elect another leader even if it can't reach Redis.
+///
+/// `on_elected` and `on_demoted` are called on the background thread when
+/// leadership is gained and lost; `is_leader` turns false when the lease
+/// ends even if the thread is busy, e.g. waiting for a connection. Stopping
+/// the elector gives leadership up.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, LeaderElector, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let elector = LeaderElector::new(pool, "scheduler")
+///         .lease(Duration::from_secs(10))
+///         .on_elected(|| println!("elected"))
+///         .on_demoted(|| println!("demoted"))
+///         .start();
+///     if elector.is_leader() {
+///         // ...
+///     }
+///     elector.stop();
+/// }
+/// ```
+pub struct LeaderElector {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    key: String,
+    identity: String,
+    lease: Duration,
+    renew_interval: Option<Duration>,
+    on_elected: Option<Callback>,
+    on_demoted: Option<Callback>,
+}
+
+impl LeaderElector {
+    /// Creates a `LeaderElector` for `role`, with connections of `pool`.
+    pub fn new<S: AsRef<str>>(pool: r2d2::Pool<RedisConnectionManager>, role: S) -> LeaderElector {
+        LeaderElector {
+            pool,
+            key: format!("leader:{}", role.as_ref()),
+            identity: token(),
+            lease: Duration::from_secs(10),
+            renew_interval: None,
+            on_elected: None,
+            on_demoted: None,
+        }
+    }
+
+    /// Sets the value of the key while the elector leads, e.g. a host name
+    /// for other processes to find the leader. It must be unique.
+    ///
+    /// Defaults to a random token.
+    pub fn identity<S: Into<String>>(mut self, identity: S) -> LeaderElector {
+        self.identity = identity.into();
+        self
+    }
+
+    /// Sets how long leadership lasts without being renewed, and so how long
+    /// the role may go without a leader when the leader dies.
+    ///
+    /// Defaults to 10 seconds.
+    pub fn lease(mut self, lease: Duration) -> LeaderElector {
+        self.lease = lease.max(Duration::from_millis(10));
+        self
+    }
+
+    /// Sets how often leadership is renewed, or tried for by the other
+    /// electors. Retries after errors back off, up to the lease.
+    ///
+    /// Defaults to a third of the lease.
+    pub fn renew_interval(mut self, renew_interval: Duration) -> LeaderElector {
+        self.renew_interval = Some(renew_interval);
+        self
+    }
+
+    /// Sets a function called when the elector becomes the leader.
+    pub fn on_elected<F: FnMut() + Send + 'static>(mut self, on_elected: F) -> LeaderElector {
+        self.on_elected = Some(Box::new(on_elected));
+        self
+    }
+
+    /// Sets a function called when the elector stops being the leader.
+    pub fn on_demoted<F: FnMut() + Send + 'static>(mut self, on_demoted: F) -> LeaderElector {
+        self.on_demoted = Some(Box::new(on_demoted));
+        self
+    }
+
+    /// Starts running for leadership on a background thread, until the
+    /// returned `LeaderElectorHandle` is stopped or dropped.
+    pub fn start(self) -> LeaderElectorHandle {
+        let leader_until = Arc::new(Mutex::new(None));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let leader_until = leader_until.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || self.run(&leader_until, &stopped))
+        };
+        LeaderElectorHandle {
+            leader_until,
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    fn run(mut self, leader_until: &Mutex<Option<Instant>>, stopped: &AtomicBool) {
+        let renew_interval = self.renew_interval.unwrap_or(self.lease / 3);
+        let backoff = ReconnectPolicy {
+            initial_delay: renew_interval,
+            multiplier: 2.0,
+            max_delay: self.lease.max(renew_interval),
+            jitter: 0.2,
+        };
+        let mut leading = false;
+        let mut failures = 0;
+        while !stopped.load(Ordering::Relaxed) {
+            let start = Instant::now();
+            match self.try_lead(leading) {
+                Ok(true) => {
+                    failures = 0;
+                    *leader_until.lock().unwrap() = Some(start + self.validity());
+                    if !leading {
+                        leading = true;
+                        run_callback(&mut self.on_elected);
+                    }
+                }
+                Ok(false) => {
+                    failures = 0;
+                    *leader_until.lock().unwrap() = None;
+                    if leading {
+                        log::warn!("lost the leadership of {}", self.key);
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    log::warn!("leader election of {} failed: {}", self.key, e);
+                }
+            }
+            let until = *leader_until.lock().unwrap();
+            if leading && until.is_none_or(|until| until <= Instant::now()) {
+                leading = false;
+                *leader_until.lock().unwrap() = None;
+                run_callback(&mut self.on_demoted);
+            }
+            let mut wait = if failures > 0 {
+                backoff.delay(failures)
+            } else {
+                renew_interval.saturating_sub(start.elapsed())
+            };
+            if let (true, Some(until)) = (leading, until) {
+                // Wake up in time to notice the end of the lease.
+                wait = wait.min(until.saturating_duration_since(Instant::now()));
+            }
+            thread::park_timeout(wait);
+        }
+        *leader_until.lock().unwrap() = None;
+        if leading {
+            if let Err(e) = self.resign() {
+                log::warn!("couldn't give up the leadership of {}: {}", self.key, e);
+            }
+            run_callback(&mut self.on_demoted);
+        }
+    }
+
+    /// Renews the lease if `leading`, or tries to take it, returning whether
+    /// the elector holds it.
+    fn try_lead(&self, leading: bool) -> redis::RedisResult<bool> {
+        let mut conn = checkout(&self.pool)?;
+        let lease = self.lease.as_millis() as u64;
+        if leading {
+            let renewed: i64 = redis::Script::new(EXTEND_SCRIPT)
+                .key(&self.key)
+                .arg(&self.identity)
+                .arg(lease)
+                .invoke(&mut *conn)?;
+            Ok(renewed > 0)
+        } else {
+            let taken: Option<()> = redis::cmd("SET")
+                .arg(&self.key)
+                .arg(&self.identity)
+                .arg("NX")
+                .arg("PX")
+                .arg(lease)
+                .query(&mut *conn)?;
+            Ok(taken.is_some())
+        }
+    }
+
+    fn resign(&self) -> redis::RedisResult<()> {
+        redis::Script::new(RELEASE_SCRIPT)
+            .key(&self.key)
+            .arg(&self.identity)
+            .invoke(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns how long a lease taken or renewed is held for, at least.
+    fn validity(&self) -> Duration {
+        let drift = self.lease.mul_f64(CLOCK_DRIFT_FACTOR) + Duration::from_millis(2);
+        self.lease.saturating_sub(drift)
+    }
+}
+
+impl fmt::Debug for LeaderElector {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("LeaderElector")
+            .field("key", &self.key)
+            .field("identity", &self.identity)
+            .field("lease", &self.lease)
+            .field("renew_interval", &self.renew_interval)
+            .finish()
+    }
+}
+
+fn run_callback(callback: &mut Option<Callback>) {
+    if let Some(callback) = callback {
+        callback();
+    }
+}
+
+/// Runs a `LeaderElector` until it is stopped or dropped.
+#[derive(Debug)]
+pub struct LeaderElectorHandle {
+    leader_until: Arc<Mutex<Option<Instant>>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LeaderElectorHandle {
+    /// Returns whether the elector holds the leadership.
+    pub fn is_leader(&self) -> bool {
+        let leader_until = *self.leader_until.lock().unwrap();
+        leader_until.is_some_and(|until| until > Instant::now())
+    }
+
+    /// Stops the elector, waiting for it to give up the leadership if it
+    /// holds it.
+    ///
+    /// Dropping the handle stops the elector without waiting.
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LeaderElectorHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = &self.thread {
+            thread.thread().unpark();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::fake_server;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    fn elector(role: &str, events: &mpsc::Sender<&'static str>) -> LeaderElector {
+        let pool = crate::local_pool(1);
+        let elected = events.clone();
+        let demoted = events.clone();
+        LeaderElector::new(pool, role)
+            .lease(Duration::from_millis(300))
+            .renew_interval(Duration::from_millis(30))
+            .on_elected(move || elected.send("elected").unwrap())
+            .on_demoted(move || demoted.send("demoted").unwrap())
+    }
+
+    #[test]
+    fn test_leader_elector() {
+        let role = format!("redis_r2d2-role-{}", token());
+        let timeout = Duration::from_secs(5);
+        let (first_events, first_received) = mpsc::channel();
+        let (second_events, second_received) = mpsc::channel();
+        let first = elector(&role, &first_events).start();
+        assert_eq!("elected", first_received.recv_timeout(timeout).unwrap());
+        assert!(first.is_leader());
+
+        let second = elector(&role, &second_events).start();
+        thread::sleep(Duration::from_millis(400));
+        // The lease was renewed, and the second elector wasn't elected.
+        assert!(first.is_leader());
+        assert!(!second.is_leader());
+        assert!(second_received.try_recv().is_err());
+
+        first.stop();
+        assert_eq!("demoted", first_received.recv_timeout(timeout).unwrap());
+        assert_eq!("elected", second_received.recv_timeout(timeout).unwrap());
+        assert!(second.is_leader());
+        second.stop();
+        assert_eq!("demoted", second_received.recv_timeout(timeout).unwrap());
+    }
+
+    #[test]
+    fn test_backoff_while_failing() {
+        // A server which elects the elector, then fails every request.
+        let failed = Arc::new(AtomicUsize::new(0));
+        let addr = {
+            let elected = AtomicBool::new(false);
+            let failed = failed.clone();
+            fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), move |request| {
+                if request.contains("PING") {
+                    "+PONG\r\n".to_string()
+                } else if request.contains("SET") && !elected.swap(true, Ordering::SeqCst) {
+                    "+OK\r\n".to_string()
+                } else {
+                    failed.fetch_add(1, Ordering::SeqCst);
+                    "-ERR unavailable\r\n".to_string()
+                }
+            })
+        };
+        let manager = RedisConnectionManager::new(format!("redis://{}", addr)).unwrap();
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let (events, received) = mpsc::channel();
+        let elector = elector("failing", &events);
+        let elector = LeaderElector { pool, ..elector }.start();
+        let timeout = Duration::from_secs(5);
+        assert_eq!("elected", received.recv_timeout(timeout).unwrap());
+        assert_eq!("demoted", received.recv_timeout(timeout).unwrap());
+        assert!(!elector.is_leader());
+
+        // Retries back off rather than spinning once the lease ended.
+        let before = failed.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(600));
+        let retries = failed.load(Ordering::SeqCst) - before;
+        assert!(retries <= 5, "{} retries", retries);
+        drop(elector);
+    }
+}
@@ -0,0 +1,359 @@
+use std::time::{Duration, SystemTime};
+
+use redis::ToRedisArgs;
+
+use crate::pool_ext::checkout;
+use crate::{buckets, RedisConnectionManager};
+
+/// A member of a `Leaderboard` with its score and rank.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaderboardEntry {
+    /// The member, e.g. a player ID.
+    pub member: String,
+    /// The score of the member.
+    pub score: f64,
+    /// The rank of the member, 1 for the best score.
+    pub rank: u64,
+}
+
+/// Ranks members by score in a sorted set, e.g. for the high scores of a
+/// game.
+///
+/// Ranks start at 1 for the best score, the highest one unless the board is
+/// `lowest_first`; members with the same score are ranked by member, in
+/// reverse lexicographic order for highest-first boards and lexicographic
+/// order otherwise.
+///
+/// A board can be split into time buckets with `per`, e.g. a daily board:
+/// scores then go to the bucket of the current time, stored under
+/// `<key>:<bucket>` and expiring a `retention` after the bucket ends, and
+/// `at` reads or writes the bucket of another time, e.g. yesterday's.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::{Duration, SystemTime};
+///
+/// use redis_r2d2::{r2d2, Leaderboard, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let day = Duration::from_secs(24 * 3600);
+///     let daily = Leaderboard::new(pool, "scores:daily").per(day);
+///     daily.submit("alice", 1200.0).unwrap();
+///     daily.submit("bob", 900.0).unwrap();
+///     for entry in daily.top(10).unwrap() {
+///         println!("{}. {} {}", entry.rank, entry.member, entry.score);
+///     }
+///     let yesterday = daily.at(SystemTime::now() - day);
+///     println!("{:?}", yesterday.around("bob", 2).unwrap());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Leaderboard {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    key: String,
+    lowest_first: bool,
+    period: Option<Duration>,
+    retention: Option<Duration>,
+    pinned: Option<SystemTime>,
+}
+
+impl Leaderboard {
+    /// Creates a `Leaderboard` stored under `key`, with connections of
+    /// `pool`.
+    pub fn new<S: Into<String>>(pool: r2d2::Pool<RedisConnectionManager>, key: S) -> Leaderboard {
+        Leaderboard {
+            pool,
+            key: key.into(),
+            lowest_first: false,
+            period: None,
+            retention: None,
+            pinned: None,
+        }
+    }
+
+    /// Ranks the lowest scores first, e.g. for lap times.
+    pub fn lowest_first(mut self) -> Leaderboard {
+        self.lowest_first = true;
+        self
+    }
+
+    /// Splits the board into buckets of `period`, starting at multiples of
+    /// `period` since the Unix epoch.
+    pub fn per(mut self, period: Duration) -> Leaderboard {
+        self.period = Some(period);
+        self
+    }
+
+    /// Sets how long buckets are kept after they end.
+    ///
+    /// Defaults to one period.
+    pub fn retention(mut self, retention: Duration) -> Leaderboard {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Returns the board of the bucket containing `time`, instead of the
+    /// current one. Without `per`, returns the same board.
+    pub fn at(&self, time: SystemTime) -> Leaderboard {
+        Leaderboard {
+            pinned: Some(time),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the key of the sorted set, for the current bucket if the
+    /// board has buckets.
+    pub fn key(&self) -> String {
+        match self.bucket() {
+            Some((bucket, _)) => format!("{}:{}", self.key, bucket),
+            None => self.key.clone(),
+        }
+    }
+
+    /// Records `score` for `member` if it is better than its current one, or
+    /// if it has none (`ZADD GT`, or `LT`, Redis 6.2 and later), returning
+    /// whether it was recorded.
+    pub fn submit<M: ToRedisArgs>(&self, member: M, score: f64) -> redis::RedisResult<bool> {
+        let mut cmd = redis::cmd("ZADD");
+        cmd.arg(self.key())
+            .arg(if self.lowest_first { "LT" } else { "GT" })
+            .arg("CH")
+            .arg(score)
+            .arg(member);
+        let (changed,): (u32,) = self.write(cmd)?;
+        Ok(changed > 0)
+    }
+
+    /// Sets the score of `member` to `score`, even if it is worse than its
+    /// current one.
+    pub fn set_score<M: ToRedisArgs>(&self, member: M, score: f64) -> redis::RedisResult<()> {
+        let mut cmd = redis::cmd("ZADD");
+        cmd.arg(self.key()).arg(score).arg(member);
+        self.write::<(u32,)>(cmd).map(drop)
+    }
+
+    /// Adds `by` to the score of `member`, which starts at 0, returning the
+    /// new score.
+    pub fn increment<M: ToRedisArgs>(&self, member: M, by: f64) -> redis::RedisResult<f64> {
+        let mut cmd = redis::cmd("ZINCRBY");
+        cmd.arg(self.key()).arg(by).arg(member);
+        let (score,) = self.write(cmd)?;
+        Ok(score)
+    }
+
+    /// Removes `member` from the board, returning false if it wasn't on it.
+    pub fn remove<M: ToRedisArgs>(&self, member: M) -> redis::RedisResult<bool> {
+        let removed: u32 = redis::cmd("ZREM")
+            .arg(self.key())
+            .arg(member)
+            .query(&mut *checkout(&self.pool)?)?;
+        Ok(removed > 0)
+    }
+
+    /// Returns the score of `member`, or `None` if it isn't on the board.
+    pub fn score<M: ToRedisArgs>(&self, member: M) -> redis::RedisResult<Option<f64>> {
+        redis::cmd("ZSCORE")
+            .arg(self.key())
+            .arg(member)
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns the rank of `member`, or `None` if it isn't on the board.
+    pub fn rank<M: ToRedisArgs>(&self, member: M) -> redis::RedisResult<Option<u64>> {
+        self.rank_on(&mut *checkout(&self.pool)?, member)
+    }
+
+    /// Returns the number of members on the board.
+    pub fn len(&self) -> redis::RedisResult<u64> {
+        redis::cmd("ZCARD")
+            .arg(self.key())
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns whether nobody is on the board.
+    pub fn is_empty(&self) -> redis::RedisResult<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Returns the `n` best members, best first.
+    pub fn top(&self, n: u64) -> redis::RedisResult<Vec<LeaderboardEntry>> {
+        self.page(0, n)
+    }
+
+    /// Returns `count` members, best first, skipping the `offset` best ones,
+    /// e.g. `page(20, 10)` for ranks 21 to 30.
+    pub fn page(&self, offset: u64, count: u64) -> redis::RedisResult<Vec<LeaderboardEntry>> {
+        self.entries(&mut *checkout(&self.pool)?, offset, count)
+    }
+
+    /// Returns `member` with up to `radius` members ranked just above and
+    /// below it, or nothing if it isn't on the board.
+    ///
+    /// The rank is read before the window, so the window may be off by the
+    /// members that moved in the meantime.
+    pub fn around<M: ToRedisArgs>(
+        &self,
+        member: M,
+        radius: u64,
+    ) -> redis::RedisResult<Vec<LeaderboardEntry>> {
+        let mut conn = checkout(&self.pool)?;
+        match self.rank_on(&mut *conn, member)? {
+            Some(rank) => {
+                let offset = (rank - 1).saturating_sub(radius);
+                let count = rank - offset + radius;
+                self.entries(&mut *conn, offset, count)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn rank_on<M: ToRedisArgs>(
+        &self,
+        conn: &mut dyn redis::ConnectionLike,
+        member: M,
+    ) -> redis::RedisResult<Option<u64>> {
+        let rank: Option<u64> = redis::cmd(if self.lowest_first {
+            "ZRANK"
+        } else {
+            "ZREVRANK"
+        })
+        .arg(self.key())
+        .arg(member)
+        .query(conn)?;
+        Ok(rank.map(|rank| rank + 1))
+    }
+
+    fn entries(
+        &self,
+        conn: &mut dyn redis::ConnectionLike,
+        offset: u64,
+        count: u64,
+    ) -> redis::RedisResult<Vec<LeaderboardEntry>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let members: Vec<(String, f64)> = redis::cmd(if self.lowest_first {
+            "ZRANGE"
+        } else {
+            "ZREVRANGE"
+        })
+        .arg(self.key())
+        .arg(offset)
+        .arg(offset + count - 1)
+        .arg("WITHSCORES")
+        .query(conn)?;
+        Ok(members
+            .into_iter()
+            .zip(offset + 1..)
+            .map(|((member, score), rank)| LeaderboardEntry {
+                member,
+                score,
+                rank,
+            })
+            .collect())
+    }
+
+    /// Runs `cmd` on the current bucket, and refreshes its expiry in the
+    /// same transaction.
+    fn write<T: redis::FromRedisValue>(&self, cmd: redis::Cmd) -> redis::RedisResult<T> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().add_command(cmd);
+        if let Some((bucket, period)) = self.bucket() {
+            let ttl = buckets::ttl(bucket, period, self.retention.unwrap_or(period));
+            pipe.cmd("PEXPIRE")
+                .arg(self.key())
+                .arg(ttl.as_millis() as u64)
+                .ignore();
+        }
+        pipe.query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns the index of the bucket of the board, and the period.
+    fn bucket(&self) -> Option<(u64, Duration)> {
+        let period = self.period?;
+        let time = self.pinned.unwrap_or_else(SystemTime::now);
+        Some((buckets::index(time, period), period))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board() -> Leaderboard {
+        let pool = crate::local_pool(1);
+        let key = format!("redis_r2d2-leaderboard-{}", crate::lock::token());
+        Leaderboard::new(pool, key)
+    }
+
+    fn members(entries: &[LeaderboardEntry]) -> Vec<(&str, u64)> {
+        entries
+            .iter()
+            .map(|entry| (entry.member.as_str(), entry.rank))
+            .collect()
+    }
+
+    #[test]
+    fn test_leaderboard() {
+        let board = board();
+        for (i, member) in ["a", "b", "c", "d", "e", "f"].iter().enumerate() {
+            assert!(board.submit(*member, 10.0 * i as f64).unwrap());
+        }
+        assert!(!board.submit("f", 1.0).unwrap());
+        assert!(board.submit("a", 25.0).unwrap());
+        assert_eq!(35.0, board.increment("a", 10.0).unwrap());
+        assert_eq!(Some(35.0), board.score("a").unwrap());
+        assert_eq!(None, board.rank("z").unwrap());
+        assert_eq!(6, board.len().unwrap());
+        assert!(!board.is_empty().unwrap());
+
+        // f 50, e 40, a 35, d 30, c 20, b 10
+        assert_eq!(Some(3), board.rank("a").unwrap());
+        assert_eq!(vec![("f", 1), ("e", 2)], members(&board.top(2).unwrap()));
+        assert_eq!(
+            vec![("c", 5), ("b", 6)],
+            members(&board.page(4, 10).unwrap())
+        );
+        assert_eq!(
+            vec![("e", 2), ("a", 3), ("d", 4)],
+            members(&board.around("a", 1).unwrap())
+        );
+        assert_eq!(
+            vec![("f", 1), ("e", 2)],
+            members(&board.around("f", 1).unwrap())
+        );
+        assert!(board.around("z", 1).unwrap().is_empty());
+
+        board.set_score("f", 0.0).unwrap();
+        assert_eq!(Some(6), board.rank("f").unwrap());
+        assert!(board.remove("f").unwrap());
+        assert!(!board.remove("f").unwrap());
+    }
+
+    #[test]
+    fn test_lowest_first() {
+        let board = board().lowest_first();
+        board.submit("a", 61.5).unwrap();
+        board.submit("b", 59.0).unwrap();
+        assert!(!board.submit("b", 60.0).unwrap());
+        assert!(board.submit("a", 58.25).unwrap());
+        let top = board.top(10).unwrap();
+        assert_eq!(vec![("a", 1), ("b", 2)], members(&top));
+        assert_eq!(58.25, top[0].score);
+    }
+
+    #[test]
+    fn test_buckets() {
+        let hour = Duration::from_secs(3600);
+        let board = board().per(hour);
+        let earlier = board.at(SystemTime::now() - hour);
+        assert_ne!(board.key(), earlier.key());
+        board.submit("a", 1.0).unwrap();
+        earlier.submit("b", 2.0).unwrap();
+        assert_eq!(vec![("a", 1)], members(&board.top(10).unwrap()));
+        assert_eq!(vec![("b", 1)], members(&earlier.top(10).unwrap()));
+    }
+}
@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use crate::pool_ext::checkout;
+use crate::RedisConnectionManager;
+
+/// Refills the bucket for the time elapsed since the last request, and takes
+/// `cost` tokens from it if it holds enough.
+///
+/// `KEYS`: the bucket. `ARGV`: capacity, tokens added per millisecond, cost.
+/// Returns whether the request is allowed, the tokens left and the
+/// milliseconds until enough are available, or -1 if the cost exceeds the
+/// capacity.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+redis.replicate_commands()
+local time = redis.call("time")
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local state = redis.call("hmget", KEYS[1], "tokens", "updated")
+local tokens = tonumber(state[1]) or capacity
+local updated = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * rate)
+local allowed = 0
+local retry_after = 0
+if tokens >= cost then
+    tokens = tokens - cost
+    allowed = 1
+elseif cost > capacity then
+    retry_after = -1
+else
+    retry_after = math.ceil((cost - tokens) / rate)
+end
+redis.call("hmset", KEYS[1], "tokens", tokens, "updated", now)
+redis.call("pexpire", KEYS[1], math.ceil(capacity / rate))
+return {allowed, math.floor(tokens), retry_after}
+"#;
+
+/// Counts `cost` requests in the current window, which starts with the first
+/// request and lasts `window` milliseconds, unless that exceeds the limit.
+///
+/// `KEYS`: the counter. `ARGV`: limit, window in milliseconds, cost.
+/// Returns whether the request is allowed, the requests left in the window
+/// and the milliseconds until it ends if the request is denied, or -1 if the
+/// cost exceeds the limit.
+const FIXED_WINDOW_SCRIPT: &str = r#"
+local limit = tonumber(ARGV[1])
+local cost = tonumber(ARGV[3])
+if cost > limit then
+    local count = tonumber(redis.call("get", KEYS[1])) or 0
+    return {0, math.max(0, limit - count), -1}
+end
+local count = redis.call("incrby", KEYS[1], ARGV[3])
+local ttl = redis.call("pttl", KEYS[1])
+if ttl < 0 then
+    redis.call("pexpire", KEYS[1], ARGV[2])
+    ttl = tonumber(ARGV[2])
+end
+if count > limit then
+    redis.call("decrby", KEYS[1], ARGV[3])
+    return {0, math.max(0, limit - count + cost), ttl}
+end
+return {1, limit - count, 0}
+"#;
+
+/// The algorithm and limits of a `RateLimiter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimit {
+    /// A bucket holding up to `capacity` tokens, refilled continuously at
+    /// `refill_per_second`. Each request takes tokens, so bursts of up to
+    /// `capacity` requests are allowed.
+    TokenBucket {
+        /// The maximum number of tokens in the bucket.
+        capacity: u64,
+        /// The number of tokens added per second.
+        refill_per_second: f64,
+    },
+    /// At most `limit` requests per `window`, counted from the first
+    /// request of the window.
+    FixedWindow {
+        /// The number of requests allowed per window.
+        limit: u64,
+        /// The length of a window.
+        window: Duration,
+    },
+}
+
+/// The outcome of `RateLimiter::check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    /// Whether the request may proceed.
+    pub allowed: bool,
+    /// The number of requests of cost 1 still allowed right away.
+    pub remaining: u64,
+    /// When a denied request may be retried, or `None` if it never can be,
+    /// as it costs more than the bucket's capacity or the window's limit.
+    pub retry_after: Option<Duration>,
+}
+
+/// Limits the rate of requests per key, e.g. per user or API token, across
+/// processes.
+///
+/// Each check runs a Lua script on a pooled connection, so checking and
+/// updating the count is atomic, without racing `GET` and `INCR`
+/// sequences. The state of key `key` is kept at `<prefix>:<key>` and expires
+/// once it no longer matters.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RateLimit, RateLimiter, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let limiter = RateLimiter::new(
+///         pool,
+///         "ratelimit:api",
+///         RateLimit::TokenBucket {
+///             capacity: 20,
+///             refill_per_second: 5.0,
+///         },
+///     );
+///     let decision = limiter.check("user:42").unwrap();
+///     if !decision.allowed {
+///         println!("retry after {:?}", decision.retry_after);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    prefix: String,
+    limit: RateLimit,
+}
+
+impl RateLimiter {
+    /// Creates a `RateLimiter` enforcing `limit` on keys under `prefix`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is a token bucket with a capacity of 0, or a refill
+    /// rate that isn't positive and finite.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        prefix: S,
+        limit: RateLimit,
+    ) -> RateLimiter {
+        if let RateLimit::TokenBucket {
+            capacity,
+            refill_per_second,
+        } = limit
+        {
+            assert_ne!(capacity, 0, "capacity must be positive");
+            assert!(
+                refill_per_second > 0.0 && refill_per_second.is_finite(),
+                "refill_per_second must be positive and finite"
+            );
+        }
+        RateLimiter {
+            pool,
+            prefix: prefix.into(),
+            limit,
+        }
+    }
+
+    /// Counts a request for `key`, and decides whether it is allowed.
+    pub fn check(&self, key: &str) -> redis::RedisResult<RateLimitDecision> {
+        self.check_n(key, 1)
+    }
+
+    /// Counts a request of cost `cost`, e.g. the size of a batch, for `key`,
+    /// and decides whether it is allowed.
+    ///
+    /// Denied requests aren't counted. A request costing more than the
+    /// bucket's capacity or the window's limit is always denied, without a
+    /// `retry_after`.
+    pub fn check_n(&self, key: &str, cost: u64) -> redis::RedisResult<RateLimitDecision> {
+        let key = format!("{}:{}", self.prefix, key);
+        let mut conn = checkout(&self.pool)?;
+        let (allowed, remaining, retry_after): (bool, u64, i64) = match self.limit {
+            RateLimit::TokenBucket {
+                capacity,
+                refill_per_second,
+            } => redis::Script::new(TOKEN_BUCKET_SCRIPT)
+                .key(key)
+                .arg(capacity)
+                .arg(refill_per_second / 1000.0)
+                .arg(cost)
+                .invoke(&mut *conn)?,
+            RateLimit::FixedWindow { limit, window } => redis::Script::new(FIXED_WINDOW_SCRIPT)
+                .key(key)
+                .arg(limit)
+                .arg(window.as_millis().max(1) as u64)
+                .arg(cost)
+                .invoke(&mut *conn)?,
+        };
+        Ok(RateLimitDecision {
+            allowed,
+            remaining,
+            retry_after: if allowed || retry_after < 0 {
+                None
+            } else {
+                Some(Duration::from_millis(retry_after as u64))
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(
+            crate::local_pool(1),
+            "redis_r2d2-bucket",
+            RateLimit::TokenBucket {
+                capacity: 2,
+                refill_per_second: 10.0,
+            },
+        );
+        let key = crate::lock::token();
+        assert!(limiter.check(&key).unwrap().allowed);
+        assert_eq!(0, limiter.check(&key).unwrap().remaining);
+        let denied = limiter.check(&key).unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.retry_after.unwrap() <= Duration::from_millis(100));
+        std::thread::sleep(denied.retry_after.unwrap() + Duration::from_millis(10));
+        assert!(limiter.check(&key).unwrap().allowed);
+
+        // A request costing more than the capacity can never succeed.
+        let denied = limiter.check_n(&key, 3).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(None, denied.retry_after);
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let limiter = RateLimiter::new(
+            crate::local_pool(1),
+            "redis_r2d2-window",
+            RateLimit::FixedWindow {
+                limit: 3,
+                window: Duration::from_secs(10),
+            },
+        );
+        let key = crate::lock::token();
+        assert_eq!(1, limiter.check_n(&key, 2).unwrap().remaining);
+        let denied = limiter.check_n(&key, 2).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(1, denied.remaining);
+        assert!(denied.retry_after.unwrap() > Duration::from_secs(9));
+        let denied = limiter.check_n(&key, 4).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(1, denied.remaining);
+        assert_eq!(None, denied.retry_after);
+        let decision = limiter.check(&key).unwrap();
+        assert!(decision.allowed);
+        assert_eq!(0, decision.remaining);
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity must be positive")]
+    fn test_empty_bucket() {
+        let bucket = RateLimit::TokenBucket {
+            capacity: 0,
+            refill_per_second: 1.0,
+        };
+        RateLimiter::new(crate::local_pool(1), "redis_r2d2-bucket", bucket);
+    }
+
+    #[test]
+    #[should_panic(expected = "refill_per_second must be positive and finite")]
+    fn test_bucket_without_refill() {
+        let bucket = RateLimit::TokenBucket {
+            capacity: 10,
+            refill_per_second: 0.0,
+        };
+        RateLimiter::new(crate::local_pool(1), "redis_r2d2-bucket", bucket);
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::backoff::jittered;
+use crate::pool_ext::checkout;
+use crate::RedisConnectionManager;
+
+/// Deletes the lock if it still holds the caller's token.
+pub(crate) const RELEASE_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("del", KEYS[1])
+else
+    return 0
+end
+"#;
+
+/// Resets the lock's TTL if it still holds the caller's token.
+pub(crate) const EXTEND_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("pexpire", KEYS[1], ARGV[2])
+else
+    return 0
+end
+"#;
+
+/// The share of the TTL assumed lost to clock drift between the servers.
+pub(crate) const CLOCK_DRIFT_FACTOR: f64 = 0.01;
+
+/// A lock shared by processes through Redis.
+///
+/// The lock is a key set with `SET key token NX PX ttl` to a random token,
+/// which is checked by a Lua script before the key is deleted or its TTL
+/// extended, so a holder whose lock expired can't release another's. With
+/// several independent servers, given with `with_pools`, the Redlock
+/// algorithm is used: the lock is held once it is set on a majority of them,
+/// for the TTL minus the time that took and an allowance for clock drift.
+///
+/// `acquire` retries a few times with a random delay when the lock is held
+/// elsewhere, and the returned `LockGuard` releases it when dropped. As the
+/// lock expires after its TTL whatever the holder is doing, long tasks
+/// should `extend` it and check `LockGuard::validity`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, DistributedLock, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let lock = DistributedLock::new(r2d2::Pool::builder().build(manager).unwrap());
+///     if let Some(guard) = lock.acquire("locks:report", Duration::from_secs(10)).unwrap() {
+///         // ...
+///         guard.release().unwrap();
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DistributedLock {
+    pools: Arc<Vec<r2d2::Pool<RedisConnectionManager>>>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl DistributedLock {
+    /// Creates a `DistributedLock` on the server of `pool`.
+    pub fn new(pool: r2d2::Pool<RedisConnectionManager>) -> DistributedLock {
+        DistributedLock::with_pools(vec![pool])
+    }
+
+    /// Creates a `DistributedLock` on the independent servers of `pools`,
+    /// held when acquired on a majority of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pools` is empty.
+    pub fn with_pools(pools: Vec<r2d2::Pool<RedisConnectionManager>>) -> DistributedLock {
+        assert!(!pools.is_empty(), "a lock needs at least one pool");
+        DistributedLock {
+            pools: Arc::new(pools),
+            retries: 3,
+            retry_delay: Duration::from_millis(200),
+        }
+    }
+
+    /// Sets how many more times `acquire` tries when the lock is held
+    /// elsewhere, and roughly how long it waits in between.
+    ///
+    /// Defaults to 3 retries, 200 milliseconds apart.
+    pub fn retry(mut self, retries: u32, delay: Duration) -> DistributedLock {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Acquires the lock `key` for `ttl`, or returns `None` if it is still
+    /// held elsewhere after the retries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if none of the servers could be reached.
+    pub fn acquire<K: Into<String>>(
+        &self,
+        key: K,
+        ttl: Duration,
+    ) -> redis::RedisResult<Option<LockGuard>> {
+        let key = key.into();
+        let token = token();
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                thread::sleep(jittered(self.retry_delay, 0.5));
+            }
+            let start = Instant::now();
+            let acquired = self.quorum(|conn| {
+                redis::cmd("SET")
+                    .arg(&key)
+                    .arg(&token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64)
+                    .query::<Option<()>>(conn)
+                    .map(|reply| reply.is_some())
+            })?;
+            if let Some(valid_until) = self.valid_until(acquired, start, ttl) {
+                return Ok(Some(LockGuard {
+                    lock: self.clone(),
+                    key,
+                    token,
+                    valid_until,
+                    released: false,
+                }));
+            }
+            // Don't leave the lock set on a minority of the servers until it
+            // expires.
+            let _ = self.release(&key, &token);
+        }
+        Ok(None)
+    }
+
+    /// Runs `f` on each server, returning how many it succeeded on, or the
+    /// last error if no server could be reached.
+    fn quorum<F>(&self, mut f: F) -> redis::RedisResult<usize>
+    where
+        F: FnMut(&mut crate::RedisConnection) -> redis::RedisResult<bool>,
+    {
+        let mut succeeded = 0;
+        let mut reached = false;
+        let mut last_error = None;
+        for pool in self.pools.iter() {
+            let result = checkout(pool).and_then(|mut conn| f(&mut conn));
+            match result {
+                Ok(success) => {
+                    reached = true;
+                    if success {
+                        succeeded += 1;
+                    }
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) if !reached => Err(e),
+            _ => Ok(succeeded),
+        }
+    }
+
+    /// Returns the number of servers the lock must be set on to be held.
+    fn majority(&self) -> usize {
+        self.pools.len() / 2 + 1
+    }
+
+    /// Returns until when a lock set on `succeeded` servers from `start` is
+    /// held, if it is.
+    fn valid_until(&self, succeeded: usize, start: Instant, ttl: Duration) -> Option<Instant> {
+        if succeeded < self.majority() {
+            return None;
+        }
+        let drift = ttl.mul_f64(CLOCK_DRIFT_FACTOR) + Duration::from_millis(2);
+        let valid_until = start + ttl.checked_sub(drift)?;
+        if valid_until > Instant::now() {
+            Some(valid_until)
+        } else {
+            None
+        }
+    }
+
+    /// Deletes the lock on every server where it holds `token`, returning
+    /// on how many it did.
+    fn release(&self, key: &str, token: &str) -> redis::RedisResult<usize> {
+        let script = redis::Script::new(RELEASE_SCRIPT);
+        self.quorum(|conn| {
+            script
+                .key(key)
+                .arg(token)
+                .invoke::<i64>(conn)
+                .map(|deleted| deleted > 0)
+        })
+    }
+}
+
+/// A held `DistributedLock`, released when dropped.
+#[derive(Debug)]
+pub struct LockGuard {
+    lock: DistributedLock,
+    key: String,
+    token: String,
+    valid_until: Instant,
+    released: bool,
+}
+
+impl LockGuard {
+    /// Returns the key of the lock.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the random token identifying this holder of the lock.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Returns how long the lock is still held for, at least.
+    pub fn validity(&self) -> Duration {
+        self.valid_until.saturating_duration_since(Instant::now())
+    }
+
+    /// Resets the TTL of the lock to `ttl`, returning false if it was lost,
+    /// i.e. it couldn't be extended on a majority of the servers.
+    pub fn extend(&mut self, ttl: Duration) -> redis::RedisResult<bool> {
+        let script = redis::Script::new(EXTEND_SCRIPT);
+        let start = Instant::now();
+        let extended = self.lock.quorum(|conn| {
+            script
+                .key(&self.key)
+                .arg(&self.token)
+                .arg(ttl.as_millis() as u64)
+                .invoke::<i64>(conn)
+                .map(|extended| extended > 0)
+        })?;
+        match self.lock.valid_until(extended, start, ttl) {
+            Some(valid_until) => {
+                self.valid_until = valid_until;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Releases the lock, returning false if it had already expired.
+    pub fn release(mut self) -> redis::RedisResult<bool> {
+        self.released = true;
+        let released = self.lock.release(&self.key, &self.token)?;
+        Ok(released >= self.lock.majority())
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.lock.release(&self.key, &self.token);
+        }
+    }
+}
+
+/// Returns a random token, unique among the locks of this process.
+///
+/// The randomly keyed hashers of `RandomState` are fed the process ID, the
+/// wall-clock time and a counter, so tokens of different processes and hosts
+/// don't collide either.
+pub(crate) fn token() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos());
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.write_u128(now);
+    hasher.write_u64(count);
+    let random = hasher.finish();
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(random);
+    format!("{:016x}{:016x}{:x}", random, hasher.finish(), count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_lock() -> DistributedLock {
+        DistributedLock::new(crate::local_pool(2)).retry(1, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn test_token() {
+        let tokens: std::collections::HashSet<String> = (0..1000).map(|_| token()).collect();
+        assert_eq!(1000, tokens.len());
+        assert!(tokens.iter().all(|token| token.len() > 32));
+    }
+
+    #[test]
+    fn test_lock() {
+        let lock = local_lock();
+        let ttl = Duration::from_secs(10);
+        let mut guard = lock.acquire("redis_r2d2-lock", ttl).unwrap().unwrap();
+        assert!(guard.validity() > Duration::from_secs(9));
+        assert!(lock.acquire("redis_r2d2-lock", ttl).unwrap().is_none());
+        assert!(guard.extend(Duration::from_secs(20)).unwrap());
+        assert!(guard.validity() > Duration::from_secs(19));
+        assert!(guard.release().unwrap());
+
+        let guard = lock.acquire("redis_r2d2-lock", ttl).unwrap().unwrap();
+        let token = guard.token().to_string();
+        drop(guard);
+        let guard = lock.acquire("redis_r2d2-lock", ttl).unwrap().unwrap();
+        assert_ne!(token, guard.token());
+    }
+
+    #[test]
+    fn test_quorum() {
+        let unreachable = r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(RedisConnectionManager::new("redis://127.0.0.1:1").unwrap());
+        let reachable = || local_lock().pools[0].clone();
+
+        let lock = DistributedLock::with_pools(vec![unreachable.clone()]);
+        assert!(lock
+            .acquire("redis_r2d2-lock-quorum", Duration::from_secs(10))
+            .is_err());
+
+        // Two of three servers are enough, but not one of two.
+        let lock = DistributedLock::with_pools(vec![reachable(), unreachable.clone()])
+            .retry(0, Duration::from_millis(0));
+        assert!(lock
+            .acquire("redis_r2d2-lock-quorum", Duration::from_secs(10))
+            .unwrap()
+            .is_none());
+        let lock = DistributedLock::with_pools(vec![reachable(), unreachable, reachable()]);
+        assert!(lock
+            .valid_until(2, Instant::now(), Duration::from_secs(1))
+            .is_some());
+        assert!(lock
+            .valid_until(1, Instant::now(), Duration::from_secs(1))
+            .is_none());
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::RedisConnection;
+
+/// A trait which receives the connection lifecycle events of a
+/// `RedisConnectionManager`, e.g. to count them as metrics.
+///
+/// All methods are called synchronously from the manager, so they should be
+/// quick.
+///
+/// ## Example
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// use redis_r2d2::{r2d2, redis, PoolMetricsSink, RedisConnectionManager};
+///
+/// #[derive(Debug, Default)]
+/// struct ConnectErrors(AtomicUsize);
+///
+/// impl PoolMetricsSink for ConnectErrors {
+///     fn connect_failed(&self, _error: &redis::RedisError) {
+///         self.0.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::builder()
+///         .metrics_sink(Box::new(ConnectErrors::default()))
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder()
+///         .build(manager)
+///         .unwrap();
+///
+///     pool.get().unwrap();
+/// }
+/// ```
+pub trait PoolMetricsSink: fmt::Debug + Send + Sync + 'static {
+    /// Called when a new connection was established, with the time it took
+    /// (including any backoff delay and setup commands).
+    ///
+    /// The default implementation does nothing.
+    #[allow(unused_variables)]
+    fn connection_created(&self, connect_time: Duration) {}
+
+    /// Called when establishing a new connection failed.
+    ///
+    /// The default implementation does nothing.
+    #[allow(unused_variables)]
+    fn connect_failed(&self, error: &redis::RedisError) {}
+
+    /// Called when a connection failed validation on checkout.
+    ///
+    /// The default implementation does nothing.
+    #[allow(unused_variables)]
+    fn validation_failed(&self, error: &redis::RedisError) {}
+
+    /// Called when a healthy connection returned to the pool is closed
+    /// because it reached its `max_lifetime` or `max_uses`, or because the
+    /// pool is draining.
+    ///
+    /// The default implementation does nothing.
+    #[allow(unused_variables)]
+    fn connection_recycled(&self, conn: &RedisConnection) {}
+
+    /// Called when a connection returned to the pool is closed because it
+    /// is no longer usable.
+    ///
+    /// The default implementation does nothing.
+    #[allow(unused_variables)]
+    fn broken_detected(&self, conn: &RedisConnection) {}
+}
+
+/// A `PoolMetricsSink` which does nothing.
+#[derive(Copy, Clone, Debug)]
+pub struct NopMetricsSink;
+
+impl PoolMetricsSink for NopMetricsSink {}
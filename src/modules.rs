@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use redis::Value;
+
+/// Checks that the server behind `conn` has loaded the modules named
+/// `required`, e.g. `ReJSON`, with `MODULE LIST`.
+pub(crate) fn check_required(
+    conn: &mut redis::Connection,
+    required: &[String],
+) -> redis::RedisResult<()> {
+    if required.is_empty() {
+        return Ok(());
+    }
+    let loaded = loaded_modules(conn)?;
+    match required
+        .iter()
+        .find(|module| !loaded.iter().any(|name| name.eq_ignore_ascii_case(module)))
+    {
+        Some(module) => Err((
+            redis::ErrorKind::ClientError,
+            "required module not loaded",
+            module.clone(),
+        )
+            .into()),
+        None => Ok(()),
+    }
+}
+
+/// Returns the names of the modules loaded on the server.
+fn loaded_modules(conn: &mut redis::Connection) -> redis::RedisResult<Vec<String>> {
+    let modules: Vec<HashMap<String, Value>> = redis::cmd("MODULE").arg("LIST").query(conn)?;
+    modules
+        .iter()
+        .filter_map(|module| module.get("name"))
+        .map(redis::from_redis_value)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::fake_server;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_check_required() {
+        let addr = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |request| {
+            if request.contains("MODULE") {
+                "*1\r\n*4\r\n$4\r\nname\r\n$6\r\nReJSON\r\n$3\r\nver\r\n:20008\r\n".to_string()
+            } else {
+                "+OK\r\n".to_string()
+            }
+        });
+        let mut conn = redis::Client::open(format!("redis://{}", addr))
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        check_required(&mut conn, &[]).unwrap();
+        check_required(&mut conn, &["rejson".to_string()]).unwrap();
+        let err =
+            check_required(&mut conn, &["ReJSON".to_string(), "search".to_string()]).unwrap_err();
+        assert_eq!(redis::ErrorKind::ClientError, err.kind());
+        assert_eq!(Some("search"), err.detail());
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use redis::Value;
+
+use crate::subscriber::POLL_INTERVAL;
+use crate::RedisConnection;
+
+/// A command reported by `MONITOR`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorLine {
+    /// When the server processed the command.
+    pub timestamp: SystemTime,
+    /// The database the command ran against.
+    pub db: i64,
+    /// The address of the client that sent the command, or `lua` for
+    /// commands run by scripts.
+    pub client: String,
+    /// The name of the command, as sent by the client.
+    pub command: String,
+    /// The arguments of the command.
+    pub args: Vec<Vec<u8>>,
+}
+
+impl MonitorLine {
+    /// Parses a line such as
+    /// `1339518083.107412 [0 127.0.0.1:60866] "keys" "*"`.
+    fn parse(line: &str) -> Option<MonitorLine> {
+        let (timestamp, rest) = line.split_once(' ')?;
+        let (source, rest) = rest.strip_prefix('[')?.split_once("] ")?;
+        let (db, client) = source.split_once(' ')?;
+        let mut words = parse_quoted(rest)?.into_iter();
+        let command = String::from_utf8(words.next()?).ok()?;
+        Some(MonitorLine {
+            timestamp: UNIX_EPOCH + Duration::try_from_secs_f64(timestamp.parse().ok()?).ok()?,
+            db: db.parse().ok()?,
+            client: client.to_string(),
+            command,
+            args: words.collect(),
+        })
+    }
+}
+
+/// Splits the space-separated, quoted and escaped arguments of a `MONITOR`
+/// line.
+fn parse_quoted(s: &str) -> Option<Vec<Vec<u8>>> {
+    let mut words = Vec::new();
+    let mut chars = s.bytes();
+    loop {
+        match chars.next() {
+            None => return Some(words),
+            Some(b' ') => {}
+            Some(b'"') => {
+                let mut word = Vec::new();
+                loop {
+                    match chars.next()? {
+                        b'"' => break,
+                        b'\\' => match chars.next()? {
+                            b'n' => word.push(b'\n'),
+                            b'r' => word.push(b'\r'),
+                            b't' => word.push(b'\t'),
+                            b'a' => word.push(7),
+                            b'b' => word.push(8),
+                            b'x' => {
+                                let hex = [chars.next()?, chars.next()?];
+                                let hex = std::str::from_utf8(&hex).ok()?;
+                                word.push(u8::from_str_radix(hex, 16).ok()?);
+                            }
+                            c => word.push(c),
+                        },
+                        c => word.push(c),
+                    }
+                }
+                words.push(word);
+            }
+            Some(_) => return None,
+        }
+    }
+}
+
+/// A connection in `MONITOR` mode, iterating over the commands the server
+/// processes.
+///
+/// It is opened outside the pool by `RedisConnectionManager::monitor`, as a
+/// monitoring connection can't run other commands, and closed when dropped.
+/// The iterator blocks until the next command, and ends when the connection
+/// fails, after yielding the error, or when `MonitorStopHandle::stop` is
+/// called, within a fraction of a second.
+///
+/// `MONITOR` slows the server down noticeably, so it is meant for ad-hoc
+/// debugging rather than continuous use.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::RedisConnectionManager;
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let monitor = manager.monitor().unwrap();
+///     for line in monitor.take(100) {
+///         let line = line.unwrap();
+///         println!("[{} {}] {} {:?}", line.db, line.client, line.command, line.args);
+///     }
+/// }
+/// ```
+pub struct Monitor {
+    conn: RedisConnection,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    pub(crate) fn start(mut conn: RedisConnection) -> redis::RedisResult<Monitor> {
+        redis::cmd("MONITOR").query::<()>(&mut conn)?;
+        conn.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(Monitor {
+            conn,
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Returns a handle that stops the monitor, e.g. from another thread.
+    pub fn stop_handle(&self) -> MonitorStopHandle {
+        MonitorStopHandle {
+            stopped: self.stopped.clone(),
+        }
+    }
+}
+
+impl Iterator for Monitor {
+    type Item = redis::RedisResult<MonitorLine>;
+
+    fn next(&mut self) -> Option<redis::RedisResult<MonitorLine>> {
+        while !self.stopped.load(Ordering::Relaxed) {
+            let line = match self.conn.recv_response() {
+                Ok(Value::Status(line)) => line,
+                Ok(_) => continue,
+                Err(ref e) if e.is_timeout() => continue,
+                Err(e) => {
+                    self.stopped.store(true, Ordering::Relaxed);
+                    return Some(Err(e));
+                }
+            };
+            return Some(MonitorLine::parse(&line).ok_or_else(|| {
+                (redis::ErrorKind::TypeError, "invalid MONITOR line", line).into()
+            }));
+        }
+        None
+    }
+}
+
+impl std::fmt::Debug for Monitor {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Monitor")
+            .field("stopped", &self.stopped.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Stops a `Monitor`.
+#[derive(Debug, Clone)]
+pub struct MonitorStopHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl MonitorStopHandle {
+    /// Ends the monitor's iterator.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConnectionManager;
+    use std::thread;
+
+    #[test]
+    fn test_parse() {
+        let line = MonitorLine::parse(
+            r#"1339518083.107412 [3 127.0.0.1:60866] "SET" "k\"ey" "a b\n\x01""#,
+        )
+        .unwrap();
+        assert_eq!(3, line.db);
+        assert_eq!("127.0.0.1:60866", line.client);
+        assert_eq!("SET", line.command);
+        assert_eq!(vec![b"k\"ey".to_vec(), b"a b\n\x01".to_vec()], line.args);
+        assert_eq!(
+            1339518083,
+            line.timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        );
+        assert_eq!(
+            "lua",
+            MonitorLine::parse(r#"1339518083.1 [0 lua] "get" "k""#)
+                .unwrap()
+                .client
+        );
+        assert_eq!(None, MonitorLine::parse("OK"));
+    }
+
+    #[test]
+    fn test_monitor() {
+        let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+        let mut monitor = manager.monitor().unwrap();
+        let stop = monitor.stop_handle();
+
+        let mut conn = redis::Client::open("redis://localhost")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        redis::cmd("ECHO")
+            .arg("redis_r2d2-monitor")
+            .query::<String>(&mut conn)
+            .unwrap();
+        let line = monitor
+            .find(|line| line.as_ref().unwrap().args == [b"redis_r2d2-monitor".to_vec()])
+            .unwrap()
+            .unwrap();
+        assert_eq!("ECHO", line.command.to_uppercase());
+
+        thread::spawn(move || stop.stop());
+        assert!(monitor.all(|line| line.is_ok()));
+    }
+}
@@ -0,0 +1,369 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+
+use crate::aio::{self, AsyncConnection};
+use crate::{runtime, ConnectionCustomizer, RedisConnectionManager, ValidationMode};
+
+/// A manager for `redis::aio::MultiplexedConnection`s, which pipeline the
+/// commands of every task sharing them over one socket.
+///
+/// It is configured with the same `RedisConnectionManagerBuilder` as
+/// `RedisAsyncConnectionManager`, through `build_multiplexed`, and accepts
+/// the same settings except a `connection_customizer`, which has no hook for
+/// multiplexed connections.
+///
+/// Validating a shared connection with a `PING` on checkout would queue
+/// behind the commands of the other tasks, so connections aren't checked
+/// with a round-trip on checkout. Instead, a connection is marked broken
+/// when a command fails with an I/O error, and a background task runs the
+/// `ValidationMode` command every `heartbeat_interval`, marking it broken if
+/// that fails or takes longer than the `validation_timeout`. Checkouts and
+/// check-ins only look at the mark.
+///
+/// The manager implements `bb8::ManageConnection` and
+/// `deadpool::managed::Manager` with the corresponding features; `connect`
+/// and `is_valid` can be used with other pools. Requires the `tokio` or
+/// `async-std` feature; the connections' tasks are spawned on the runtime
+/// `connect` is called from, see `RedisAsyncConnectionManager`.
+#[derive(Debug)]
+pub struct RedisMultiplexedConnectionManager {
+    manager: RedisConnectionManager,
+    heartbeat_interval: Option<Duration>,
+}
+
+impl RedisMultiplexedConnectionManager {
+    /// Creates a new `RedisMultiplexedConnectionManager`.
+    ///
+    /// See `redis::Client::open` for a description of the parameter
+    /// types.
+    pub fn new<T: redis::IntoConnectionInfo>(
+        params: T,
+    ) -> Result<RedisMultiplexedConnectionManager, redis::RedisError> {
+        RedisConnectionManager::builder().build_multiplexed(params)
+    }
+
+    pub(crate) fn from_parts(
+        manager: RedisConnectionManager,
+        heartbeat_interval: Option<Duration>,
+    ) -> Result<RedisMultiplexedConnectionManager, redis::RedisError> {
+        // `ConnectionCustomizer::on_async_connect` takes a plain connection,
+        // so there is no hook for multiplexed ones.
+        let unsupported = if manager.customized {
+            Some("connection_customizer")
+        } else {
+            aio::unsupported_setting(&manager)
+        };
+        match unsupported {
+            Some(setting) => Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "setting not supported by async connections",
+                setting.to_string(),
+            )
+                .into()),
+            None => Ok(RedisMultiplexedConnectionManager {
+                manager,
+                heartbeat_interval,
+            }),
+        }
+    }
+
+    /// Returns the URLs of the endpoints with any password masked, see
+    /// `RedisConnectionManager::display_safe_url`.
+    pub fn display_safe_url(&self) -> String {
+        self.manager.display_safe_url()
+    }
+
+    /// Returns a handle to the circuit breaker of this manager, if one was
+    /// configured.
+    pub fn circuit_breaker_handle(&self) -> Option<crate::CircuitBreakerHandle> {
+        self.manager.circuit_breaker_handle()
+    }
+
+    /// Opens a new connection, starting its heartbeat.
+    pub async fn connect(&self) -> redis::RedisResult<RedisMultiplexedConnection> {
+        let conn: MultiplexedConnection = aio::connect(&self.manager).await?;
+        let conn = RedisMultiplexedConnection {
+            conn,
+            broken: Arc::new(AtomicBool::new(false)),
+        };
+        if let Some(interval) = self.heartbeat_interval {
+            if !matches!(
+                self.manager.validation,
+                ValidationMode::None | ValidationMode::CheckConnection
+            ) {
+                runtime::spawn(heartbeat(
+                    conn.conn.clone(),
+                    Arc::downgrade(&conn.broken),
+                    self.manager.validation.clone(),
+                    self.manager.validation_timeout.unwrap_or(interval),
+                    interval,
+                ));
+            }
+        }
+        Ok(conn)
+    }
+
+    /// Checks a connection is usable before it is handed out, without a
+    /// round-trip to the server.
+    pub fn is_valid(&self, conn: &RedisMultiplexedConnection) -> redis::RedisResult<()> {
+        if conn.is_broken() {
+            let e = redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "the multiplexed connection is broken",
+            ));
+            self.manager.metrics_sink.validation_failed(&e);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// A `redis::aio::MultiplexedConnection` that remembers I/O errors.
+///
+/// Clones share the underlying connection and whether it is broken.
+pub struct RedisMultiplexedConnection {
+    conn: MultiplexedConnection,
+    broken: Arc<AtomicBool>,
+}
+
+impl RedisMultiplexedConnection {
+    /// Returns true if a command failed with an I/O error, or the heartbeat
+    /// failed.
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Relaxed)
+    }
+
+    fn record<T>(&self, result: &redis::RedisResult<T>) {
+        if let Err(ref e) = *result {
+            if e.is_io_error() || e.is_connection_dropped() || e.is_timeout() {
+                self.broken.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Clone for RedisMultiplexedConnection {
+    fn clone(&self) -> RedisMultiplexedConnection {
+        RedisMultiplexedConnection {
+            conn: self.conn.clone(),
+            broken: self.broken.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for RedisMultiplexedConnection {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("RedisMultiplexedConnection")
+            .field("db", &self.conn.get_db())
+            .field("broken", &self.is_broken())
+            .finish()
+    }
+}
+
+impl ConnectionLike for RedisMultiplexedConnection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        Box::pin(async move {
+            let result = self.conn.req_packed_command(cmd).await;
+            self.record(&result);
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        Box::pin(async move {
+            let result = self.conn.req_packed_commands(cmd, offset, count).await;
+            self.record(&result);
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+}
+
+impl AsyncConnection for MultiplexedConnection {
+    fn open(client: redis::Client) -> redis::RedisFuture<'static, Self> {
+        Box::pin(async move { runtime::multiplexed_connection(&client).await })
+    }
+
+    /// Multiplexed managers reject a `connection_customizer`, see
+    /// `RedisMultiplexedConnectionManager::from_parts`.
+    fn customize<'a>(
+        &'a mut self,
+        _customizer: &'a dyn ConnectionCustomizer,
+    ) -> redis::RedisFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Runs `validation` on `conn` every `interval` until the connection whose
+/// `broken` mark this is is dropped, setting the mark if a check fails.
+async fn heartbeat(
+    mut conn: MultiplexedConnection,
+    broken: Weak<AtomicBool>,
+    validation: ValidationMode,
+    timeout: Duration,
+    interval: Duration,
+) {
+    loop {
+        runtime::sleep(interval).await;
+        let broken = match broken.upgrade() {
+            Some(broken) => broken,
+            None => return,
+        };
+        if broken.load(Ordering::Relaxed) {
+            return;
+        }
+        let check = async {
+            match validation {
+                ValidationMode::Command(ref cmd) => cmd
+                    .query_async::<_, redis::Value>(&mut conn)
+                    .await
+                    .map(|_| ()),
+                _ => redis::cmd("PING").query_async(&mut conn).await,
+            }
+        };
+        if let Err(e) = aio::with_timeout(Some(timeout), check).await {
+            log::warn!("multiplexed connection failed its heartbeat: {}", e);
+            broken.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+#[cfg(feature = "bb8")]
+#[async_trait::async_trait]
+impl bb8::ManageConnection for RedisMultiplexedConnectionManager {
+    type Connection = RedisMultiplexedConnection;
+    type Error = redis::RedisError;
+
+    async fn connect(&self) -> Result<RedisMultiplexedConnection, Self::Error> {
+        RedisMultiplexedConnectionManager::connect(self).await
+    }
+
+    async fn is_valid(
+        &self,
+        conn: &mut bb8::PooledConnection<'_, Self>,
+    ) -> Result<(), Self::Error> {
+        RedisMultiplexedConnectionManager::is_valid(self, conn)
+    }
+
+    fn has_broken(&self, conn: &mut RedisMultiplexedConnection) -> bool {
+        conn.is_broken()
+    }
+}
+
+#[cfg(feature = "deadpool")]
+#[async_trait::async_trait]
+impl deadpool::managed::Manager<RedisMultiplexedConnection, redis::RedisError>
+    for RedisMultiplexedConnectionManager
+{
+    async fn create(&self) -> Result<RedisMultiplexedConnection, redis::RedisError> {
+        self.connect().await
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut RedisMultiplexedConnection,
+    ) -> deadpool::managed::RecycleResult<redis::RedisError> {
+        Ok(self.is_valid(conn)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::fake_server;
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_multiplexed() {
+        let manager = RedisConnectionManager::builder()
+            .client_name("redis_r2d2-multiplexed")
+            .build_multiplexed("redis://localhost/2")
+            .unwrap();
+        let mut conn = manager.connect().await.unwrap();
+        let name: String = redis::cmd("CLIENT")
+            .arg("GETNAME")
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!("redis_r2d2-multiplexed", name);
+        assert_eq!(2, conn.get_db());
+        manager.is_valid(&conn).unwrap();
+    }
+
+    #[test]
+    fn test_connection_customizer() {
+        #[derive(Debug)]
+        struct Nothing;
+
+        impl ConnectionCustomizer for Nothing {}
+
+        let error = RedisConnectionManager::builder()
+            .connection_customizer(Box::new(Nothing))
+            .build_multiplexed("redis://localhost")
+            .unwrap_err();
+        assert_eq!(redis::ErrorKind::InvalidClientConfig, error.kind());
+        assert_eq!(Some("connection_customizer"), error.detail());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        // Answers the connect, then stops answering.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let answered = Arc::new(AtomicBool::new(false));
+        let addr = {
+            let answered = answered.clone();
+            fake_server(listener, move |_| {
+                if answered.swap(true, Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_secs(5));
+                }
+                "+PONG\r\n".to_string()
+            })
+        };
+        let manager = RedisConnectionManager::builder()
+            .validation_timeout(Some(Duration::from_millis(50)))
+            .heartbeat_interval(Some(Duration::from_millis(20)))
+            .build_multiplexed(format!("redis://{}", addr))
+            .unwrap();
+        let conn = manager.connect().await.unwrap();
+        redis::cmd("PING")
+            .query_async::<_, String>(&mut conn.clone())
+            .await
+            .unwrap();
+        manager.is_valid(&conn).unwrap();
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        assert!(conn.is_broken());
+        assert!(manager.is_valid(&conn).is_err());
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_multiplexed_async_std() {
+        async_std::task::block_on(async {
+            let manager = RedisConnectionManager::builder()
+                .heartbeat_interval(Some(Duration::from_millis(20)))
+                .build_multiplexed("redis://localhost")
+                .unwrap();
+            let mut conn = manager.connect().await.unwrap();
+            let pong: String = redis::cmd("PING").query_async(&mut conn).await.unwrap();
+            assert_eq!("PONG", pong);
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            manager.is_valid(&conn).unwrap();
+        });
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{BlockingConnection, RedisConnection};
+
+/// A snapshot of the health of a pool and the server behind it, see
+/// `RedisPoolExt::pool_status`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStatus {
+    /// Whether a connection could be checked out and answered a `PING`.
+    pub reachable: bool,
+    /// The round-trip time of the `PING`.
+    pub latency: Option<Duration>,
+    /// The `redis_version` reported by `INFO`.
+    pub server_version: Option<String>,
+    /// The replication role reported by `INFO`, e.g. `master` or `slave`.
+    pub role: Option<String>,
+    /// Why the server is not reachable.
+    pub error: Option<String>,
+    /// The number of connections in the pool, idle or checked out, before
+    /// the check.
+    pub connections: u32,
+    /// The number of idle connections in the pool before the check.
+    pub idle_connections: u32,
+    /// The maximum number of connections of the pool.
+    pub max_size: u32,
+}
+
+/// Extension methods for pools of `RedisConnection`s, e.g. those of a
+/// `RedisConnectionManager`.
+pub trait RedisPoolExt {
+    /// The manager of the pool.
+    type Manager: r2d2::ManageConnection<Connection = RedisConnection>;
+
+    /// Checks out a connection, waiting at most `timeout`, and reports on
+    /// the health of the pool and the server.
+    ///
+    /// This never fails: problems are reported as an unreachable status.
+    fn pool_status(&self, timeout: Duration) -> PoolStatus;
+
+    /// Establishes and validates up to `n` connections concurrently, so the
+    /// pool doesn't have to open them on the first burst of requests.
+    ///
+    /// All `n` connections are checked out at once, waiting at most
+    /// `timeout` each, so `n` is capped at the pool's `max_size`.
+    fn warm_up(&self, n: u32, timeout: Duration) -> WarmUpReport;
+
+    /// Checks out a connection for commands blocking for up to `block`,
+    /// e.g. `BLPOP` or `XREAD BLOCK`, with zero meaning indefinitely.
+    ///
+    /// The connection's read timeout is extended accordingly while it is
+    /// checked out, and it is cleaned up when returned, see
+    /// `BlockingConnection`.
+    fn get_blocking(
+        &self,
+        block: Duration,
+    ) -> Result<BlockingConnection<Self::Manager>, r2d2::Error>;
+}
+
+/// The outcome of `RedisPoolExt::warm_up`.
+#[derive(Debug)]
+pub struct WarmUpReport {
+    /// The number of connections that were checked out successfully.
+    pub ready: u32,
+    /// The errors of the checkouts that failed.
+    pub errors: Vec<r2d2::Error>,
+}
+
+impl<M> RedisPoolExt for r2d2::Pool<M>
+where
+    M: r2d2::ManageConnection<Connection = RedisConnection>,
+{
+    type Manager = M;
+
+    fn pool_status(&self, timeout: Duration) -> PoolStatus {
+        let state = self.state();
+        let mut status = PoolStatus {
+            reachable: false,
+            latency: None,
+            server_version: None,
+            role: None,
+            error: None,
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_size: self.max_size(),
+        };
+
+        let mut conn = match self.get_timeout(timeout) {
+            Ok(conn) => conn,
+            Err(e) => {
+                status.error = Some(e.to_string());
+                return status;
+            }
+        };
+        let start = Instant::now();
+        if let Err(e) = redis::cmd("PING").query::<()>(&mut *conn) {
+            status.error = Some(e.to_string());
+            return status;
+        }
+        status.reachable = true;
+        status.latency = Some(start.elapsed());
+
+        if let Ok(info) = redis::cmd("INFO").arg("server").query::<String>(&mut *conn) {
+            status.server_version = info_field(&info, "redis_version");
+        }
+        if let Ok(info) = redis::cmd("INFO")
+            .arg("replication")
+            .query::<String>(&mut *conn)
+        {
+            status.role = info_field(&info, "role");
+        }
+        status
+    }
+
+    fn warm_up(&self, n: u32, timeout: Duration) -> WarmUpReport {
+        let n = n.min(self.max_size());
+        // Every connection is held until all checkouts are done, so each
+        // checkout gets a distinct connection.
+        let barrier = Barrier::new(n as usize);
+        let results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..n)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let result = self.get_timeout(timeout);
+                        barrier.wait();
+                        result.map(drop)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        let mut report = WarmUpReport {
+            ready: 0,
+            errors: vec![],
+        };
+        for result in results {
+            match result {
+                Ok(()) => report.ready += 1,
+                Err(e) => report.errors.push(e),
+            }
+        }
+        report
+    }
+
+    fn get_blocking(&self, block: Duration) -> Result<BlockingConnection<M>, r2d2::Error> {
+        Ok(BlockingConnection::new(self.get()?, block))
+    }
+}
+
+/// Checks out a connection of `pool`, reporting a failed checkout as a
+/// `RedisError`.
+pub(crate) fn checkout<M: r2d2::ManageConnection>(
+    pool: &r2d2::Pool<M>,
+) -> redis::RedisResult<r2d2::PooledConnection<M>> {
+    pool.get().map_err(checkout_error)
+}
+
+/// Converts the error of a failed checkout, e.g. by `get_blocking`, into
+/// a `RedisError`.
+pub(crate) fn checkout_error<E: fmt::Display>(e: E) -> redis::RedisError {
+    redis::RedisError::from((
+        redis::ErrorKind::IoError,
+        "couldn't check out a connection",
+        e.to_string(),
+    ))
+}
+
+/// Returns the value of `field` in an `INFO` reply.
+fn info_field(info: &str, field: &str) -> Option<String> {
+    info.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name == field {
+            Some(value.trim().to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConnectionManager;
+
+    #[test]
+    fn test_info_field() {
+        let info = "# Server\r\nredis_version:6.2.6\r\nredis_mode:standalone\r\n";
+        assert_eq!(Some("6.2.6".to_owned()), info_field(info, "redis_version"));
+        assert_eq!(None, info_field(info, "role"));
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+        let pool = r2d2::Pool::builder()
+            .max_size(4)
+            .min_idle(Some(0))
+            .build(manager)
+            .unwrap();
+
+        let report = pool.warm_up(8, Duration::from_secs(1));
+        assert_eq!(4, report.ready);
+        assert!(report.errors.is_empty());
+        assert_eq!(4, pool.state().idle_connections);
+    }
+
+    #[test]
+    fn test_pool_status() {
+        let pool = crate::local_pool(2);
+
+        let status = pool.pool_status(Duration::from_secs(1));
+        assert!(status.reachable, "{:?}", status);
+        assert!(status.latency.is_some());
+        assert!(status.server_version.is_some());
+        assert_eq!(Some("master"), status.role.as_deref());
+        assert_eq!(2, status.max_size);
+
+        let manager = RedisConnectionManager::builder()
+            .connect_timeout(Some(Duration::from_millis(100)))
+            .build("redis://127.0.0.1:1")
+            .unwrap();
+        let pool = r2d2::Pool::builder().max_size(1).build_unchecked(manager);
+
+        let status = pool.pool_status(Duration::from_millis(200));
+        assert!(!status.reachable);
+        assert!(status.error.is_some());
+    }
+}
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntGauge, Opts};
+use r2d2::event::{AcquireEvent, CheckinEvent, CheckoutEvent, ReleaseEvent, TimeoutEvent};
+
+use crate::{PoolMetricsSink, RedisConnection};
+
+/// Prometheus metrics for a pool of `RedisConnection`s.
+///
+/// The metrics are fed both as the manager's `PoolMetricsSink` and as the
+/// pool's `r2d2::HandleEvent`, and are exported by registering the value as
+/// a `prometheus::core::Collector`. Clones share the same metrics.
+///
+/// With a `namespace` of `redis_pool`, the metrics are:
+///
+/// * `redis_pool_connections`: open connections.
+/// * `redis_pool_connections_in_use`: connections checked out of the pool.
+/// * `redis_pool_connections_created_total`
+/// * `redis_pool_connect_errors_total`
+/// * `redis_pool_validation_failures_total`
+/// * `redis_pool_connections_recycled_total`: healthy connections closed
+///   because of `max_lifetime` or `max_uses`.
+/// * `redis_pool_connections_broken_total`
+/// * `redis_pool_checkout_timeouts_total`
+/// * `redis_pool_connect_duration_seconds`: a histogram of connect times.
+/// * `redis_pool_checkout_wait_seconds`: a histogram of the time checkouts
+///   waited for a connection.
+///
+/// Requires the `prometheus` feature.
+///
+/// ## Example
+///
+/// ```
+/// use redis_r2d2::{r2d2, PrometheusMetrics, RedisConnectionManager};
+///
+/// fn main() {
+///     let metrics = PrometheusMetrics::new("redis_pool").unwrap();
+///     let registry = prometheus::Registry::new();
+///     registry.register(Box::new(metrics.clone())).unwrap();
+///
+///     let manager = RedisConnectionManager::builder()
+///         .metrics_sink(Box::new(metrics.clone()))
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder()
+///         .event_handler(Box::new(metrics))
+///         .build(manager)
+///         .unwrap();
+///
+///     pool.get().unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PrometheusMetrics {
+    inner: Arc<Metrics>,
+}
+
+#[derive(Debug)]
+struct Metrics {
+    connections: IntGauge,
+    connections_in_use: IntGauge,
+    connections_created: IntCounter,
+    connect_errors: IntCounter,
+    validation_failures: IntCounter,
+    connections_recycled: IntCounter,
+    connections_broken: IntCounter,
+    checkout_timeouts: IntCounter,
+    connect_duration: Histogram,
+    checkout_wait: Histogram,
+}
+
+impl PrometheusMetrics {
+    /// Creates the metrics, with names prefixed by `namespace`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `namespace` is not a valid metric name.
+    pub fn new(namespace: &str) -> prometheus::Result<PrometheusMetrics> {
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
+        let histogram_opts =
+            |name: &str, help: &str| HistogramOpts::new(name, help).namespace(namespace);
+
+        Ok(PrometheusMetrics {
+            inner: Arc::new(Metrics {
+                connections: IntGauge::with_opts(opts("connections", "Open connections."))?,
+                connections_in_use: IntGauge::with_opts(opts(
+                    "connections_in_use",
+                    "Connections checked out of the pool.",
+                ))?,
+                connections_created: IntCounter::with_opts(opts(
+                    "connections_created_total",
+                    "Connections established.",
+                ))?,
+                connect_errors: IntCounter::with_opts(opts(
+                    "connect_errors_total",
+                    "Failed connection attempts.",
+                ))?,
+                validation_failures: IntCounter::with_opts(opts(
+                    "validation_failures_total",
+                    "Connections that failed validation on checkout.",
+                ))?,
+                connections_recycled: IntCounter::with_opts(opts(
+                    "connections_recycled_total",
+                    "Healthy connections closed after reaching their lifetime or use limit.",
+                ))?,
+                connections_broken: IntCounter::with_opts(opts(
+                    "connections_broken_total",
+                    "Connections closed because they were no longer usable.",
+                ))?,
+                checkout_timeouts: IntCounter::with_opts(opts(
+                    "checkout_timeouts_total",
+                    "Checkouts that timed out waiting for a connection.",
+                ))?,
+                connect_duration: Histogram::with_opts(histogram_opts(
+                    "connect_duration_seconds",
+                    "Time taken to establish connections.",
+                ))?,
+                checkout_wait: Histogram::with_opts(histogram_opts(
+                    "checkout_wait_seconds",
+                    "Time checkouts waited for a connection.",
+                ))?,
+            }),
+        })
+    }
+
+    fn collectors(&self) -> [&dyn Collector; 10] {
+        let m = &*self.inner;
+        [
+            &m.connections,
+            &m.connections_in_use,
+            &m.connections_created,
+            &m.connect_errors,
+            &m.validation_failures,
+            &m.connections_recycled,
+            &m.connections_broken,
+            &m.checkout_timeouts,
+            &m.connect_duration,
+            &m.checkout_wait,
+        ]
+    }
+}
+
+impl Collector for PrometheusMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors()
+            .iter()
+            .flat_map(|collector| collector.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.collectors()
+            .iter()
+            .flat_map(|collector| collector.collect())
+            .collect()
+    }
+}
+
+impl PoolMetricsSink for PrometheusMetrics {
+    fn connection_created(&self, connect_time: Duration) {
+        self.inner.connections_created.inc();
+        self.inner
+            .connect_duration
+            .observe(connect_time.as_secs_f64());
+    }
+
+    fn connect_failed(&self, _: &redis::RedisError) {
+        self.inner.connect_errors.inc();
+    }
+
+    fn validation_failed(&self, _: &redis::RedisError) {
+        self.inner.validation_failures.inc();
+    }
+
+    fn connection_recycled(&self, _: &RedisConnection) {
+        self.inner.connections_recycled.inc();
+    }
+
+    fn broken_detected(&self, _: &RedisConnection) {
+        self.inner.connections_broken.inc();
+    }
+}
+
+impl r2d2::HandleEvent for PrometheusMetrics {
+    fn handle_acquire(&self, _: AcquireEvent) {
+        self.inner.connections.inc();
+    }
+
+    fn handle_release(&self, _: ReleaseEvent) {
+        self.inner.connections.dec();
+    }
+
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        self.inner.connections_in_use.inc();
+        self.inner
+            .checkout_wait
+            .observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, _: TimeoutEvent) {
+        self.inner.checkout_timeouts.inc();
+    }
+
+    fn handle_checkin(&self, _: CheckinEvent) {
+        self.inner.connections_in_use.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConnectionManager;
+    use prometheus::proto::MetricType;
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let metrics = PrometheusMetrics::new("redis_pool").unwrap();
+        let registry = prometheus::Registry::new();
+        registry.register(Box::new(metrics.clone())).unwrap();
+
+        let manager = RedisConnectionManager::builder()
+            .metrics_sink(Box::new(metrics.clone()))
+            .build("redis://localhost")
+            .unwrap();
+        let pool = r2d2::Pool::builder()
+            .max_size(2)
+            .event_handler(Box::new(metrics.clone()))
+            .build(manager)
+            .unwrap();
+        let conn = pool.get().unwrap();
+
+        let value = |name: &str| -> f64 {
+            let family = registry
+                .gather()
+                .into_iter()
+                .find(|family| family.get_name() == name)
+                .unwrap();
+            let metric = &family.get_metric()[0];
+            match family.get_field_type() {
+                MetricType::HISTOGRAM => metric.get_histogram().get_sample_count() as f64,
+                MetricType::GAUGE => metric.get_gauge().get_value(),
+                _ => metric.get_counter().get_value(),
+            }
+        };
+
+        assert_eq!(10, registry.gather().len());
+        assert_eq!(2.0, value("redis_pool_connections"));
+        assert_eq!(1.0, value("redis_pool_connections_in_use"));
+        assert_eq!(2.0, value("redis_pool_connections_created_total"));
+        assert_eq!(2.0, value("redis_pool_connect_duration_seconds"));
+        assert_eq!(1.0, value("redis_pool_checkout_wait_seconds"));
+
+        drop(conn);
+        assert_eq!(0.0, value("redis_pool_connections_in_use"));
+    }
+}
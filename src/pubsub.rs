@@ -0,0 +1,508 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::Deref;
+use std::time::Duration;
+
+use redis::{ConnectionLike, Msg, ToRedisArgs, Value};
+
+use crate::{RedisConnection, RedisConnectionManager, ValidationMode};
+
+/// A `r2d2::ManageConnection` for connections in subscriber mode.
+///
+/// `redis::Connection::as_pubsub` borrows a connection and unsubscribes it
+/// when the borrow ends, so it can't keep subscriptions across checkouts.
+/// The `RedisPubSubConnection`s of this manager stay subscribed instead:
+/// new connections are subscribed to the channels and patterns given to
+/// `subscribe` and `psubscribe`, and further subscriptions made on a
+/// checked out connection are kept when it is returned.
+///
+/// Connections are otherwise managed like those of a
+/// `RedisConnectionManager`, configured with
+/// `RedisConnectionManagerBuilder::build_pubsub`. While a connection is
+/// subscribed, the server only accepts subscription commands and `PING`,
+/// so it is validated with a `PING` if the `ValidationMode` is `Ping`, and
+/// messages arriving before the reply are kept for `get_message`. Settings
+/// that send other commands, such as a validation command or
+/// `reset_on_checkin`, are rejected.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisPubSubConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisPubSubConnectionManager::new("redis://localhost")
+///         .unwrap()
+///         .subscribe("events");
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///
+///     let mut conn = pool.get().unwrap();
+///     let msg = conn.get_message().unwrap();
+///     let payload: String = msg.get_payload().unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RedisPubSubConnectionManager {
+    manager: RedisConnectionManager,
+    pub(crate) channels: Vec<Vec<u8>>,
+    pub(crate) patterns: Vec<Vec<u8>>,
+}
+
+impl RedisPubSubConnectionManager {
+    /// Creates a new `RedisPubSubConnectionManager`.
+    ///
+    /// See `redis::Client::open` for a description of the parameter
+    /// types.
+    pub fn new<T: redis::IntoConnectionInfo>(
+        params: T,
+    ) -> Result<RedisPubSubConnectionManager, redis::RedisError> {
+        RedisConnectionManager::builder().build_pubsub(params)
+    }
+
+    /// Creates a `RedisPubSubConnectionManager` managing connections like
+    /// `manager`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidClientConfig` error naming the first setting of
+    /// `manager` that subscribed connections can't honor.
+    pub fn from_manager(
+        manager: RedisConnectionManager,
+    ) -> Result<RedisPubSubConnectionManager, redis::RedisError> {
+        match unsupported_setting(&manager) {
+            Some(setting) => Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "setting not supported by pubsub connections",
+                setting.to_string(),
+            )
+                .into()),
+            None => Ok(RedisPubSubConnectionManager {
+                manager,
+                channels: Vec::new(),
+                patterns: Vec::new(),
+            }),
+        }
+    }
+
+    /// Subscribes new connections to `channel`, which may be several.
+    pub fn subscribe<T: ToRedisArgs>(mut self, channel: T) -> RedisPubSubConnectionManager {
+        self.channels.extend(channel.to_redis_args());
+        self
+    }
+
+    /// Subscribes new connections to the channels matching `pattern`, which
+    /// may be several.
+    pub fn psubscribe<T: ToRedisArgs>(mut self, pattern: T) -> RedisPubSubConnectionManager {
+        self.patterns.extend(pattern.to_redis_args());
+        self
+    }
+
+    /// Returns the URLs of the endpoints with any password masked, see
+    /// `RedisConnectionManager::display_safe_url`.
+    pub fn display_safe_url(&self) -> String {
+        self.manager.display_safe_url()
+    }
+
+    /// Returns a handle for draining the pool of this manager on shutdown.
+    pub fn drain_handle(&self) -> crate::DrainHandle {
+        self.manager.drain_handle()
+    }
+
+    /// Returns a handle to the circuit breaker of this manager, if one was
+    /// configured.
+    pub fn circuit_breaker_handle(&self) -> Option<crate::CircuitBreakerHandle> {
+        self.manager.circuit_breaker_handle()
+    }
+
+    pub(crate) fn validation_timeout(&self) -> Option<Duration> {
+        self.manager.validation_timeout
+    }
+
+    /// Returns the manager of the underlying connections, for commands that
+    /// can't be sent while subscribed.
+    pub(crate) fn manager(&self) -> &RedisConnectionManager {
+        &self.manager
+    }
+
+    fn validate(&self, conn: &mut RedisPubSubConnection) -> redis::RedisResult<()> {
+        self.manager.check_current(&mut conn.conn)?;
+        conn.conn.mark_checked_out();
+        if let Some(validation_interval) = self.manager.validation_interval {
+            if conn.conn.idle_time() < validation_interval {
+                return Ok(());
+            }
+        }
+        match self.manager.validation {
+            ValidationMode::Ping => conn.ping(self.manager.validation_timeout),
+            ValidationMode::CheckConnection if !conn.conn.is_open() => {
+                Err((redis::ErrorKind::IoError, "connection is closed").into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the name of the first setting of `manager` that subscribed
+/// connections can't honor.
+fn unsupported_setting(manager: &RedisConnectionManager) -> Option<&'static str> {
+    if matches!(
+        manager.validation,
+        ValidationMode::Command(_) | ValidationMode::Custom(_)
+    ) {
+        Some("validation")
+    } else if manager.server_role.is_some() {
+        Some("server_role")
+    } else if manager.reauthenticate_interval.is_some() {
+        Some("reauthenticate_interval")
+    } else if manager.reset_on_checkin {
+        Some("reset_on_checkin")
+    } else if manager.check_unread_replies {
+        Some("check_unread_replies")
+    } else {
+        None
+    }
+}
+
+impl r2d2::ManageConnection for RedisPubSubConnectionManager {
+    type Connection = RedisPubSubConnection;
+    type Error = redis::RedisError;
+
+    fn connect(&self) -> Result<RedisPubSubConnection, Self::Error> {
+        let mut conn =
+            RedisPubSubConnection::new(self.manager.connect()?, self.manager.read_timeout);
+        if !self.channels.is_empty() {
+            conn.subscribe(&self.channels[..])?;
+        }
+        if !self.patterns.is_empty() {
+            conn.psubscribe(&self.patterns[..])?;
+        }
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut RedisPubSubConnection) -> Result<(), Self::Error> {
+        if !conn.is_subscribed() {
+            return self.manager.is_valid(&mut conn.conn);
+        }
+        let result = self.validate(conn);
+        if let Err(ref e) = result {
+            self.manager.metrics_sink.validation_failed(e);
+        }
+        result
+    }
+
+    fn has_broken(&self, conn: &mut RedisPubSubConnection) -> bool {
+        self.manager.has_broken(&mut conn.conn)
+    }
+}
+
+/// A connection managed by `RedisPubSubConnectionManager`, which stays in
+/// subscriber mode while it is subscribed to any channel or pattern.
+///
+/// It dereferences to the `RedisConnection` for its statistics. Only the
+/// subscription commands can be sent through it, since the server refuses
+/// most others while the connection is subscribed.
+pub struct RedisPubSubConnection {
+    conn: RedisConnection,
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+    pending: VecDeque<Msg>,
+    read_timeout: Option<Duration>,
+}
+
+impl RedisPubSubConnection {
+    pub(crate) fn new(
+        conn: RedisConnection,
+        read_timeout: Option<Duration>,
+    ) -> RedisPubSubConnection {
+        RedisPubSubConnection {
+            conn,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            pending: VecDeque::new(),
+            read_timeout,
+        }
+    }
+
+    /// Subscribes to `channel`, which may be several.
+    pub fn subscribe<T: ToRedisArgs>(&mut self, channel: T) -> redis::RedisResult<()> {
+        let channels = channel.to_redis_args();
+        self.request("SUBSCRIBE", &channels, channels.len())?;
+        self.channels.extend(channels);
+        Ok(())
+    }
+
+    /// Subscribes to the channels matching `pattern`, which may be several.
+    pub fn psubscribe<T: ToRedisArgs>(&mut self, pattern: T) -> redis::RedisResult<()> {
+        let patterns = pattern.to_redis_args();
+        self.request("PSUBSCRIBE", &patterns, patterns.len())?;
+        self.patterns.extend(patterns);
+        Ok(())
+    }
+
+    /// Unsubscribes from `channel`, which may be several, or from every
+    /// channel if it is empty.
+    pub fn unsubscribe<T: ToRedisArgs>(&mut self, channel: T) -> redis::RedisResult<()> {
+        let channels = channel.to_redis_args();
+        let replies = replies(&channels, &self.channels);
+        self.request("UNSUBSCRIBE", &channels, replies)?;
+        if channels.is_empty() {
+            self.channels.clear();
+        }
+        for channel in &channels {
+            self.channels.remove(channel);
+        }
+        Ok(())
+    }
+
+    /// Unsubscribes from `pattern`, which may be several, or from every
+    /// pattern if it is empty.
+    pub fn punsubscribe<T: ToRedisArgs>(&mut self, pattern: T) -> redis::RedisResult<()> {
+        let patterns = pattern.to_redis_args();
+        let replies = replies(&patterns, &self.patterns);
+        self.request("PUNSUBSCRIBE", &patterns, replies)?;
+        if patterns.is_empty() {
+            self.patterns.clear();
+        }
+        for pattern in &patterns {
+            self.patterns.remove(pattern);
+        }
+        Ok(())
+    }
+
+    /// Returns the channels the connection is subscribed to.
+    pub fn channels(&self) -> impl Iterator<Item = &[u8]> {
+        self.channels.iter().map(Vec::as_slice)
+    }
+
+    /// Returns the patterns the connection is subscribed to.
+    pub fn patterns(&self) -> impl Iterator<Item = &[u8]> {
+        self.patterns.iter().map(Vec::as_slice)
+    }
+
+    /// Returns true if the connection is subscribed to any channel or
+    /// pattern.
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+
+    /// Returns the next message, waiting for one for at most the read
+    /// timeout.
+    ///
+    /// Messages that arrived while the connection was waiting for another
+    /// reply, e.g. to a `PING` on checkout, are returned first.
+    pub fn get_message(&mut self) -> redis::RedisResult<Msg> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+        loop {
+            let value = match self.conn.recv_response() {
+                Ok(value) => value,
+                Err(e) => {
+                    // Timing out between messages leaves the connection
+                    // usable.
+                    if e.is_io_error() && !e.is_timeout() {
+                        self.conn.mark_broken();
+                    }
+                    return Err(e);
+                }
+            };
+            self.conn.touch();
+            if let Some(msg) = Msg::from_value(&value) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Returns the read timeout of the connection.
+    #[cfg(feature = "serde")]
+    pub(crate) fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Sets the read timeout of the connection, for `get_message` and the
+    /// replies to subscription commands.
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> redis::RedisResult<()> {
+        self.conn.set_read_timeout(dur)?;
+        self.read_timeout = dur;
+        Ok(())
+    }
+
+    /// Sends `command` with `args` and reads its `replies` confirmations,
+    /// keeping the messages received in between.
+    fn request(
+        &mut self,
+        command: &str,
+        args: &[Vec<u8>],
+        replies: usize,
+    ) -> redis::RedisResult<()> {
+        let kind = command.to_ascii_lowercase();
+        let packed = redis::cmd(command).arg(args).get_packed_command();
+        let mut remaining = replies;
+        self.send(&packed, |value| {
+            if kind_of(value) == Some(kind.as_bytes()) {
+                remaining -= 1;
+            }
+            remaining == 0
+        })
+    }
+
+    /// Sends a `PING` and reads until its reply, failing if that takes
+    /// longer than `timeout`.
+    pub(crate) fn ping(&mut self, timeout: Option<Duration>) -> redis::RedisResult<()> {
+        if let Some(timeout) = timeout {
+            self.conn.set_read_timeout(Some(timeout))?;
+        }
+        let packed = redis::cmd("PING").get_packed_command();
+        let result = self.send(&packed, |value| kind_of(value) == Some(b"pong"));
+        if timeout.is_some() {
+            self.conn.set_read_timeout(self.read_timeout)?;
+        }
+        result
+    }
+
+    /// Sends `packed` and reads replies until `done` returns true for one,
+    /// keeping the messages among them.
+    ///
+    /// The connection is marked broken on I/O errors, as replies may be left
+    /// unread.
+    fn send<F>(&mut self, packed: &[u8], mut done: F) -> redis::RedisResult<()>
+    where
+        F: FnMut(&Value) -> bool,
+    {
+        let result: redis::RedisResult<()> = (|| {
+            self.conn.send_packed_command(packed)?;
+            loop {
+                let value = self.conn.recv_response()?;
+                match Msg::from_value(&value) {
+                    Some(msg) => self.pending.push_back(msg),
+                    None if done(&value) => return Ok(()),
+                    None => {}
+                }
+            }
+        })();
+        match result {
+            Ok(()) => self.conn.touch(),
+            Err(ref e) if e.is_io_error() => self.conn.mark_broken(),
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+/// Returns how many confirmations to expect when unsubscribing from `args`,
+/// or from all of `subscribed` if `args` is empty.
+fn replies(args: &[Vec<u8>], subscribed: &BTreeSet<Vec<u8>>) -> usize {
+    match (args.len(), subscribed.len()) {
+        (0, 0) => 1,
+        (0, n) => n,
+        (n, _) => n,
+    }
+}
+
+/// Returns the kind of a subscription reply, e.g. `subscribe` or `pong`.
+fn kind_of(value: &Value) -> Option<&[u8]> {
+    match *value {
+        Value::Bulk(ref items) => match items.first() {
+            Some(Value::Data(ref kind)) => Some(kind),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Deref for RedisPubSubConnection {
+    type Target = RedisConnection;
+
+    fn deref(&self) -> &RedisConnection {
+        &self.conn
+    }
+}
+
+impl std::fmt::Debug for RedisPubSubConnection {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("RedisPubSubConnection")
+            .field("channels", &self.channels.len())
+            .field("patterns", &self.patterns.len())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::ManageConnection;
+
+    fn publish(channel: &str, message: &str) {
+        let mut conn = redis::Client::open("redis://localhost")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        redis::cmd("PUBLISH")
+            .arg(channel)
+            .arg(message)
+            .query::<i64>(&mut conn)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let manager = RedisPubSubConnectionManager::new("redis://localhost")
+            .unwrap()
+            .subscribe("redis_r2d2-pubsub")
+            .psubscribe("redis_r2d2-pubsub.*");
+        let mut conn = manager.connect().unwrap();
+        assert!(conn.is_subscribed());
+        assert_eq!(
+            vec![&b"redis_r2d2-pubsub"[..]],
+            conn.channels().collect::<Vec<_>>()
+        );
+
+        publish("redis_r2d2-pubsub", "one");
+        publish("redis_r2d2-pubsub.b", "two");
+        // The messages published before the PING are kept.
+        manager.is_valid(&mut conn).unwrap();
+        assert!(!manager.has_broken(&mut conn));
+
+        let msg = conn.get_message().unwrap();
+        assert_eq!("redis_r2d2-pubsub", msg.get_channel_name());
+        assert_eq!("one", msg.get_payload::<String>().unwrap());
+        let msg = conn.get_message().unwrap();
+        assert!(msg.from_pattern());
+        assert_eq!("two", msg.get_payload::<String>().unwrap());
+
+        conn.set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(conn.get_message().unwrap_err().is_timeout());
+        assert!(!conn.is_broken());
+
+        conn.subscribe(&["redis_r2d2-pubsub-2", "redis_r2d2-pubsub-3"][..])
+            .unwrap();
+        assert_eq!(3, conn.channels().count());
+        conn.unsubscribe("redis_r2d2-pubsub-2").unwrap();
+        assert_eq!(2, conn.channels().count());
+        conn.unsubscribe(Vec::<String>::new()).unwrap();
+        conn.punsubscribe(Vec::<String>::new()).unwrap();
+        assert!(!conn.is_subscribed());
+
+        // Unsubscribed connections are validated like any other.
+        manager.is_valid(&mut conn).unwrap();
+        let pong: String = redis::cmd("PING").query(&mut conn.conn).unwrap();
+        assert_eq!("PONG", pong);
+    }
+
+    #[test]
+    fn test_unsupported_settings() {
+        let e = RedisConnectionManager::builder()
+            .reset_on_checkin(true)
+            .build_pubsub("redis://localhost")
+            .unwrap_err();
+        assert_eq!(redis::ErrorKind::InvalidClientConfig, e.kind());
+        assert_eq!(Some("reset_on_checkin"), e.detail());
+
+        let e = RedisConnectionManager::builder()
+            .validation(ValidationMode::Command(redis::cmd("INFO")))
+            .build_pubsub("redis://localhost")
+            .unwrap_err();
+        assert_eq!(Some("validation"), e.detail());
+    }
+}
@@ -0,0 +1,617 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::backoff::Backoff;
+use crate::pool_ext::{checkout, checkout_error};
+use crate::{Codec, JsonCodec, ReconnectPolicy, RedisConnectionManager, RedisPoolExt};
+
+/// Moves a job from a processing list back to the pending list, unless it
+/// was acknowledged or requeued already, and drops its lease.
+///
+/// `KEYS`: the processing list, the pending list, the leases. `ARGV`: the
+/// job. Returns whether the job was requeued.
+const REQUEUE_SCRIPT: &str = r#"
+local requeued = 0
+if redis.call("lrem", KEYS[1], 1, ARGV[1]) > 0 then
+    redis.call("lpush", KEYS[2], ARGV[1])
+    requeued = 1
+end
+redis.call("zrem", KEYS[3], ARGV[1])
+return requeued
+"#;
+
+/// A job taken from a `ReliableQueue`, to acknowledge with
+/// `ReliableQueue::ack` once it is done.
+#[derive(Debug, Clone)]
+pub struct Job<T> {
+    pub(crate) id: String,
+    data: T,
+    pub(crate) worker: String,
+    pub(crate) raw: Vec<u8>,
+}
+
+impl<T> Job<T> {
+    /// Returns the ID `ReliableQueue::push` returned for the job.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the data of the job.
+    pub fn data(&self) -> &T {
+        &self.data
+    }
+
+    /// Returns the data of the job, consuming it.
+    pub fn into_data(self) -> T {
+        self.data
+    }
+}
+
+/// A job queue whose jobs survive the crash of the worker handling them.
+///
+/// Jobs are pushed to the list `<name>:pending`. A worker takes each one
+/// with `BLMOVE` (Redis 6.2 and later) into its own list,
+/// `<name>:processing:<worker>`, atomically, so a job is always in a list,
+/// and leases it for the `visibility_timeout`. Once done, the job is
+/// acknowledged, which removes it, or requeued to the back of the queue. The
+/// jobs whose lease expired, e.g. because their worker crashed, are requeued
+/// by `sweep`, which workers started with `worker` also run regularly.
+///
+/// Jobs are therefore delivered at least once, and may be handled again if
+/// handling one takes longer than the visibility timeout, so handlers must
+/// be idempotent. Leases are timed with the clocks of the workers, which
+/// should be in sync. Jobs whose data can't be decoded are moved to
+/// `<name>:dead`.
+///
+/// Requires the `serde` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, ReliableQueue};
+///
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct Email {
+///     to: String,
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let queue = ReliableQueue::<Email>::new(pool, "emails");
+///     queue.push(&Email { to: "ada@example.com".to_string() }).unwrap();
+///
+///     let worker = queue
+///         .worker("mailer-1")
+///         .start(|job| {
+///             println!("sending to {}", job.data().to);
+///             Ok::<(), String>(())
+///         });
+///     // ...
+///     worker.stop();
+/// }
+/// ```
+pub struct ReliableQueue<T, C = JsonCodec> {
+    pub(crate) pool: r2d2::Pool<RedisConnectionManager>,
+    name: String,
+    codec: C,
+    visibility_timeout: Duration,
+    _job: PhantomData<fn(&T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> ReliableQueue<T> {
+    /// Creates a `ReliableQueue` named `name` of JSON jobs, with connections
+    /// of `pool`.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        name: S,
+    ) -> ReliableQueue<T> {
+        ReliableQueue::with_codec(pool, name, JsonCodec)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> ReliableQueue<T, C> {
+    /// Creates a `ReliableQueue` named `name` of jobs encoded with `codec`,
+    /// with connections of `pool`.
+    pub fn with_codec<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        name: S,
+        codec: C,
+    ) -> ReliableQueue<T, C> {
+        ReliableQueue {
+            pool,
+            name: name.into(),
+            codec,
+            visibility_timeout: Duration::from_secs(60),
+            _job: PhantomData,
+        }
+    }
+
+    /// Sets how long a worker may take to acknowledge a job before `sweep`
+    /// requeues it.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn visibility_timeout(mut self, visibility_timeout: Duration) -> ReliableQueue<T, C> {
+        self.visibility_timeout = visibility_timeout;
+        self
+    }
+
+    /// Adds a job holding `data` to the queue, returning its ID.
+    pub fn push(&self, data: &T) -> redis::RedisResult<String> {
+        let (id, raw) = self.envelope(data)?;
+        redis::cmd("LPUSH")
+            .arg(self.key("pending"))
+            .arg(raw)
+            .query::<()>(&mut *checkout(&self.pool)?)?;
+        Ok(id)
+    }
+
+    /// Returns the number of jobs waiting to be taken.
+    pub fn len(&self) -> redis::RedisResult<u64> {
+        redis::cmd("LLEN")
+            .arg(self.key("pending"))
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns whether no job is waiting to be taken.
+    pub fn is_empty(&self) -> redis::RedisResult<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Takes the oldest job for `worker`, waiting up to `timeout` for one,
+    /// with zero meaning indefinitely, and returns `None` if there was none.
+    ///
+    /// The wait is done on a connection checked out with
+    /// `RedisPoolExt::get_blocking`, so the pool's read timeout may be
+    /// shorter.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `TypeError` if the data of the job can't be
+    /// decoded; the job is then moved to `<name>:dead`.
+    pub fn reserve(&self, worker: &str, timeout: Duration) -> redis::RedisResult<Option<Job<T>>> {
+        let processing = self.processing_key(worker);
+        let raw: Option<Vec<u8>> = {
+            let mut conn = self.pool.get_blocking(timeout).map_err(checkout_error)?;
+            let mut pipe = redis::pipe();
+            pipe.cmd("SADD")
+                .arg(self.key("workers"))
+                .arg(worker)
+                .ignore()
+                .cmd("BLMOVE")
+                .arg(self.key("pending"))
+                .arg(&processing)
+                .arg("RIGHT")
+                .arg("LEFT")
+                .arg(timeout.as_secs_f64());
+            let (raw,) = pipe.query(&mut conn)?;
+            raw
+        };
+        let raw = match raw {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let mut conn = checkout(&self.pool)?;
+        redis::cmd("ZADD")
+            .arg(self.key("leases"))
+            .arg(now_ms() + self.visibility_timeout.as_millis() as u64)
+            .arg(&raw[..])
+            .query::<()>(&mut *conn)?;
+
+        match self.decode(&raw) {
+            Ok((id, data)) => Ok(Some(Job {
+                id,
+                data,
+                worker: worker.to_string(),
+                raw,
+            })),
+            Err(e) => {
+                let mut pipe = redis::pipe();
+                pipe.atomic()
+                    .cmd("LREM")
+                    .arg(&processing)
+                    .arg(1)
+                    .arg(&raw[..])
+                    .ignore()
+                    .cmd("LPUSH")
+                    .arg(self.key("dead"))
+                    .arg(&raw[..])
+                    .ignore()
+                    .cmd("ZREM")
+                    .arg(self.key("leases"))
+                    .arg(&raw[..])
+                    .ignore();
+                pipe.query::<()>(&mut *conn)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes `job` for good, returning false if its lease had expired and
+    /// it was requeued in the meantime.
+    pub fn ack(&self, job: &Job<T>) -> redis::RedisResult<bool> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("LREM")
+            .arg(self.processing_key(&job.worker))
+            .arg(1)
+            .arg(&job.raw[..])
+            .cmd("ZREM")
+            .arg(self.key("leases"))
+            .arg(&job.raw[..])
+            .ignore();
+        let (removed,): (u32,) = pipe.query(&mut *checkout(&self.pool)?)?;
+        Ok(removed > 0)
+    }
+
+    /// Puts `job` back at the end of the queue, e.g. because it failed,
+    /// returning false if its lease had expired and it was requeued already.
+    pub fn requeue(&self, job: &Job<T>) -> redis::RedisResult<bool> {
+        self.requeue_raw(&job.worker, &job.raw)
+    }
+
+    /// Requeues the jobs whose lease expired, returning how many there were.
+    ///
+    /// A job taken by a worker that crashed before leasing it gets a lease
+    /// instead, so it is requeued by a later sweep.
+    pub fn sweep(&self) -> redis::RedisResult<u64> {
+        let mut conn = checkout(&self.pool)?;
+        let workers: Vec<String> = redis::cmd("SMEMBERS")
+            .arg(self.key("workers"))
+            .query(&mut *conn)?;
+        let now = now_ms();
+        let mut requeued = 0;
+        for worker in workers {
+            let jobs: Vec<Vec<u8>> = redis::cmd("LRANGE")
+                .arg(self.processing_key(&worker))
+                .arg(0)
+                .arg(-1)
+                .query(&mut *conn)?;
+            for raw in jobs {
+                let deadline: Option<u64> = redis::cmd("ZSCORE")
+                    .arg(self.key("leases"))
+                    .arg(&raw[..])
+                    .query(&mut *conn)?;
+                match deadline {
+                    Some(deadline) if deadline <= now => {
+                        if self.requeue_raw(&worker, &raw)? {
+                            requeued += 1;
+                        }
+                    }
+                    Some(_) => {}
+                    None => redis::cmd("ZADD")
+                        .arg(self.key("leases"))
+                        .arg("NX")
+                        .arg(now + self.visibility_timeout.as_millis() as u64)
+                        .arg(&raw[..])
+                        .query(&mut *conn)?,
+                }
+            }
+        }
+        Ok(requeued)
+    }
+
+    fn requeue_raw(&self, worker: &str, raw: &[u8]) -> redis::RedisResult<bool> {
+        redis::Script::new(REQUEUE_SCRIPT)
+            .key(self.processing_key(worker))
+            .key(self.key("pending"))
+            .key(self.key("leases"))
+            .arg(raw)
+            .invoke(&mut *checkout(&self.pool)?)
+    }
+
+    fn decode(&self, raw: &[u8]) -> redis::RedisResult<(String, T)> {
+        let colon = raw
+            .iter()
+            .position(|&byte| byte == b':')
+            .ok_or_else(|| redis::RedisError::from((redis::ErrorKind::TypeError, "invalid job")))?;
+        let id = String::from_utf8_lossy(&raw[..colon]).into_owned();
+        Ok((id, self.codec.decode(&raw[colon + 1..])?))
+    }
+
+    /// Returns a new ID for a job holding `data`, and what is stored for it.
+    pub(crate) fn envelope(&self, data: &T) -> redis::RedisResult<(String, Vec<u8>)> {
+        let id = crate::lock::token();
+        let mut raw = format!("{}:", id).into_bytes();
+        raw.extend(self.codec.encode(data)?);
+        Ok((id, raw))
+    }
+
+    pub(crate) fn key(&self, suffix: &str) -> String {
+        format!("{}:{}", self.name, suffix)
+    }
+
+    pub(crate) fn processing_key(&self, worker: &str) -> String {
+        format!("{}:processing:{}", self.name, worker)
+    }
+}
+
+impl<T, C: Clone> ReliableQueue<T, C> {
+    /// Returns a builder for a worker named `worker`, which must be unique
+    /// among the workers of the queue and should be stable across restarts,
+    /// e.g. a host name.
+    pub fn worker<S: Into<String>>(&self, worker: S) -> QueueWorker<T, C> {
+        QueueWorker {
+            queue: self.clone(),
+            name: worker.into(),
+            block: Duration::from_secs(2),
+            sweep_interval: Some(Duration::from_secs(30)),
+            reconnect_policy: ReconnectPolicy::default(),
+        }
+    }
+}
+
+impl<T, C: Clone> Clone for ReliableQueue<T, C> {
+    fn clone(&self) -> ReliableQueue<T, C> {
+        ReliableQueue {
+            pool: self.pool.clone(),
+            name: self.name.clone(),
+            codec: self.codec.clone(),
+            visibility_timeout: self.visibility_timeout,
+            _job: PhantomData,
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for ReliableQueue<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ReliableQueue")
+            .field("pool", &self.pool)
+            .field("name", &self.name)
+            .field("codec", &self.codec)
+            .field("visibility_timeout", &self.visibility_timeout)
+            .finish()
+    }
+}
+
+/// A builder for a worker of a `ReliableQueue`, see `ReliableQueue::worker`.
+pub struct QueueWorker<T, C = JsonCodec> {
+    queue: ReliableQueue<T, C>,
+    name: String,
+    block: Duration,
+    sweep_interval: Option<Duration>,
+    reconnect_policy: ReconnectPolicy,
+}
+
+impl<T, C> QueueWorker<T, C>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+    C: Codec + Send + 'static,
+{
+    /// Sets how long the worker waits for a job before checking whether it
+    /// was stopped, and sweeping the queue if it is time to.
+    ///
+    /// Defaults to 2 seconds.
+    pub fn block(mut self, block: Duration) -> QueueWorker<T, C> {
+        self.block = block.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Sets how often the worker runs `ReliableQueue::sweep`, or disables
+    /// sweeping, e.g. because another process does it.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn sweep_interval(mut self, sweep_interval: Option<Duration>) -> QueueWorker<T, C> {
+        self.sweep_interval = sweep_interval;
+        self
+    }
+
+    /// Sets how connection failures are retried.
+    pub fn reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> QueueWorker<T, C> {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// Starts passing jobs to `handler` on a background thread, until the
+    /// returned `QueueWorkerHandle` is stopped or dropped.
+    ///
+    /// Jobs `handler` succeeds for are acknowledged, and the others requeued.
+    pub fn start<F, E>(self, handler: F) -> QueueWorkerHandle
+    where
+        F: FnMut(&Job<T>) -> Result<(), E> + Send + 'static,
+        E: fmt::Display,
+    {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || self.run(handler, &stopped))
+        };
+        QueueWorkerHandle {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    fn run<F, E>(self, mut handler: F, stopped: &AtomicBool)
+    where
+        F: FnMut(&Job<T>) -> Result<(), E>,
+        E: fmt::Display,
+    {
+        let backoff = Backoff::new(self.reconnect_policy);
+        let mut last_swept: Option<Instant> = None;
+        while !stopped.load(Ordering::Relaxed) {
+            backoff.wait();
+            let result = (|| {
+                if let Some(interval) = self.sweep_interval {
+                    let due = match last_swept {
+                        Some(last) => last.elapsed() >= interval,
+                        None => true,
+                    };
+                    if due {
+                        self.queue.sweep()?;
+                        last_swept = Some(Instant::now());
+                    }
+                }
+                let job = match self.queue.reserve(&self.name, self.block) {
+                    Ok(Some(job)) => job,
+                    Ok(None) => return Ok(()),
+                    Err(ref e) if e.kind() == redis::ErrorKind::TypeError => {
+                        log::warn!("queue worker {} dropped a job: {}", self.name, e);
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
+                match handler(&job) {
+                    Ok(()) => self.queue.ack(&job).map(drop),
+                    Err(e) => {
+                        log::warn!(
+                            "queue worker {} failed to handle {}: {}",
+                            self.name,
+                            job.id,
+                            e
+                        );
+                        self.queue.requeue(&job).map(drop)
+                    }
+                }
+            })();
+            match result {
+                Ok(()) => backoff.succeeded(),
+                Err(e) => {
+                    log::warn!("queue worker {} failed: {}", self.name, e);
+                    backoff.failed();
+                }
+            }
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for QueueWorker<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("QueueWorker")
+            .field("queue", &self.queue)
+            .field("name", &self.name)
+            .field("block", &self.block)
+            .field("sweep_interval", &self.sweep_interval)
+            .finish()
+    }
+}
+
+/// Runs a `QueueWorker` until it is stopped or dropped.
+#[derive(Debug)]
+pub struct QueueWorkerHandle {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl QueueWorkerHandle {
+    /// Stops the worker, waiting for the job being handled, if any, to be
+    /// acknowledged or requeued, and for the current wait to return.
+    ///
+    /// Dropping the handle stops the worker without waiting.
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for QueueWorkerHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Task {
+        n: u32,
+    }
+
+    fn queue() -> ReliableQueue<Task> {
+        let pool = crate::local_pool(3);
+        let name = format!("redis_r2d2-queue-{}", crate::lock::token());
+        ReliableQueue::new(pool, name)
+    }
+
+    #[test]
+    fn test_ack_and_requeue() {
+        let queue = queue().visibility_timeout(Duration::from_millis(100));
+        assert!(queue
+            .reserve("w1", Duration::from_millis(50))
+            .unwrap()
+            .is_none());
+        let first = queue.push(&Task { n: 1 }).unwrap();
+        queue.push(&Task { n: 2 }).unwrap();
+        assert_eq!(2, queue.len().unwrap());
+
+        let job = queue
+            .reserve("w1", Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, job.id());
+        assert_eq!(&Task { n: 1 }, job.data());
+        assert!(queue.requeue(&job).unwrap());
+        assert!(!queue.ack(&job).unwrap());
+
+        // The requeued job goes to the back of the queue.
+        let job = queue
+            .reserve("w1", Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, job.data().n);
+        assert!(queue.ack(&job).unwrap());
+
+        // A job whose lease expired is requeued by the sweeper.
+        let job = queue
+            .reserve("w2", Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(0, queue.sweep().unwrap());
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(1, queue.sweep().unwrap());
+        assert!(!queue.ack(&job).unwrap());
+        let job = queue
+            .reserve("w1", Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, job.id());
+        assert!(queue.ack(&job).unwrap());
+        assert!(queue.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_worker() {
+        let queue = queue();
+        for n in 0..5 {
+            queue.push(&Task { n }).unwrap();
+        }
+        let (handled, received) = mpsc::channel();
+        let mut failed_once = false;
+        let worker = queue
+            .worker("w1")
+            .block(Duration::from_millis(100))
+            .start(move |job| {
+                if job.data().n == 2 && !failed_once {
+                    failed_once = true;
+                    return Err("try again");
+                }
+                handled.send(job.data().n).unwrap();
+                Ok(())
+            });
+        let ns: Vec<u32> = (0..5)
+            .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        worker.stop();
+        assert_eq!(vec![0, 1, 3, 4, 2], ns);
+        assert!(queue.is_empty().unwrap());
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits how fast `RedisConnectionManager` establishes new connections.
+///
+/// At most `max_in_flight` connection attempts run at the same time, and
+/// each attempt starts at least `min_interval` after the previous one, so
+/// many pools starting at once don't overwhelm a small server with
+/// handshakes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRateLimit {
+    /// The number of connection attempts allowed to run concurrently.
+    pub max_in_flight: usize,
+    /// The minimum time between the start of two connection attempts.
+    pub min_interval: Duration,
+}
+
+impl Default for ConnectRateLimit {
+    fn default() -> ConnectRateLimit {
+        ConnectRateLimit {
+            max_in_flight: 1,
+            min_interval: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Hands out permits to connect according to a `ConnectRateLimit`.
+#[derive(Debug)]
+pub(crate) struct ConnectLimiter {
+    limit: ConnectRateLimit,
+    state: Mutex<ConnectLimiterState>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ConnectLimiterState {
+    in_flight: usize,
+    last_start: Option<Instant>,
+}
+
+/// Allows one connection attempt, until dropped.
+pub(crate) struct Permit<'a> {
+    limiter: &'a ConnectLimiter,
+}
+
+impl ConnectLimiter {
+    pub(crate) fn new(limit: ConnectRateLimit) -> ConnectLimiter {
+        ConnectLimiter {
+            limit,
+            state: Mutex::new(ConnectLimiterState::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a connection attempt may start.
+    pub(crate) fn acquire(&self) -> Permit<'_> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.in_flight >= self.limit.max_in_flight {
+                state = self.released.wait(state).unwrap();
+                continue;
+            }
+            let now = Instant::now();
+            if let Some(last_start) = state.last_start {
+                let next_start = last_start + self.limit.min_interval;
+                if now < next_start {
+                    state = self
+                        .released
+                        .wait_timeout(state, next_start - now)
+                        .unwrap()
+                        .0;
+                    continue;
+                }
+            }
+            state.in_flight += 1;
+            state.last_start = Some(now);
+            return Permit { limiter: self };
+        }
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = ConnectLimiter::new(ConnectRateLimit {
+            max_in_flight: 2,
+            min_interval: Duration::from_millis(20),
+        });
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let starts = Mutex::new(Vec::new());
+
+        thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| {
+                    let _permit = limiter.acquire();
+                    starts.lock().unwrap().push(Instant::now());
+                    let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(n, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(30));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert!(max_in_flight.load(Ordering::SeqCst) <= 2);
+        let starts = starts.into_inner().unwrap();
+        let first = starts.iter().min().unwrap();
+        let last = starts.iter().max().unwrap();
+        assert!(*last - *first >= Duration::from_millis(90));
+    }
+}
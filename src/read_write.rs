@@ -0,0 +1,256 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use r2d2::{ManageConnection, Pool, PooledConnection};
+
+use crate::RedisConnectionManager;
+
+/// Where `ReadWritePool::get_read` checks out connections from.
+///
+/// The preference is evaluated on every checkout, so a fallback only lasts
+/// as long as the preferred pool can't provide a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadPreference {
+    /// Reads are served by the primary.
+    Primary,
+    /// Reads are served by the replicas, or by the primary when the replica
+    /// pool can't provide a connection. This is the default.
+    #[default]
+    PreferReplica,
+    /// Reads are served by the replicas only.
+    ReplicaOnly,
+    /// Reads are served by whichever pool recently provided connections
+    /// faster, or by the other one when it can't provide a connection.
+    ///
+    /// Since pools validate connections on checkout by default, the
+    /// checkout time mostly reflects the round-trip time to the server.
+    /// Every 16th read goes to the other pool to keep its estimate current.
+    Nearest,
+}
+
+/// How often `ReadPreference::Nearest` tries the pool it doesn't prefer.
+const EXPLORE_EVERY: usize = 16;
+
+/// A pair of pools, one for writes to the primary and one for reads from
+/// replicas.
+///
+/// Where reads are served from is governed by a `ReadPreference`. Give
+/// the pools short connection timeouts so reads falling back from one pool
+/// to the other don't wait long for servers that are down.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, ReadWritePool, RedisConnectionManager, ServerRole};
+///
+/// fn main() {
+///     let primary = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let replica = RedisConnectionManager::builder()
+///         .server_role(Some(ServerRole::Replica))
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = ReadWritePool::new(
+///         r2d2::Pool::builder().build(primary).unwrap(),
+///         r2d2::Pool::builder()
+///             .connection_timeout(Duration::from_millis(100))
+///             .build_unchecked(replica),
+///     );
+///
+///     let mut conn = pool.get_read().unwrap();
+///     redis::cmd("GET").arg("key").query::<Option<String>>(&mut *conn).unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ReadWritePool<M: ManageConnection = RedisConnectionManager> {
+    primary: Pool<M>,
+    replicas: Pool<M>,
+    read_preference: ReadPreference,
+    latencies: Arc<Latencies>,
+}
+
+/// Moving averages of the checkout times of the pools, in nanoseconds, or 0
+/// if unknown.
+#[derive(Debug, Default)]
+struct Latencies {
+    primary: AtomicU64,
+    replicas: AtomicU64,
+    reads: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Primary,
+    Replicas,
+}
+
+impl Side {
+    fn other(self) -> Side {
+        match self {
+            Side::Primary => Side::Replicas,
+            Side::Replicas => Side::Primary,
+        }
+    }
+}
+
+impl<M: ManageConnection> Clone for ReadWritePool<M> {
+    fn clone(&self) -> ReadWritePool<M> {
+        ReadWritePool {
+            primary: self.primary.clone(),
+            replicas: self.replicas.clone(),
+            read_preference: self.read_preference,
+            latencies: self.latencies.clone(),
+        }
+    }
+}
+
+impl<M: ManageConnection> ReadWritePool<M> {
+    /// Creates a `ReadWritePool` writing to `primary` and reading from
+    /// `replicas`.
+    pub fn new(primary: Pool<M>, replicas: Pool<M>) -> ReadWritePool<M> {
+        ReadWritePool {
+            primary,
+            replicas,
+            read_preference: ReadPreference::default(),
+            latencies: Arc::new(Latencies::default()),
+        }
+    }
+
+    /// Sets where reads are served from.
+    ///
+    /// Defaults to `ReadPreference::PreferReplica`.
+    pub fn read_preference(mut self, read_preference: ReadPreference) -> ReadWritePool<M> {
+        self.read_preference = read_preference;
+        self
+    }
+
+    /// Checks out a connection to the primary.
+    pub fn get_write(&self) -> Result<PooledConnection<M>, r2d2::Error> {
+        self.checkout(Side::Primary)
+    }
+
+    /// Checks out a connection for reads, according to the
+    /// `ReadPreference`.
+    pub fn get_read(&self) -> Result<PooledConnection<M>, r2d2::Error> {
+        let first = match self.read_preference {
+            ReadPreference::Primary => return self.checkout(Side::Primary),
+            ReadPreference::ReplicaOnly => return self.checkout(Side::Replicas),
+            ReadPreference::PreferReplica => Side::Replicas,
+            ReadPreference::Nearest => self.nearest(),
+        };
+        self.checkout(first)
+            .or_else(|_| self.checkout(first.other()))
+    }
+
+    /// Returns the pool of the primary.
+    pub fn primary(&self) -> &Pool<M> {
+        &self.primary
+    }
+
+    /// Returns the pool of the replicas.
+    pub fn replicas(&self) -> &Pool<M> {
+        &self.replicas
+    }
+
+    fn nearest(&self) -> Side {
+        let primary = self.latencies.primary.load(Ordering::Relaxed);
+        let replicas = self.latencies.replicas.load(Ordering::Relaxed);
+        let nearest = if primary < replicas {
+            Side::Primary
+        } else {
+            Side::Replicas
+        };
+        let reads = self.latencies.reads.fetch_add(1, Ordering::Relaxed);
+        if reads % EXPLORE_EVERY == EXPLORE_EVERY - 1 {
+            nearest.other()
+        } else {
+            nearest
+        }
+    }
+
+    fn checkout(&self, side: Side) -> Result<PooledConnection<M>, r2d2::Error> {
+        let (pool, latency) = match side {
+            Side::Primary => (&self.primary, &self.latencies.primary),
+            Side::Replicas => (&self.replicas, &self.latencies.replicas),
+        };
+        let start = Instant::now();
+        let conn = pool.get()?;
+        record(latency, start.elapsed());
+        Ok(conn)
+    }
+}
+
+/// Adds `sample` to the moving average in `latency`.
+fn record(latency: &AtomicU64, sample: Duration) {
+    let sample = u64::try_from(sample.as_nanos()).unwrap_or(u64::MAX).max(1);
+    let _ = latency.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+        Some(if average == 0 {
+            sample
+        } else {
+            average - average / 8 + sample / 8
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::ConnectionLike;
+
+    #[test]
+    fn test_read_write_pool() {
+        let pool = |url: &str| {
+            r2d2::Pool::builder()
+                .max_size(1)
+                .connection_timeout(Duration::from_millis(100))
+                .build_unchecked(RedisConnectionManager::new(url).unwrap())
+        };
+
+        let rw = ReadWritePool::new(pool("redis://localhost"), pool("redis://localhost/1"));
+        assert_eq!(0, rw.get_write().unwrap().get_db());
+        assert_eq!(1, rw.get_read().unwrap().get_db());
+
+        let rw = ReadWritePool::new(pool("redis://localhost"), pool("redis://127.0.0.1:1"));
+        assert_eq!(0, rw.get_read().unwrap().get_db());
+        assert!(rw
+            .clone()
+            .read_preference(ReadPreference::ReplicaOnly)
+            .get_read()
+            .is_err());
+        let nearest = rw.read_preference(ReadPreference::Nearest);
+        for _ in 0..20 {
+            assert_eq!(0, nearest.get_read().unwrap().get_db());
+        }
+    }
+
+    #[test]
+    fn test_read_preference() {
+        let pool = |url: &str| {
+            r2d2::Pool::builder()
+                .max_size(1)
+                .build(RedisConnectionManager::new(url).unwrap())
+                .unwrap()
+        };
+        let rw = ReadWritePool::new(pool("redis://localhost"), pool("redis://localhost/1"));
+
+        let db = |preference| {
+            rw.clone()
+                .read_preference(preference)
+                .get_read()
+                .unwrap()
+                .get_db()
+        };
+        assert_eq!(0, db(ReadPreference::Primary));
+        assert_eq!(1, db(ReadPreference::PreferReplica));
+        assert_eq!(1, db(ReadPreference::ReplicaOnly));
+
+        let latency = AtomicU64::new(0);
+        record(&latency, Duration::from_nanos(800));
+        assert_eq!(800, latency.load(Ordering::Relaxed));
+        record(&latency, Duration::from_nanos(1600));
+        assert_eq!(900, latency.load(Ordering::Relaxed));
+    }
+}
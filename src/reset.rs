@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Returns a connection to a clean state before it goes back to the pool.
+///
+/// Uses `RESET` where the server supports it (Redis 6.2 and later) and
+/// remembers in `supports_reset` if it does not. Returns true if `RESET` was
+/// used, in which case the caller must restore authentication, the selected
+/// database and anything else established on connect.
+///
+/// Without `RESET` this discards an open transaction, unwatches keys and
+/// leaves any subscriptions. A connection left in `CLIENT REPLY OFF` mode
+/// cannot be repaired this way.
+pub(crate) fn reset(
+    conn: &mut redis::Connection,
+    supports_reset: &AtomicBool,
+) -> redis::RedisResult<bool> {
+    if supports_reset.load(Ordering::Relaxed) {
+        match redis::cmd("RESET").query::<()>(conn) {
+            Ok(()) => return Ok(true),
+            // An `ERR` reply can only mean the command is unknown.
+            Err(ref e) if e.kind() == redis::ErrorKind::ResponseError => {
+                supports_reset.store(false, Ordering::Relaxed);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    ignore_response_error(redis::cmd("DISCARD").query::<()>(conn))?;
+    redis::cmd("UNWATCH").query::<()>(conn)?;
+    unsubscribe(conn)?;
+    Ok(false)
+}
+
+/// Leaves all channel and pattern subscriptions, reading replies until the
+/// server reports none are left.
+fn unsubscribe(conn: &mut redis::Connection) -> redis::RedisResult<()> {
+    conn.send_packed_command(&redis::cmd("UNSUBSCRIBE").get_packed_command())?;
+    conn.send_packed_command(&redis::cmd("PUNSUBSCRIBE").get_packed_command())?;
+
+    let mut unsubscribed = false;
+    let mut punsubscribed = false;
+    loop {
+        let (kind, _, remaining): (Vec<u8>, (), isize) =
+            redis::from_redis_value(&conn.recv_response()?)?;
+        match &kind[..] {
+            b"unsubscribe" => unsubscribed = true,
+            b"punsubscribe" => punsubscribed = true,
+            _ => {}
+        }
+        if unsubscribed && punsubscribed && remaining == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn ignore_response_error(result: redis::RedisResult<()>) -> redis::RedisResult<()> {
+    match result {
+        Err(ref e) if e.kind() == redis::ErrorKind::ResponseError => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// A trait which allows for custom resolution of the host names
+/// `RedisConnectionManager` connects to.
+///
+/// The resolver is consulted on every connection attempt to a `redis://`
+/// endpoint, and the addresses it returns are tried in order until one
+/// accepts the connection. It is not used for TLS endpoints, whose host name
+/// is needed to verify the certificate, or for Unix sockets.
+///
+/// Without a resolver the system resolver is asked afresh on every
+/// connection attempt as well, so a resolver is only needed to change how
+/// names are resolved.
+///
+/// ## Example
+///
+/// ```
+/// use std::io;
+/// use std::net::SocketAddr;
+///
+/// use redis_r2d2::{r2d2, RedisConnectionManager, Resolver};
+///
+/// #[derive(Debug)]
+/// struct Static(SocketAddr);
+///
+/// impl Resolver for Static {
+///     fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+///         Ok(vec![self.0])
+///     }
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::builder()
+///         .resolver(Box::new(Static("127.0.0.1:6379".parse().unwrap())))
+///         .build("redis://redis.internal")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder()
+///         .build(manager)
+///         .unwrap();
+///
+///     pool.get().unwrap();
+/// }
+/// ```
+pub trait Resolver: fmt::Debug + Send + Sync + 'static {
+    /// Returns the addresses to try for `host` and `port`, in order.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection attempt fails with it.
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// A `Resolver` which asks the operating system, like `redis` itself does.
+#[derive(Copy, Clone, Debug)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// Which IP versions `RedisConnectionManager` connects over.
+///
+/// Applied to the addresses a host name resolves to, which are otherwise
+/// tried in the order the resolver returns them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    /// Use addresses in the order they are resolved. This is the default.
+    #[default]
+    Any,
+    /// Try IPv4 addresses before IPv6 addresses.
+    PreferIpv4,
+    /// Try IPv6 addresses before IPv4 addresses.
+    PreferIpv6,
+    /// Only use IPv4 addresses.
+    Ipv4Only,
+    /// Only use IPv6 addresses.
+    Ipv6Only,
+}
+
+impl AddressFamily {
+    /// Orders and filters `addrs` according to the preference.
+    pub(crate) fn apply(self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            AddressFamily::Any => {}
+            AddressFamily::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            AddressFamily::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+            AddressFamily::Ipv4Only => addrs.retain(|addr| addr.is_ipv4()),
+            AddressFamily::Ipv6Only => addrs.retain(|addr| addr.is_ipv6()),
+        }
+        addrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_family() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "127.0.0.1:1", "[::1]:2", "127.0.0.1:2"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let apply = |family: AddressFamily| -> Vec<String> {
+            family
+                .apply(addrs.clone())
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            vec!["[::1]:1", "127.0.0.1:1", "[::1]:2", "127.0.0.1:2"],
+            apply(AddressFamily::Any)
+        );
+        assert_eq!(
+            vec!["127.0.0.1:1", "127.0.0.1:2", "[::1]:1", "[::1]:2"],
+            apply(AddressFamily::PreferIpv4)
+        );
+        assert_eq!(
+            vec!["[::1]:1", "[::1]:2", "127.0.0.1:1", "127.0.0.1:2"],
+            apply(AddressFamily::PreferIpv6)
+        );
+        assert_eq!(
+            vec!["127.0.0.1:1", "127.0.0.1:2"],
+            apply(AddressFamily::Ipv4Only)
+        );
+        assert_eq!(vec!["[::1]:1", "[::1]:2"], apply(AddressFamily::Ipv6Only));
+    }
+
+    #[test]
+    fn test_system_resolver() {
+        let addrs = SystemResolver.resolve("127.0.0.1", 6379).unwrap();
+        assert_eq!(vec!["127.0.0.1:6379".parse::<SocketAddr>().unwrap()], addrs);
+
+        let addrs = SystemResolver.resolve("localhost", 6379).unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+    }
+}
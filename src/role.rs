@@ -0,0 +1,36 @@
+/// The replication role a server is required to have, see
+/// `RedisConnectionManagerBuilder::server_role`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerRole {
+    /// A master, which accepts writes.
+    Master,
+    /// A replica, for read-only connections.
+    Replica,
+}
+
+impl ServerRole {
+    /// Checks with `ROLE` that `conn` is connected to a server with this
+    /// role.
+    pub(crate) fn check(self, conn: &mut redis::Connection) -> redis::RedisResult<()> {
+        let reply: Vec<redis::Value> = redis::cmd("ROLE").query(conn)?;
+        let role: String = match reply.first() {
+            Some(value) => redis::from_redis_value(value)?,
+            None => String::new(),
+        };
+        match (self, role.as_str()) {
+            (ServerRole::Master, "master") | (ServerRole::Replica, "slave") => Ok(()),
+            (ServerRole::Master, _) => Err((
+                redis::ErrorKind::ClientError,
+                "server is not a master",
+                role,
+            )
+                .into()),
+            (ServerRole::Replica, _) => Err((
+                redis::ErrorKind::ClientError,
+                "server is not a replica",
+                role,
+            )
+                .into()),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! The async runtime the async managers and `AsyncPoolBridge` run on.
+//!
+//! As in `redis`, that is tokio when called from within a tokio runtime,
+//! and async-std otherwise, as far as the enabled features allow.
+
+use std::future::Future;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+enum Runtime {
+    #[cfg(any(feature = "bb8", feature = "deadpool", feature = "tokio"))]
+    Tokio,
+    #[cfg(feature = "async-std")]
+    AsyncStd,
+}
+
+fn current() -> Runtime {
+    #[cfg(all(
+        any(feature = "bb8", feature = "deadpool", feature = "tokio"),
+        feature = "async-std"
+    ))]
+    {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            Runtime::Tokio
+        } else {
+            Runtime::AsyncStd
+        }
+    }
+    #[cfg(all(
+        any(feature = "bb8", feature = "deadpool", feature = "tokio"),
+        not(feature = "async-std")
+    ))]
+    {
+        Runtime::Tokio
+    }
+    #[cfg(all(
+        not(any(feature = "bb8", feature = "deadpool", feature = "tokio")),
+        feature = "async-std"
+    ))]
+    {
+        Runtime::AsyncStd
+    }
+}
+
+/// Waits for `duration`.
+pub(crate) async fn sleep(duration: Duration) {
+    match current() {
+        #[cfg(any(feature = "bb8", feature = "deadpool", feature = "tokio"))]
+        Runtime::Tokio => tokio::time::delay_for(duration).await,
+        #[cfg(feature = "async-std")]
+        Runtime::AsyncStd => async_std::task::sleep(duration).await,
+    }
+}
+
+/// Awaits `future`, returning `None` if it takes longer than `duration`.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    match current() {
+        #[cfg(any(feature = "bb8", feature = "deadpool", feature = "tokio"))]
+        Runtime::Tokio => tokio::time::timeout(duration, future).await.ok(),
+        #[cfg(feature = "async-std")]
+        Runtime::AsyncStd => async_std::future::timeout(duration, future).await.ok(),
+    }
+}
+
+/// Runs `future` in the background.
+///
+/// Without the `tokio` feature, tasks go to async-std even within a tokio
+/// runtime.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+    match current() {
+        #[cfg(feature = "tokio")]
+        Runtime::Tokio => drop(tokio::spawn(future)),
+        #[cfg(feature = "async-std")]
+        _ => drop(async_std::task::spawn(future)),
+    }
+}
+
+/// Runs `f` on a thread where blocking is fine, propagating its panics.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub(crate) async fn spawn_blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match current() {
+        #[cfg(feature = "tokio")]
+        Runtime::Tokio => match tokio::task::spawn_blocking(f).await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        },
+        #[cfg(feature = "async-std")]
+        _ => async_std::task::spawn_blocking(f).await,
+    }
+}
+
+/// Opens a multiplexed connection, whose driver runs in the background.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub(crate) async fn multiplexed_connection(
+    client: &redis::Client,
+) -> redis::RedisResult<redis::aio::MultiplexedConnection> {
+    match current() {
+        #[cfg(feature = "tokio")]
+        Runtime::Tokio => client.get_multiplexed_tokio_connection().await,
+        #[cfg(feature = "async-std")]
+        _ => client.get_multiplexed_async_std_connection().await,
+    }
+}
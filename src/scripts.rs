@@ -0,0 +1,234 @@
+use std::sync::{Arc, RwLock};
+
+use redis::{FromRedisValue, ToRedisArgs};
+
+use crate::RedisConnection;
+
+/// The Lua scripts loaded on every connection of a `RedisConnectionManager`.
+///
+/// Scripts are registered once, e.g. at startup, and the registry given to
+/// `RedisConnectionManagerBuilder::script_registry` loads them with
+/// `SCRIPT LOAD` on each new connection. Each connection remembers which
+/// scripts it loaded, so `RegisteredScript::invoke` sends the short
+/// `EVALSHA` right away instead of trying it and falling back on `NOSCRIPT`.
+/// If the server lost its script cache anyway, e.g. after `SCRIPT FLUSH` or
+/// a failover to a replica that never saw the scripts, `invoke` falls back
+/// to `EVAL`, which loads the script again.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, ScriptRegistry};
+///
+/// fn main() {
+///     let scripts = ScriptRegistry::new();
+///     let incr_max = scripts.register(
+///         r#"return math.min(redis.call("incr", KEYS[1]), tonumber(ARGV[1]))"#,
+///     );
+///
+///     let manager = RedisConnectionManager::builder()
+///         .script_registry(Some(scripts))
+///         .build("redis://localhost")
+///         .unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut conn = pool.get().unwrap();
+///     let n: i64 = incr_max.key("counter").arg(10).invoke(&mut conn).unwrap();
+///     println!("{}", n);
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ScriptRegistry {
+    scripts: Arc<RwLock<Vec<RegisteredScript>>>,
+}
+
+impl ScriptRegistry {
+    /// Creates an empty `ScriptRegistry`.
+    pub fn new() -> ScriptRegistry {
+        ScriptRegistry::default()
+    }
+
+    /// Registers the script `code`, returning a handle to invoke it with.
+    ///
+    /// Registering the same code twice returns the same script. Connections
+    /// established before the script was registered load it on its first
+    /// invocation.
+    pub fn register(&self, code: &str) -> RegisteredScript {
+        let script = RegisteredScript {
+            sha: redis::Script::new(code).get_hash().into(),
+            code: code.into(),
+        };
+        let mut scripts = self.scripts.write().unwrap();
+        if !scripts
+            .iter()
+            .any(|registered| registered.sha == script.sha)
+        {
+            scripts.push(script.clone());
+        }
+        script
+    }
+
+    /// Returns the number of registered scripts.
+    pub fn len(&self) -> usize {
+        self.scripts.read().unwrap().len()
+    }
+
+    /// Returns true if no script is registered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Loads the registered scripts on `conn` in a single round trip.
+    pub(crate) fn prime(&self, conn: &mut RedisConnection) -> redis::RedisResult<()> {
+        let scripts = self.scripts.read().unwrap().clone();
+        if scripts.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for script in &scripts {
+            pipe.cmd("SCRIPT").arg("LOAD").arg(&*script.code);
+        }
+        let shas: Vec<String> = pipe.query(conn)?;
+        for sha in shas {
+            conn.set_script_loaded(&sha);
+        }
+        Ok(())
+    }
+}
+
+/// A Lua script of a `ScriptRegistry`.
+#[derive(Debug, Clone)]
+pub struct RegisteredScript {
+    sha: Arc<str>,
+    code: Arc<str>,
+}
+
+impl RegisteredScript {
+    /// Returns the SHA1 digest of the script, as used by `EVALSHA`.
+    pub fn sha(&self) -> &str {
+        &self.sha
+    }
+
+    /// Starts a call of the script with the key `key`.
+    pub fn key<T: ToRedisArgs>(&self, key: T) -> ScriptCall<'_> {
+        let mut call = ScriptCall::new(self);
+        call.key(key);
+        call
+    }
+
+    /// Starts a call of the script with the argument `arg`.
+    pub fn arg<T: ToRedisArgs>(&self, arg: T) -> ScriptCall<'_> {
+        let mut call = ScriptCall::new(self);
+        call.arg(arg);
+        call
+    }
+
+    /// Runs the script without keys or arguments.
+    pub fn invoke<T: FromRedisValue>(&self, conn: &mut RedisConnection) -> redis::RedisResult<T> {
+        ScriptCall::new(self).invoke(conn)
+    }
+}
+
+/// A call of a `RegisteredScript`, with its keys and arguments.
+#[derive(Debug, Clone)]
+pub struct ScriptCall<'a> {
+    script: &'a RegisteredScript,
+    keys: Vec<Vec<u8>>,
+    args: Vec<Vec<u8>>,
+}
+
+impl<'a> ScriptCall<'a> {
+    fn new(script: &'a RegisteredScript) -> ScriptCall<'a> {
+        ScriptCall {
+            script,
+            keys: Vec::new(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Adds a key, available to the script in `KEYS`.
+    pub fn key<T: ToRedisArgs>(&mut self, key: T) -> &mut ScriptCall<'a> {
+        key.write_redis_args(&mut self.keys);
+        self
+    }
+
+    /// Adds an argument, available to the script in `ARGV`.
+    pub fn arg<T: ToRedisArgs>(&mut self, arg: T) -> &mut ScriptCall<'a> {
+        arg.write_redis_args(&mut self.args);
+        self
+    }
+
+    /// Runs the script on `conn`, with `EVALSHA` if the connection loaded
+    /// it, and with `EVAL` otherwise or if the server no longer has it.
+    pub fn invoke<T: FromRedisValue>(&self, conn: &mut RedisConnection) -> redis::RedisResult<T> {
+        let sha = self.script.sha();
+        if conn.has_script(sha) {
+            match self.command("EVALSHA", sha).query(conn) {
+                Err(ref e) if e.kind() == redis::ErrorKind::NoScriptError => {
+                    // The server's script cache was flushed, so none of the
+                    // scripts are loaded anymore.
+                    conn.clear_scripts();
+                }
+                result => return result,
+            }
+        }
+        let result = self.command("EVAL", &self.script.code).query(conn);
+        if result.is_ok() {
+            conn.set_script_loaded(sha);
+        }
+        result
+    }
+
+    fn command(&self, name: &str, script: &str) -> redis::Cmd {
+        let mut cmd = redis::cmd(name);
+        cmd.arg(script).arg(self.keys.len());
+        for arg in self.keys.iter().chain(&self.args) {
+            cmd.arg(&arg[..]);
+        }
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConnectionManager;
+
+    const UNLINK_SCRIPT: &str = r#"return redis.call("unlink", KEYS[1])"#;
+
+    #[test]
+    fn test_register() {
+        let scripts = ScriptRegistry::new();
+        let script = scripts.register(UNLINK_SCRIPT);
+        assert_eq!(redis::Script::new(UNLINK_SCRIPT).get_hash(), script.sha());
+        assert_eq!(script.sha(), scripts.register(UNLINK_SCRIPT).sha());
+        assert_eq!(1, scripts.len());
+    }
+
+    #[test]
+    fn test_invoke() {
+        let scripts = ScriptRegistry::new();
+        let script = scripts.register(UNLINK_SCRIPT);
+        let manager = RedisConnectionManager::builder()
+            .script_registry(Some(scripts))
+            .build("redis://localhost")
+            .unwrap();
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+        assert!(conn.has_script(script.sha()));
+
+        redis::cmd("SET")
+            .arg("redis_r2d2-scripts")
+            .arg(1)
+            .query::<()>(&mut *conn)
+            .unwrap();
+        redis::cmd("SCRIPT")
+            .arg("FLUSH")
+            .query::<()>(&mut *conn)
+            .unwrap();
+        let deleted: i64 = script.key("redis_r2d2-scripts").invoke(&mut conn).unwrap();
+        assert_eq!(1, deleted);
+        assert!(conn.has_script(script.sha()));
+        let deleted: i64 = script.key("redis_r2d2-scripts").invoke(&mut conn).unwrap();
+        assert_eq!(0, deleted);
+    }
+}
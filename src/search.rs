@@ -0,0 +1,867 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use redis::{ConnectionLike, FromRedisValue, Value};
+
+use crate::pool_ext::checkout;
+use crate::RedisConnectionManager;
+
+/// The type of the documents a `SearchIndex` indexes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexOn {
+    /// Hashes, whose fields are the indexed fields.
+    Hash,
+    /// RedisJSON documents, whose fields are given as JSONPaths.
+    Json,
+}
+
+/// A field of the schema of a `SearchIndex`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchField {
+    name: String,
+    alias: Option<String>,
+    kind: &'static str,
+    sortable: bool,
+    weight: Option<f64>,
+    separator: Option<char>,
+}
+
+impl SearchField {
+    fn new(name: &str, kind: &'static str) -> SearchField {
+        SearchField {
+            name: name.to_string(),
+            alias: None,
+            kind,
+            sortable: false,
+            weight: None,
+            separator: None,
+        }
+    }
+
+    /// A full-text field.
+    pub fn text(name: &str) -> SearchField {
+        SearchField::new(name, "TEXT")
+    }
+
+    /// A tag field, matched exactly, e.g. with `@field:{value}`.
+    pub fn tag(name: &str) -> SearchField {
+        SearchField::new(name, "TAG")
+    }
+
+    /// A numeric field, matched by range, e.g. with `@field:[1 10]`.
+    pub fn numeric(name: &str) -> SearchField {
+        SearchField::new(name, "NUMERIC")
+    }
+
+    /// A geographic field, holding `longitude,latitude`.
+    pub fn geo(name: &str) -> SearchField {
+        SearchField::new(name, "GEO")
+    }
+
+    /// Sets the name the field goes by in queries, e.g. for a JSONPath.
+    pub fn alias(mut self, alias: &str) -> SearchField {
+        self.alias = Some(alias.to_string());
+        self
+    }
+
+    /// Allows sorting by the field.
+    pub fn sortable(mut self) -> SearchField {
+        self.sortable = true;
+        self
+    }
+
+    /// Sets the relevance of matches in a text field, 1 by default.
+    pub fn weight(mut self, weight: f64) -> SearchField {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// Sets the character separating the values of a tag field, `,` by
+    /// default.
+    pub fn separator(mut self, separator: char) -> SearchField {
+        self.separator = Some(separator);
+        self
+    }
+
+    fn write_args(&self, cmd: &mut redis::Cmd) {
+        cmd.arg(&self.name);
+        if let Some(ref alias) = self.alias {
+            cmd.arg("AS").arg(alias);
+        }
+        cmd.arg(self.kind);
+        if let Some(weight) = self.weight {
+            cmd.arg("WEIGHT").arg(weight);
+        }
+        if let Some(separator) = self.separator {
+            cmd.arg("SEPARATOR").arg(separator.to_string());
+        }
+        if self.sortable {
+            cmd.arg("SORTABLE");
+        }
+    }
+}
+
+/// The definition of a RediSearch index, created with `FT.CREATE`.
+///
+/// Requires the `search` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, SearchField, SearchIndex, SearchQuery};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut conn = pool.get().unwrap();
+///     SearchIndex::new("users")
+///         .prefix("user:")
+///         .field(SearchField::text("name"))
+///         .field(SearchField::numeric("age").sortable())
+///         .create(&mut *conn)
+///         .unwrap();
+///
+///     let results = SearchQuery::new("users", "@age:[18 +inf]")
+///         .sort_by("age", true)
+///         .limit(0, 20)
+///         .execute(&mut *conn)
+///         .unwrap();
+///     for document in results.documents {
+///         println!("{}: {:?}", document.id, document.get::<String>("name"));
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchIndex {
+    name: String,
+    on: IndexOn,
+    prefixes: Vec<String>,
+    filter: Option<String>,
+    fields: Vec<SearchField>,
+}
+
+impl SearchIndex {
+    /// Starts the definition of the index `name`, on hashes.
+    pub fn new(name: &str) -> SearchIndex {
+        SearchIndex {
+            name: name.to_string(),
+            on: IndexOn::Hash,
+            prefixes: Vec::new(),
+            filter: None,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Sets the type of the indexed documents.
+    ///
+    /// Defaults to `IndexOn::Hash`.
+    pub fn on(mut self, on: IndexOn) -> SearchIndex {
+        self.on = on;
+        self
+    }
+
+    /// Indexes the keys starting with `prefix`, besides those of the other
+    /// prefixes.
+    ///
+    /// Defaults to all keys.
+    pub fn prefix(mut self, prefix: &str) -> SearchIndex {
+        self.prefixes.push(prefix.to_string());
+        self
+    }
+
+    /// Only indexes the documents for which the expression `filter` holds.
+    pub fn filter(mut self, filter: &str) -> SearchIndex {
+        self.filter = Some(filter.to_string());
+        self
+    }
+
+    /// Adds `field` to the schema.
+    pub fn field(mut self, field: SearchField) -> SearchIndex {
+        self.fields.push(field);
+        self
+    }
+
+    /// Returns the `FT.CREATE` command creating the index.
+    pub fn command(&self) -> redis::Cmd {
+        let mut cmd = redis::cmd("FT.CREATE");
+        cmd.arg(&self.name).arg("ON").arg(match self.on {
+            IndexOn::Hash => "HASH",
+            IndexOn::Json => "JSON",
+        });
+        if !self.prefixes.is_empty() {
+            cmd.arg("PREFIX")
+                .arg(self.prefixes.len())
+                .arg(&self.prefixes[..]);
+        }
+        if let Some(ref filter) = self.filter {
+            cmd.arg("FILTER").arg(filter);
+        }
+        cmd.arg("SCHEMA");
+        for field in &self.fields {
+            field.write_args(&mut cmd);
+        }
+        cmd
+    }
+
+    /// Creates the index on `conn`.
+    pub fn create<C: ConnectionLike>(&self, conn: &mut C) -> redis::RedisResult<()> {
+        self.command().query(conn)
+    }
+
+    /// Drops the index `name` on `conn` (`FT.DROPINDEX`), and deletes the
+    /// indexed documents too if `delete_documents` is true.
+    pub fn drop_index<C: ConnectionLike>(
+        name: &str,
+        delete_documents: bool,
+        conn: &mut C,
+    ) -> redis::RedisResult<()> {
+        let mut cmd = redis::cmd("FT.DROPINDEX");
+        cmd.arg(name);
+        if delete_documents {
+            cmd.arg("DD");
+        }
+        cmd.query(conn)
+    }
+}
+
+/// A document found by a `SearchQuery`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchDocument {
+    /// The key of the document.
+    pub id: String,
+    /// The returned fields of the document, none with `no_content`.
+    pub fields: HashMap<String, Value>,
+}
+
+impl SearchDocument {
+    /// Returns the field `name`, converted to `T`, if present and
+    /// convertible.
+    pub fn get<T: FromRedisValue>(&self, name: &str) -> Option<T> {
+        get_field(&self.fields, name)
+    }
+}
+
+/// The reply of a `SearchQuery`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResults {
+    /// The number of matching documents, including those beyond the
+    /// `limit`.
+    pub total: u64,
+    /// The returned documents.
+    pub documents: Vec<SearchDocument>,
+}
+
+/// A full-text query of a RediSearch index, run with `FT.SEARCH`.
+///
+/// See `SearchIndex` for an example. Requires the `search` feature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchQuery {
+    index: String,
+    query: String,
+    no_content: bool,
+    return_fields: Vec<String>,
+    sort_by: Option<(String, bool)>,
+    limit: Option<(usize, usize)>,
+    params: Vec<(String, String)>,
+    dialect: Option<u32>,
+}
+
+impl SearchQuery {
+    /// Starts a query of `index` for the documents matching `query`, e.g.
+    /// `*` for all of them.
+    pub fn new(index: &str, query: &str) -> SearchQuery {
+        SearchQuery {
+            index: index.to_string(),
+            query: query.to_string(),
+            no_content: false,
+            return_fields: Vec::new(),
+            sort_by: None,
+            limit: None,
+            params: Vec::new(),
+            dialect: None,
+        }
+    }
+
+    /// Only returns the IDs of the documents.
+    pub fn no_content(mut self) -> SearchQuery {
+        self.no_content = true;
+        self
+    }
+
+    /// Only returns the field `name` of the documents, besides the other
+    /// returned fields.
+    ///
+    /// Defaults to all fields.
+    pub fn return_field(mut self, name: &str) -> SearchQuery {
+        self.return_fields.push(name.to_string());
+        self
+    }
+
+    /// Sorts the documents by the sortable field `name`.
+    pub fn sort_by(mut self, name: &str, ascending: bool) -> SearchQuery {
+        self.sort_by = Some((name.to_string(), ascending));
+        self
+    }
+
+    /// Returns `count` documents, skipping the first `offset`.
+    ///
+    /// Defaults to the first 10.
+    pub fn limit(mut self, offset: usize, count: usize) -> SearchQuery {
+        self.limit = Some((offset, count));
+        self
+    }
+
+    /// Sets the value of the parameter `$name` of the query, which requires
+    /// dialect 2 or later.
+    pub fn param<V: ToString>(mut self, name: &str, value: V) -> SearchQuery {
+        self.params.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the dialect the query is parsed with.
+    ///
+    /// Defaults to the server's, or to 2 if parameters are set.
+    pub fn dialect(mut self, dialect: u32) -> SearchQuery {
+        self.dialect = Some(dialect);
+        self
+    }
+
+    /// Returns the `FT.SEARCH` command running the query.
+    pub fn command(&self) -> redis::Cmd {
+        let mut cmd = redis::cmd("FT.SEARCH");
+        cmd.arg(&self.index).arg(&self.query);
+        if self.no_content {
+            cmd.arg("NOCONTENT");
+        }
+        if !self.return_fields.is_empty() {
+            cmd.arg("RETURN")
+                .arg(self.return_fields.len())
+                .arg(&self.return_fields[..]);
+        }
+        if let Some((ref name, ascending)) = self.sort_by {
+            cmd.arg("SORTBY")
+                .arg(name)
+                .arg(if ascending { "ASC" } else { "DESC" });
+        }
+        if let Some((offset, count)) = self.limit {
+            cmd.arg("LIMIT").arg(offset).arg(count);
+        }
+        write_params(&mut cmd, &self.params, self.dialect);
+        cmd
+    }
+
+    /// Runs the query on `conn`.
+    pub fn execute<C: ConnectionLike>(&self, conn: &mut C) -> redis::RedisResult<SearchResults> {
+        let reply: Vec<Value> = self.command().query(conn)?;
+        parse_search(&reply, self.no_content)
+    }
+}
+
+/// A reducer of an `Aggregate`'s `group_by`, e.g. `COUNT`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reducer {
+    function: String,
+    args: Vec<String>,
+    alias: Option<String>,
+}
+
+impl Reducer {
+    /// The reducer `function` with the arguments `args`.
+    pub fn new(function: &str, args: &[&str]) -> Reducer {
+        Reducer {
+            function: function.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            alias: None,
+        }
+    }
+
+    /// Counts the rows of each group.
+    pub fn count() -> Reducer {
+        Reducer::new("COUNT", &[])
+    }
+
+    /// Counts the distinct values of `property`, e.g. `@user`.
+    pub fn count_distinct(property: &str) -> Reducer {
+        Reducer::new("COUNT_DISTINCT", &[property])
+    }
+
+    /// Sums `property`.
+    pub fn sum(property: &str) -> Reducer {
+        Reducer::new("SUM", &[property])
+    }
+
+    /// Averages `property`.
+    pub fn avg(property: &str) -> Reducer {
+        Reducer::new("AVG", &[property])
+    }
+
+    /// Takes the smallest value of `property`.
+    pub fn min(property: &str) -> Reducer {
+        Reducer::new("MIN", &[property])
+    }
+
+    /// Takes the largest value of `property`.
+    pub fn max(property: &str) -> Reducer {
+        Reducer::new("MAX", &[property])
+    }
+
+    /// Names the result `alias`.
+    pub fn alias(mut self, alias: &str) -> Reducer {
+        self.alias = Some(alias.to_string());
+        self
+    }
+}
+
+/// A row returned by an `Aggregate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateRow {
+    /// The properties of the row.
+    pub fields: HashMap<String, Value>,
+}
+
+impl AggregateRow {
+    /// Returns the property `name`, converted to `T`, if present and
+    /// convertible.
+    pub fn get<T: FromRedisValue>(&self, name: &str) -> Option<T> {
+        get_field(&self.fields, name)
+    }
+}
+
+/// An aggregation of a RediSearch index, run with `FT.AGGREGATE`.
+///
+/// The steps of the pipeline run in the order they are added. `execute`
+/// returns all the rows at once, while `cursor` reads them in batches.
+///
+/// Requires the `search` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, Aggregate, RedisConnectionManager, Reducer};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let rows = Aggregate::new("orders", "*")
+///         .group_by(&["@country"], vec![Reducer::sum("@total").alias("revenue")])
+///         .sort_by(&[("@revenue", false)])
+///         .cursor(&pool, 1000)
+///         .unwrap();
+///     for row in rows {
+///         let row = row.unwrap();
+///         println!("{:?}: {:?}", row.get::<String>("country"), row.get::<f64>("revenue"));
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    index: String,
+    query: String,
+    steps: Vec<Vec<String>>,
+    params: Vec<(String, String)>,
+    dialect: Option<u32>,
+    max_idle: Option<Duration>,
+}
+
+impl Aggregate {
+    /// Starts an aggregation of the documents of `index` matching `query`.
+    pub fn new(index: &str, query: &str) -> Aggregate {
+        Aggregate {
+            index: index.to_string(),
+            query: query.to_string(),
+            steps: Vec::new(),
+            params: Vec::new(),
+            dialect: None,
+            max_idle: None,
+        }
+    }
+
+    fn step<I: IntoIterator<Item = String>>(mut self, args: I) -> Aggregate {
+        self.steps.push(args.into_iter().collect());
+        self
+    }
+
+    /// Loads the document fields `fields`, e.g. `@name`, which aren't
+    /// sortable.
+    pub fn load(self, fields: &[&str]) -> Aggregate {
+        let args = vec!["LOAD".to_string(), fields.len().to_string()];
+        self.step(args.into_iter().chain(fields.iter().map(|f| f.to_string())))
+    }
+
+    /// Groups the rows by the properties `by`, e.g. `@country`, reducing
+    /// each group with `reducers`.
+    pub fn group_by(self, by: &[&str], reducers: Vec<Reducer>) -> Aggregate {
+        let mut args = vec!["GROUPBY".to_string(), by.len().to_string()];
+        args.extend(by.iter().map(|property| property.to_string()));
+        for reducer in reducers {
+            args.push("REDUCE".to_string());
+            args.push(reducer.function);
+            args.push(reducer.args.len().to_string());
+            args.extend(reducer.args);
+            if let Some(alias) = reducer.alias {
+                args.push("AS".to_string());
+                args.push(alias);
+            }
+        }
+        self.step(args)
+    }
+
+    /// Adds the property `alias`, computed by the expression `expression`.
+    pub fn apply(self, expression: &str, alias: &str) -> Aggregate {
+        self.step(vec![
+            "APPLY".to_string(),
+            expression.to_string(),
+            "AS".to_string(),
+            alias.to_string(),
+        ])
+    }
+
+    /// Keeps the rows for which the expression `expression` holds.
+    pub fn filter(self, expression: &str) -> Aggregate {
+        self.step(vec!["FILTER".to_string(), expression.to_string()])
+    }
+
+    /// Sorts the rows by the properties `by`, each ascending or not.
+    pub fn sort_by(self, by: &[(&str, bool)]) -> Aggregate {
+        let mut args = vec!["SORTBY".to_string(), (by.len() * 2).to_string()];
+        for &(property, ascending) in by {
+            args.push(property.to_string());
+            args.push(if ascending { "ASC" } else { "DESC" }.to_string());
+        }
+        self.step(args)
+    }
+
+    /// Keeps `count` rows, skipping the first `offset`.
+    pub fn limit(self, offset: usize, count: usize) -> Aggregate {
+        self.step(vec![
+            "LIMIT".to_string(),
+            offset.to_string(),
+            count.to_string(),
+        ])
+    }
+
+    /// Sets the value of the parameter `$name` of the query, which requires
+    /// dialect 2 or later.
+    pub fn param<V: ToString>(mut self, name: &str, value: V) -> Aggregate {
+        self.params.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the dialect the query is parsed with.
+    ///
+    /// Defaults to the server's, or to 2 if parameters are set.
+    pub fn dialect(mut self, dialect: u32) -> Aggregate {
+        self.dialect = Some(dialect);
+        self
+    }
+
+    /// Sets how long the server keeps an unread `cursor`.
+    ///
+    /// Defaults to the server's, 5 minutes.
+    pub fn max_idle(mut self, max_idle: Duration) -> Aggregate {
+        self.max_idle = Some(max_idle);
+        self
+    }
+
+    /// Returns the `FT.AGGREGATE` command running the aggregation, reading
+    /// the rows with a cursor in batches of `cursor_count` if set.
+    pub fn command(&self, cursor_count: Option<usize>) -> redis::Cmd {
+        let mut cmd = redis::cmd("FT.AGGREGATE");
+        cmd.arg(&self.index).arg(&self.query);
+        for step in &self.steps {
+            cmd.arg(&step[..]);
+        }
+        if let Some(count) = cursor_count {
+            cmd.arg("WITHCURSOR").arg("COUNT").arg(count);
+            if let Some(max_idle) = self.max_idle {
+                cmd.arg("MAXIDLE").arg(max_idle.as_millis() as u64);
+            }
+        }
+        write_params(&mut cmd, &self.params, self.dialect);
+        cmd
+    }
+
+    /// Runs the aggregation on `conn`, returning all the rows.
+    pub fn execute<C: ConnectionLike>(
+        &self,
+        conn: &mut C,
+    ) -> redis::RedisResult<Vec<AggregateRow>> {
+        let reply: Vec<Value> = self.command(None).query(conn)?;
+        parse_rows(&reply)
+    }
+
+    /// Runs the aggregation with a cursor, returning an iterator over the
+    /// rows that reads them in batches of `count`, on connections of
+    /// `pool`.
+    ///
+    /// Cursors live on the server that ran the aggregation, so the pool
+    /// must not spread its connections over several servers. Dropping the
+    /// iterator before the end deletes the cursor.
+    pub fn cursor(
+        &self,
+        pool: &r2d2::Pool<RedisConnectionManager>,
+        count: usize,
+    ) -> redis::RedisResult<AggregateCursor> {
+        let reply: (Vec<Value>, u64) = self.command(Some(count)).query(&mut *checkout(pool)?)?;
+        Ok(AggregateCursor {
+            pool: pool.clone(),
+            index: self.index.clone(),
+            id: reply.1,
+            count,
+            rows: parse_rows(&reply.0)?.into(),
+        })
+    }
+}
+
+/// The rows of an `Aggregate`, read with a cursor, see `Aggregate::cursor`.
+#[derive(Debug)]
+pub struct AggregateCursor {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    index: String,
+    id: u64,
+    count: usize,
+    rows: VecDeque<AggregateRow>,
+}
+
+impl AggregateCursor {
+    /// Reads the next batch of rows (`FT.CURSOR READ`).
+    fn read(&mut self) -> redis::RedisResult<()> {
+        let reply: (Vec<Value>, u64) = redis::cmd("FT.CURSOR")
+            .arg("READ")
+            .arg(&self.index)
+            .arg(self.id)
+            .arg("COUNT")
+            .arg(self.count)
+            .query(&mut *checkout(&self.pool)?)?;
+        self.id = reply.1;
+        self.rows.extend(parse_rows(&reply.0)?);
+        Ok(())
+    }
+}
+
+impl Iterator for AggregateCursor {
+    type Item = redis::RedisResult<AggregateRow>;
+
+    fn next(&mut self) -> Option<redis::RedisResult<AggregateRow>> {
+        while self.rows.is_empty() && self.id != 0 {
+            if let Err(e) = self.read() {
+                // The cursor may be gone, so don't try again.
+                self.id = 0;
+                return Some(Err(e));
+            }
+        }
+        self.rows.pop_front().map(Ok)
+    }
+}
+
+impl Drop for AggregateCursor {
+    fn drop(&mut self) {
+        if self.id == 0 {
+            return;
+        }
+        if let Ok(mut conn) = checkout(&self.pool) {
+            let _ = redis::cmd("FT.CURSOR")
+                .arg("DEL")
+                .arg(&self.index)
+                .arg(self.id)
+                .query::<()>(&mut *conn);
+        }
+    }
+}
+
+fn write_params(cmd: &mut redis::Cmd, params: &[(String, String)], dialect: Option<u32>) {
+    if !params.is_empty() {
+        cmd.arg("PARAMS").arg(params.len() * 2);
+        for (name, value) in params {
+            cmd.arg(name).arg(value);
+        }
+    }
+    match dialect {
+        Some(dialect) => {
+            cmd.arg("DIALECT").arg(dialect);
+        }
+        None if !params.is_empty() => {
+            cmd.arg("DIALECT").arg(2);
+        }
+        None => {}
+    }
+}
+
+fn get_field<T: FromRedisValue>(fields: &HashMap<String, Value>, name: &str) -> Option<T> {
+    fields
+        .get(name)
+        .and_then(|value| redis::from_redis_value(value).ok())
+}
+
+/// Parses the flat `[name, value, ...]` list of a document or row.
+fn parse_fields(value: &Value) -> redis::RedisResult<HashMap<String, Value>> {
+    match *value {
+        Value::Bulk(ref items) => items
+            .chunks(2)
+            .map(|pair| match pair {
+                [name, value] => Ok((redis::from_redis_value(name)?, value.clone())),
+                _ => Err((redis::ErrorKind::TypeError, "odd number of fields").into()),
+            })
+            .collect(),
+        _ => Err((redis::ErrorKind::TypeError, "invalid fields").into()),
+    }
+}
+
+/// Parses a `FT.SEARCH` reply: the total, then each document's ID, followed
+/// by its fields unless `no_content`.
+fn parse_search(reply: &[Value], no_content: bool) -> redis::RedisResult<SearchResults> {
+    let (total, rest) = match reply.split_first() {
+        Some((total, rest)) => (redis::from_redis_value(total)?, rest),
+        None => return Err((redis::ErrorKind::TypeError, "empty search reply").into()),
+    };
+    let step = if no_content { 1 } else { 2 };
+    let documents = rest
+        .chunks(step)
+        .map(|document| {
+            Ok(SearchDocument {
+                id: redis::from_redis_value(&document[0])?,
+                fields: match document.get(1) {
+                    Some(fields) => parse_fields(fields)?,
+                    None if no_content => HashMap::new(),
+                    None => return Err((redis::ErrorKind::TypeError, "missing fields").into()),
+                },
+            })
+        })
+        .collect::<redis::RedisResult<_>>()?;
+    Ok(SearchResults { total, documents })
+}
+
+/// Parses a `FT.AGGREGATE` reply: the number of rows, then the rows.
+fn parse_rows(reply: &[Value]) -> redis::RedisResult<Vec<AggregateRow>> {
+    reply
+        .iter()
+        .skip(1)
+        .map(|row| {
+            Ok(AggregateRow {
+                fields: parse_fields(row)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(s: &str) -> Value {
+        Value::Data(s.as_bytes().to_vec())
+    }
+
+    fn packed(name: &str, args: &str) -> Vec<u8> {
+        redis::cmd(name)
+            .arg(args.split(' ').collect::<Vec<_>>())
+            .get_packed_command()
+    }
+
+    #[test]
+    fn test_commands() {
+        let index = SearchIndex::new("users")
+            .prefix("user:")
+            .field(SearchField::text("$.name").alias("name").weight(2.0))
+            .field(SearchField::tag("tags").separator(';').sortable())
+            .on(IndexOn::Json);
+        assert_eq!(
+            packed(
+                "FT.CREATE",
+                "users ON JSON PREFIX 1 user: SCHEMA $.name AS name TEXT WEIGHT 2.0 \
+                 tags TAG SEPARATOR ; SORTABLE"
+            ),
+            index.command().get_packed_command()
+        );
+
+        let query = SearchQuery::new("users", "@name:$name")
+            .param("name", "ada")
+            .limit(0, 5);
+        assert_eq!(
+            packed(
+                "FT.SEARCH",
+                "users @name:$name LIMIT 0 5 PARAMS 2 name ada DIALECT 2"
+            ),
+            query.command().get_packed_command()
+        );
+    }
+
+    #[test]
+    fn test_parse_search() {
+        let reply = vec![
+            Value::Int(7),
+            data("user:1"),
+            Value::Bulk(vec![data("name"), data("Ada")]),
+        ];
+        let results = parse_search(&reply, false).unwrap();
+        assert_eq!(7, results.total);
+        assert_eq!("user:1", results.documents[0].id);
+        assert_eq!(Some("Ada".to_string()), results.documents[0].get("name"));
+
+        let reply = vec![Value::Int(2), data("user:1"), data("user:2")];
+        let results = parse_search(&reply, true).unwrap();
+        assert_eq!(2, results.documents.len());
+        assert!(results.documents[1].fields.is_empty());
+    }
+
+    #[test]
+    fn test_search_and_aggregate() {
+        let pool = crate::local_pool(1);
+        let token = crate::lock::token();
+        let name = format!("redis_r2d2-search-{}", token);
+        let prefix = format!("{}:", name);
+        {
+            let mut conn = pool.get().unwrap();
+            SearchIndex::new(&name)
+                .prefix(&prefix)
+                .field(SearchField::text("name"))
+                .field(SearchField::tag("country"))
+                .field(SearchField::numeric("age").sortable())
+                .create(&mut *conn)
+                .unwrap();
+            let users = [("ada", "uk", 36), ("alan", "uk", 41), ("grace", "us", 85)];
+            for (i, &(user, country, age)) in users.iter().enumerate() {
+                redis::cmd("HSET")
+                    .arg(format!("{}{}", prefix, i))
+                    .arg(&["name", user, "country", country])
+                    .arg("age")
+                    .arg(age)
+                    .query::<()>(&mut *conn)
+                    .unwrap();
+            }
+
+            let results = SearchQuery::new(&name, "@country:{uk}")
+                .sort_by("age", false)
+                .execute(&mut *conn)
+                .unwrap();
+            assert_eq!(2, results.total);
+            let names: Vec<String> = results
+                .documents
+                .iter()
+                .map(|document| document.get("name").unwrap())
+                .collect();
+            assert_eq!(vec!["alan", "ada"], names);
+
+            let rows = Aggregate::new(&name, "*")
+                .group_by(&["@country"], vec![Reducer::count().alias("users")])
+                .sort_by(&[("@users", false)])
+                .execute(&mut *conn)
+                .unwrap();
+            assert_eq!(Some("uk".to_string()), rows[0].get("country"));
+            assert_eq!(Some(2), rows[0].get::<u64>("users"));
+        }
+
+        // The cursor reads the rows in batches on pooled connections.
+        let ages: Vec<u64> = Aggregate::new(&name, "*")
+            .load(&["@age"])
+            .sort_by(&[("@age", true)])
+            .cursor(&pool, 2)
+            .unwrap()
+            .map(|row| row.unwrap().get("age").unwrap())
+            .collect();
+        assert_eq!(vec![36, 41, 85], ages);
+
+        let mut conn = pool.get().unwrap();
+        SearchIndex::drop_index(&name, true, &mut *conn).unwrap();
+    }
+}
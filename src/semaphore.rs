@@ -0,0 +1,287 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::lock::token;
+use crate::pool_ext::checkout;
+use crate::RedisConnectionManager;
+
+/// Queues the caller, and makes it a holder if it is among the first
+/// waiters for the free slots. Expired leases and waiters that stopped
+/// polling are dropped first.
+///
+/// `KEYS`: holders, queue, waiters, ticket counter. `ARGV`: token, limit,
+/// lease and waiter timeout in milliseconds.
+const ACQUIRE_SCRIPT: &str = r#"
+redis.replicate_commands()
+local time = redis.call("time")
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+redis.call("zremrangebyscore", KEYS[1], "-inf", now)
+for _, waiter in ipairs(redis.call("zrangebyscore", KEYS[3], "-inf", now)) do
+    redis.call("zrem", KEYS[2], waiter)
+    redis.call("zrem", KEYS[3], waiter)
+end
+if redis.call("zscore", KEYS[1], ARGV[1]) then
+    return 1
+end
+if not redis.call("zscore", KEYS[2], ARGV[1]) then
+    redis.call("zadd", KEYS[2], redis.call("incr", KEYS[4]), ARGV[1])
+end
+redis.call("zadd", KEYS[3], now + tonumber(ARGV[4]), ARGV[1])
+local free = tonumber(ARGV[2]) - redis.call("zcard", KEYS[1])
+if redis.call("zrank", KEYS[2], ARGV[1]) < free then
+    redis.call("zrem", KEYS[2], ARGV[1])
+    redis.call("zrem", KEYS[3], ARGV[1])
+    redis.call("zadd", KEYS[1], now + tonumber(ARGV[3]), ARGV[1])
+    return 1
+end
+return 0
+"#;
+
+/// Renews the caller's lease if it hasn't expired.
+///
+/// `KEYS`: holders. `ARGV`: token, lease in milliseconds.
+const EXTEND_SCRIPT: &str = r#"
+redis.replicate_commands()
+local time = redis.call("time")
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local expires = redis.call("zscore", KEYS[1], ARGV[1])
+if expires and tonumber(expires) > now then
+    redis.call("zadd", KEYS[1], now + tonumber(ARGV[2]), ARGV[1])
+    return 1
+end
+return 0
+"#;
+
+/// A semaphore limiting how many holders across processes can use a
+/// resource at once.
+///
+/// The holders are kept in a sorted set scored by the expiry of their
+/// lease, and the callers waiting for a slot in another sorted set, by
+/// arrival, so slots are granted first come, first served. Waiters are
+/// dropped from the queue when they stop polling, and holders once their
+/// lease expires, so a crashed process doesn't hold a slot forever. The
+/// lists are updated by Lua scripts using the server's clock.
+///
+/// The keys are named after the semaphore with a hash tag, e.g.
+/// `{name}:holders`, so they live on the same Redis Cluster node.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, RedisConnectionManager, RedisSemaphore};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let semaphore = RedisSemaphore::new(pool, "exports", 4);
+///     if let Some(permit) = semaphore.acquire(Duration::from_secs(10)).unwrap() {
+///         // ...
+///         permit.release().unwrap();
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RedisSemaphore {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    name: String,
+    limit: u32,
+    lease: Duration,
+    poll_interval: Duration,
+}
+
+impl RedisSemaphore {
+    /// Creates a `RedisSemaphore` named `name` allowing `limit` holders at
+    /// once.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        name: S,
+        limit: u32,
+    ) -> RedisSemaphore {
+        RedisSemaphore {
+            pool,
+            name: name.into(),
+            limit,
+            lease: Duration::from_secs(30),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+
+    /// Sets how long a permit is held unless it is extended.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn lease(mut self, lease: Duration) -> RedisSemaphore {
+        self.lease = lease;
+        self
+    }
+
+    /// Sets how often `acquire` checks whether a slot is free. A waiter that
+    /// doesn't check for 3 intervals, and at least a second, loses its place
+    /// in the queue.
+    ///
+    /// Defaults to 100 milliseconds.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> RedisSemaphore {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Acquires a permit if a slot is free and nobody is waiting for one.
+    pub fn try_acquire(&self) -> redis::RedisResult<Option<SemaphorePermit>> {
+        self.acquire(Duration::from_secs(0))
+    }
+
+    /// Acquires a permit, waiting at most `timeout` for a slot in order of
+    /// arrival, or returns `None` if none was free in time.
+    pub fn acquire(&self, timeout: Duration) -> redis::RedisResult<Option<SemaphorePermit>> {
+        let token = token();
+        let deadline = Instant::now() + timeout;
+        let waiter_timeout = (self.poll_interval * 3).max(Duration::from_secs(1));
+        let script = redis::Script::new(ACQUIRE_SCRIPT);
+        loop {
+            let start = Instant::now();
+            let acquired: bool = {
+                let mut conn = checkout(&self.pool)?;
+                script
+                    .key(self.key("holders"))
+                    .key(self.key("queue"))
+                    .key(self.key("waiters"))
+                    .key(self.key("tickets"))
+                    .arg(&token)
+                    .arg(self.limit)
+                    .arg(self.lease.as_millis() as u64)
+                    .arg(waiter_timeout.as_millis() as u64)
+                    .invoke(&mut *conn)?
+            };
+            if acquired {
+                return Ok(Some(SemaphorePermit {
+                    semaphore: self.clone(),
+                    token,
+                    valid_until: start + self.lease,
+                    released: false,
+                }));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                self.leave_queue(&token)?;
+                return Ok(None);
+            }
+            thread::sleep(self.poll_interval.min(deadline - now));
+        }
+    }
+
+    fn leave_queue(&self, token: &str) -> redis::RedisResult<()> {
+        let mut conn = checkout(&self.pool)?;
+        redis::pipe()
+            .cmd("ZREM")
+            .arg(self.key("queue"))
+            .arg(token)
+            .ignore()
+            .cmd("ZREM")
+            .arg(self.key("waiters"))
+            .arg(token)
+            .ignore()
+            .query(&mut *conn)
+    }
+
+    fn key(&self, suffix: &str) -> String {
+        format!("{{{}}}:{}", self.name, suffix)
+    }
+}
+
+/// A slot of a `RedisSemaphore`, released when dropped.
+#[derive(Debug)]
+pub struct SemaphorePermit {
+    semaphore: RedisSemaphore,
+    token: String,
+    valid_until: Instant,
+    released: bool,
+}
+
+impl SemaphorePermit {
+    /// Returns how long the lease is still held for, at least.
+    pub fn validity(&self) -> Duration {
+        self.valid_until.saturating_duration_since(Instant::now())
+    }
+
+    /// Renews the lease for the semaphore's lease time, returning false if
+    /// it had already expired.
+    pub fn extend(&mut self) -> redis::RedisResult<bool> {
+        let start = Instant::now();
+        let mut conn = checkout(&self.semaphore.pool)?;
+        let extended: bool = redis::Script::new(EXTEND_SCRIPT)
+            .key(self.semaphore.key("holders"))
+            .arg(&self.token)
+            .arg(self.semaphore.lease.as_millis() as u64)
+            .invoke(&mut *conn)?;
+        if extended {
+            self.valid_until = start + self.semaphore.lease;
+        }
+        Ok(extended)
+    }
+
+    /// Releases the slot, returning false if the lease had already expired.
+    pub fn release(mut self) -> redis::RedisResult<bool> {
+        self.released = true;
+        self.remove()
+    }
+
+    fn remove(&self) -> redis::RedisResult<bool> {
+        let mut conn = checkout(&self.semaphore.pool)?;
+        redis::cmd("ZREM")
+            .arg(self.semaphore.key("holders"))
+            .arg(&self.token)
+            .query(&mut *conn)
+    }
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_semaphore() {
+        let pool = crate::local_pool(2);
+        let semaphore = RedisSemaphore::new(pool, "redis_r2d2-semaphore", 2)
+            .poll_interval(Duration::from_millis(10));
+
+        let mut first = semaphore.try_acquire().unwrap().unwrap();
+        let second = semaphore.try_acquire().unwrap().unwrap();
+        assert!(semaphore.try_acquire().unwrap().is_none());
+        assert!(first.extend().unwrap());
+
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || semaphore.acquire(Duration::from_secs(5)).unwrap())
+        };
+        thread::sleep(Duration::from_millis(100));
+        // The waiter arrived first.
+        drop(second);
+        assert!(semaphore.try_acquire().unwrap().is_none());
+        let third = waiter.join().unwrap().unwrap();
+
+        assert!(first.release().unwrap());
+        drop(third);
+        assert!(semaphore.try_acquire().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_lease_expiry() {
+        let pool = crate::local_pool(1);
+        let semaphore = RedisSemaphore::new(pool, "redis_r2d2-semaphore-lease", 1)
+            .lease(Duration::from_millis(50));
+        let mut permit = semaphore.try_acquire().unwrap().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!permit.extend().unwrap());
+        assert!(semaphore.try_acquire().unwrap().is_some());
+        assert!(!permit.release().unwrap());
+    }
+}
@@ -0,0 +1,439 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::{Endpoint, RedisConnection, RedisConnectionManager};
+
+/// A `r2d2::ManageConnection` for connections to the master of a Redis
+/// Sentinel deployment.
+///
+/// The sentinels are asked for the current master address whenever a new
+/// connection is established, and the server is checked to actually be a
+/// master before the connection is handed to the pool, since the sentinels'
+/// view may be outdated during a failover. Connections are
+/// otherwise managed like those of a `RedisConnectionManager`, configured
+/// with `RedisConnectionManagerBuilder::build_sentinel`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisSentinelConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisSentinelConnectionManager::new(
+///         vec!["redis://sentinel-1:26379", "redis://sentinel-2:26379"],
+///         "mymaster",
+///     )
+///     .unwrap();
+///     let pool = r2d2::Pool::builder()
+///         .build(manager)
+///         .unwrap();
+///
+///     pool.get().unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RedisSentinelConnectionManager {
+    manager: RedisConnectionManager,
+}
+
+impl RedisSentinelConnectionManager {
+    pub(crate) fn from_manager(manager: RedisConnectionManager) -> RedisSentinelConnectionManager {
+        RedisSentinelConnectionManager { manager }
+    }
+
+    /// Creates a new `RedisSentinelConnectionManager` for the master named
+    /// `master_name`, monitored by `sentinels`.
+    ///
+    /// See `redis::Client::open` for a description of the parameter
+    /// types.
+    pub fn new<I, T, N>(
+        sentinels: I,
+        master_name: N,
+    ) -> Result<RedisSentinelConnectionManager, redis::RedisError>
+    where
+        I: IntoIterator<Item = T>,
+        T: redis::IntoConnectionInfo,
+        N: Into<String>,
+    {
+        RedisConnectionManager::builder().build_sentinel(sentinels, master_name)
+    }
+
+    /// Creates a new `RedisSentinelConnectionManager` from a
+    /// `redis+sentinel://` URL, see
+    /// `RedisConnectionManagerBuilder::build_sentinel_url`.
+    pub fn from_url(url: &str) -> Result<RedisSentinelConnectionManager, redis::RedisError> {
+        RedisConnectionManager::builder().build_sentinel_url(url)
+    }
+
+    /// Returns a handle for draining the pool of this manager on shutdown.
+    pub fn drain_handle(&self) -> crate::DrainHandle {
+        self.manager.drain_handle()
+    }
+
+    /// Returns a handle to the circuit breaker of this manager, if one was
+    /// configured.
+    pub fn circuit_breaker_handle(&self) -> Option<crate::CircuitBreakerHandle> {
+        self.manager.circuit_breaker_handle()
+    }
+}
+
+impl r2d2::ManageConnection for RedisSentinelConnectionManager {
+    type Connection = RedisConnection;
+    type Error = redis::RedisError;
+
+    fn connect(&self) -> Result<RedisConnection, Self::Error> {
+        self.manager.connect()
+    }
+
+    fn is_valid(&self, conn: &mut RedisConnection) -> Result<(), Self::Error> {
+        self.manager.is_valid(conn)
+    }
+
+    fn has_broken(&self, conn: &mut RedisConnection) -> bool {
+        self.manager.has_broken(conn)
+    }
+}
+
+/// How long the watcher waits for a `+switch-master` event before checking
+/// whether the manager is still alive, and before retrying the sentinels.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The sentinels a `RedisConnectionManager` asks for the master address.
+pub(crate) struct Sentinel {
+    sentinels: Vec<Endpoint>,
+    master_name: String,
+    timeout: Option<Duration>,
+    master: Arc<Master>,
+}
+
+/// The last known address of the master.
+#[derive(Debug, Default)]
+struct Master {
+    addr: Mutex<Option<(String, u16)>>,
+    generation: AtomicU64,
+}
+
+impl Master {
+    /// Records `addr` as the master's address, starting a new generation if
+    /// it moved.
+    fn update(&self, addr: &(String, u16)) {
+        let mut current = self.addr.lock().unwrap();
+        if current.as_ref() != Some(addr) {
+            if current.is_some() {
+                self.generation.fetch_add(1, Ordering::Relaxed);
+            }
+            *current = Some(addr.clone());
+        }
+    }
+}
+
+impl Sentinel {
+    pub(crate) fn new(
+        sentinels: Vec<Endpoint>,
+        master_name: String,
+        timeout: Option<Duration>,
+    ) -> Sentinel {
+        Sentinel {
+            sentinels,
+            master_name,
+            timeout,
+            master: Arc::new(Master::default()),
+        }
+    }
+
+    /// Returns a number that changes whenever the master moves, so
+    /// connections made before can be told apart.
+    pub(crate) fn generation(&self) -> u64 {
+        self.master.generation.load(Ordering::Relaxed)
+    }
+
+    /// Starts a thread which subscribes to `+switch-master` events on the
+    /// sentinels, so a failover is noticed without waiting for a new
+    /// connection to be made. The thread stops once the sentinel is
+    /// dropped.
+    pub(crate) fn watch(&self) {
+        let clients = self
+            .sentinels
+            .iter()
+            .map(|sentinel| sentinel.client.clone())
+            .collect::<Vec<_>>();
+        let master_name = self.master_name.clone();
+        let master = Arc::downgrade(&self.master);
+        thread::spawn(move || {
+            while master.strong_count() > 0 {
+                for client in &clients {
+                    // Errors just move on to the next sentinel.
+                    let _ = watch(client, &master_name, &master);
+                    if master.strong_count() == 0 {
+                        return;
+                    }
+                }
+                thread::sleep(WATCH_INTERVAL);
+            }
+        });
+    }
+
+    /// Asks the sentinels in turn for the address of the master, returning
+    /// the first answer.
+    pub(crate) fn master_addr(&self) -> redis::RedisResult<(String, u16)> {
+        let mut last_error = None;
+        for sentinel in &self.sentinels {
+            match self.ask(sentinel) {
+                Ok(Some(addr)) => {
+                    self.master.update(&addr);
+                    return Ok(addr);
+                }
+                // Another sentinel may know the master, e.g. if this one's
+                // configuration is stale.
+                Ok(None) => {
+                    last_error = Some(
+                        (
+                            redis::ErrorKind::ClientError,
+                            "sentinel does not know the master",
+                            self.master_name.clone(),
+                        )
+                            .into(),
+                    )
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("no sentinels"))
+    }
+
+    fn ask(&self, sentinel: &Endpoint) -> redis::RedisResult<Option<(String, u16)>> {
+        let mut conn = match self.timeout {
+            Some(timeout) => sentinel.client.get_connection_with_timeout(timeout)?,
+            None => sentinel.client.get_connection()?,
+        };
+        conn.set_read_timeout(self.timeout)?;
+        conn.set_write_timeout(self.timeout)?;
+        redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&self.master_name)
+            .query(&mut conn)
+    }
+}
+
+/// Follows the `+switch-master` events of one sentinel until it fails or
+/// `master` is dropped.
+fn watch(
+    client: &redis::Client,
+    master_name: &str,
+    master: &Weak<Master>,
+) -> redis::RedisResult<()> {
+    let mut conn = client.get_connection_with_timeout(WATCH_INTERVAL)?;
+    conn.set_read_timeout(Some(WATCH_INTERVAL))?;
+    let mut pubsub = conn.as_pubsub();
+    pubsub.subscribe("+switch-master")?;
+    loop {
+        let msg = match pubsub.get_message() {
+            Ok(msg) => msg,
+            Err(ref e) if e.is_timeout() => match master.upgrade() {
+                Some(_) => continue,
+                None => return Ok(()),
+            },
+            Err(e) => return Err(e),
+        };
+        // The payload is `<name> <old-ip> <old-port> <new-ip> <new-port>`.
+        let payload: String = msg.get_payload()?;
+        let fields = payload.split(' ').collect::<Vec<_>>();
+        if let [name, _, _, host, port] = fields[..] {
+            if name != master_name {
+                continue;
+            }
+            let master = match master.upgrade() {
+                Some(master) => master,
+                None => return Ok(()),
+            };
+            if let Ok(port) = port.parse() {
+                master.update(&(host.to_owned(), port));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Sentinel {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Sentinel")
+            .field("sentinels", &self.sentinels)
+            .field("master_name", &self.master_name)
+            .field("master", &self.master)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::fake_server;
+    use r2d2::ManageConnection;
+    use redis::ConnectionLike;
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Instant;
+
+    fn addr_reply(addr: SocketAddr) -> String {
+        let host = addr.ip().to_string();
+        let port = addr.port().to_string();
+        format!(
+            "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+            host.len(),
+            host,
+            port.len(),
+            port
+        )
+    }
+
+    #[test]
+    fn test_sentinel() {
+        let sentinel = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |_| {
+            addr_reply("127.0.0.1:6379".parse().unwrap())
+        });
+        let manager = RedisSentinelConnectionManager::new(
+            vec![
+                "redis://127.0.0.1:1".to_string(),
+                format!("redis://{}", sentinel),
+            ],
+            "mymaster",
+        )
+        .unwrap();
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let mut conn = pool.get().unwrap();
+        redis::cmd("PING").query::<()>(&mut *conn).unwrap();
+    }
+
+    #[test]
+    fn test_sentinel_url() {
+        let sentinel = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |_| {
+            addr_reply("127.0.0.1:6379".parse().unwrap())
+        });
+        let manager = RedisSentinelConnectionManager::from_url(&format!(
+            "redis+sentinel://:secret@127.0.0.1:1,{}/mymaster?db=2",
+            sentinel
+        ))
+        .unwrap();
+        assert!(!format!("{:?}", manager).contains("secret"));
+        let conn = manager.connect().unwrap();
+        assert_eq!(2, conn.get_db());
+
+        for url in &[
+            "redis+sentinel://127.0.0.1:26379",
+            "redis+sentinel://127.0.0.1:26379/mymaster?db=x",
+            "redis+sentinel://127.0.0.1:26379/mymaster?foo=1",
+            "redis://127.0.0.1:26379/mymaster",
+        ] {
+            assert!(
+                RedisSentinelConnectionManager::from_url(url).is_err(),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn test_sentinel_unknown_master() {
+        let sentinel = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |_| {
+            "*-1\r\n".to_string()
+        });
+        let manager =
+            RedisSentinelConnectionManager::new(vec![format!("redis://{}", sentinel)], "mymaster")
+                .unwrap();
+        let err = manager.connect().err().unwrap();
+        assert!(err.to_string().contains("does not know the master"));
+
+        // The next sentinel is asked.
+        let other = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |_| {
+            addr_reply("127.0.0.1:6379".parse().unwrap())
+        });
+        let manager = RedisSentinelConnectionManager::new(
+            vec![
+                format!("redis://{}", sentinel),
+                format!("redis://{}", other),
+            ],
+            "mymaster",
+        )
+        .unwrap();
+        manager.connect().unwrap();
+    }
+
+    #[test]
+    fn test_sentinel_replica() {
+        // The fake acts as a sentinel pointing at itself, and as a replica.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        fake_server(listener, move |request| {
+            if request.contains("SENTINEL") {
+                addr_reply(addr)
+            } else {
+                "*5\r\n$5\r\nslave\r\n$9\r\n127.0.0.1\r\n:6380\r\n$9\r\nconnected\r\n:1\r\n"
+                    .to_string()
+            }
+        });
+        let manager =
+            RedisSentinelConnectionManager::new(vec![format!("redis://{}", addr)], "mymaster")
+                .unwrap();
+        let err = manager.connect().err().unwrap();
+        assert!(err.to_string().contains("not a master"));
+    }
+
+    #[test]
+    fn test_switch_master() {
+        let sentinel = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), |request| {
+            if request.contains("SUBSCRIBE") {
+                thread::sleep(Duration::from_millis(200));
+                let payload = "mymaster 127.0.0.1 6379 127.0.0.1 6380";
+                format!(
+                    "*3\r\n$9\r\nsubscribe\r\n$14\r\n+switch-master\r\n:1\r\n\
+                     *3\r\n$7\r\nmessage\r\n$14\r\n+switch-master\r\n${}\r\n{}\r\n",
+                    payload.len(),
+                    payload
+                )
+            } else {
+                addr_reply("127.0.0.1:6379".parse().unwrap())
+            }
+        });
+        let manager =
+            RedisSentinelConnectionManager::new(vec![format!("redis://{}", sentinel)], "mymaster")
+                .unwrap();
+        let mut conn = manager.connect().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while manager.is_valid(&mut conn).is_ok() {
+            assert!(Instant::now() < deadline, "switch not noticed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(manager.has_broken(&mut conn));
+    }
+
+    #[test]
+    fn test_sentinel_credentials() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let sentinel_requests = requests.clone();
+        let sentinel = fake_server(TcpListener::bind("127.0.0.1:0").unwrap(), move |request| {
+            sentinel_requests.lock().unwrap().push(request.to_owned());
+            if request.contains("AUTH") {
+                "+OK\r\n".to_string()
+            } else {
+                addr_reply("127.0.0.1:6379".parse().unwrap())
+            }
+        });
+        let manager = RedisConnectionManager::builder()
+            .watch_switch_master(false)
+            .password("master-secret")
+            .sentinel_password("sentinel-secret")
+            .build_sentinel(
+                vec![format!("redis://:url-secret@{}", sentinel)],
+                "mymaster",
+            )
+            .unwrap();
+        manager.connect().unwrap();
+
+        let requests = requests.lock().unwrap().join("");
+        assert!(requests.contains("SENTINEL-SECRET"));
+        assert!(!requests.contains("MASTER-SECRET"));
+        assert!(!requests.contains("URL-SECRET"));
+    }
+}
@@ -0,0 +1,328 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::lock::{token, EXTEND_SCRIPT, RELEASE_SCRIPT};
+use crate::pool_ext::checkout;
+use crate::RedisConnectionManager;
+
+/// The number of bits of a snowflake ID holding the worker ID.
+const WORKER_BITS: u32 = 10;
+/// The number of bits of a snowflake ID holding the sequence number.
+const SEQUENCE_BITS: u32 = 12;
+/// The number of worker IDs.
+const WORKERS: u64 = 1 << WORKER_BITS;
+
+/// Generates increasing IDs, unique across the processes sharing the key,
+/// allocated from Redis in blocks.
+///
+/// Each generator takes a block of `block_size` IDs at a time with
+/// `INCRBY`, and hands them out without a round trip, so IDs are unique but
+/// not ordered across processes, and the IDs of a block not handed out when
+/// a process stops are skipped. The first ID is 1.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, SequenceGenerator};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let orders = SequenceGenerator::new(pool, "sequences:orders").block_size(1000);
+///     println!("order {}", orders.next().unwrap());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SequenceGenerator {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    key: String,
+    block_size: u64,
+    /// The next ID of the block, and the end of the block.
+    block: Arc<Mutex<(u64, u64)>>,
+}
+
+impl SequenceGenerator {
+    /// Creates a `SequenceGenerator` of the counter `key`, with connections
+    /// of `pool`.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        key: S,
+    ) -> SequenceGenerator {
+        SequenceGenerator {
+            pool,
+            key: key.into(),
+            block_size: 100,
+            block: Arc::new(Mutex::new((0, 0))),
+        }
+    }
+
+    /// Sets how many IDs are allocated at a time.
+    ///
+    /// Defaults to 100.
+    pub fn block_size(mut self, block_size: u64) -> SequenceGenerator {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    /// Returns the next ID, allocating a block of IDs if the current one is
+    /// used up.
+    pub fn next(&self) -> redis::RedisResult<u64> {
+        let mut block = self.block.lock().unwrap();
+        if block.0 == block.1 {
+            let end: u64 = redis::cmd("INCRBY")
+                .arg(&self.key)
+                .arg(self.block_size)
+                .query(&mut *checkout(&self.pool)?)?;
+            *block = (end - self.block_size + 1, end + 1);
+        }
+        let id = block.0;
+        block.0 += 1;
+        Ok(id)
+    }
+}
+
+/// Generates 64-bit IDs ordered by time, unique across the processes sharing
+/// the name, made of a timestamp, a worker ID leased from Redis and a
+/// sequence number.
+///
+/// An ID holds the milliseconds since the `epoch` in its top 42 bits, the
+/// worker ID in the next 10 and a sequence number in the last 12, so each
+/// generator makes up to 4096 IDs per millisecond without a round trip. The
+/// worker ID is taken on first use by setting `<name>:worker:<id>` with `SET
+/// NX PX` for the duration of the `lease`, and the lease is renewed once half
+/// of it elapsed, or a new worker ID taken if it was lost, so at most 1024
+/// generators may run at once.
+///
+/// IDs never go backwards: if the clock does, or more than 4096 IDs are
+/// made in a millisecond, the generator carries on from the last timestamp.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, SnowflakeGenerator};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let ids = SnowflakeGenerator::new(pool, "snowflake:orders");
+///     let id = ids.next_id().unwrap();
+///     let (time, worker, sequence) = ids.decompose(id);
+///     println!("{} made by {} at {:?} ({})", id, worker, time, sequence);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SnowflakeGenerator {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    name: String,
+    epoch: SystemTime,
+    lease: Duration,
+    state: Arc<Mutex<SnowflakeState>>,
+}
+
+#[derive(Debug, Default)]
+struct SnowflakeState {
+    worker: Option<Worker>,
+    last_millis: u64,
+    sequence: u64,
+}
+
+#[derive(Debug)]
+struct Worker {
+    id: u64,
+    token: String,
+    renewed: Instant,
+}
+
+impl SnowflakeGenerator {
+    /// Creates a `SnowflakeGenerator` leasing worker IDs under `name`, with
+    /// connections of `pool`.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        name: S,
+    ) -> SnowflakeGenerator {
+        SnowflakeGenerator {
+            pool,
+            name: name.into(),
+            epoch: UNIX_EPOCH + Duration::from_secs(1_577_836_800),
+            lease: Duration::from_secs(60),
+            state: Arc::new(Mutex::new(SnowflakeState::default())),
+        }
+    }
+
+    /// Sets the time IDs count from, which must be the same for every
+    /// generator sharing the name.
+    ///
+    /// Defaults to 2020-01-01 UTC.
+    pub fn epoch(mut self, epoch: SystemTime) -> SnowflakeGenerator {
+        self.epoch = epoch;
+        self
+    }
+
+    /// Sets how long worker IDs are leased for.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn lease(mut self, lease: Duration) -> SnowflakeGenerator {
+        self.lease = lease.max(Duration::from_millis(2));
+        self
+    }
+
+    /// Returns the next ID, leasing a worker ID or renewing the lease first
+    /// if needed.
+    pub fn next_id(&self) -> redis::RedisResult<u64> {
+        let mut state = self.state.lock().unwrap();
+        let worker = self.ensure_worker(&mut state)?;
+        let now = SystemTime::now()
+            .duration_since(self.epoch)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        if now > state.last_millis {
+            state.last_millis = now;
+            state.sequence = 0;
+        } else if state.sequence + 1 < 1 << SEQUENCE_BITS {
+            state.sequence += 1;
+        } else {
+            state.last_millis += 1;
+            state.sequence = 0;
+        }
+        Ok(state.last_millis << (WORKER_BITS + SEQUENCE_BITS)
+            | worker << SEQUENCE_BITS
+            | state.sequence)
+    }
+
+    /// Returns the worker ID currently leased, if any.
+    pub fn worker_id(&self) -> Option<u16> {
+        let state = self.state.lock().unwrap();
+        state.worker.as_ref().map(|worker| worker.id as u16)
+    }
+
+    /// Splits `id` into the time it was made at, its worker ID and its
+    /// sequence number.
+    pub fn decompose(&self, id: u64) -> (SystemTime, u16, u16) {
+        let millis = id >> (WORKER_BITS + SEQUENCE_BITS);
+        let worker = (id >> SEQUENCE_BITS) & (WORKERS - 1);
+        let sequence = id & ((1 << SEQUENCE_BITS) - 1);
+        (
+            self.epoch + Duration::from_millis(millis),
+            worker as u16,
+            sequence as u16,
+        )
+    }
+
+    /// Gives up the worker ID, if one is leased, so another generator can
+    /// take it; a later `next_id` leases one again.
+    pub fn release(&self) -> redis::RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(worker) = state.worker.take() {
+            redis::Script::new(RELEASE_SCRIPT)
+                .key(self.worker_key(worker.id))
+                .arg(&worker.token)
+                .invoke::<()>(&mut *checkout(&self.pool)?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the worker ID, leased or renewed if half of the lease elapsed.
+    fn ensure_worker(&self, state: &mut SnowflakeState) -> redis::RedisResult<u64> {
+        if let Some(ref mut worker) = state.worker {
+            if worker.renewed.elapsed() < self.lease / 2 {
+                return Ok(worker.id);
+            }
+            let start = Instant::now();
+            let renewed: i64 = redis::Script::new(EXTEND_SCRIPT)
+                .key(self.worker_key(worker.id))
+                .arg(&worker.token)
+                .arg(self.lease.as_millis() as u64)
+                .invoke(&mut *checkout(&self.pool)?)?;
+            if renewed > 0 {
+                worker.renewed = start;
+                return Ok(worker.id);
+            }
+            log::warn!(
+                "snowflake worker ID {} of {} was lost",
+                worker.id,
+                self.name
+            );
+            state.worker = None;
+        }
+
+        let mut conn = checkout(&self.pool)?;
+        let token = token();
+        let first: u64 = redis::cmd("INCR")
+            .arg(format!("{}:next-worker", self.name))
+            .query(&mut *conn)?;
+        for i in 0..WORKERS {
+            let id = (first + i) % WORKERS;
+            let start = Instant::now();
+            let leased: Option<()> = redis::cmd("SET")
+                .arg(self.worker_key(id))
+                .arg(&token)
+                .arg("NX")
+                .arg("PX")
+                .arg(self.lease.as_millis() as u64)
+                .query(&mut *conn)?;
+            if leased.is_some() {
+                state.worker = Some(Worker {
+                    id,
+                    token,
+                    renewed: start,
+                });
+                return Ok(id);
+            }
+        }
+        Err((
+            redis::ErrorKind::ClientError,
+            "no snowflake worker ID is free",
+        )
+            .into())
+    }
+
+    fn worker_key(&self, id: u64) -> String {
+        format!("{}:worker:{}", self.name, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_sequence_generator() {
+        let key = format!("redis_r2d2-sequence-{}", crate::lock::token());
+        let first = SequenceGenerator::new(crate::local_pool(2), &key[..]).block_size(3);
+        let second = SequenceGenerator::new(crate::local_pool(2), &key[..]).block_size(3);
+        assert_eq!(1, first.next().unwrap());
+        assert_eq!(4, second.next().unwrap());
+        assert_eq!(2, first.clone().next().unwrap());
+        let ids: HashSet<u64> = (0..10)
+            .flat_map(|_| vec![first.next().unwrap(), second.next().unwrap()])
+            .collect();
+        assert_eq!(20, ids.len());
+        assert!(!ids.contains(&1) && !ids.contains(&2) && !ids.contains(&4));
+    }
+
+    #[test]
+    fn test_snowflake_generator() {
+        let name = format!("redis_r2d2-snowflake-{}", crate::lock::token());
+        let first = SnowflakeGenerator::new(crate::local_pool(2), &name[..]);
+        let second = SnowflakeGenerator::new(crate::local_pool(2), &name[..]);
+        assert_eq!(None, first.worker_id());
+
+        let before = SystemTime::now() - Duration::from_millis(1);
+        let ids: Vec<u64> = (0..5000).map(|_| first.next_id().unwrap()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let (time, worker, sequence) = first.decompose(ids[0]);
+        assert!(time >= before && time <= SystemTime::now());
+        assert_eq!(first.worker_id(), Some(worker));
+        assert_eq!(0, sequence);
+
+        let other = second.next_id().unwrap();
+        assert_ne!(first.worker_id(), second.worker_id());
+        assert!(!ids.contains(&other));
+
+        first.release().unwrap();
+        assert_eq!(None, first.worker_id());
+        first.next_id().unwrap();
+        assert_ne!(first.worker_id(), second.worker_id());
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::pool_ext::checkout;
+use crate::{Codec, JsonCodec, RedisConnectionManager};
+
+/// The number of random bytes in a session ID.
+const ID_BYTES: usize = 32;
+
+/// Stores sessions of type `T` in Redis, e.g. for web middleware.
+///
+/// Each session is stored under `<prefix>:<id>` with a TTL that slides: it
+/// is reset whenever the session is loaded or updated, so idle sessions
+/// expire. Session IDs are 256 random bits from the operating system, in
+/// hex. With a `signing_key`, the IDs handed out also carry an HMAC-SHA256 of
+/// the random part, and IDs with a wrong signature are treated as unknown
+/// without a round trip, so forged cookies don't reach the server.
+///
+/// Requires the `sessions` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, RedisConnectionManager, SessionStore};
+///
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct Login {
+///     user_id: u64,
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let sessions = SessionStore::<Login>::new(pool, "sessions", Duration::from_secs(1800))
+///         .signing_key(b"a long random secret");
+///     let id = sessions.create(&Login { user_id: 42 }).unwrap();
+///     let login = sessions.load(&id).unwrap();
+///     assert_eq!(Some(42), login.map(|login| login.user_id));
+/// }
+/// ```
+pub struct SessionStore<T, C = JsonCodec> {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    prefix: String,
+    ttl: Duration,
+    codec: C,
+    signing_key: Option<Arc<[u8]>>,
+    _value: PhantomData<fn(&T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> SessionStore<T> {
+    /// Creates a `SessionStore` storing JSON sessions under `prefix`, which
+    /// expire after being idle for `ttl`.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        prefix: S,
+        ttl: Duration,
+    ) -> SessionStore<T> {
+        SessionStore::with_codec(pool, prefix, ttl, JsonCodec)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> SessionStore<T, C> {
+    /// Creates a `SessionStore` storing sessions encoded with `codec` under
+    /// `prefix`, which expire after being idle for `ttl`.
+    pub fn with_codec<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        prefix: S,
+        ttl: Duration,
+        codec: C,
+    ) -> SessionStore<T, C> {
+        SessionStore {
+            pool,
+            prefix: prefix.into(),
+            ttl,
+            codec,
+            signing_key: None,
+            _value: PhantomData,
+        }
+    }
+
+    /// Signs the session IDs with `key`, which should be long and random,
+    /// and kept secret.
+    ///
+    /// Sessions created without the key, or with another one, can't be
+    /// loaded anymore.
+    pub fn signing_key(mut self, key: &[u8]) -> SessionStore<T, C> {
+        self.signing_key = Some(key.into());
+        self
+    }
+
+    /// Returns how long sessions are kept after their last use.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Stores a new session holding `data`, returning its ID.
+    pub fn create(&self, data: &T) -> redis::RedisResult<String> {
+        let mut bytes = [0; ID_BYTES];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let id = hex(&bytes);
+        redis::cmd("SET")
+            .arg(self.key(&id))
+            .arg(self.codec.encode(data)?)
+            .arg("PX")
+            .arg(self.ttl_ms())
+            .arg("NX")
+            .query::<()>(&mut *checkout(&self.pool)?)?;
+        Ok(match self.signing_key {
+            Some(ref key) => format!("{}.{}", id, hex(&sign(key, &id).finalize().into_bytes())),
+            None => id,
+        })
+    }
+
+    /// Returns the data of the session `id` and resets its TTL, or `None` if
+    /// it expired, was destroyed or never existed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `TypeError` if the stored data can't be
+    /// decoded, e.g. because it was written by an older version of `T`.
+    pub fn load(&self, id: &str) -> redis::RedisResult<Option<T>> {
+        let key = match self.verify(id) {
+            Some(id) => self.key(id),
+            None => return Ok(None),
+        };
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("GET")
+            .arg(&key)
+            .cmd("PEXPIRE")
+            .arg(&key)
+            .arg(self.ttl_ms())
+            .ignore();
+        let (payload,): (Option<Vec<u8>>,) = pipe.query(&mut *checkout(&self.pool)?)?;
+        payload
+            .map(|payload| self.codec.decode(&payload))
+            .transpose()
+    }
+
+    /// Replaces the data of the session `id` with `data` and resets its
+    /// TTL, returning false, without storing anything, if the session
+    /// expired, was destroyed or never existed.
+    pub fn update(&self, id: &str, data: &T) -> redis::RedisResult<bool> {
+        let key = match self.verify(id) {
+            Some(id) => self.key(id),
+            None => return Ok(false),
+        };
+        let reply: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(self.codec.encode(data)?)
+            .arg("PX")
+            .arg(self.ttl_ms())
+            .arg("XX")
+            .query(&mut *checkout(&self.pool)?)?;
+        Ok(reply.is_some())
+    }
+
+    /// Deletes the session `id`, returning false if there was none.
+    pub fn destroy(&self, id: &str) -> redis::RedisResult<bool> {
+        let key = match self.verify(id) {
+            Some(id) => self.key(id),
+            None => return Ok(false),
+        };
+        let deleted: u32 = redis::cmd("DEL")
+            .arg(key)
+            .query(&mut *checkout(&self.pool)?)?;
+        Ok(deleted > 0)
+    }
+
+    /// Returns the random part of `id` if its signature is valid, or if IDs
+    /// aren't signed.
+    fn verify<'a>(&self, id: &'a str) -> Option<&'a str> {
+        let key = match self.signing_key {
+            Some(ref key) => key,
+            None => return Some(id),
+        };
+        let (id, signature) = id.split_at(id.find('.')?);
+        let signature = unhex(&signature[1..])?;
+        sign(key, id).verify_slice(&signature).ok()?;
+        Some(id)
+    }
+
+    fn key(&self, id: &str) -> String {
+        format!("{}:{}", self.prefix, id)
+    }
+
+    fn ttl_ms(&self) -> u64 {
+        self.ttl.as_millis().max(1) as u64
+    }
+}
+
+impl<T, C: Clone> Clone for SessionStore<T, C> {
+    fn clone(&self) -> SessionStore<T, C> {
+        SessionStore {
+            pool: self.pool.clone(),
+            prefix: self.prefix.clone(),
+            ttl: self.ttl,
+            codec: self.codec.clone(),
+            signing_key: self.signing_key.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for SessionStore<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SessionStore")
+            .field("pool", &self.pool)
+            .field("prefix", &self.prefix)
+            .field("ttl", &self.ttl)
+            .field("codec", &self.codec)
+            .field(
+                "signing_key",
+                &self.signing_key.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+/// Returns the HMAC-SHA256 of `id` with `key`, to finalize or verify.
+fn sign(key: &[u8], id: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(id.as_bytes());
+    mac
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Login {
+        user_id: u64,
+    }
+
+    fn store(ttl: Duration) -> SessionStore<Login> {
+        let pool = crate::local_pool(1);
+        SessionStore::new(pool, "redis_r2d2-sessions", ttl)
+    }
+
+    #[test]
+    fn test_sessions() {
+        let sessions = store(Duration::from_secs(10));
+        let id = sessions.create(&Login { user_id: 1 }).unwrap();
+        assert_eq!(2 * ID_BYTES, id.len());
+        assert_eq!(Some(Login { user_id: 1 }), sessions.load(&id).unwrap());
+        assert!(sessions.update(&id, &Login { user_id: 2 }).unwrap());
+        assert_eq!(Some(Login { user_id: 2 }), sessions.load(&id).unwrap());
+        assert!(sessions.destroy(&id).unwrap());
+        assert_eq!(None, sessions.load(&id).unwrap());
+        assert!(!sessions.update(&id, &Login { user_id: 3 }).unwrap());
+        assert!(!sessions.destroy(&id).unwrap());
+    }
+
+    #[test]
+    fn test_sliding_expiry() {
+        let sessions = store(Duration::from_millis(300));
+        let id = sessions.create(&Login { user_id: 1 }).unwrap();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(150));
+            assert!(sessions.load(&id).unwrap().is_some());
+        }
+        thread::sleep(Duration::from_millis(450));
+        assert_eq!(None, sessions.load(&id).unwrap());
+    }
+
+    #[test]
+    fn test_signed_ids() {
+        let sessions = store(Duration::from_secs(10)).signing_key(b"secret");
+        let id = sessions.create(&Login { user_id: 1 }).unwrap();
+        assert_eq!(Some(Login { user_id: 1 }), sessions.load(&id).unwrap());
+
+        let (raw, _) = id.split_at(id.find('.').unwrap());
+        assert_eq!(None, sessions.load(raw).unwrap());
+        let forged = format!("{}.{}", raw, "0".repeat(64));
+        assert_eq!(None, sessions.load(&forged).unwrap());
+        assert!(!sessions.destroy(&forged).unwrap());
+        let other = store(Duration::from_secs(10)).signing_key(b"other");
+        assert_eq!(None, other.load(&id).unwrap());
+    }
+}
@@ -0,0 +1,252 @@
+use r2d2::{ManageConnection, Pool, PooledConnection};
+use redis::ToRedisArgs;
+
+use crate::RedisConnectionManager;
+
+/// A set of pools to standalone Redis servers, each owning a share of the
+/// keys.
+///
+/// Keys are assigned to shards by rendezvous hashing on the shard names, so
+/// adding a shard only moves the keys the new shard takes over, and removing
+/// one only moves the keys it owned. The assignment only depends on the
+/// names, not on the order the shards were added in, so every client with the
+/// same shard names agrees on it.
+///
+/// As in Redis Cluster, if a key contains a `{...}` hash tag, only the tag is
+/// hashed, so keys sharing a tag end up on the same shard.
+///
+/// ## Example
+///
+/// ```
+/// use redis_r2d2::{r2d2, RedisConnectionManager, ShardedPool};
+///
+/// fn main() {
+///     let pool = |url| {
+///         r2d2::Pool::builder()
+///             .build(RedisConnectionManager::new(url).unwrap())
+///             .unwrap()
+///     };
+///     let mut shards = ShardedPool::new();
+///     shards.add_shard("shard-0", pool("redis://localhost/0"));
+///     shards.add_shard("shard-1", pool("redis://localhost/1"));
+///
+///     let mut conn = shards.get_for_key("user:{42}:name").unwrap();
+///     redis::cmd("GET").arg("user:{42}:name").query::<Option<String>>(&mut *conn).unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ShardedPool<M: ManageConnection = RedisConnectionManager> {
+    shards: Vec<(String, Pool<M>)>,
+}
+
+impl<M: ManageConnection> Clone for ShardedPool<M> {
+    fn clone(&self) -> ShardedPool<M> {
+        ShardedPool {
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<M: ManageConnection> Default for ShardedPool<M> {
+    fn default() -> ShardedPool<M> {
+        ShardedPool::new()
+    }
+}
+
+impl<M: ManageConnection> ShardedPool<M> {
+    /// Creates a `ShardedPool` without shards.
+    pub fn new() -> ShardedPool<M> {
+        ShardedPool { shards: Vec::new() }
+    }
+
+    /// Adds a shard, returning the pool it replaces if a shard with the same
+    /// name already existed.
+    pub fn add_shard<N: Into<String>>(&mut self, name: N, pool: Pool<M>) -> Option<Pool<M>> {
+        let name = name.into();
+        match self.shards.iter_mut().find(|(n, _)| *n == name) {
+            Some(shard) => Some(std::mem::replace(&mut shard.1, pool)),
+            None => {
+                self.shards.push((name, pool));
+                None
+            }
+        }
+    }
+
+    /// Removes a shard, returning its pool.
+    pub fn remove_shard(&mut self, name: &str) -> Option<Pool<M>> {
+        let index = self.shards.iter().position(|(n, _)| n == name)?;
+        Some(self.shards.remove(index).1)
+    }
+
+    /// Makes the shards the ones named in `names`, removing the others and
+    /// adding the missing ones with pools from `new_pool`, e.g. to follow
+    /// the pods discovered behind a service. Returns the removed shards.
+    pub fn set_shards<I, N, F>(&mut self, names: I, mut new_pool: F) -> Vec<(String, Pool<M>)>
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+        F: FnMut(&str) -> Pool<M>,
+    {
+        let names = names.into_iter().map(Into::into).collect::<Vec<String>>();
+        let (kept, removed) = std::mem::take(&mut self.shards)
+            .into_iter()
+            .partition(|(name, _)| names.contains(name));
+        self.shards = kept;
+        for name in names {
+            if !self.shards.iter().any(|(n, _)| *n == name) {
+                let pool = new_pool(&name);
+                self.shards.push((name, pool));
+            }
+        }
+        removed
+    }
+
+    /// Returns the names of the shards.
+    pub fn shard_names(&self) -> impl Iterator<Item = &str> {
+        self.shards.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Returns the name of the shard owning `key`, or `None` if there are no
+    /// shards.
+    pub fn shard_for_key<K: ToRedisArgs>(&self, key: K) -> Option<&str> {
+        self.find(key).map(|(name, _)| name.as_str())
+    }
+
+    /// Returns the pool of the shard owning `key`, or `None` if there are no
+    /// shards.
+    pub fn pool_for_key<K: ToRedisArgs>(&self, key: K) -> Option<&Pool<M>> {
+        self.find(key).map(|(_, pool)| pool)
+    }
+
+    /// Checks out a connection to the shard owning `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no shards.
+    pub fn get_for_key<K: ToRedisArgs>(&self, key: K) -> Result<PooledConnection<M>, r2d2::Error> {
+        self.pool_for_key(key)
+            .expect("the sharded pool has no shards")
+            .get()
+    }
+
+    fn find<K: ToRedisArgs>(&self, key: K) -> Option<&(String, Pool<M>)> {
+        let key = key.to_redis_args().concat();
+        let key = hash_tag(&key);
+        self.shards
+            .iter()
+            .max_by_key(|(name, _)| (score(name.as_bytes(), key), name.as_str()))
+    }
+}
+
+/// Returns the part of `key` that is hashed, following the hash tag rules of
+/// Redis Cluster.
+fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|&b| b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|&b| b == b'}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+/// The rendezvous score of `key` on the shard `name`.
+///
+/// FNV-1a followed by a 64 bit finalizer, which unlike the std hashers is
+/// guaranteed to stay the same across releases.
+fn score(name: &[u8], key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in name.iter().chain(&[0xff]).chain(key) {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::ConnectionLike;
+
+    fn pool(url: &str) -> Pool<RedisConnectionManager> {
+        r2d2::Pool::builder()
+            .max_size(1)
+            .build_unchecked(RedisConnectionManager::new(url).unwrap())
+    }
+
+    #[test]
+    fn test_sharded_pool() {
+        let mut shards = ShardedPool::new();
+        assert_eq!(None, shards.shard_for_key("key"));
+        shards.add_shard("a", pool("redis://localhost/0"));
+        shards.add_shard("b", pool("redis://localhost/1"));
+
+        let shard = shards.shard_for_key("key").unwrap().to_string();
+        let db = shards.get_for_key("key").unwrap().get_db();
+        assert_eq!(if shard == "a" { 0 } else { 1 }, db);
+
+        // Keys sharing a hash tag share a shard.
+        for i in 0..20 {
+            assert_eq!(
+                shards.shard_for_key("{user:1}"),
+                shards.shard_for_key(format!("{{user:1}}:{}", i))
+            );
+        }
+        assert_eq!(b"user:1", hash_tag(b"x{user:1}y"));
+        assert_eq!(b"x{}y", hash_tag(b"x{}y"));
+
+        assert!(shards.add_shard("a", pool("redis://localhost/2")).is_some());
+        assert!(shards.remove_shard("a").is_some());
+        assert!(shards.remove_shard("a").is_none());
+        assert_eq!(vec!["b"], shards.shard_names().collect::<Vec<_>>());
+
+        let removed = shards.set_shards(vec!["c", "d"], |_| pool("redis://localhost"));
+        assert_eq!(
+            vec!["b"],
+            removed.iter().map(|(n, _)| n).collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["c", "d"], shards.shard_names().collect::<Vec<_>>());
+        shards.set_shards(vec!["d", "c"], |_| unreachable!());
+    }
+
+    #[test]
+    fn test_rebalancing() {
+        let mut shards = ShardedPool::new();
+        for name in &["a", "b", "c"] {
+            shards.add_shard(*name, pool("redis://localhost"));
+        }
+        let owners = |shards: &ShardedPool| {
+            (0..1000)
+                .map(|i| shards.shard_for_key(i).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let before = owners(&shards);
+        for name in &["a", "b", "c"] {
+            let count = before.iter().filter(|owner| owner == name).count();
+            assert!(count > 250 && count < 420, "{}: {}", name, count);
+        }
+
+        // Only the keys taken over by the new shard move.
+        shards.add_shard("d", pool("redis://localhost"));
+        let after = owners(&shards);
+        for (before, after) in before.iter().zip(&after) {
+            assert!(before == after || after == "d");
+        }
+
+        // Removing it moves them back.
+        shards.remove_shard("d");
+        assert_eq!(before, owners(&shards));
+
+        // The order shards are added in doesn't matter.
+        let mut reordered = ShardedPool::new();
+        for name in &["c", "a", "b"] {
+            reordered.add_shard(*name, pool("redis://localhost"));
+        }
+        assert_eq!(before, owners(&reordered));
+    }
+}
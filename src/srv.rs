@@ -0,0 +1,572 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backoff::random;
+
+/// A DNS SRV record, naming a host and port that provide a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Targets with a lower priority are tried first.
+    pub priority: u16,
+    /// Among targets of the same priority, the relative chance of a target
+    /// being tried first.
+    pub weight: u16,
+    pub port: u16,
+    /// The host name of the target, without the trailing dot.
+    pub target: String,
+}
+
+/// Looks up DNS SRV records for `RedisConnectionManagerBuilder::build_srv`.
+///
+/// The standard library can't query SRV records, so `DnsSrvResolver`
+/// implements a minimal DNS client. Implement this trait to use another
+/// one, e.g. a caching resolver library.
+pub trait SrvResolver: fmt::Debug + Send + Sync + 'static {
+    /// Returns the SRV records of `name`, in any order.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection attempt fails with it
+    /// unless records from an earlier lookup are still cached.
+    fn resolve_srv(&self, name: &str) -> io::Result<Vec<SrvRecord>>;
+}
+
+/// A `SrvResolver` sending queries over UDP to a list of name servers in
+/// turn, by default those of `/etc/resolv.conf`.
+///
+/// Answers too large for UDP, which name servers mark as truncated, are
+/// fetched again over TCP.
+#[derive(Debug, Clone)]
+pub struct DnsSrvResolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl DnsSrvResolver {
+    /// Creates a `DnsSrvResolver` asking the name servers of
+    /// `/etc/resolv.conf`, or `127.0.0.1` if none are listed.
+    pub fn system() -> DnsSrvResolver {
+        let conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+        let mut nameservers = conf
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some("nameserver"), Some(ip)) => ip.parse::<IpAddr>().ok(),
+                    _ => None,
+                }
+            })
+            .map(|ip| SocketAddr::new(ip, 53))
+            .collect::<Vec<_>>();
+        if nameservers.is_empty() {
+            nameservers.push(SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 53));
+        }
+        DnsSrvResolver::new(nameservers)
+    }
+
+    /// Creates a `DnsSrvResolver` asking `nameservers`.
+    pub fn new(nameservers: Vec<SocketAddr>) -> DnsSrvResolver {
+        DnsSrvResolver {
+            nameservers,
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Sets how long to wait for each name server to answer.
+    ///
+    /// Defaults to 2 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> DnsSrvResolver {
+        self.timeout = timeout;
+        self
+    }
+
+    fn query(&self, nameserver: SocketAddr, name: &str) -> io::Result<Vec<SrvRecord>> {
+        let bind: SocketAddr = if nameserver.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(nameserver)?;
+
+        // A random ID, so that forged answers are hard to pass off as ours.
+        let id = (random() * 65536.0) as u16;
+        let query = encode_query(id, name)?;
+        socket.send(&query)?;
+        let mut buf = [0; 4096];
+        loop {
+            let n = socket.recv(&mut buf)?;
+            // Ignore stray answers to earlier queries.
+            if n < 2 || buf[..2] != id.to_be_bytes() {
+                continue;
+            }
+            if is_truncated(&buf[..n]) {
+                return self.query_tcp(nameserver, &query);
+            }
+            return decode_answer(&buf[..n]);
+        }
+    }
+
+    /// Sends `query` over TCP, for answers that don't fit in a datagram.
+    fn query_tcp(&self, nameserver: SocketAddr, query: &[u8]) -> io::Result<Vec<SrvRecord>> {
+        let mut stream = TcpStream::connect_timeout(&nameserver, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        // Messages are prefixed with their length over TCP.
+        let mut request = (query.len() as u16).to_be_bytes().to_vec();
+        request.extend_from_slice(query);
+        stream.write_all(&request)?;
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut answer = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut answer)?;
+        if answer.get(..2) != query.get(..2) {
+            return Err(malformed("answer to another query"));
+        }
+        decode_answer(&answer)
+    }
+}
+
+impl SrvResolver for DnsSrvResolver {
+    fn resolve_srv(&self, name: &str) -> io::Result<Vec<SrvRecord>> {
+        let mut last_error = None;
+        for &nameserver in &self.nameservers {
+            match self.query(nameserver, name) {
+                Ok(records) => return Ok(records),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no name servers")))
+    }
+}
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+fn encode_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(malformed("invalid name"));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Returns whether `msg` has the TC flag, set by name servers on answers too
+/// large for a datagram.
+fn is_truncated(msg: &[u8]) -> bool {
+    msg.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// Decodes the SRV records of an answer.
+///
+/// Fails with an `InvalidData` error reading "truncated answer" if the
+/// answer has the TC flag, as its records may be incomplete.
+fn decode_answer(msg: &[u8]) -> io::Result<Vec<SrvRecord>> {
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        msg.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| malformed("truncated message"))
+    };
+    let flags = u16_at(2)?;
+    if is_truncated(msg) {
+        return Err(malformed("truncated answer"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        // The name doesn't exist.
+        3 => return Ok(Vec::new()),
+        rcode => {
+            return Err(io::Error::other(format!(
+                "name server answered with error {}",
+                rcode
+            )))
+        }
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let rtype = u16_at(pos)?;
+        let len = u16_at(pos + 8)? as usize;
+        let data = pos + 10;
+        if rtype == TYPE_SRV {
+            records.push(SrvRecord {
+                priority: u16_at(data)?,
+                weight: u16_at(data + 2)?,
+                port: u16_at(data + 4)?,
+                target: read_name(msg, data + 6)?.0,
+            });
+        }
+        pos = data + len;
+    }
+    Ok(records)
+}
+
+/// Reads the possibly compressed name at `pos`, returning it and the
+/// position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Each pointer must go backwards, which bounds the jumps.
+    let mut limit = pos;
+    loop {
+        let len = *msg.get(pos).ok_or_else(|| malformed("truncated name"))? as usize;
+        if len & 0xc0 == 0xc0 {
+            let low = *msg
+                .get(pos + 1)
+                .ok_or_else(|| malformed("truncated name"))? as usize;
+            let target = (len & 0x3f) << 8 | low;
+            if target >= limit {
+                return Err(malformed("invalid name pointer"));
+            }
+            end.get_or_insert(pos + 2);
+            limit = target;
+            pos = target;
+        } else if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        } else {
+            let label = msg
+                .get(pos + 1..pos + 1 + len)
+                .ok_or_else(|| malformed("truncated name"))?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+}
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("DNS: {}", reason))
+}
+
+/// The hosts and ports of an SRV record, in the order they are tried.
+type Targets = Vec<(String, u16)>;
+
+/// The cached records of the SRV record a manager connects to.
+pub(crate) struct Srv {
+    name: String,
+    resolver: Arc<dyn SrvResolver>,
+    refresh_interval: Duration,
+    /// The records and when they must be looked up again.
+    cached: Mutex<Option<(Vec<SrvRecord>, Instant)>>,
+}
+
+impl Srv {
+    pub(crate) fn new(
+        name: String,
+        resolver: Arc<dyn SrvResolver>,
+        refresh_interval: Duration,
+    ) -> Srv {
+        Srv {
+            name,
+            resolver,
+            refresh_interval,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Returns the targets to try, in the order of `weighted_order`, looking
+    /// the record up again if the cached records are older than the refresh
+    /// interval.
+    ///
+    /// If the lookup fails, the cached records are used until it succeeds.
+    pub(crate) fn targets(&self) -> redis::RedisResult<Targets> {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((ref records, refresh_at)) = *cached {
+            if Instant::now() < refresh_at {
+                return Ok(weighted_order(records.clone()));
+            }
+        }
+        match self.resolver.resolve_srv(&self.name) {
+            Ok(records) if !records.is_empty() => {
+                *cached = Some((records.clone(), Instant::now() + self.refresh_interval));
+                Ok(weighted_order(records))
+            }
+            result => match *cached {
+                Some((ref records, _)) => {
+                    log::warn!(
+                        "SRV lookup of {} failed, using the previous targets",
+                        self.name
+                    );
+                    Ok(weighted_order(records.clone()))
+                }
+                None => Err(match result {
+                    Err(e) => e.into(),
+                    Ok(_) => (
+                        redis::ErrorKind::IoError,
+                        "SRV record has no targets",
+                        self.name.clone(),
+                    )
+                        .into(),
+                }),
+            },
+        }
+    }
+
+    /// Makes the next `targets` call look the record up again, e.g. after
+    /// none of the targets accepted a connection.
+    pub(crate) fn invalidate(&self) {
+        if let Some((_, ref mut refresh_at)) = *self.cached.lock().unwrap() {
+            *refresh_at = Instant::now();
+        }
+    }
+}
+
+/// Orders `records` as RFC 2782 says targets are tried: by ascending
+/// priority, then within each priority by repeatedly picking one of the
+/// remaining records at random, with a chance proportional to its weight.
+/// Records of weight 0 come last within their priority, in the order given.
+fn weighted_order(mut records: Vec<SrvRecord>) -> Targets {
+    records.sort_by_key(|record| record.priority);
+    let mut targets = Vec::with_capacity(records.len());
+    let mut records = records.into_iter().peekable();
+    while let Some(first) = records.next() {
+        let priority = first.priority;
+        let mut group = vec![first];
+        while let Some(record) = records.next_if(|record| record.priority == priority) {
+            group.push(record);
+        }
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|record| u32::from(record.weight)).sum();
+            let index = if total == 0 {
+                0
+            } else {
+                // Between 1 and `total`, inclusive.
+                let pick = 1 + (random() * f64::from(total)) as u32;
+                let mut sum = 0;
+                group
+                    .iter()
+                    .position(|record| {
+                        sum += u32::from(record.weight);
+                        sum >= pick
+                    })
+                    .unwrap_or(0)
+            };
+            let record = group.remove(index);
+            targets.push((record.target, record.port));
+        }
+    }
+    targets
+}
+
+impl fmt::Debug for Srv {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Srv")
+            .field("name", &self.name)
+            .field("resolver", &self.resolver)
+            .field("refresh_interval", &self.refresh_interval)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Answers one SRV query on `socket` with two records, the second using
+    /// a compressed target name.
+    fn answer(socket: &UdpSocket) {
+        let mut buf = [0; 512];
+        let (n, peer) = socket.recv_from(&mut buf).unwrap();
+        let query = &buf[..n];
+        let mut msg = query[..2].to_vec();
+        msg.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0]);
+        msg.extend_from_slice(&query[12..]);
+        let record = |msg: &mut Vec<u8>, priority: u8, port: u16, target: &[u8]| {
+            msg.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60]);
+            msg.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&[0, priority, 0, 5]);
+            msg.extend_from_slice(&port.to_be_bytes());
+            msg.extend_from_slice(target);
+        };
+        record(&mut msg, 10, 6380, b"\x06redis2\x05cache\x08internal\x00");
+        record(&mut msg, 0, 6379, b"\x06redis1\xc0\x18");
+        socket.send_to(&msg, peer).unwrap();
+    }
+
+    #[test]
+    fn test_dns_srv_resolver() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || answer(&socket));
+
+        let resolver = DnsSrvResolver::new(vec![addr]);
+        let records = resolver.resolve_srv("_redis._tcp.cache.internal").unwrap();
+        assert_eq!(
+            vec![
+                SrvRecord {
+                    priority: 10,
+                    weight: 5,
+                    port: 6380,
+                    target: "redis2.cache.internal".to_string(),
+                },
+                SrvRecord {
+                    priority: 0,
+                    weight: 5,
+                    port: 6379,
+                    target: "redis1.cache.internal".to_string(),
+                },
+            ],
+            records
+        );
+
+        assert!(decode_answer(&[0, 0, 0x81]).is_err());
+        assert!(read_name(&[0xc0, 0], 0).is_err());
+    }
+
+    #[derive(Debug)]
+    struct Flaky(AtomicUsize);
+
+    impl SrvResolver for Flaky {
+        fn resolve_srv(&self, _name: &str) -> io::Result<Vec<SrvRecord>> {
+            let record = |priority, weight, target: &str| SrvRecord {
+                priority,
+                weight,
+                port: 6379,
+                target: target.to_string(),
+            };
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(vec![
+                    record(1, 0, "c"),
+                    record(0, 1, "b"),
+                    record(0, 5, "a"),
+                ]),
+                _ => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_srv_targets() {
+        let resolver = Arc::new(Flaky(AtomicUsize::new(0)));
+        let srv = Srv::new(
+            "_redis._tcp.cache.internal".into(),
+            resolver.clone(),
+            Duration::from_secs(60),
+        );
+        let hosts = |targets: Targets| {
+            let mut hosts = targets
+                .into_iter()
+                .map(|(host, _)| host)
+                .collect::<Vec<_>>();
+            // The order of the first two is random.
+            hosts[..2].sort();
+            hosts
+        };
+        let expected = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(expected, hosts(srv.targets().unwrap()));
+        assert_eq!(expected, hosts(srv.targets().unwrap()));
+        assert_eq!(1, resolver.0.load(Ordering::SeqCst));
+
+        // A failed lookup keeps the previous targets.
+        srv.invalidate();
+        assert_eq!(expected, hosts(srv.targets().unwrap()));
+        assert_eq!(2, resolver.0.load(Ordering::SeqCst));
+
+        let srv = Srv::new("x".into(), resolver, Duration::from_secs(60));
+        assert!(srv.targets().is_err());
+    }
+
+    #[test]
+    fn test_weighted_order() {
+        let record = |priority, weight, target: &str| SrvRecord {
+            priority,
+            weight,
+            port: 6379,
+            target: target.to_string(),
+        };
+        let records = vec![
+            record(0, 1, "light"),
+            record(1, 10, "backup"),
+            record(0, 3, "heavy"),
+        ];
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let targets = weighted_order(records.clone());
+            assert_eq!("backup", targets[2].0);
+            if targets[0].0 == "heavy" {
+                heavy_first += 1;
+            }
+        }
+        // About 3 out of 4.
+        assert!(heavy_first > 650 && heavy_first < 850, "{}", heavy_first);
+
+        // Without weights, the order is kept.
+        let records = vec![record(0, 0, "b"), record(0, 0, "a"), record(0, 0, "c")];
+        let hosts: Vec<String> = weighted_order(records)
+            .into_iter()
+            .map(|(host, _)| host)
+            .collect();
+        assert_eq!(vec!["b", "a", "c"], hosts);
+    }
+
+    #[test]
+    fn test_truncated_answer() {
+        // A name server which sets TC over UDP and answers over TCP.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (n, peer) = socket.recv_from(&mut buf).unwrap();
+            let mut msg = buf[..n].to_vec();
+            msg[2] = 0x83;
+            msg[3] = 0x80;
+            socket.send_to(&msg, peer).unwrap();
+        });
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).unwrap();
+            let mut msg = query[..2].to_vec();
+            msg.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+            msg.extend_from_slice(&query[12..]);
+            msg.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 15]);
+            msg.extend_from_slice(&[0, 0, 0, 0, 0x18, 0xeb, 6]);
+            msg.extend_from_slice(b"redis1\0");
+            let mut answer = (msg.len() as u16).to_be_bytes().to_vec();
+            answer.extend_from_slice(&msg);
+            stream.write_all(&answer).unwrap();
+        });
+
+        let resolver = DnsSrvResolver::new(vec![addr]);
+        let records = resolver.resolve_srv("_redis._tcp.cache.internal").unwrap();
+        assert_eq!(
+            vec![SrvRecord {
+                priority: 0,
+                weight: 0,
+                port: 6379,
+                target: "redis1".to_string(),
+            }],
+            records
+        );
+
+        let err = decode_answer(&[0, 0, 0x83, 0x80, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("truncated answer"));
+    }
+}
@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use redis::streams::StreamId;
+use redis::{FromRedisValue, Value};
+
+use crate::backoff::Backoff;
+use crate::pool_ext::{checkout, checkout_error};
+use crate::{ReconnectPolicy, RedisConnectionManager, RedisPoolExt};
+
+/// An entry of the stream, with the ID the group delivered it under. `None`
+/// fields are those of an entry deleted while it was pending.
+pub(crate) type Entry = (String, Option<HashMap<String, Value>>);
+
+/// A consumer of a Redis stream consumer group.
+///
+/// A background thread checks out connections from the pool to read new
+/// entries with `XREADGROUP` and passes each to the handler given to
+/// `start`, acknowledging it with `XACK` when the handler returns `Ok`.
+/// Entries the handler fails for stay pending and are delivered again:
+///
+/// - when the consumer starts, the entries still pending for it, e.g. after a
+///   crash, are read back first, once each.
+/// - every `claim_interval`, the entries pending for longer than `claim_idle`
+///   for any consumer of the group, including this one, are taken over with
+///   `XAUTOCLAIM` (Redis 6.2 and later) and delivered again.
+///
+/// The handler must therefore be idempotent. Reading keeps the consumer's
+/// idle time in the group low while it runs. When stuck entries are claimed,
+/// the consumers idle for longer than `delete_idle_consumers` and without
+/// pending entries are deleted from the group; as `XAUTOCLAIM` moves the
+/// entries of a dead consumer away first, none are lost. The consumer also
+/// deletes itself when it stops, unless entries are pending for it.
+///
+/// With `max_deliveries`, entries delivered that many times are given up on
+/// when they are next claimed, instead of being delivered again: they are
+/// moved to the `dead_letter_stream`, see `DeadLetterStream`, or dropped.
+///
+/// Connection failures are retried according to the `ReconnectPolicy`, and
+/// the group is created with `XGROUP CREATE ... MKSTREAM` if it doesn't exist
+/// yet, starting at the end of the stream unless `create_group` says
+/// otherwise.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, StreamConsumer};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let consumer = StreamConsumer::new(pool, "orders", "billing", "billing-1")
+///         .start(|entry| {
+///             let amount: Option<i64> = entry.get("amount");
+///             println!("{}: {:?}", entry.id, amount);
+///             Ok::<(), String>(())
+///         })
+///         .unwrap();
+///     // ...
+///     consumer.stop();
+/// }
+/// ```
+pub struct StreamConsumer {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    stream: String,
+    group: String,
+    consumer: String,
+    count: usize,
+    block: Duration,
+    create_group: Option<String>,
+    claim_idle: Option<Duration>,
+    claim_interval: Duration,
+    delete_idle_consumers: Option<Duration>,
+    max_deliveries: Option<u64>,
+    dead_letter_stream: Option<String>,
+    reconnect_policy: ReconnectPolicy,
+}
+
+impl StreamConsumer {
+    /// Creates a `StreamConsumer` named `consumer` reading `stream` in
+    /// `group`, with connections from `pool`.
+    pub fn new<S, G, C>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        stream: S,
+        group: G,
+        consumer: C,
+    ) -> StreamConsumer
+    where
+        S: Into<String>,
+        G: Into<String>,
+        C: Into<String>,
+    {
+        StreamConsumer {
+            pool,
+            stream: stream.into(),
+            group: group.into(),
+            consumer: consumer.into(),
+            count: 10,
+            block: Duration::from_secs(2),
+            create_group: Some("$".to_string()),
+            claim_idle: Some(Duration::from_secs(60)),
+            claim_interval: Duration::from_secs(30),
+            delete_idle_consumers: None,
+            max_deliveries: None,
+            dead_letter_stream: None,
+            reconnect_policy: ReconnectPolicy::default(),
+        }
+    }
+
+    /// Sets how many entries are read at a time.
+    ///
+    /// Defaults to 10.
+    pub fn count(mut self, count: usize) -> StreamConsumer {
+        self.count = count.max(1);
+        self
+    }
+
+    /// Sets how long `XREADGROUP` waits for new entries before the consumer
+    /// checks whether it was stopped and claims stuck entries.
+    ///
+    /// Defaults to 2 seconds. The read is done on a connection checked out
+    /// with `RedisPoolExt::get_blocking`, so the pool's read timeout may be
+    /// shorter.
+    pub fn block(mut self, block: Duration) -> StreamConsumer {
+        self.block = block;
+        self
+    }
+
+    /// Sets the ID the group is created at if it doesn't exist, e.g. `0` to
+    /// read the whole stream, or disables creating it with `None`.
+    ///
+    /// Defaults to `$`, the end of the stream.
+    pub fn create_group(mut self, start_id: Option<&str>) -> StreamConsumer {
+        self.create_group = start_id.map(str::to_string);
+        self
+    }
+
+    /// Sets how long an entry may stay pending before it is claimed and
+    /// delivered again, or disables claiming with `None`.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn claim_idle(mut self, claim_idle: Option<Duration>) -> StreamConsumer {
+        self.claim_idle = claim_idle;
+        self
+    }
+
+    /// Sets how often stuck entries are claimed and idle consumers deleted.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn claim_interval(mut self, claim_interval: Duration) -> StreamConsumer {
+        self.claim_interval = claim_interval;
+        self
+    }
+
+    /// Deletes the consumers of the group idle for longer than `idle` and
+    /// without pending entries, or disables deleting them with `None`.
+    ///
+    /// Defaults to `None`.
+    pub fn delete_idle_consumers(mut self, idle: Option<Duration>) -> StreamConsumer {
+        self.delete_idle_consumers = idle;
+        self
+    }
+
+    /// Sets how many times an entry is delivered at most, or lets entries be
+    /// delivered again until they are handled with `None`.
+    ///
+    /// Entries delivered that many times are moved to the
+    /// `dead_letter_stream` when they are next claimed, or acknowledged and
+    /// dropped with a warning if there is none. Requires `claim_idle`.
+    ///
+    /// Defaults to `None`.
+    pub fn max_deliveries(mut self, max_deliveries: Option<u64>) -> StreamConsumer {
+        self.max_deliveries = max_deliveries;
+        self
+    }
+
+    /// Sets the stream the entries delivered `max_deliveries` times are moved
+    /// to, or drops them with `None`.
+    ///
+    /// Defaults to `None`.
+    pub fn dead_letter_stream(mut self, stream: Option<&str>) -> StreamConsumer {
+        self.dead_letter_stream = stream.map(str::to_string);
+        self
+    }
+
+    /// Sets how failed commands are retried.
+    pub fn reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> StreamConsumer {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// Creates the group if requested, and starts delivering entries to
+    /// `handler` until the returned `StreamConsumerHandle` is stopped or
+    /// dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the group couldn't be created.
+    pub fn start<F, E>(self, handler: F) -> redis::RedisResult<StreamConsumerHandle>
+    where
+        F: FnMut(&StreamId) -> Result<(), E> + Send + 'static,
+        E: fmt::Display,
+    {
+        if let Some(ref start_id) = self.create_group {
+            self.ensure_group(start_id)?;
+        }
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || self.run(handler, &stopped))
+        };
+        Ok(StreamConsumerHandle {
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    fn ensure_group(&self, start_id: &str) -> redis::RedisResult<()> {
+        let mut conn = checkout(&self.pool)?;
+        let result = redis::cmd("XGROUP")
+            .arg("CREATE")
+            .arg(&self.stream)
+            .arg(&self.group)
+            .arg(start_id)
+            .arg("MKSTREAM")
+            .query::<()>(&mut *conn);
+        match result {
+            Err(ref e) if e.code() == Some("BUSYGROUP") => Ok(()),
+            result => result,
+        }
+    }
+
+    fn run<F, E>(self, mut handler: F, stopped: &AtomicBool)
+    where
+        F: FnMut(&StreamId) -> Result<(), E>,
+        E: fmt::Display,
+    {
+        let backoff = Backoff::new(self.reconnect_policy);
+        // The entries still pending for this consumer are read back from
+        // this ID on, until none are left.
+        let mut backlog = Some("0".to_string());
+        let mut last_claimed: Option<Instant> = None;
+        while !stopped.load(Ordering::Relaxed) {
+            backoff.wait();
+            let result = (|| {
+                if last_claimed.is_none_or(|last| last.elapsed() >= self.claim_interval) {
+                    self.claim(&mut handler, stopped)?;
+                    self.delete_idle()?;
+                    last_claimed = Some(Instant::now());
+                }
+                let entries = self.read(backlog.as_deref().unwrap_or(">"))?;
+                if backlog.is_some() {
+                    backlog = entries.last().map(|(id, _)| id.clone());
+                }
+                self.deliver(entries, &mut handler, stopped)
+            })();
+            match result {
+                Ok(()) => backoff.succeeded(),
+                Err(e) => {
+                    log::warn!("stream consumer {} failed: {}", self.consumer, e);
+                    backoff.failed();
+                }
+            }
+        }
+        if let Err(e) = self.delete_self() {
+            log::warn!("couldn't delete stream consumer {}: {}", self.consumer, e);
+        }
+    }
+
+    /// Reads the entries after `id`, or new entries if `id` is `>`.
+    fn read(&self, id: &str) -> redis::RedisResult<Vec<Entry>> {
+        let mut conn = self.pool.get_blocking(self.block).map_err(checkout_error)?;
+        let reply = redis::cmd("XREADGROUP")
+            .arg("GROUP")
+            .arg(&self.group)
+            .arg(&self.consumer)
+            .arg("COUNT")
+            .arg(self.count)
+            .arg("BLOCK")
+            .arg(self.block.as_millis() as u64)
+            .arg("STREAMS")
+            .arg(&self.stream)
+            .arg(id)
+            .query::<Value>(&mut conn)?;
+        match reply {
+            Value::Bulk(streams) => match streams.first() {
+                Some(Value::Bulk(stream)) if stream.len() == 2 => parse_entries(&stream[1]),
+                _ => Ok(Vec::new()),
+            },
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Claims and delivers the entries pending for longer than `claim_idle`.
+    fn claim<F, E>(&self, handler: &mut F, stopped: &AtomicBool) -> redis::RedisResult<()>
+    where
+        F: FnMut(&StreamId) -> Result<(), E>,
+        E: fmt::Display,
+    {
+        let claim_idle = match self.claim_idle {
+            Some(claim_idle) => claim_idle,
+            None => return Ok(()),
+        };
+        self.dead_letter(claim_idle)?;
+        let mut cursor = "0-0".to_string();
+        loop {
+            let reply: Vec<Value> = {
+                let mut conn = checkout(&self.pool)?;
+                redis::cmd("XAUTOCLAIM")
+                    .arg(&self.stream)
+                    .arg(&self.group)
+                    .arg(&self.consumer)
+                    .arg(claim_idle.as_millis() as u64)
+                    .arg(&cursor)
+                    .arg("COUNT")
+                    .arg(self.count)
+                    .query(&mut *conn)?
+            };
+            let entries = match reply.get(1) {
+                Some(entries) => parse_entries(entries)?,
+                None => Vec::new(),
+            };
+            self.deliver(entries, handler, stopped)?;
+            cursor = match reply.first() {
+                Some(next) => String::from_redis_value(next)?,
+                None => return Ok(()),
+            };
+            if cursor == "0-0" || stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Moves the entries pending for longer than `claim_idle` and delivered
+    /// `max_deliveries` times to the dead-letter stream, if any.
+    fn dead_letter(&self, claim_idle: Duration) -> redis::RedisResult<()> {
+        let max_deliveries = match self.max_deliveries {
+            Some(max_deliveries) => max_deliveries,
+            None => return Ok(()),
+        };
+        let mut conn = checkout(&self.pool)?;
+        let mut start = "-".to_string();
+        loop {
+            let reply: Vec<Value> = redis::cmd("XPENDING")
+                .arg(&self.stream)
+                .arg(&self.group)
+                .arg("IDLE")
+                .arg(claim_idle.as_millis() as u64)
+                .arg(&start)
+                .arg("+")
+                .arg(self.count)
+                .query(&mut *conn)?;
+            let mut exhausted = HashMap::new();
+            for pending in &reply {
+                let (id, _, _, deliveries): (String, String, u64, u64) =
+                    FromRedisValue::from_redis_value(pending)?;
+                start = format!("({}", id);
+                if deliveries >= max_deliveries {
+                    exhausted.insert(id, deliveries);
+                }
+            }
+            if !exhausted.is_empty() {
+                // Claiming the entries first keeps other consumers from
+                // moving them too.
+                let claimed: Value = redis::cmd("XCLAIM")
+                    .arg(&self.stream)
+                    .arg(&self.group)
+                    .arg(&self.consumer)
+                    .arg(claim_idle.as_millis() as u64)
+                    .arg(exhausted.keys().cloned().collect::<Vec<_>>())
+                    .query(&mut *conn)?;
+                for (id, map) in parse_entries(&claimed)? {
+                    let mut pipe = redis::pipe();
+                    pipe.atomic();
+                    match (&self.dead_letter_stream, map) {
+                        (Some(dead_letter_stream), Some(map)) => {
+                            let cmd = pipe.cmd("XADD").arg(dead_letter_stream).arg("*");
+                            for (field, value) in map {
+                                if let Value::Data(value) = value {
+                                    cmd.arg(field).arg(value);
+                                }
+                            }
+                            cmd.arg(crate::dead_letter::STREAM_FIELD)
+                                .arg(&self.stream)
+                                .arg(crate::dead_letter::ID_FIELD)
+                                .arg(&id)
+                                .arg(crate::dead_letter::DELIVERIES_FIELD)
+                                .arg(exhausted[&id])
+                                .ignore();
+                        }
+                        (None, Some(_)) => log::warn!(
+                            "stream consumer {} dropped {} after {} deliveries",
+                            self.consumer,
+                            id,
+                            exhausted[&id]
+                        ),
+                        (_, None) => {}
+                    }
+                    pipe.cmd("XACK")
+                        .arg(&self.stream)
+                        .arg(&self.group)
+                        .arg(&id)
+                        .ignore();
+                    pipe.query::<()>(&mut *conn)?;
+                }
+            }
+            if reply.len() < self.count {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Passes `entries` to `handler`, acknowledging those it succeeded for
+    /// and the deleted ones.
+    fn deliver<F, E>(
+        &self,
+        entries: Vec<Entry>,
+        handler: &mut F,
+        stopped: &AtomicBool,
+    ) -> redis::RedisResult<()>
+    where
+        F: FnMut(&StreamId) -> Result<(), E>,
+        E: fmt::Display,
+    {
+        let mut done = Vec::new();
+        for (id, map) in entries {
+            // Entries not handled yet stay pending for this consumer.
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            match map {
+                Some(map) => {
+                    let entry = StreamId { id, map };
+                    match handler(&entry) {
+                        Ok(()) => done.push(entry.id),
+                        Err(e) => log::warn!(
+                            "stream consumer {} failed to handle {}: {}",
+                            self.consumer,
+                            entry.id,
+                            e
+                        ),
+                    }
+                }
+                None => done.push(id),
+            }
+        }
+        if done.is_empty() {
+            return Ok(());
+        }
+        let mut conn = checkout(&self.pool)?;
+        redis::cmd("XACK")
+            .arg(&self.stream)
+            .arg(&self.group)
+            .arg(done)
+            .query(&mut *conn)
+    }
+
+    /// Deletes the other consumers idle for longer than
+    /// `delete_idle_consumers` and without pending entries.
+    fn delete_idle(&self) -> redis::RedisResult<()> {
+        let idle = match self.delete_idle_consumers {
+            Some(idle) => idle,
+            None => return Ok(()),
+        };
+        let mut conn = checkout(&self.pool)?;
+        for (name, pending, consumer_idle) in self.consumers(&mut conn)? {
+            if name != self.consumer && pending == 0 && consumer_idle >= idle {
+                redis::cmd("XGROUP")
+                    .arg("DELCONSUMER")
+                    .arg(&self.stream)
+                    .arg(&self.group)
+                    .arg(name)
+                    .query::<()>(&mut *conn)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes this consumer from the group unless entries are pending for
+    /// it.
+    fn delete_self(&self) -> redis::RedisResult<()> {
+        let mut conn = checkout(&self.pool)?;
+        let pending = self
+            .consumers(&mut conn)?
+            .into_iter()
+            .any(|(name, pending, _)| name == self.consumer && pending > 0);
+        if !pending {
+            redis::cmd("XGROUP")
+                .arg("DELCONSUMER")
+                .arg(&self.stream)
+                .arg(&self.group)
+                .arg(&self.consumer)
+                .query::<()>(&mut *conn)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the name, number of pending entries and idle time of the
+    /// group's consumers.
+    fn consumers(
+        &self,
+        conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+    ) -> redis::RedisResult<Vec<(String, u64, Duration)>> {
+        let consumers: Vec<HashMap<String, Value>> = redis::cmd("XINFO")
+            .arg("CONSUMERS")
+            .arg(&self.stream)
+            .arg(&self.group)
+            .query(&mut **conn)?;
+        consumers
+            .into_iter()
+            .map(|consumer| {
+                let field = |name: &str| consumer.get(name).unwrap_or(&Value::Nil);
+                Ok((
+                    String::from_redis_value(field("name"))?,
+                    u64::from_redis_value(field("pending"))?,
+                    Duration::from_millis(u64::from_redis_value(field("idle"))?),
+                ))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for StreamConsumer {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("StreamConsumer")
+            .field("stream", &self.stream)
+            .field("group", &self.group)
+            .field("consumer", &self.consumer)
+            .field("count", &self.count)
+            .field("block", &self.block)
+            .field("create_group", &self.create_group)
+            .field("claim_idle", &self.claim_idle)
+            .field("claim_interval", &self.claim_interval)
+            .field("delete_idle_consumers", &self.delete_idle_consumers)
+            .field("max_deliveries", &self.max_deliveries)
+            .field("dead_letter_stream", &self.dead_letter_stream)
+            .finish()
+    }
+}
+
+/// Parses the `[id, [field, value, ...]]` entries of an `XREADGROUP` or
+/// `XAUTOCLAIM` reply.
+pub(crate) fn parse_entries(value: &Value) -> redis::RedisResult<Vec<Entry>> {
+    let entries = match *value {
+        Value::Bulk(ref entries) => entries,
+        _ => return Ok(Vec::new()),
+    };
+    entries
+        .iter()
+        .map(|entry| match *entry {
+            Value::Bulk(ref entry) if entry.len() == 2 => {
+                let id = String::from_redis_value(&entry[0])?;
+                let map = match entry[1] {
+                    Value::Nil => None,
+                    ref map => Some(HashMap::from_redis_value(map)?),
+                };
+                Ok((id, map))
+            }
+            _ => Err((redis::ErrorKind::TypeError, "invalid stream entry").into()),
+        })
+        .collect()
+}
+
+/// Runs a `StreamConsumer` until it is stopped or dropped.
+#[derive(Debug)]
+pub struct StreamConsumerHandle {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StreamConsumerHandle {
+    /// Stops the consumer, waiting for the entry being handled, if any, and
+    /// for the current read to return.
+    ///
+    /// Dropping the handle stops the consumer without waiting.
+    pub fn stop(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for StreamConsumerHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_parse_entries() {
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![
+                Value::Data(b"1-0".to_vec()),
+                Value::Bulk(vec![Value::Data(b"f".to_vec()), Value::Data(b"v".to_vec())]),
+            ]),
+            Value::Bulk(vec![Value::Data(b"2-0".to_vec()), Value::Nil]),
+        ]);
+        let entries = parse_entries(&reply).unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!("1-0", entries[0].0);
+        let entry = StreamId {
+            id: entries[0].0.clone(),
+            map: entries[0].1.clone().unwrap(),
+        };
+        assert_eq!(Some("v".to_string()), entry.get("f"));
+        assert_eq!(("2-0".to_string(), None), entries[1]);
+    }
+
+    #[test]
+    fn test_stream_consumer() {
+        let pool = crate::local_pool(2);
+        let (handled, received) = mpsc::channel();
+        let mut failed = false;
+        let consumer = StreamConsumer::new(
+            pool.clone(),
+            "redis_r2d2-stream",
+            "redis_r2d2-group",
+            "consumer-1",
+        )
+        .block(Duration::from_millis(100))
+        .claim_idle(Some(Duration::from_millis(200)))
+        .claim_interval(Duration::from_millis(300))
+        .start(move |entry| {
+            let value: String = entry.get("value").unwrap();
+            // The first attempt at "retry" fails, so it is claimed again.
+            if value == "retry" && !failed {
+                failed = true;
+                return Err("failed");
+            }
+            let _ = handled.send(value);
+            Ok(())
+        })
+        .unwrap();
+
+        let mut conn = pool.get().unwrap();
+        for value in ["first", "retry"] {
+            redis::cmd("XADD")
+                .arg("redis_r2d2-stream")
+                .arg("*")
+                .arg("value")
+                .arg(value)
+                .query::<String>(&mut *conn)
+                .unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        assert_eq!("first", received.recv_timeout(timeout).unwrap());
+        assert_eq!("retry", received.recv_timeout(timeout).unwrap());
+        consumer.stop();
+
+        let consumers: Vec<HashMap<String, Value>> = redis::cmd("XINFO")
+            .arg("CONSUMERS")
+            .arg("redis_r2d2-stream")
+            .arg("redis_r2d2-group")
+            .query(&mut *conn)
+            .unwrap();
+        assert!(consumers.is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use redis::streams::StreamId;
+use redis::Value;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::pool_ext::checkout;
+use crate::{
+    Codec, DeadLetterStream, JsonCodec, RedisConnectionManager, StreamConsumer,
+    StreamConsumerHandle,
+};
+
+/// The field of a stream entry holding the task.
+const TASK_FIELD: &str = "task";
+
+/// A task queue on a Redis stream, whose tasks are handled by the consumers
+/// of a consumer group and given up on after a number of deliveries.
+///
+/// Tasks are added to the stream with `push` and handled by
+/// `StreamConsumer`s of the group, created at the start of the stream.
+/// Tasks a consumer fails for are claimed and delivered again, until they
+/// were delivered `max_deliveries` times: they are then moved to the
+/// dead-letter stream `<stream>:dead`, which `dead_letters` returns to
+/// replay them once the cause is fixed. Tasks that can't be decoded fail
+/// every time, and end up there too. Handled tasks stay in the stream until
+/// it is trimmed, e.g. with `XTRIM`.
+///
+/// Requires the `serde` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, StreamTaskQueue};
+///
+/// #[derive(serde::Serialize, serde::Deserialize)]
+/// struct Invoice {
+///     order: u64,
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let queue = StreamTaskQueue::<Invoice>::new(pool, "invoices", "billing")
+///         .max_deliveries(3);
+///     queue.push(&Invoice { order: 42 }).unwrap();
+///
+///     let consumer = queue
+///         .start("billing-1", |invoice| {
+///             println!("billing order {}", invoice.order);
+///             Ok::<(), String>(())
+///         })
+///         .unwrap();
+///     // ...
+///     consumer.stop();
+///     println!("{} dead letters", queue.dead_letters().len().unwrap());
+/// }
+/// ```
+pub struct StreamTaskQueue<T, C = JsonCodec> {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    stream: String,
+    group: String,
+    codec: C,
+    max_deliveries: u64,
+    _task: PhantomData<fn(&T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> StreamTaskQueue<T> {
+    /// Creates a `StreamTaskQueue` of JSON tasks on `stream`, handled by
+    /// `group`, with connections of `pool`.
+    pub fn new<S, G>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        stream: S,
+        group: G,
+    ) -> StreamTaskQueue<T>
+    where
+        S: Into<String>,
+        G: Into<String>,
+    {
+        StreamTaskQueue::with_codec(pool, stream, group, JsonCodec)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec> StreamTaskQueue<T, C> {
+    /// Creates a `StreamTaskQueue` of tasks encoded with `codec` on `stream`,
+    /// handled by `group`, with connections of `pool`.
+    pub fn with_codec<S, G>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        stream: S,
+        group: G,
+        codec: C,
+    ) -> StreamTaskQueue<T, C>
+    where
+        S: Into<String>,
+        G: Into<String>,
+    {
+        StreamTaskQueue {
+            pool,
+            stream: stream.into(),
+            group: group.into(),
+            codec,
+            max_deliveries: 5,
+            _task: PhantomData,
+        }
+    }
+
+    /// Sets how many times a task is delivered before it is moved to the
+    /// dead-letter stream.
+    ///
+    /// Defaults to 5.
+    pub fn max_deliveries(mut self, max_deliveries: u64) -> StreamTaskQueue<T, C> {
+        self.max_deliveries = max_deliveries.max(1);
+        self
+    }
+
+    /// Adds `task` to the stream, returning the ID of its entry.
+    pub fn push(&self, task: &T) -> redis::RedisResult<String> {
+        redis::cmd("XADD")
+            .arg(&self.stream)
+            .arg("*")
+            .arg(TASK_FIELD)
+            .arg(self.codec.encode(task)?)
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns the dead-letter stream of the tasks given up on.
+    pub fn dead_letters(&self) -> DeadLetterStream {
+        DeadLetterStream::new(self.pool.clone(), self.dead_letter_key())
+    }
+
+    /// Returns a `StreamConsumer` named `consumer` of the group, with the
+    /// delivery limit and dead-letter stream of the queue, to configure
+    /// further and start with a handler using `decode`.
+    pub fn consumer<S: Into<String>>(&self, consumer: S) -> StreamConsumer {
+        StreamConsumer::new(
+            self.pool.clone(),
+            &self.stream[..],
+            &self.group[..],
+            consumer,
+        )
+        .create_group(Some("0"))
+        .max_deliveries(Some(self.max_deliveries))
+        .dead_letter_stream(Some(&self.dead_letter_key()))
+    }
+
+    /// Decodes the task of `entry`, an entry of the stream.
+    pub fn decode(&self, entry: &StreamId) -> redis::RedisResult<T> {
+        decode(&self.codec, entry)
+    }
+
+    /// Starts a `StreamConsumer` named `consumer` passing the tasks to
+    /// `handler`, see `consumer` for one with a custom configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the group couldn't be created.
+    pub fn start<S, F, E>(
+        &self,
+        consumer: S,
+        mut handler: F,
+    ) -> redis::RedisResult<StreamConsumerHandle>
+    where
+        S: Into<String>,
+        T: 'static,
+        C: Clone + Send + 'static,
+        F: FnMut(&T) -> Result<(), E> + Send + 'static,
+        E: fmt::Display,
+    {
+        let codec = self.codec.clone();
+        self.consumer(consumer).start(move |entry| {
+            let task = decode(&codec, entry).map_err(|e| e.to_string())?;
+            handler(&task).map_err(|e| e.to_string())
+        })
+    }
+
+    fn dead_letter_key(&self) -> String {
+        format!("{}:dead", self.stream)
+    }
+}
+
+impl<T, C: Clone> Clone for StreamTaskQueue<T, C> {
+    fn clone(&self) -> StreamTaskQueue<T, C> {
+        StreamTaskQueue {
+            pool: self.pool.clone(),
+            stream: self.stream.clone(),
+            group: self.group.clone(),
+            codec: self.codec.clone(),
+            max_deliveries: self.max_deliveries,
+            _task: PhantomData,
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for StreamTaskQueue<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("StreamTaskQueue")
+            .field("pool", &self.pool)
+            .field("stream", &self.stream)
+            .field("group", &self.group)
+            .field("codec", &self.codec)
+            .field("max_deliveries", &self.max_deliveries)
+            .finish()
+    }
+}
+
+fn decode<T: DeserializeOwned, C: Codec>(codec: &C, entry: &StreamId) -> redis::RedisResult<T> {
+    match entry.map.get(TASK_FIELD) {
+        Some(Value::Data(task)) => codec.decode(task),
+        _ => Err((redis::ErrorKind::TypeError, "entry without a task").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_dead_letters() {
+        let pool = crate::local_pool(3);
+        let stream = format!("redis_r2d2-tasks-{}", crate::lock::token());
+        let queue = StreamTaskQueue::<i32>::new(pool, stream, "workers").max_deliveries(2);
+        queue.push(&1).unwrap();
+        let poison = queue.push(&-1).unwrap();
+        queue.push(&2).unwrap();
+
+        let (handled, received) = mpsc::channel();
+        let decoder = queue.clone();
+        let consumer = queue
+            .consumer("w1")
+            .block(Duration::from_millis(50))
+            .claim_idle(Some(Duration::from_millis(100)))
+            .claim_interval(Duration::from_millis(150))
+            .start(move |entry| {
+                let task = decoder.decode(entry).map_err(|e| e.to_string())?;
+                if task < 0 {
+                    return Err("negative task".to_string());
+                }
+                handled.send(task).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(1, received.recv_timeout(timeout).unwrap());
+        assert_eq!(2, received.recv_timeout(timeout).unwrap());
+
+        let dead_letters = queue.dead_letters();
+        let deadline = Instant::now() + timeout;
+        while dead_letters.is_empty().unwrap() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        consumer.stop();
+        let listed = dead_letters.list(10).unwrap();
+        assert_eq!(1, listed.len());
+        assert_eq!(poison, listed[0].entry.id);
+        assert_eq!(2, listed[0].deliveries);
+        assert_eq!(-1, queue.decode(&listed[0].entry).unwrap());
+        assert!(dead_letters.replay(&listed[0].id).unwrap().is_some());
+        assert!(dead_letters.is_empty().unwrap());
+    }
+}
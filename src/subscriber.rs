@@ -0,0 +1,416 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use r2d2::ManageConnection;
+use redis::ToRedisArgs;
+
+use crate::backoff::Backoff;
+use crate::{ReconnectPolicy, RedisPubSubConnection, RedisPubSubConnectionManager};
+
+/// How long the subscriber thread waits for a message before applying
+/// subscription changes and checking whether the `ResilientSubscriber` was
+/// dropped.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a connection may go without a message before it is checked with
+/// a `PING`.
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a `ResilientSubscriber` received.
+#[derive(Debug)]
+pub enum SubscriberEvent {
+    /// A message on one of the subscribed channels or patterns.
+    Message(redis::Msg),
+    /// The connection was lost, or couldn't be established. Messages
+    /// published until the next `Resubscribed` are missed.
+    Disconnected(redis::RedisError),
+    /// The subscriptions were restored on a new connection, after `gap`
+    /// without one.
+    Resubscribed {
+        /// How long the subscriber was disconnected.
+        gap: Duration,
+    },
+}
+
+/// Subscriptions that survive reconnects.
+///
+/// A background thread keeps a connection of a
+/// `RedisPubSubConnectionManager` subscribed to the manager's channels and
+/// patterns and to those added with `subscribe` and `psubscribe`. When the
+/// connection fails, or doesn't answer a `PING` after 10 seconds without
+/// messages, the subscriber reports a `SubscriberEvent::Disconnected`,
+/// reconnects according to its `ReconnectPolicy`, restores the
+/// subscriptions and reports the gap with `SubscriberEvent::Resubscribed`.
+///
+/// Subscription changes are applied by the background thread within a
+/// fraction of a second. The thread stops when the subscriber is dropped.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{RedisPubSubConnectionManager, ResilientSubscriber, SubscriberEvent};
+///
+/// fn main() {
+///     let manager = RedisPubSubConnectionManager::new("redis://localhost").unwrap();
+///     let subscriber = ResilientSubscriber::new(manager);
+///     subscriber.subscribe("events");
+///
+///     for event in subscriber.iter() {
+///         match event {
+///             SubscriberEvent::Message(msg) => println!("{}", msg.get_channel_name()),
+///             SubscriberEvent::Disconnected(e) => eprintln!("disconnected: {}", e),
+///             SubscriberEvent::Resubscribed { gap } => eprintln!("missed up to {:?}", gap),
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ResilientSubscriber {
+    shared: Arc<Shared>,
+    events: Receiver<SubscriberEvent>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    subscriptions: Mutex<Subscriptions>,
+    changed: AtomicBool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Subscriptions {
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+}
+
+impl ResilientSubscriber {
+    /// Creates a `ResilientSubscriber` reconnecting with the default
+    /// `ReconnectPolicy`.
+    pub fn new(manager: RedisPubSubConnectionManager) -> ResilientSubscriber {
+        ResilientSubscriber::with_reconnect_policy(manager, ReconnectPolicy::default())
+    }
+
+    /// Creates a `ResilientSubscriber` reconnecting according to
+    /// `reconnect_policy`.
+    pub fn with_reconnect_policy(
+        manager: RedisPubSubConnectionManager,
+        reconnect_policy: ReconnectPolicy,
+    ) -> ResilientSubscriber {
+        let subscriptions = Subscriptions {
+            channels: manager.channels.iter().cloned().collect(),
+            patterns: manager.patterns.iter().cloned().collect(),
+        };
+        let shared = Arc::new(Shared {
+            subscriptions: Mutex::new(subscriptions),
+            changed: AtomicBool::new(false),
+        });
+        let (sender, events) = mpsc::channel();
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || run(&manager, &weak, &sender, reconnect_policy));
+        ResilientSubscriber { shared, events }
+    }
+
+    /// Subscribes to `channel`, which may be several.
+    pub fn subscribe<T: ToRedisArgs>(&self, channel: T) {
+        self.update(|subscriptions| subscriptions.channels.extend(channel.to_redis_args()));
+    }
+
+    /// Subscribes to the channels matching `pattern`, which may be several.
+    pub fn psubscribe<T: ToRedisArgs>(&self, pattern: T) {
+        self.update(|subscriptions| subscriptions.patterns.extend(pattern.to_redis_args()));
+    }
+
+    /// Unsubscribes from `channel`, which may be several.
+    pub fn unsubscribe<T: ToRedisArgs>(&self, channel: T) {
+        self.update(|subscriptions| {
+            for channel in channel.to_redis_args() {
+                subscriptions.channels.remove(&channel);
+            }
+        });
+    }
+
+    /// Unsubscribes from `pattern`, which may be several.
+    pub fn punsubscribe<T: ToRedisArgs>(&self, pattern: T) {
+        self.update(|subscriptions| {
+            for pattern in pattern.to_redis_args() {
+                subscriptions.patterns.remove(&pattern);
+            }
+        });
+    }
+
+    fn update<F: FnOnce(&mut Subscriptions)>(&self, f: F) {
+        f(&mut self.shared.subscriptions.lock().unwrap());
+        self.shared.changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits for the next event.
+    ///
+    /// Returns `None` only if the background thread panicked.
+    pub fn recv(&self) -> Option<SubscriberEvent> {
+        self.events.recv().ok()
+    }
+
+    /// Waits at most `timeout` for the next event.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<SubscriberEvent> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Returns the next event if there is one, without waiting.
+    pub fn try_recv(&self) -> Option<SubscriberEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Returns an iterator waiting for events.
+    pub fn iter(&self) -> impl Iterator<Item = SubscriberEvent> + '_ {
+        self.events.iter()
+    }
+}
+
+/// Keeps a connection subscribed until the `ResilientSubscriber` is dropped.
+fn run(
+    manager: &RedisPubSubConnectionManager,
+    shared: &Weak<Shared>,
+    events: &Sender<SubscriberEvent>,
+    reconnect_policy: ReconnectPolicy,
+) {
+    let backoff = Backoff::new(reconnect_policy);
+    let mut down_since: Option<Instant> = None;
+    loop {
+        backoff.wait();
+        if shared.strong_count() == 0 {
+            return;
+        }
+        let error = match connect(manager, shared) {
+            Ok(mut conn) => {
+                backoff.succeeded();
+                if let Some(since) = down_since.take() {
+                    let gap = since.elapsed();
+                    if events.send(SubscriberEvent::Resubscribed { gap }).is_err() {
+                        return;
+                    }
+                }
+                let timeout = manager.validation_timeout().unwrap_or(HEARTBEAT_INTERVAL);
+                match listen(&mut conn, shared, events, timeout) {
+                    Ok(()) => return,
+                    Err(e) => e,
+                }
+            }
+            Err(e) => {
+                backoff.failed();
+                if down_since.is_some() {
+                    continue;
+                }
+                e
+            }
+        };
+        log::warn!("subscriber disconnected: {}", error);
+        down_since = Some(Instant::now());
+        if events.send(SubscriberEvent::Disconnected(error)).is_err() {
+            return;
+        }
+    }
+}
+
+fn connect(
+    manager: &RedisPubSubConnectionManager,
+    shared: &Weak<Shared>,
+) -> redis::RedisResult<RedisPubSubConnection> {
+    let mut conn = manager.connect()?;
+    conn.set_read_timeout(Some(POLL_INTERVAL))?;
+    if let Some(shared) = shared.upgrade() {
+        shared.changed.store(false, Ordering::SeqCst);
+        let subscriptions = shared.subscriptions.lock().unwrap().clone();
+        sync(&mut conn, &subscriptions)?;
+    }
+    Ok(conn)
+}
+
+/// Forwards the messages of `conn` to `events`, returning `Ok` once the
+/// `ResilientSubscriber` is dropped.
+fn listen(
+    conn: &mut RedisPubSubConnection,
+    shared: &Weak<Shared>,
+    events: &Sender<SubscriberEvent>,
+    heartbeat_timeout: Duration,
+) -> redis::RedisResult<()> {
+    loop {
+        match shared.upgrade() {
+            Some(shared) => {
+                if shared.changed.swap(false, Ordering::SeqCst) {
+                    let subscriptions = shared.subscriptions.lock().unwrap().clone();
+                    sync(conn, &subscriptions)?;
+                }
+            }
+            None => return Ok(()),
+        }
+        match conn.get_message() {
+            Ok(msg) => {
+                if events.send(SubscriberEvent::Message(msg)).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(ref e) if e.is_timeout() => {
+                if conn.idle_time() >= HEARTBEAT_INTERVAL {
+                    conn.ping(Some(heartbeat_timeout))?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Subscribes and unsubscribes `conn` so it has `subscriptions`.
+fn sync(conn: &mut RedisPubSubConnection, subscriptions: &Subscriptions) -> redis::RedisResult<()> {
+    let (subscribe, unsubscribe) = diff(conn.channels(), &subscriptions.channels);
+    if !unsubscribe.is_empty() {
+        conn.unsubscribe(unsubscribe)?;
+    }
+    if !subscribe.is_empty() {
+        conn.subscribe(subscribe)?;
+    }
+    let (subscribe, unsubscribe) = diff(conn.patterns(), &subscriptions.patterns);
+    if !unsubscribe.is_empty() {
+        conn.punsubscribe(unsubscribe)?;
+    }
+    if !subscribe.is_empty() {
+        conn.psubscribe(subscribe)?;
+    }
+    Ok(())
+}
+
+/// Returns the entries of `wanted` missing from `current`, and those of
+/// `current` that aren't wanted.
+fn diff<'a, I>(current: I, wanted: &BTreeSet<Vec<u8>>) -> (Vec<Vec<u8>>, Vec<Vec<u8>>)
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    let current = current.map(<[u8]>::to_vec).collect::<BTreeSet<_>>();
+    (
+        wanted.difference(&current).cloned().collect(),
+        current.difference(wanted).cloned().collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    /// Forwards connections to the local server until `streams` are shut
+    /// down.
+    fn proxy(streams: Arc<Mutex<Vec<TcpStream>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = TcpStream::connect("127.0.0.1:6379").unwrap();
+                let mut streams = streams.lock().unwrap();
+                streams.push(client.try_clone().unwrap());
+                streams.push(server.try_clone().unwrap());
+                for (mut from, mut to) in [
+                    (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                    (server, client),
+                ] {
+                    thread::spawn(move || {
+                        let _ = io::copy(&mut from, &mut to);
+                        let _ = to.shutdown(Shutdown::Both);
+                    });
+                }
+            }
+        });
+        format!("redis://{}", addr)
+    }
+
+    /// Returns the next event that isn't a message.
+    fn next_event(subscriber: &ResilientSubscriber) -> Option<SubscriberEvent> {
+        loop {
+            match subscriber.recv_timeout(Duration::from_secs(5)) {
+                Some(SubscriberEvent::Message(_)) => {}
+                event => return event,
+            }
+        }
+    }
+
+    /// Publishes to `channel` until `subscriber` receives a message on it.
+    fn publish(subscriber: &ResilientSubscriber, channel: &str) -> redis::Msg {
+        let mut conn = redis::Client::open("redis://localhost")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        for _ in 0..100 {
+            redis::cmd("PUBLISH")
+                .arg(channel)
+                .arg("hello")
+                .query::<i64>(&mut conn)
+                .unwrap();
+            match subscriber.recv_timeout(Duration::from_millis(50)) {
+                Some(SubscriberEvent::Message(msg)) if msg.get_channel_name() == channel => {
+                    return msg
+                }
+                Some(SubscriberEvent::Message(_)) => {}
+                Some(event) => panic!("unexpected event {:?}", event),
+                None => {}
+            }
+        }
+        panic!("no message on {}", channel);
+    }
+
+    #[test]
+    fn test_resilient_subscriber() {
+        let streams = Arc::new(Mutex::new(Vec::new()));
+        let manager = RedisPubSubConnectionManager::new(proxy(streams.clone()))
+            .unwrap()
+            .subscribe("redis_r2d2-resilient");
+        let subscriber = ResilientSubscriber::with_reconnect_policy(
+            manager,
+            ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                ..ReconnectPolicy::default()
+            },
+        );
+        subscriber.psubscribe("redis_r2d2-resilient.*");
+
+        let msg = publish(&subscriber, "redis_r2d2-resilient");
+        assert_eq!("redis_r2d2-resilient", msg.get_channel_name());
+        let msg = publish(&subscriber, "redis_r2d2-resilient.a");
+        assert!(msg.from_pattern());
+
+        for stream in streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        match next_event(&subscriber) {
+            Some(SubscriberEvent::Disconnected(_)) => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+        match next_event(&subscriber) {
+            Some(SubscriberEvent::Resubscribed { .. }) => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+        let msg = publish(&subscriber, "redis_r2d2-resilient.b");
+        assert_eq!("redis_r2d2-resilient.b", msg.get_channel_name());
+
+        subscriber.unsubscribe("redis_r2d2-resilient");
+        subscriber.punsubscribe("redis_r2d2-resilient.*");
+        subscriber.subscribe("redis_r2d2-resilient-2");
+        let msg = publish(&subscriber, "redis_r2d2-resilient-2");
+        assert_eq!("redis_r2d2-resilient-2", msg.get_channel_name());
+    }
+
+    #[test]
+    fn test_diff() {
+        let current = [b"a".to_vec(), b"b".to_vec()];
+        let wanted = vec![b"b".to_vec(), b"c".to_vec()].into_iter().collect();
+        assert_eq!(
+            (vec![b"c".to_vec()], vec![b"a".to_vec()]),
+            diff(current.iter().map(Vec::as_slice), &wanted)
+        );
+    }
+}
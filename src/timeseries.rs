@@ -0,0 +1,712 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use redis::{ConnectionLike, FromRedisValue, RedisWrite, ToRedisArgs, Value};
+
+use crate::pool_ext::checkout;
+use crate::RedisConnectionManager;
+
+/// A RedisTimeSeries timestamp, in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// The timestamp `millis` milliseconds after the Unix epoch.
+    pub fn from_millis(millis: u64) -> Timestamp {
+        Timestamp(millis)
+    }
+
+    /// The current time of this host.
+    pub fn now() -> Timestamp {
+        Timestamp::from(SystemTime::now())
+    }
+
+    /// Returns the number of milliseconds since the Unix epoch.
+    pub fn as_millis(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    /// Converts `time`, truncated to the millisecond; times before the epoch
+    /// are the epoch.
+    fn from(time: SystemTime) -> Timestamp {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Timestamp(since_epoch.as_millis() as u64)
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(timestamp.0)
+    }
+}
+
+impl ToRedisArgs for Timestamp {
+    fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
+        self.0.write_redis_args(out)
+    }
+}
+
+impl FromRedisValue for Timestamp {
+    fn from_redis_value(v: &Value) -> redis::RedisResult<Timestamp> {
+        u64::from_redis_value(v).map(Timestamp)
+    }
+}
+
+/// A value of a time series at a timestamp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// When the value was measured.
+    pub timestamp: Timestamp,
+    /// The value.
+    pub value: f64,
+}
+
+impl FromRedisValue for Sample {
+    fn from_redis_value(v: &Value) -> redis::RedisResult<Sample> {
+        let (timestamp, value) = FromRedisValue::from_redis_value(v)?;
+        Ok(Sample { timestamp, value })
+    }
+}
+
+/// What to do with a sample whose timestamp is already in the series, see
+/// `TimeSeriesOptions::duplicate_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DuplicatePolicy {
+    /// Reject the new sample.
+    Block,
+    /// Keep the existing value.
+    First,
+    /// Keep the new value.
+    Last,
+    /// Keep the smaller value.
+    Min,
+    /// Keep the larger value.
+    Max,
+    /// Add the new value to the existing one.
+    Sum,
+}
+
+impl DuplicatePolicy {
+    fn as_str(self) -> &'static str {
+        match self {
+            DuplicatePolicy::Block => "BLOCK",
+            DuplicatePolicy::First => "FIRST",
+            DuplicatePolicy::Last => "LAST",
+            DuplicatePolicy::Min => "MIN",
+            DuplicatePolicy::Max => "MAX",
+            DuplicatePolicy::Sum => "SUM",
+        }
+    }
+}
+
+/// The settings of a time series, see `TimeSeriesCommands::ts_create`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimeSeriesOptions {
+    retention: Option<Duration>,
+    chunk_size: Option<u32>,
+    duplicate_policy: Option<DuplicatePolicy>,
+    labels: Vec<(String, String)>,
+}
+
+impl TimeSeriesOptions {
+    /// A series with the server's defaults and no labels.
+    pub fn new() -> TimeSeriesOptions {
+        TimeSeriesOptions::default()
+    }
+
+    /// Drops the samples older than `retention`, relative to the latest one.
+    ///
+    /// Defaults to the server's, which keeps them forever.
+    pub fn retention(mut self, retention: Duration) -> TimeSeriesOptions {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Sets the size of the memory chunks of the series, in bytes.
+    ///
+    /// Defaults to the server's, 4096.
+    pub fn chunk_size(mut self, chunk_size: u32) -> TimeSeriesOptions {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Sets what to do with samples whose timestamp is already in the series.
+    ///
+    /// Defaults to the server's, `DuplicatePolicy::Block`.
+    pub fn duplicate_policy(mut self, policy: DuplicatePolicy) -> TimeSeriesOptions {
+        self.duplicate_policy = Some(policy);
+        self
+    }
+
+    /// Adds the label `name` with `value`, used by `ts_mrange` filters.
+    pub fn label<N: Into<String>, V: Into<String>>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> TimeSeriesOptions {
+        self.labels.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// How samples are combined into buckets, see `Aggregation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Aggregator {
+    /// The mean of the values.
+    Avg,
+    /// The sum of the values.
+    Sum,
+    /// The smallest value.
+    Min,
+    /// The largest value.
+    Max,
+    /// The difference between the largest and smallest values.
+    Range,
+    /// The number of values.
+    Count,
+    /// The value with the lowest timestamp.
+    First,
+    /// The value with the highest timestamp.
+    Last,
+    /// The population standard deviation of the values.
+    StdP,
+    /// The sample standard deviation of the values.
+    StdS,
+    /// The population variance of the values.
+    VarP,
+    /// The sample variance of the values.
+    VarS,
+    /// The time-weighted average of the values.
+    Twa,
+}
+
+impl Aggregator {
+    fn as_str(self) -> &'static str {
+        match self {
+            Aggregator::Avg => "AVG",
+            Aggregator::Sum => "SUM",
+            Aggregator::Min => "MIN",
+            Aggregator::Max => "MAX",
+            Aggregator::Range => "RANGE",
+            Aggregator::Count => "COUNT",
+            Aggregator::First => "FIRST",
+            Aggregator::Last => "LAST",
+            Aggregator::StdP => "STD.P",
+            Aggregator::StdS => "STD.S",
+            Aggregator::VarP => "VAR.P",
+            Aggregator::VarS => "VAR.S",
+            Aggregator::Twa => "TWA",
+        }
+    }
+}
+
+/// Combines the samples of a range into buckets of a fixed duration, see
+/// `TimeSeriesRange::aggregation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aggregation {
+    aggregator: Aggregator,
+    bucket: Duration,
+    empty: bool,
+}
+
+impl Aggregation {
+    /// Combines the samples into buckets of `bucket` with `aggregator`; each
+    /// bucket starts at a multiple of `bucket` since the epoch, and its
+    /// sample has the start timestamp.
+    pub fn new(aggregator: Aggregator, bucket: Duration) -> Aggregation {
+        Aggregation {
+            aggregator,
+            bucket,
+            empty: false,
+        }
+    }
+
+    /// Also returns the buckets without samples (`EMPTY`, RedisTimeSeries
+    /// 1.8 and later), e.g. to plot gaps.
+    pub fn empty(mut self) -> Aggregation {
+        self.empty = true;
+        self
+    }
+}
+
+/// The samples to return from `ts_range`, `ts_revrange` or `ts_mrange`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TimeSeriesRange {
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+    count: Option<usize>,
+    values: Option<(f64, f64)>,
+    aggregation: Option<Aggregation>,
+}
+
+impl TimeSeriesRange {
+    /// Every sample of the series.
+    pub fn all() -> TimeSeriesRange {
+        TimeSeriesRange::default()
+    }
+
+    /// The samples from `start` to `end`, both included.
+    pub fn between(start: Timestamp, end: Timestamp) -> TimeSeriesRange {
+        TimeSeriesRange::all().start(start).end(end)
+    }
+
+    /// Skips the samples before `start`.
+    pub fn start(mut self, start: Timestamp) -> TimeSeriesRange {
+        self.start = Some(start);
+        self
+    }
+
+    /// Skips the samples after `end`.
+    pub fn end(mut self, end: Timestamp) -> TimeSeriesRange {
+        self.end = Some(end);
+        self
+    }
+
+    /// Returns at most `count` samples, or buckets with an `aggregation`.
+    pub fn count(mut self, count: usize) -> TimeSeriesRange {
+        self.count = Some(count);
+        self
+    }
+
+    /// Skips the samples whose value isn't between `min` and `max`, both
+    /// included, before aggregating them.
+    pub fn filter_by_value(mut self, min: f64, max: f64) -> TimeSeriesRange {
+        self.values = Some((min, max));
+        self
+    }
+
+    /// Returns the buckets of `aggregation` instead of the samples.
+    pub fn aggregation(mut self, aggregation: Aggregation) -> TimeSeriesRange {
+        self.aggregation = Some(aggregation);
+        self
+    }
+
+    fn write_args(&self, cmd: &mut redis::Cmd) {
+        match self.start {
+            Some(start) => cmd.arg(start),
+            None => cmd.arg("-"),
+        };
+        match self.end {
+            Some(end) => cmd.arg(end),
+            None => cmd.arg("+"),
+        };
+        if let Some((min, max)) = self.values {
+            cmd.arg("FILTER_BY_VALUE").arg(min).arg(max);
+        }
+        if let Some(count) = self.count {
+            cmd.arg("COUNT").arg(count);
+        }
+        if let Some(aggregation) = self.aggregation {
+            cmd.arg("AGGREGATION")
+                .arg(aggregation.aggregator.as_str())
+                .arg(aggregation.bucket.as_millis().max(1) as u64);
+            if aggregation.empty {
+                cmd.arg("EMPTY");
+            }
+        }
+    }
+}
+
+/// A series returned by `ts_mrange`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeSeries {
+    /// The key of the series.
+    pub key: String,
+    /// The labels of the series.
+    pub labels: HashMap<String, String>,
+    /// The samples, or buckets, in the range.
+    pub samples: Vec<Sample>,
+}
+
+impl FromRedisValue for TimeSeries {
+    fn from_redis_value(v: &Value) -> redis::RedisResult<TimeSeries> {
+        let (key, labels, samples): (String, Vec<Value>, Vec<Sample>) =
+            FromRedisValue::from_redis_value(v)?;
+        // Each label is a `[name, value]` pair, which tuples in a `Vec` don't
+        // parse: they expect the pairs flattened.
+        let labels = labels
+            .iter()
+            .map(<(String, String)>::from_redis_value)
+            .collect::<redis::RedisResult<_>>()?;
+        Ok(TimeSeries {
+            key,
+            labels,
+            samples,
+        })
+    }
+}
+
+/// Typed RedisTimeSeries commands, for any connection, e.g. a pooled
+/// `RedisConnection`.
+///
+/// To write many samples at once, see `TimeSeriesIngest`.
+///
+/// Requires the `timeseries` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{
+///     r2d2, Aggregation, Aggregator, RedisConnectionManager, TimeSeriesCommands,
+///     TimeSeriesOptions, TimeSeriesRange, Timestamp,
+/// };
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut conn = pool.get().unwrap();
+///     let options = TimeSeriesOptions::new()
+///         .retention(Duration::from_secs(7 * 24 * 3600))
+///         .label("sensor", "42")
+///         .label("kind", "temperature");
+///     conn.ts_create("sensor:42:temperature", &options).unwrap();
+///     conn.ts_add("sensor:42:temperature", Timestamp::now(), 21.5)
+///         .unwrap();
+///
+///     let hourly = TimeSeriesRange::all()
+///         .aggregation(Aggregation::new(Aggregator::Avg, Duration::from_secs(3600)));
+///     for series in conn.ts_mrange(&hourly, &["kind=temperature"]).unwrap() {
+///         println!("{}: {:?}", series.key, series.samples);
+///     }
+/// }
+/// ```
+pub trait TimeSeriesCommands: ConnectionLike + Sized {
+    /// Creates the time series `key` (`TS.CREATE`).
+    fn ts_create<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        options: &TimeSeriesOptions,
+    ) -> redis::RedisResult<()> {
+        let mut cmd = redis::cmd("TS.CREATE");
+        cmd.arg(key);
+        if let Some(retention) = options.retention {
+            cmd.arg("RETENTION").arg(retention.as_millis() as u64);
+        }
+        if let Some(chunk_size) = options.chunk_size {
+            cmd.arg("CHUNK_SIZE").arg(chunk_size);
+        }
+        if let Some(policy) = options.duplicate_policy {
+            cmd.arg("DUPLICATE_POLICY").arg(policy.as_str());
+        }
+        if !options.labels.is_empty() {
+            cmd.arg("LABELS");
+            for (name, value) in &options.labels {
+                cmd.arg(name).arg(value);
+            }
+        }
+        cmd.query(self)
+    }
+
+    /// Appends `value` at `timestamp` to the time series `key` (`TS.ADD`),
+    /// creating it with the server's defaults if there is none, and returns
+    /// the timestamp.
+    fn ts_add<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        timestamp: Timestamp,
+        value: f64,
+    ) -> redis::RedisResult<Timestamp> {
+        redis::cmd("TS.ADD")
+            .arg(key)
+            .arg(timestamp)
+            .arg(value)
+            .query(self)
+    }
+
+    /// Appends `samples`, for any series, in a single command (`TS.MADD`),
+    /// and returns their timestamps.
+    fn ts_madd<K: ToRedisArgs>(
+        &mut self,
+        samples: &[(K, Timestamp, f64)],
+    ) -> redis::RedisResult<Vec<Timestamp>> {
+        let mut cmd = redis::cmd("TS.MADD");
+        for (key, timestamp, value) in samples {
+            key.write_redis_args(&mut cmd);
+            cmd.arg(*timestamp).arg(*value);
+        }
+        cmd.query(self)
+    }
+
+    /// Returns the latest sample of the time series `key` (`TS.GET`), or
+    /// `None` if it is empty.
+    fn ts_get<K: ToRedisArgs>(&mut self, key: K) -> redis::RedisResult<Option<Sample>> {
+        let reply: Vec<Value> = redis::cmd("TS.GET").arg(key).query(self)?;
+        if reply.is_empty() {
+            return Ok(None);
+        }
+        Sample::from_redis_value(&Value::Bulk(reply)).map(Some)
+    }
+
+    /// Returns the samples of the time series `key` in `range`, oldest first
+    /// (`TS.RANGE`).
+    fn ts_range<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        range: &TimeSeriesRange,
+    ) -> redis::RedisResult<Vec<Sample>> {
+        let mut cmd = redis::cmd("TS.RANGE");
+        cmd.arg(key);
+        range.write_args(&mut cmd);
+        cmd.query(self)
+    }
+
+    /// Returns the samples of the time series `key` in `range`, latest first
+    /// (`TS.REVRANGE`).
+    fn ts_revrange<K: ToRedisArgs>(
+        &mut self,
+        key: K,
+        range: &TimeSeriesRange,
+    ) -> redis::RedisResult<Vec<Sample>> {
+        let mut cmd = redis::cmd("TS.REVRANGE");
+        cmd.arg(key);
+        range.write_args(&mut cmd);
+        cmd.query(self)
+    }
+
+    /// Returns the samples in `range` of every time series whose labels match
+    /// `filters`, e.g. `["kind=temperature", "sensor!=42"]` (`TS.MRANGE ...
+    /// WITHLABELS`).
+    fn ts_mrange(
+        &mut self,
+        range: &TimeSeriesRange,
+        filters: &[&str],
+    ) -> redis::RedisResult<Vec<TimeSeries>> {
+        let mut cmd = redis::cmd("TS.MRANGE");
+        range.write_args(&mut cmd);
+        cmd.arg("WITHLABELS").arg("FILTER").arg(filters);
+        cmd.query(self)
+    }
+}
+
+impl<C: ConnectionLike> TimeSeriesCommands for C {}
+
+/// Writes large numbers of samples to RedisTimeSeries, in chunks of
+/// `TS.MADD` sent over several pooled connections at once, e.g. to backfill
+/// metrics or to flush a buffer of IoT readings.
+///
+/// Requires the `timeseries` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, TimeSeriesIngest, Timestamp};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let readings = (0..100_000u64).map(|i| {
+///         let key = format!("sensor:{}:temperature", i % 100);
+///         (key, Timestamp::from_millis(1_700_000_000_000 + i), 20.0)
+///     });
+///     let written = TimeSeriesIngest::new(pool)
+///         .chunk_size(1000)
+///         .concurrency(4)
+///         .ingest(readings)
+///         .unwrap();
+///     println!("{} samples written", written);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TimeSeriesIngest {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    chunk_size: usize,
+    concurrency: usize,
+}
+
+impl TimeSeriesIngest {
+    /// Creates a `TimeSeriesIngest` writing with connections of `pool`.
+    pub fn new(pool: r2d2::Pool<RedisConnectionManager>) -> TimeSeriesIngest {
+        TimeSeriesIngest {
+            pool,
+            chunk_size: 500,
+            concurrency: 1,
+        }
+    }
+
+    /// Sets how many samples each `TS.MADD` writes.
+    ///
+    /// Defaults to 500.
+    pub fn chunk_size(mut self, chunk_size: usize) -> TimeSeriesIngest {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets how many chunks are written at once, each on its own connection;
+    /// capped by the size of the pool.
+    ///
+    /// Defaults to 1.
+    pub fn concurrency(mut self, concurrency: usize) -> TimeSeriesIngest {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Writes `samples`, creating the series that don't exist with the
+    /// server's defaults, and returns how many were written.
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first chunk that fails, e.g. because a sample is older
+    /// than the retention of its series, and returns its error; the chunks
+    /// written until then, possibly on other connections, are kept.
+    pub fn ingest<I, K>(&self, samples: I) -> redis::RedisResult<u64>
+    where
+        I: IntoIterator<Item = (K, Timestamp, f64)>,
+        I::IntoIter: Send,
+        K: ToRedisArgs,
+    {
+        let samples = Mutex::new(samples.into_iter());
+        let written = AtomicU64::new(0);
+        let failed = AtomicBool::new(false);
+        let workers = self.concurrency.min(self.pool.max_size() as usize);
+        let errors: Vec<redis::RedisError> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let result = self.write_chunks(&samples, &written, &failed);
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        result
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().unwrap().err())
+                .collect()
+        });
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(written.into_inner()),
+        }
+    }
+
+    /// Writes chunks taken from `samples` on a single connection until there
+    /// are none left or another worker failed.
+    fn write_chunks<I, K>(
+        &self,
+        samples: &Mutex<I>,
+        written: &AtomicU64,
+        failed: &AtomicBool,
+    ) -> redis::RedisResult<()>
+    where
+        I: Iterator<Item = (K, Timestamp, f64)>,
+        K: ToRedisArgs,
+    {
+        let mut conn = checkout(&self.pool)?;
+        while !failed.load(Ordering::Relaxed) {
+            let mut cmd = redis::cmd("TS.MADD");
+            let mut len = 0;
+            {
+                let mut samples = samples.lock().unwrap();
+                for (key, timestamp, value) in samples.by_ref().take(self.chunk_size) {
+                    cmd.arg(key).arg(timestamp).arg(value);
+                    len += 1;
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            cmd.query::<()>(&mut *conn)?;
+            written.fetch_add(len, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(millis: u64) -> Timestamp {
+        Timestamp::from_millis(millis)
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_500);
+        assert_eq!(ts(1_500), Timestamp::from(time));
+        assert_eq!(time, SystemTime::from(ts(1_500)));
+        assert_eq!(ts(0), Timestamp::from(UNIX_EPOCH - Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut conn = crate::local_pool(3).get().unwrap();
+        let key = format!("redis_r2d2-ts-{}", crate::lock::token());
+        let options = TimeSeriesOptions::new()
+            .retention(Duration::from_secs(3600))
+            .duplicate_policy(DuplicatePolicy::Last)
+            .label("test", &key);
+        conn.ts_create(&key, &options).unwrap();
+        assert_eq!(None, conn.ts_get(&key).unwrap());
+
+        for (millis, value) in [(1000, 1.0), (1500, 3.0), (2000, 5.0), (3500, 7.5)] {
+            assert_eq!(ts(millis), conn.ts_add(&key, ts(millis), value).unwrap());
+        }
+        let latest = Sample {
+            timestamp: ts(3500),
+            value: 7.5,
+        };
+        assert_eq!(Some(latest), conn.ts_get(&key).unwrap());
+
+        let samples = conn
+            .ts_range(&key, &TimeSeriesRange::between(ts(1500), ts(3000)))
+            .unwrap();
+        assert_eq!(vec![ts(1500), ts(2000)], timestamps(&samples));
+        let samples = conn
+            .ts_revrange(&key, &TimeSeriesRange::all().count(2))
+            .unwrap();
+        assert_eq!(vec![ts(3500), ts(2000)], timestamps(&samples));
+
+        let per_second = TimeSeriesRange::all()
+            .filter_by_value(2.0, 10.0)
+            .aggregation(Aggregation::new(Aggregator::Avg, Duration::from_secs(1)));
+        let buckets = conn.ts_range(&key, &per_second).unwrap();
+        let values: Vec<_> = buckets.iter().map(|sample| sample.value).collect();
+        assert_eq!(vec![ts(1000), ts(2000), ts(3000)], timestamps(&buckets));
+        assert_eq!(vec![3.0, 5.0, 7.5], values);
+
+        let filter = format!("test={}", key);
+        let series = conn
+            .ts_mrange(&TimeSeriesRange::all().count(1), &[&filter])
+            .unwrap();
+        assert_eq!(1, series.len());
+        assert_eq!(key, series[0].key);
+        assert_eq!(Some(&key), series[0].labels.get("test"));
+        assert_eq!(vec![ts(1000)], timestamps(&series[0].samples));
+    }
+
+    #[test]
+    fn test_ingest() {
+        let pool = crate::local_pool(3);
+        let prefix = format!("redis_r2d2-ts-{}", crate::lock::token());
+        let samples: Vec<_> = (0..1000u64)
+            .map(|i| (format!("{}:{}", prefix, i % 3), ts(i + 1), i as f64))
+            .collect();
+        let written = TimeSeriesIngest::new(pool.clone())
+            .chunk_size(64)
+            .concurrency(3)
+            .ingest(samples)
+            .unwrap();
+        assert_eq!(1000, written);
+
+        let mut conn = pool.get().unwrap();
+        for i in 0..3 {
+            let key = format!("{}:{}", prefix, i);
+            let samples = conn.ts_range(&key, &TimeSeriesRange::all()).unwrap();
+            assert_eq!(if i == 0 { 334 } else { 333 }, samples.len());
+        }
+    }
+
+    fn timestamps(samples: &[Sample]) -> Vec<Timestamp> {
+        samples.iter().map(|sample| sample.timestamp).collect()
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{Credentials, CredentialsProvider};
+
+/// Short-lived credentials produced by a `TokenGenerator`.
+#[derive(Debug, Clone)]
+pub struct Token {
+    /// The credentials to authenticate with, usually the token as the
+    /// password.
+    pub credentials: Credentials,
+    /// When the token stops being accepted for new authentications.
+    pub expires_at: Instant,
+}
+
+/// Generates the short-lived tokens used to authenticate with managed
+/// offerings such as AWS ElastiCache with IAM authentication or Azure Cache
+/// for Redis with Microsoft Entra ID.
+///
+/// See `TokenCredentialsProvider`.
+pub trait TokenGenerator: fmt::Debug + Send + Sync + 'static {
+    /// Generates a new token.
+    ///
+    /// This is only called when the previous token is about to expire, but
+    /// it is called synchronously while a connection is being authenticated.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection attempt fails with it.
+    fn generate(&self) -> redis::RedisResult<Token>;
+}
+
+/// A `CredentialsProvider` authenticating with tokens from a
+/// `TokenGenerator`, which are reused until shortly before they expire.
+///
+/// For AWS ElastiCache, the generator returns the IAM-enabled user ID as
+/// the username and a SigV4-presigned `connect` request for the replication
+/// group as the password, valid for 15 minutes. ElastiCache closes IAM
+/// authenticated connections after 12 hours, so set a shorter
+/// `max_lifetime` on the manager.
+///
+/// For Azure Cache for Redis, the generator returns the object ID of the
+/// managed identity or service principal as the username and an Entra ID
+/// access token for the `https://redis.azure.com/.default` scope as the
+/// password, expiring when the token does. Azure closes connections whose
+/// token expired, so pooled connections are re-authenticated with a fresh
+/// token when they are checked out within a minute of the expiry.
+///
+/// For GCP Memorystore, the generator returns an OAuth access token of the
+/// service account as the password, without a username. Set a
+/// `reauthenticate_interval` on the manager shorter than the token lifetime
+/// so pooled connections keep authenticating with current tokens.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::{Duration, Instant};
+///
+/// use redis_r2d2::{
+///     r2d2, redis, Credentials, RedisConnectionManager, Token, TokenCredentialsProvider,
+///     TokenGenerator,
+/// };
+///
+/// #[derive(Debug)]
+/// struct ElastiCacheIam {
+///     user_id: String,
+/// }
+///
+/// impl TokenGenerator for ElastiCacheIam {
+///     fn generate(&self) -> redis::RedisResult<Token> {
+///         // Presign the request with the AWS SDK here.
+///         let token = String::new();
+///         Ok(Token {
+///             credentials: Credentials::user(self.user_id.clone(), token),
+///             expires_at: Instant::now() + Duration::from_secs(15 * 60),
+///         })
+///     }
+/// }
+///
+/// fn main() {
+///     let generator = ElastiCacheIam {
+///         user_id: "app".to_string(),
+///     };
+///     let manager = RedisConnectionManager::builder()
+///         .credentials_provider(Box::new(TokenCredentialsProvider::new(Box::new(generator))))
+///         .max_lifetime(Some(Duration::from_secs(11 * 60 * 60)))
+///         .build("rediss://my-cache.xxxxxx.cache.amazonaws.com:6379");
+/// }
+/// ```
+pub struct TokenCredentialsProvider {
+    generator: Box<dyn TokenGenerator>,
+    refresh_margin: Duration,
+    cached: Mutex<Option<Token>>,
+}
+
+impl TokenCredentialsProvider {
+    /// Creates a `TokenCredentialsProvider` using the tokens of `generator`.
+    pub fn new(generator: Box<dyn TokenGenerator>) -> TokenCredentialsProvider {
+        TokenCredentialsProvider {
+            generator,
+            refresh_margin: Duration::from_secs(60),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Sets how long before its expiry a token is replaced, so it doesn't
+    /// expire between being handed out and being sent.
+    ///
+    /// Defaults to 1 minute.
+    pub fn refresh_margin(mut self, refresh_margin: Duration) -> TokenCredentialsProvider {
+        self.refresh_margin = refresh_margin;
+        self
+    }
+
+    /// Returns the cached token, generating a new one if it is about to
+    /// expire.
+    pub fn token(&self) -> redis::RedisResult<Token> {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some(ref token) = *cached {
+            if Instant::now() + self.refresh_margin < token.expires_at {
+                return Ok(token.clone());
+            }
+        }
+        let token = self.generator.generate()?;
+        *cached = Some(token.clone());
+        Ok(token)
+    }
+}
+
+impl fmt::Debug for TokenCredentialsProvider {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TokenCredentialsProvider")
+            .field("generator", &self.generator)
+            .field("refresh_margin", &self.refresh_margin)
+            .finish()
+    }
+}
+
+impl CredentialsProvider for TokenCredentialsProvider {
+    fn credentials(&self) -> redis::RedisResult<Credentials> {
+        self.token().map(|token| token.credentials)
+    }
+
+    fn expiring_credentials(&self) -> redis::RedisResult<(Credentials, Option<Instant>)> {
+        self.token()
+            .map(|token| (token.credentials, Some(token.expires_at)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct Counting {
+        generated: Arc<AtomicUsize>,
+        valid_for: Duration,
+    }
+
+    impl TokenGenerator for Counting {
+        fn generate(&self) -> redis::RedisResult<Token> {
+            let n = self.generated.fetch_add(1, Ordering::SeqCst);
+            Ok(Token {
+                credentials: Credentials::user("app", format!("token-{}", n)),
+                expires_at: Instant::now() + self.valid_for,
+            })
+        }
+    }
+
+    #[test]
+    fn test_token_credentials_provider() {
+        let generated = Arc::new(AtomicUsize::new(0));
+        let provider = TokenCredentialsProvider::new(Box::new(Counting {
+            generated: generated.clone(),
+            valid_for: Duration::from_secs(15 * 60),
+        }));
+        assert_eq!(
+            Credentials::user("app", "token-0"),
+            provider.credentials().unwrap()
+        );
+        assert_eq!(
+            Credentials::user("app", "token-0"),
+            provider.credentials().unwrap()
+        );
+        assert_eq!(1, generated.load(Ordering::SeqCst));
+        assert!(!format!("{:?}", provider).contains("token-0"));
+
+        // Tokens within the refresh margin of their expiry are replaced.
+        let provider = TokenCredentialsProvider::new(Box::new(Counting {
+            generated: generated.clone(),
+            valid_for: Duration::from_secs(30),
+        }));
+        provider.credentials().unwrap();
+        provider.credentials().unwrap();
+        assert_eq!(3, generated.load(Ordering::SeqCst));
+
+        // With a shorter margin, the last one is still good.
+        let provider = provider.refresh_margin(Duration::from_secs(10));
+        provider.credentials().unwrap();
+        assert_eq!(3, generated.load(Ordering::SeqCst));
+    }
+}
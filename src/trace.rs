@@ -0,0 +1,15 @@
+use std::time::Instant;
+
+/// Emits an event for the outcome of a manager operation started at
+/// `start`, within the operation's span.
+pub(crate) fn finished<T>(result: &redis::RedisResult<T>, start: Instant) {
+    match *result {
+        Ok(_) => tracing::debug!(elapsed = ?start.elapsed(), "succeeded"),
+        Err(ref e) => tracing::warn!(
+            elapsed = ?start.elapsed(),
+            error_kind = ?e.kind(),
+            error = %e,
+            "failed"
+        ),
+    }
+}
@@ -0,0 +1,238 @@
+use std::thread;
+use std::time::Duration;
+
+use redis::ToRedisArgs;
+
+use crate::pool_ext::checkout;
+use crate::{ReconnectPolicy, RedisConnection, RedisConnectionManager};
+
+/// Runs an optimistic transaction on a connection of `pool`, retrying it
+/// with the default `Transaction` settings while the watched `keys` change.
+///
+/// See `Transaction::run`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, transaction, RedisConnectionManager};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let balance: i64 = transaction(&pool, &["balance"], |conn, pipe| {
+///         let balance: i64 = redis::cmd("GET").arg("balance").query(conn)?;
+///         pipe.cmd("SET").arg("balance").arg(balance * 2).ignore();
+///         pipe.cmd("GET").arg("balance");
+///         pipe.query::<Option<(i64,)>>(conn)
+///             .map(|reply| reply.map(|(balance,)| balance))
+///     })
+///     .unwrap();
+///     println!("{}", balance);
+/// }
+/// ```
+pub fn transaction<K, T, F>(
+    pool: &r2d2::Pool<RedisConnectionManager>,
+    keys: &[K],
+    f: F,
+) -> redis::RedisResult<T>
+where
+    K: ToRedisArgs,
+    F: FnMut(&mut RedisConnection, &mut redis::Pipeline) -> redis::RedisResult<Option<T>>,
+{
+    Transaction::new().run(pool, keys, f)
+}
+
+/// Settings for optimistic transactions with `WATCH` and `MULTI`/`EXEC`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transaction {
+    max_retries: u32,
+    backoff: ReconnectPolicy,
+}
+
+impl Default for Transaction {
+    fn default() -> Transaction {
+        Transaction {
+            max_retries: 10,
+            backoff: ReconnectPolicy {
+                initial_delay: Duration::from_millis(5),
+                multiplier: 2.0,
+                max_delay: Duration::from_millis(200),
+                jitter: 0.5,
+            },
+        }
+    }
+}
+
+impl Transaction {
+    /// Creates a `Transaction` with the default settings.
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    /// Sets how many more times the transaction runs when the watched keys
+    /// changed before `EXEC`.
+    ///
+    /// Defaults to 10.
+    pub fn max_retries(mut self, max_retries: u32) -> Transaction {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets how long to wait before each retry.
+    ///
+    /// Defaults to 5 milliseconds, doubling up to 200 milliseconds, spread
+    /// by half of that either way so competing clients don't collide again.
+    pub fn backoff(mut self, backoff: ReconnectPolicy) -> Transaction {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Runs an optimistic transaction on a connection of `pool`.
+    ///
+    /// The connection `WATCH`es `keys`, and `f` is given it along with an
+    /// atomic pipeline. It reads what it needs on the connection, queues
+    /// the writes on the pipeline and runs the pipeline, returning its
+    /// reply, which is `None` if a watched key changed in the meantime and
+    /// the transaction was aborted. It then runs again, after a short delay,
+    /// up to `max_retries` times; the pipeline is recreated for each run.
+    ///
+    /// The connection is always `UNWATCH`ed before it is returned to the
+    /// pool, even if `f` returned early or failed, and discarded if that
+    /// fails, so no later borrower's transaction is aborted by stale
+    /// watches.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `f`, an error if no connection could be checked
+    /// out, and an error of kind `TryAgain` if the watched keys changed on
+    /// every attempt.
+    pub fn run<K, T, F>(
+        &self,
+        pool: &r2d2::Pool<RedisConnectionManager>,
+        keys: &[K],
+        mut f: F,
+    ) -> redis::RedisResult<T>
+    where
+        K: ToRedisArgs,
+        F: FnMut(&mut RedisConnection, &mut redis::Pipeline) -> redis::RedisResult<Option<T>>,
+    {
+        let mut conn = checkout(pool)?;
+        let mut attempts = 0;
+        loop {
+            let result = attempt(&mut conn, keys, &mut f);
+            if redis::cmd("UNWATCH").query::<()>(&mut *conn).is_err() {
+                conn.mark_broken();
+            }
+            match result? {
+                Some(value) => return Ok(value),
+                None if attempts == self.max_retries => {
+                    return Err((
+                        redis::ErrorKind::TryAgain,
+                        "watched keys kept changing",
+                        format!("transaction aborted {} times", attempts + 1),
+                    )
+                        .into())
+                }
+                None => {
+                    attempts += 1;
+                    thread::sleep(self.backoff.delay(attempts));
+                }
+            }
+        }
+    }
+}
+
+fn attempt<K, T, F>(
+    conn: &mut RedisConnection,
+    keys: &[K],
+    f: &mut F,
+) -> redis::RedisResult<Option<T>>
+where
+    K: ToRedisArgs,
+    F: FnMut(&mut RedisConnection, &mut redis::Pipeline) -> redis::RedisResult<Option<T>>,
+{
+    if !keys.is_empty() {
+        redis::cmd("WATCH").arg(keys).query::<()>(conn)?;
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    f(conn, &mut pipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incr(
+        conn: &mut RedisConnection,
+        pipe: &mut redis::Pipeline,
+    ) -> redis::RedisResult<Option<i64>> {
+        let n: Option<i64> = redis::cmd("GET").arg("redis_r2d2-tx").query(conn)?;
+        let n = n.unwrap_or(0) + 1;
+        pipe.cmd("SET").arg("redis_r2d2-tx").arg(n).ignore();
+        pipe.query::<Option<()>>(conn)
+            .map(|reply| reply.map(|()| n))
+    }
+
+    #[test]
+    fn test_transaction() {
+        let pool = crate::local_pool(1);
+        let mut other = redis::Client::open("redis://localhost")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        redis::cmd("SET")
+            .arg("redis_r2d2-tx")
+            .arg(1)
+            .query::<()>(&mut other)
+            .unwrap();
+
+        let mut attempts = 0;
+        let n = transaction(&pool, &["redis_r2d2-tx"], |conn, pipe| {
+            attempts += 1;
+            if attempts == 1 {
+                // Another client wins the race.
+                redis::cmd("INCR")
+                    .arg("redis_r2d2-tx")
+                    .query::<()>(&mut other)?;
+            }
+            incr(conn, pipe)
+        })
+        .unwrap();
+        assert_eq!(2, attempts);
+        assert_eq!(3, n);
+    }
+
+    #[test]
+    fn test_retries_exhausted() {
+        let pool = crate::local_pool(1);
+        let mut other = redis::Client::open("redis://localhost")
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let mut attempts = 0;
+        let err = Transaction::new()
+            .max_retries(2)
+            .run(&pool, &["redis_r2d2-tx-busy"], |conn, pipe| {
+                attempts += 1;
+                redis::cmd("INCR")
+                    .arg("redis_r2d2-tx-busy")
+                    .query::<()>(&mut other)?;
+                pipe.cmd("INCR").arg("redis_r2d2-tx-busy").ignore();
+                pipe.query::<Option<()>>(conn)
+            })
+            .unwrap_err();
+        assert_eq!(redis::ErrorKind::TryAgain, err.kind());
+        assert_eq!(3, attempts);
+
+        // The connection was unwatched before it was returned.
+        let mut conn = pool.get().unwrap();
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("INCR").arg("redis_r2d2-tx-busy").ignore();
+        redis::cmd("INCR")
+            .arg("redis_r2d2-tx-busy")
+            .query::<()>(&mut other)
+            .unwrap();
+        assert!(pipe.query::<Option<()>>(&mut *conn).unwrap().is_some());
+    }
+}
@@ -0,0 +1,300 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::pool_ext::checkout;
+use crate::{RedisConnectionManager, RedisPubSubConnectionManager};
+
+/// Turns the messages of a `TypedPublisher` and `TypedSubscriber`, and the
+/// values of a `Cache`, into payloads and back.
+///
+/// Requires the `serde` feature.
+pub trait Codec {
+    /// Serializes `value` into a payload.
+    fn encode<T: Serialize>(&self, value: &T) -> redis::RedisResult<Vec<u8>>;
+
+    /// Deserializes a payload.
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> redis::RedisResult<T>;
+}
+
+/// Encodes messages as JSON with `serde_json`.
+///
+/// Requires the `serde` feature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> redis::RedisResult<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| {
+            (
+                redis::ErrorKind::TypeError,
+                "couldn't encode message",
+                e.to_string(),
+            )
+                .into()
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> redis::RedisResult<T> {
+        serde_json::from_slice(payload).map_err(|e| {
+            (
+                redis::ErrorKind::TypeError,
+                "couldn't decode message",
+                e.to_string(),
+            )
+                .into()
+        })
+    }
+}
+
+/// Publishes messages of type `T` to a channel, on connections of a pool.
+///
+/// Requires the `serde` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisConnectionManager, TypedPublisher};
+///
+/// #[derive(serde::Serialize)]
+/// struct OrderPlaced {
+///     id: u64,
+/// }
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let publisher = TypedPublisher::new(pool, "orders");
+///     publisher.publish(&OrderPlaced { id: 1 }).unwrap();
+/// }
+/// ```
+pub struct TypedPublisher<T, C = JsonCodec> {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    channel: String,
+    codec: C,
+    _message: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize> TypedPublisher<T> {
+    /// Creates a `TypedPublisher` for `channel` encoding messages as JSON.
+    pub fn new<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        channel: S,
+    ) -> TypedPublisher<T> {
+        TypedPublisher::with_codec(pool, channel, JsonCodec)
+    }
+}
+
+impl<T: Serialize, C: Codec> TypedPublisher<T, C> {
+    /// Creates a `TypedPublisher` for `channel` encoding messages with
+    /// `codec`.
+    pub fn with_codec<S: Into<String>>(
+        pool: r2d2::Pool<RedisConnectionManager>,
+        channel: S,
+        codec: C,
+    ) -> TypedPublisher<T, C> {
+        TypedPublisher {
+            pool,
+            channel: channel.into(),
+            codec,
+            _message: PhantomData,
+        }
+    }
+
+    /// Publishes `message`, returning the number of clients that received
+    /// it.
+    pub fn publish(&self, message: &T) -> redis::RedisResult<usize> {
+        let payload = self.codec.encode(message)?;
+        let mut conn = checkout(&self.pool)?;
+        redis::cmd("PUBLISH")
+            .arg(&self.channel)
+            .arg(payload)
+            .query(&mut *conn)
+    }
+}
+
+impl<T, C: Clone> Clone for TypedPublisher<T, C> {
+    fn clone(&self) -> TypedPublisher<T, C> {
+        TypedPublisher {
+            pool: self.pool.clone(),
+            channel: self.channel.clone(),
+            codec: self.codec.clone(),
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for TypedPublisher<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TypedPublisher")
+            .field("channel", &self.channel)
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+/// Receives messages of type `T` from a channel, on a connection checked
+/// out of a pool of a `RedisPubSubConnectionManager`.
+///
+/// The connection is subscribed to the channel while the subscriber lives,
+/// and unsubscribed and returned to the pool, with its read timeout
+/// restored, when it is dropped. Messages on the other channels or patterns
+/// the connection is subscribed to are skipped. A message that can't be
+/// decoded is returned as an error, without affecting the next ones.
+///
+/// Requires the `serde` feature.
+///
+/// ## Example
+///
+/// ```no_run
+/// use redis_r2d2::{r2d2, RedisPubSubConnectionManager, TypedSubscriber};
+///
+/// #[derive(Debug, serde::Deserialize)]
+/// struct OrderPlaced {
+///     id: u64,
+/// }
+///
+/// fn main() {
+///     let manager = RedisPubSubConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let mut subscriber = TypedSubscriber::<OrderPlaced>::new(&pool, "orders").unwrap();
+///     println!("{:?}", subscriber.recv().unwrap());
+/// }
+/// ```
+pub struct TypedSubscriber<T, C = JsonCodec> {
+    conn: r2d2::PooledConnection<RedisPubSubConnectionManager>,
+    channel: String,
+    codec: C,
+    read_timeout: Option<Duration>,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedSubscriber<T> {
+    /// Checks out a connection from `pool` and subscribes it to `channel`,
+    /// decoding messages as JSON.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no connection could be checked out or subscribed.
+    pub fn new<S: Into<String>>(
+        pool: &r2d2::Pool<RedisPubSubConnectionManager>,
+        channel: S,
+    ) -> redis::RedisResult<TypedSubscriber<T>> {
+        TypedSubscriber::with_codec(pool, channel, JsonCodec)
+    }
+}
+
+impl<T: DeserializeOwned, C: Codec> TypedSubscriber<T, C> {
+    /// Checks out a connection from `pool` and subscribes it to `channel`,
+    /// decoding messages with `codec`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no connection could be checked out or subscribed.
+    pub fn with_codec<S: Into<String>>(
+        pool: &r2d2::Pool<RedisPubSubConnectionManager>,
+        channel: S,
+        codec: C,
+    ) -> redis::RedisResult<TypedSubscriber<T, C>> {
+        let mut conn = checkout(pool)?;
+        let channel = channel.into();
+        conn.subscribe(&channel[..])?;
+        let read_timeout = conn.read_timeout();
+        Ok(TypedSubscriber {
+            conn,
+            channel,
+            codec,
+            read_timeout,
+            _message: PhantomData,
+        })
+    }
+
+    /// Returns the next message, waiting for one for at most the read
+    /// timeout of the pool's connections.
+    pub fn recv(&mut self) -> redis::RedisResult<T> {
+        loop {
+            let msg = self.conn.get_message()?;
+            if msg.from_pattern() || msg.get_channel_name() != self.channel {
+                continue;
+            }
+            return self.codec.decode(msg.get_payload_bytes());
+        }
+    }
+
+    /// Returns the next message, or `None` if none arrives within `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> redis::RedisResult<Option<T>> {
+        self.conn.set_read_timeout(Some(timeout))?;
+        let result = self.recv();
+        self.conn.set_read_timeout(self.read_timeout)?;
+        match result {
+            Ok(message) => Ok(Some(message)),
+            Err(ref e) if e.is_timeout() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for TypedSubscriber<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TypedSubscriber")
+            .field("channel", &self.channel)
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+impl<T, C> Drop for TypedSubscriber<T, C> {
+    fn drop(&mut self) {
+        // A failure marks the connection broken, so it isn't reused.
+        let _ = self.conn.set_read_timeout(self.read_timeout);
+        let _ = self.conn.unsubscribe(&self.channel[..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Event {
+        id: u64,
+        name: String,
+    }
+
+    #[test]
+    fn test_typed_pubsub() {
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .build(RedisPubSubConnectionManager::new("redis://localhost").unwrap())
+            .unwrap();
+        let mut subscriber = TypedSubscriber::<Event>::new(&pool, "redis_r2d2-typed").unwrap();
+
+        let publisher = TypedPublisher::new(crate::local_pool(1), "redis_r2d2-typed");
+        let event = Event {
+            id: 1,
+            name: "created".to_string(),
+        };
+        assert_eq!(1, publisher.publish(&event).unwrap());
+        assert_eq!(
+            Some(event),
+            subscriber.recv_timeout(Duration::from_secs(5)).unwrap()
+        );
+
+        let mut conn = pool.get_timeout(Duration::from_millis(10));
+        assert!(conn.is_err());
+        drop(subscriber);
+        conn = pool.get_timeout(Duration::from_secs(1));
+        assert!(!conn.unwrap().is_subscribed());
+    }
+
+    #[test]
+    fn test_json_codec() {
+        let payload = JsonCodec.encode(&vec![1, 2]).unwrap();
+        assert_eq!(b"[1,2]".to_vec(), payload);
+        assert_eq!(vec![1, 2], JsonCodec.decode::<Vec<u32>>(&payload).unwrap());
+        assert!(JsonCodec.decode::<Vec<u32>>(b"{").is_err());
+    }
+}
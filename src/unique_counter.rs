@@ -0,0 +1,226 @@
+use std::time::{Duration, SystemTime};
+
+use redis::ToRedisArgs;
+
+use crate::pool_ext::checkout;
+use crate::{buckets, RedisConnectionManager};
+
+/// Counts distinct items, e.g. unique visitors, with HyperLogLogs.
+///
+/// Each counter takes at most 12 KB however many items it sees, and its
+/// counts are estimates with a standard error of 0.81%.
+///
+/// A counter can be split into time buckets with `per`, e.g. daily uniques:
+/// items then go to the bucket of the current time, stored under
+/// `<key>:<bucket>` and expiring a `retention` after the bucket ends, and
+/// `count_between` counts the distinct items over several buckets, e.g. for
+/// weekly uniques of a daily counter. With Redis Cluster, wrap the key in a
+/// hash tag such as `{visitors}`, so the buckets are on the same node.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use redis_r2d2::{r2d2, RedisConnectionManager, UniqueCounter};
+///
+/// fn main() {
+///     let manager = RedisConnectionManager::new("redis://localhost").unwrap();
+///     let pool = r2d2::Pool::builder().build(manager).unwrap();
+///     let daily = UniqueCounter::new(pool, "{visitors}")
+///         .per(Duration::from_secs(24 * 3600))
+///         .retention(Duration::from_secs(30 * 24 * 3600));
+///     daily.add(&["user:1", "user:2", "user:1"]).unwrap();
+///     println!("today: {}", daily.count().unwrap());
+///     println!("last 7 days: {}", daily.count_last(7).unwrap());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct UniqueCounter {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    key: String,
+    period: Option<Duration>,
+    retention: Option<Duration>,
+    pinned: Option<SystemTime>,
+}
+
+impl UniqueCounter {
+    /// Creates a `UniqueCounter` stored under `key`, with connections of
+    /// `pool`.
+    pub fn new<S: Into<String>>(pool: r2d2::Pool<RedisConnectionManager>, key: S) -> UniqueCounter {
+        UniqueCounter {
+            pool,
+            key: key.into(),
+            period: None,
+            retention: None,
+            pinned: None,
+        }
+    }
+
+    /// Splits the counter into buckets of `period`, starting at multiples of
+    /// `period` since the Unix epoch.
+    pub fn per(mut self, period: Duration) -> UniqueCounter {
+        self.period = Some(period);
+        self
+    }
+
+    /// Sets how long buckets are kept after they end.
+    ///
+    /// Defaults to one period.
+    pub fn retention(mut self, retention: Duration) -> UniqueCounter {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Returns the counter of the bucket containing `time`, instead of the
+    /// current one. Without `per`, returns the same counter.
+    pub fn at(&self, time: SystemTime) -> UniqueCounter {
+        UniqueCounter {
+            pinned: Some(time),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the key of the HyperLogLog, for the current bucket if the
+    /// counter has buckets.
+    pub fn key(&self) -> String {
+        let time = self.pinned.unwrap_or_else(SystemTime::now);
+        self.key_at(time)
+    }
+
+    /// Counts `items` (`PFADD`), anything converting to one or several Redis
+    /// arguments, e.g. a slice, returning whether the count changed.
+    pub fn add<I: ToRedisArgs>(&self, items: I) -> redis::RedisResult<bool> {
+        let key = self.key();
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("PFADD").arg(&key).arg(items);
+        if let Some(period) = self.period {
+            let time = self.pinned.unwrap_or_else(SystemTime::now);
+            let retention = self.retention.unwrap_or(period);
+            let ttl = buckets::ttl(buckets::index(time, period), period, retention);
+            pipe.cmd("PEXPIRE")
+                .arg(&key)
+                .arg(ttl.as_millis() as u64)
+                .ignore();
+        }
+        let (changed,): (bool,) = pipe.query(&mut *checkout(&self.pool)?)?;
+        Ok(changed)
+    }
+
+    /// Returns the estimated number of distinct items counted (`PFCOUNT`),
+    /// in the current bucket if the counter has buckets.
+    pub fn count(&self) -> redis::RedisResult<u64> {
+        redis::cmd("PFCOUNT")
+            .arg(self.key())
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns the estimated number of distinct items counted in the buckets
+    /// from the one containing `start` to the one containing `end`, both
+    /// included; an item counted in several buckets is counted once.
+    ///
+    /// Without `per`, returns `count`.
+    pub fn count_between(&self, start: SystemTime, end: SystemTime) -> redis::RedisResult<u64> {
+        let keys = self.keys_between(start, end);
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        redis::cmd("PFCOUNT")
+            .arg(&keys[..])
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    /// Returns the estimated number of distinct items counted in the last
+    /// `buckets` buckets, up to the current one, e.g. `count_last(7)` for
+    /// the weekly uniques of a daily counter.
+    ///
+    /// Without `per`, returns `count`.
+    pub fn count_last(&self, buckets: u32) -> redis::RedisResult<u64> {
+        if buckets == 0 {
+            return Ok(0);
+        }
+        let end = self.pinned.unwrap_or_else(SystemTime::now);
+        let period = self.period.unwrap_or_default();
+        let start = end
+            .checked_sub(period * (buckets - 1))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        self.count_between(start, end)
+    }
+
+    /// Stores the union of the buckets from the one containing `start` to the
+    /// one containing `end` in the HyperLogLog `destination` (`PFMERGE`),
+    /// e.g. to keep monthly uniques after the daily buckets expire.
+    ///
+    /// `destination` keeps the items it already counted, and doesn't expire.
+    pub fn merge_between<K: ToRedisArgs>(
+        &self,
+        destination: K,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> redis::RedisResult<()> {
+        redis::cmd("PFMERGE")
+            .arg(destination)
+            .arg(&self.keys_between(start, end)[..])
+            .query(&mut *checkout(&self.pool)?)
+    }
+
+    fn key_at(&self, time: SystemTime) -> String {
+        match self.period {
+            Some(period) => format!("{}:{}", self.key, buckets::index(time, period)),
+            None => self.key.clone(),
+        }
+    }
+
+    fn keys_between(&self, start: SystemTime, end: SystemTime) -> Vec<String> {
+        let period = match self.period {
+            Some(period) => period,
+            None => return vec![self.key.clone()],
+        };
+        (buckets::index(start, period)..=buckets::index(end, period))
+            .map(|bucket| format!("{}:{}", self.key, bucket))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter() -> UniqueCounter {
+        let pool = crate::local_pool(1);
+        let key = format!("redis_r2d2-uniques-{}", crate::lock::token());
+        UniqueCounter::new(pool, key)
+    }
+
+    #[test]
+    fn test_unique_counter() {
+        let counter = counter();
+        assert_eq!(0, counter.count().unwrap());
+        assert!(counter.add(&["a", "b", "a"]).unwrap());
+        assert!(!counter.add("b").unwrap());
+        assert_eq!(2, counter.count().unwrap());
+        assert_eq!(2, counter.count_last(7).unwrap());
+    }
+
+    #[test]
+    fn test_buckets() {
+        let day = Duration::from_secs(24 * 3600);
+        let counter = counter().per(day);
+        let now = SystemTime::now();
+        let yesterday = counter.at(now - day);
+        assert_ne!(counter.key(), yesterday.key());
+
+        counter.add(&["a", "b"]).unwrap();
+        yesterday.add(&["b", "c"]).unwrap();
+        counter.at(now - 2 * day).add("d").unwrap();
+        assert_eq!(2, counter.count().unwrap());
+        assert_eq!(3, counter.count_last(2).unwrap());
+        assert_eq!(4, counter.count_between(now - 7 * day, now).unwrap());
+        assert_eq!(0, counter.count_last(0).unwrap());
+
+        let rollup = format!("{}:rollup", counter.key);
+        counter.merge_between(&rollup, now - day, now).unwrap();
+        let rollup = UniqueCounter::new(counter.pool.clone(), rollup);
+        assert_eq!(3, rollup.count().unwrap());
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::ConnectionLike;
+
+/// A user supplied connection check, see `ValidationMode::Custom`.
+pub type ValidateFn = dyn Fn(&mut redis::Connection) -> redis::RedisResult<()> + Send + Sync;
+
+/// How `RedisConnectionManager` checks that a pooled connection is still
+/// usable before it is handed out.
+#[derive(Clone, Default)]
+pub enum ValidationMode {
+    /// Issue a `PING` and expect a reply. This is the default.
+    #[default]
+    Ping,
+    /// Only check that the connection has not been closed locally, without a
+    /// round-trip to the server.
+    ///
+    /// This catches sockets that failed a previous read or write, but not a
+    /// peer that went away silently.
+    CheckConnection,
+    /// Do not validate connections at all.
+    None,
+    /// Issue the given command and accept any non-error reply.
+    ///
+    /// Useful when `PING` is blocked by ACLs or a managed offering.
+    Command(redis::Cmd),
+    /// Run a user supplied check against the connection.
+    Custom(Arc<ValidateFn>),
+}
+
+impl ValidationMode {
+    /// Creates a `ValidationMode::Custom` from a closure.
+    pub fn custom<F>(f: F) -> ValidationMode
+    where
+        F: Fn(&mut redis::Connection) -> redis::RedisResult<()> + Send + Sync + 'static,
+    {
+        ValidationMode::Custom(Arc::new(f))
+    }
+
+    pub(crate) fn validate(&self, conn: &mut redis::Connection) -> redis::RedisResult<()> {
+        match *self {
+            ValidationMode::Ping => redis::cmd("PING").query(conn),
+            ValidationMode::CheckConnection => {
+                if conn.is_open() {
+                    Ok(())
+                } else {
+                    Err((redis::ErrorKind::IoError, "connection is closed").into())
+                }
+            }
+            ValidationMode::None => Ok(()),
+            ValidationMode::Command(ref cmd) => cmd.query::<redis::Value>(conn).map(|_| ()),
+            ValidationMode::Custom(ref f) => f(conn),
+        }
+    }
+}
+
+/// Controls how validation reacts to a server that is temporarily unable to
+/// serve requests.
+///
+/// Right after a restart Redis answers `LOADING` until the dataset is in
+/// memory, a long-running script causes `BUSY`, and a cluster that lost
+/// quorum answers `CLUSTERDOWN`. None of these mean the connection itself is
+/// bad, so instead of discarding it the manager waits `delay` and validates
+/// again, up to `max_retries` times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusyRetry {
+    /// How many times validation is retried.
+    pub max_retries: u32,
+    /// How long to wait before each retry.
+    pub delay: Duration,
+}
+
+/// Returns true if the error means the server is temporarily unable to serve
+/// requests rather than that the connection is broken.
+pub(crate) fn is_server_busy(error: &redis::RedisError) -> bool {
+    match error.kind() {
+        redis::ErrorKind::BusyLoadingError
+        | redis::ErrorKind::ClusterDown
+        | redis::ErrorKind::TryAgain
+        | redis::ErrorKind::MasterDown => true,
+        redis::ErrorKind::ExtensionError => error.code() == Some("BUSY"),
+        _ => false,
+    }
+}
+
+impl fmt::Debug for ValidationMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationMode::Ping => fmt.write_str("Ping"),
+            ValidationMode::CheckConnection => fmt.write_str("CheckConnection"),
+            ValidationMode::None => fmt.write_str("None"),
+            ValidationMode::Command(ref cmd) => fmt
+                .debug_tuple("Command")
+                .field(&String::from_utf8_lossy(&cmd.get_packed_command()))
+                .finish(),
+            ValidationMode::Custom(_) => fmt.write_str("Custom(..)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug() {
+        let mut cmd = redis::cmd("ECHO");
+        cmd.arg("hi");
+
+        assert_eq!("Ping", format!("{:?}", ValidationMode::default()));
+        assert_eq!("None", format!("{:?}", ValidationMode::None));
+        assert_eq!(
+            "Command(\"*2\\r\\n$4\\r\\nECHO\\r\\n$2\\r\\nhi\\r\\n\")",
+            format!("{:?}", ValidationMode::Command(cmd))
+        );
+        assert_eq!(
+            "Custom(..)",
+            format!("{:?}", ValidationMode::custom(|_| Ok(())))
+        );
+    }
+
+    #[test]
+    fn test_is_server_busy() {
+        let server_error = |reply: &[u8]| redis::parse_redis_value(reply).unwrap_err();
+        let loading = server_error(b"-LOADING Redis is loading the dataset in memory\r\n");
+        let busy = server_error(b"-BUSY Redis is busy running a script\r\n");
+        let moved = server_error(b"-MOVED 3999 127.0.0.1:6381\r\n");
+        let io = redis::RedisError::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+
+        assert!(is_server_busy(&loading));
+        assert!(is_server_busy(&busy));
+        assert!(!is_server_busy(&moved));
+        assert!(!is_server_busy(&io));
+    }
+}